@@ -0,0 +1,225 @@
+//! A private copy of the curl handle given to `register`, made for every
+//! request.
+//!
+//! The handle given to `register` is shared by every remote of the process,
+//! so requests never set their options on it: they work on a duplicate made
+//! with `curl_easy_duphandle`, which starts with the configuration of the
+//! shared handle (proxy, TLS options, timeouts, ...) and is thrown away once
+//! the request is done.
+
+use std::ffi::CString;
+use std::io;
+use std::os::raw::{c_char, c_long, c_void};
+use std::ptr;
+use std::slice;
+
+use curl::easy::Easy;
+use curl_sys::{curl_slist, CURLoption, CURL};
+
+/// A duplicate of the shared handle, cleaned up when dropped.
+pub struct Handle {
+    raw: *mut CURL,
+    headers: *mut curl_slist,
+    error: Box<[u8; curl_sys::CURL_ERROR_SIZE]>,
+}
+
+/// The headers and body of a response, as collected by `Handle::perform`.
+#[derive(Default)]
+pub struct Transfer {
+    pub headers: Vec<Vec<u8>>,
+    pub data: Vec<u8>,
+}
+
+impl Handle {
+    /// Duplicate `handle` with its current configuration.
+    pub fn new(handle: &Easy) -> io::Result<Handle> {
+        let raw = unsafe { curl_sys::curl_easy_duphandle(handle.raw()) };
+        if raw.is_null() {
+            return Err(curl::Error::new(curl_sys::CURLE_OUT_OF_MEMORY).into());
+        }
+        let mut ret = Handle {
+            raw,
+            headers: ptr::null_mut(),
+            error: Box::new([0; curl_sys::CURL_ERROR_SIZE]),
+        };
+        // curl-rust installs callbacks on its handles whose data point into
+        // the shared handle, which other requests may be using: go back to
+        // the defaults of curl, and collect the response ourselves.
+        for &option in &[
+            curl_sys::CURLOPT_READFUNCTION,
+            curl_sys::CURLOPT_READDATA,
+            curl_sys::CURLOPT_SEEKFUNCTION,
+            curl_sys::CURLOPT_SEEKDATA,
+            curl_sys::CURLOPT_PROGRESSFUNCTION,
+            curl_sys::CURLOPT_PROGRESSDATA,
+            curl_sys::CURLOPT_DEBUGFUNCTION,
+            curl_sys::CURLOPT_DEBUGDATA,
+            curl_sys::CURLOPT_OPENSOCKETFUNCTION,
+            curl_sys::CURLOPT_OPENSOCKETDATA,
+        ] {
+            ret.setopt_ptr(option, ptr::null())?;
+        }
+        // Not every TLS backend supports these.
+        drop(ret.setopt_ptr(curl_sys::CURLOPT_SSL_CTX_FUNCTION, ptr::null()));
+        drop(ret.setopt_ptr(curl_sys::CURLOPT_SSL_CTX_DATA, ptr::null()));
+        let error = ret.error.as_mut_ptr() as *const c_void;
+        ret.setopt_ptr(curl_sys::CURLOPT_ERRORBUFFER, error)?;
+        ret.setopt_long(curl_sys::CURLOPT_NOPROGRESS, 1)?;
+        let cb: curl_sys::curl_write_callback = header_cb;
+        ret.setopt_ptr(curl_sys::CURLOPT_HEADERFUNCTION, cb as *const c_void)?;
+        let cb: curl_sys::curl_write_callback = write_cb;
+        ret.setopt_ptr(curl_sys::CURLOPT_WRITEFUNCTION, cb as *const c_void)?;
+        Ok(ret)
+    }
+
+    pub fn url(&mut self, url: &str) -> io::Result<()> {
+        self.setopt_str(curl_sys::CURLOPT_URL, Some(url))
+    }
+
+    pub fn useragent(&mut self, agent: &str) -> io::Result<()> {
+        self.setopt_str(curl_sys::CURLOPT_USERAGENT, Some(agent))
+    }
+
+    pub fn follow_location(&mut self, follow: bool) -> io::Result<()> {
+        self.setopt_long(curl_sys::CURLOPT_FOLLOWLOCATION, follow as c_long)
+    }
+
+    pub fn proxy(&mut self, proxy: &str) -> io::Result<()> {
+        self.setopt_str(curl_sys::CURLOPT_PROXY, Some(proxy))
+    }
+
+    /// Set the method of the request, and the body sent with it if any.
+    pub fn method(&mut self, method: &str, data: &[u8]) -> io::Result<()> {
+        match method {
+            "GET" => self.setopt_long(curl_sys::CURLOPT_HTTPGET, 1)?,
+            "PUT" => self.setopt_long(curl_sys::CURLOPT_UPLOAD, 1)?,
+            "POST" => self.setopt_long(curl_sys::CURLOPT_POST, 1)?,
+            other => self.setopt_str(curl_sys::CURLOPT_CUSTOMREQUEST, Some(other))?,
+        }
+        if !data.is_empty() {
+            let size = data.len() as curl_sys::curl_off_t;
+            self.cvt(unsafe {
+                curl_sys::curl_easy_setopt(self.raw, curl_sys::CURLOPT_POSTFIELDSIZE_LARGE, size)
+            })?;
+            let data = data.as_ptr() as *const c_void;
+            self.setopt_ptr(curl_sys::CURLOPT_COPYPOSTFIELDS, data)?;
+        }
+        Ok(())
+    }
+
+    /// Send a username and password with basic authentication, or stop
+    /// sending them with `None`.
+    pub fn basic_auth(&mut self, auth: Option<(&str, &str)>) -> io::Result<()> {
+        self.setopt_str(curl_sys::CURLOPT_USERNAME, auth.map(|a| a.0))?;
+        self.setopt_str(curl_sys::CURLOPT_PASSWORD, auth.map(|a| a.1))?;
+        if auth.is_some() {
+            self.setopt_long(
+                curl_sys::CURLOPT_HTTPAUTH,
+                curl_sys::CURLAUTH_BASIC as c_long,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Replace the headers sent with the request.
+    pub fn headers(&mut self, headers: &[String]) -> io::Result<()> {
+        let mut list = ptr::null_mut();
+        for header in headers {
+            let header = CString::new(&header[..])?;
+            let next = unsafe { curl_sys::curl_slist_append(list, header.as_ptr()) };
+            if next.is_null() {
+                unsafe { curl_sys::curl_slist_free_all(list) };
+                return Err(curl::Error::new(curl_sys::CURLE_OUT_OF_MEMORY).into());
+            }
+            list = next;
+        }
+        let result = self.setopt_ptr(curl_sys::CURLOPT_HTTPHEADER, list as *const c_void);
+        // curl doesn't copy the list, keep the one in use alive.
+        let old = match result {
+            Ok(()) => std::mem::replace(&mut self.headers, list),
+            Err(..) => list,
+        };
+        unsafe { curl_sys::curl_slist_free_all(old) };
+        result
+    }
+
+    /// Send the request, returning the response code with what was received.
+    pub fn perform(&mut self) -> io::Result<(u32, Transfer)> {
+        let mut transfer = Transfer::default();
+        let ptr = &mut transfer as *mut Transfer as *const c_void;
+        self.setopt_ptr(curl_sys::CURLOPT_HEADERDATA, ptr)?;
+        self.setopt_ptr(curl_sys::CURLOPT_WRITEDATA, ptr)?;
+        self.error[0] = 0;
+        let result = self.cvt(unsafe { curl_sys::curl_easy_perform(self.raw) });
+        // `transfer` is about to move, don't leave dangling pointers behind.
+        self.setopt_ptr(curl_sys::CURLOPT_HEADERDATA, ptr::null())?;
+        self.setopt_ptr(curl_sys::CURLOPT_WRITEDATA, ptr::null())?;
+        result?;
+        let mut code: c_long = 0;
+        self.cvt(unsafe {
+            curl_sys::curl_easy_getinfo(self.raw, curl_sys::CURLINFO_RESPONSE_CODE, &mut code)
+        })?;
+        Ok((code as u32, transfer))
+    }
+
+    fn setopt_long(&mut self, option: CURLoption, value: c_long) -> io::Result<()> {
+        self.cvt(unsafe { curl_sys::curl_easy_setopt(self.raw, option, value) })
+    }
+
+    fn setopt_ptr(&mut self, option: CURLoption, value: *const c_void) -> io::Result<()> {
+        self.cvt(unsafe { curl_sys::curl_easy_setopt(self.raw, option, value) })
+    }
+
+    /// Set a string option, which curl copies, or reset it with `None`.
+    fn setopt_str(&mut self, option: CURLoption, value: Option<&str>) -> io::Result<()> {
+        let value = value.map(CString::new).transpose()?;
+        let ptr = value.as_ref().map_or(ptr::null(), |s| s.as_ptr());
+        self.cvt(unsafe { curl_sys::curl_easy_setopt(self.raw, option, ptr) })
+    }
+
+    fn cvt(&self, code: curl_sys::CURLcode) -> io::Result<()> {
+        if code == curl_sys::CURLE_OK {
+            return Ok(());
+        }
+        let mut err = curl::Error::new(code);
+        let len = self.error.iter().position(|&b| b == 0).unwrap_or(0);
+        if len > 0 {
+            err.set_extra(String::from_utf8_lossy(&self.error[..len]).into_owned());
+        }
+        Err(err.into())
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe {
+            curl_sys::curl_easy_cleanup(self.raw);
+            curl_sys::curl_slist_free_all(self.headers);
+        }
+    }
+}
+
+extern "C" fn header_cb(ptr: *mut c_char, size: usize, nmemb: usize, data: *mut c_void) -> usize {
+    let (transfer, buf) = unsafe { args(ptr, size * nmemb, data) };
+    transfer.headers.push(buf.to_vec());
+    buf.len()
+}
+
+extern "C" fn write_cb(ptr: *mut c_char, size: usize, nmemb: usize, data: *mut c_void) -> usize {
+    let (transfer, buf) = unsafe { args(ptr, size * nmemb, data) };
+    transfer.data.extend_from_slice(buf);
+    buf.len()
+}
+
+unsafe fn args<'a>(
+    ptr: *mut c_char,
+    len: usize,
+    data: *mut c_void,
+) -> (&'a mut Transfer, &'a [u8]) {
+    let buf = if len == 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(ptr as *const u8, len)
+    };
+    (&mut *(data as *mut Transfer), buf)
+}
@@ -5,6 +5,22 @@
 //! At this time the `register` function is unsafe for the same reasons that
 //! `git2::transport::register` is also unsafe.
 //!
+//! Requests made by the transport honor the options of the fetch or push they
+//! are part of: the credentials callback of `RemoteCallbacks` is asked for a
+//! username and password when the server replies with `401 Unauthorized`,
+//! custom headers are sent along with every request, and a proxy URL given
+//! through `ProxyOptions` is used in place of the one configured on the
+//! handle for the requests of that operation. Redirects are followed
+//! according to `RemoteRedirect`, the same way libgit2's own HTTP transport
+//! does, and credentials are only sent to the scheme, host and port they were
+//! asked for.
+//!
+//! Fetches use version 2 of the git protocol with servers supporting it.
+//! Only the refs matched by the refspecs being fetched, `HEAD` and tags are
+//! listed then, which saves a lot of time on repositories with many refs.
+//! Connections opened outside of a fetch, and fetches without refspecs, list
+//! all refs.
+//!
 //! It is not recommended to use this crate wherever possible. The current
 //! libcurl backend used, `curl-rust`, only supports executing a request in one
 //! method call implying no streaming support. This consequently means that
@@ -26,12 +42,21 @@ use std::io::{self, Cursor};
 use std::str;
 use std::sync::{Arc, Mutex, Once};
 
-use curl::easy::{Easy, List};
-use git2::transport::SmartSubtransportStream;
+use curl::easy::Easy;
+use git2::transport::{ConnectOptions, SmartSubtransportStream, SmartTransport};
 use git2::transport::{Service, SmartSubtransport, Transport};
-use git2::Error;
+use git2::{CredentialType, Error, RemoteRedirect};
 use log::{debug, info};
-use url::Url;
+use url::{Origin, Url};
+
+use crate::handle::Handle;
+
+mod handle;
+mod v2;
+
+/// How many times a request is repeated to follow redirects or to
+/// authenticate, the same limit as libgit2's.
+const MAX_REPLAYS: u32 = 15;
 
 struct CurlTransport {
     handle: Arc<Mutex<Easy>>,
@@ -40,24 +65,63 @@ struct CurlTransport {
     /// This is an empty string until the first action is performed.
     /// If there is an HTTP redirect, this will be updated with the new URL.
     base_url: Arc<Mutex<String>>,
+    /// The credentials accepted by the server, with the origin they were
+    /// asked for.
+    ///
+    /// This is `None` until the server asks for credentials, after which they
+    /// are sent with every request of this transport to that origin.
+    auth: Arc<Mutex<Option<(Origin, Credentials)>>>,
+    /// The fetch refspecs of the remote, to know which refs to list when the
+    /// server speaks protocol v2.
+    fetch_refspecs: Arc<Vec<String>>,
+    /// The fetch in progress if the server speaks protocol v2.
+    v2: Arc<Mutex<Option<v2::Session>>>,
+    owner: Option<SmartTransport>,
 }
 
 struct CurlSubtransport {
     handle: Arc<Mutex<Easy>>,
+    action: Service,
     service: &'static str,
     url_path: &'static str,
     base_url: Arc<Mutex<String>>,
+    auth: Arc<Mutex<Option<(Origin, Credentials)>>>,
+    fetch_refspecs: Arc<Vec<String>>,
+    v2: Arc<Mutex<Option<v2::Session>>>,
+    owner: Option<SmartTransport>,
     method: &'static str,
     reader: Option<Cursor<Vec<u8>>>,
     sent_request: bool,
 }
 
+/// Credentials given by the credentials callback.
+#[derive(Clone)]
+enum Credentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+/// A response to a request made by `CurlSubtransport::perform`.
+struct Response {
+    code: u32,
+    content_type: Option<String>,
+    location: Option<String>,
+    /// The schemes the server accepts for authentication, in lowercase.
+    auth_schemes: Vec<String>,
+    data: Vec<u8>,
+}
+
 /// Register the libcurl backend for HTTP requests made by libgit2.
 ///
 /// This function takes one parameter, a `handle`, which is used to perform all
 /// future HTTP requests. The handle can be previously configured with
 /// information such as proxies, SSL information, etc.
 ///
+/// Every request is made with a copy of the handle, which is never modified
+/// itself. Operations given a proxy with `ProxyOptions::url` make their
+/// requests through it, all others through the proxy of the handle, if any:
+/// automatic proxy detection with `ProxyOptions::auto` amounts to the latter.
+///
 /// This function is unsafe largely for the same reasons as
 /// `git2::transport::register`:
 ///
@@ -80,12 +144,18 @@ pub unsafe fn register(handle: Easy) {
 }
 
 fn factory(remote: &git2::Remote<'_>, handle: Arc<Mutex<Easy>>) -> Result<Transport, Error> {
+    let refspecs = remote.fetch_refspecs()?;
+    let fetch_refspecs = refspecs.iter().flatten().map(String::from).collect();
     Transport::smart(
         remote,
         true,
         CurlTransport {
             handle: handle,
             base_url: Arc::new(Mutex::new(String::new())),
+            auth: Arc::new(Mutex::new(None)),
+            fetch_refspecs: Arc::new(fetch_refspecs),
+            v2: Arc::new(Mutex::new(None)),
+            owner: None,
         },
     )
 }
@@ -111,9 +181,14 @@ impl SmartSubtransport for CurlTransport {
         info!("action {} {}", service, path);
         Ok(Box::new(CurlSubtransport {
             handle: self.handle.clone(),
+            action,
             service: service,
             url_path: path,
             base_url: self.base_url.clone(),
+            auth: self.auth.clone(),
+            fetch_refspecs: self.fetch_refspecs.clone(),
+            v2: self.v2.clone(),
+            owner: self.owner.clone(),
             method: method,
             reader: None,
             sent_request: false,
@@ -123,9 +198,25 @@ impl SmartSubtransport for CurlTransport {
     fn close(&self) -> Result<(), Error> {
         Ok(()) // ...
     }
+
+    fn attach(&mut self, transport: SmartTransport) {
+        self.owner = Some(transport);
+    }
 }
 
 impl CurlSubtransport {
+    /// The prefixes of the refs to list with protocol v2: those matched by
+    /// the refspecs of the fetch in progress, if any.
+    fn ref_prefixes(&self) -> Vec<String> {
+        let operation = self.owner.as_ref().and_then(|owner| owner.operation().ok());
+        let refspecs = match operation.as_ref().and_then(|op| op.refspecs()) {
+            Some([]) => self.fetch_refspecs.to_vec(),
+            Some(refspecs) => refspecs.to_vec(),
+            None => Vec::new(),
+        };
+        v2::ref_prefixes(refspecs.iter().map(|s| &s[..]))
+    }
+
     fn err<E: Into<Box<dyn error::Error + Send + Sync>>>(&self, err: E) -> io::Error {
         io::Error::new(io::ErrorKind::Other, err)
     }
@@ -134,99 +225,99 @@ impl CurlSubtransport {
         if self.sent_request {
             return Err(self.err("already sent HTTP request"));
         }
+        let response = match self.action {
+            Service::UploadPackLs => {
+                let advertisement = self.request(self.url_path, self.method, data)?;
+                match v2::capabilities(&advertisement)? {
+                    Some(caps) => {
+                        debug!("server speaks protocol v2");
+                        let ls_refs = caps.ls_refs(&self.ref_prefixes());
+                        let refs = self.request("/git-upload-pack", "POST", &ls_refs)?;
+                        let advertisement = caps.advertisement(&refs)?;
+                        *self.v2.lock().unwrap() = Some(v2::Session::new(caps));
+                        advertisement
+                    }
+                    None => advertisement,
+                }
+            }
+            Service::UploadPack => {
+                let v2 = self.v2.clone();
+                let mut session = v2.lock().unwrap();
+                match *session {
+                    Some(ref mut session) => session.fetch(data, |request| {
+                        self.request(self.url_path, self.method, request)
+                    })?,
+                    None => self.request(self.url_path, self.method, data)?,
+                }
+            }
+            _ => self.request(self.url_path, self.method, data)?,
+        };
+
+        // Ok, time to read off some data.
+        let rdr = Cursor::new(response);
+        self.reader = Some(rdr);
+
+        Ok(())
+    }
+
+    fn request(&self, url_path: &str, method: &str, data: &[u8]) -> io::Result<Vec<u8>> {
         let agent = format!("git/1.0 (git2-curl {})", env!("CARGO_PKG_VERSION"));
 
         // Parse our input URL to figure out the host
-        let url = format!("{}{}", self.base_url.lock().unwrap(), self.url_path);
-        let parsed = Url::parse(&url).map_err(|_| self.err("invalid url, failed to parse"))?;
-        let host = match parsed.host_str() {
-            Some(host) => host,
-            None => return Err(self.err("invalid url, did not have a host")),
+        let url = format!("{}{}", self.base_url.lock().unwrap(), url_path);
+        let mut url = Url::parse(&url).map_err(|_| self.err("invalid url, failed to parse"))?;
+        if url.host_str().is_none() {
+            return Err(self.err("invalid url, did not have a host"));
+        }
+        let opts = match self.owner {
+            Some(ref owner) => Some(owner.connect_options().map_err(|e| self.err(e))?),
+            None => None,
+        };
+        // Redirects to the same host are always followed, like libgit2 does.
+        let offsite = match opts.as_ref().map(|opts| opts.follow_redirects()) {
+            Some(RemoteRedirect::All) => true,
+            Some(RemoteRedirect::None) => false,
+            _ => method == "GET",
         };
 
-        // Prep the request
-        debug!("request to {}", url);
-        let mut h = self.handle.lock().unwrap();
-        h.url(&url)?;
+        // Prep the request, on a copy of the handle shared by all remotes.
+        let mut h = Handle::new(&self.handle.lock().unwrap())?;
         h.useragent(&agent)?;
-        h.follow_location(true)?;
-        match self.method {
-            "GET" => h.get(true)?,
-            "PUT" => h.put(true)?,
-            "POST" => h.post(true)?,
-            other => h.custom_request(other)?,
-        }
+        h.follow_location(false)?;
+        h.method(method, data)?;
 
-        let mut headers = List::new();
-        headers.append(&format!("Host: {}", host))?;
-        if data.len() > 0 {
-            h.post_fields_copy(data)?;
-            headers.append(&format!(
-                "Accept: application/x-git-{}-result",
-                self.service
-            ))?;
-            headers.append(&format!(
-                "Content-Type: \
-                 application/x-git-{}-request",
-                self.service
-            ))?;
-        } else {
-            headers.append("Accept: */*")?;
+        // Without a proxy URL, automatic detection included, the proxy of the
+        // shared handle is used.
+        if let Some(proxy) = opts.as_ref().and_then(|opts| opts.proxy_url()) {
+            h.proxy(proxy)?;
         }
-        headers.append("Expect:")?;
-        h.http_headers(headers)?;
-
-        let mut content_type = None;
-        let mut data = Vec::new();
+        let mut auth = self.auth.lock().unwrap().clone();
+        let response = self.replay(&mut h, &mut url, data, opts.as_ref(), offsite, &mut auth)?;
+        // Credentials for another origin are of no use to the new base.
+        if auth
+            .as_ref()
+            .is_some_and(|(origin, _)| *origin != url.origin())
         {
-            let mut h = h.transfer();
-
-            // Look for the Content-Type header
-            h.header_function(|header| {
-                let header = match str::from_utf8(header) {
-                    Ok(s) => s,
-                    Err(..) => return true,
-                };
-                let mut parts = header.splitn(2, ": ");
-                let name = parts.next().unwrap();
-                let value = match parts.next() {
-                    Some(value) => value,
-                    None => return true,
-                };
-                if name.eq_ignore_ascii_case("Content-Type") {
-                    content_type = Some(value.trim().to_string());
-                }
-
-                true
-            })?;
-
-            // Collect the request's response in-memory
-            h.write_function(|buf| {
-                data.extend_from_slice(buf);
-                Ok(buf.len())
-            })?;
-
-            // Send the request
-            h.perform()?;
+            auth = None;
         }
+        *self.auth.lock().unwrap() = auth;
 
-        let code = h.response_code()?;
-        if code != 200 {
+        if response.code != 200 {
             return Err(self.err(
                 &format!(
                     "failed to receive HTTP 200 response: \
                      got {}",
-                    code
+                    response.code
                 )[..],
             ));
         }
 
         // Check returned headers
-        let expected = match self.method {
+        let expected = match method {
             "GET" => format!("application/x-git-{}-advertisement", self.service),
             _ => format!("application/x-git-{}-result", self.service),
         };
-        match content_type {
+        match response.content_type {
             Some(ref content_type) if *content_type != expected => {
                 return Err(self.err(
                     &format!(
@@ -248,24 +339,186 @@ impl CurlSubtransport {
             }
         }
 
-        // Ok, time to read off some data.
-        let rdr = Cursor::new(data);
-        self.reader = Some(rdr);
-
         // If there was a redirect, update the `CurlTransport` with the new base.
-        if let Ok(Some(effective_url)) = h.effective_url() {
-            let new_base = if effective_url.ends_with(self.url_path) {
-                // Strip the action from the end.
-                &effective_url[..effective_url.len() - self.url_path.len()]
-            } else {
-                // I'm not sure if this code path makes sense, but it's what
-                // libgit does.
-                effective_url
+        let url = url.as_str();
+        let new_base = if url.ends_with(url_path) {
+            // Strip the action from the end.
+            &url[..url.len() - url_path.len()]
+        } else {
+            // I'm not sure if this code path makes sense, but it's what
+            // libgit does.
+            url
+        };
+        *self.base_url.lock().unwrap() = new_base.to_string();
+
+        Ok(response.data)
+    }
+
+    /// Perform a request until it gets a response which is neither a
+    /// redirect, which is followed, nor `401 Unauthorized`, in which case
+    /// credentials are asked for.
+    ///
+    /// Credentials are only sent to the origin they were asked for, so that
+    /// a redirect to another scheme, host or port doesn't leak them.
+    fn replay(
+        &self,
+        h: &mut Handle,
+        url: &mut Url,
+        data: &[u8],
+        opts: Option<&ConnectOptions>,
+        offsite: bool,
+        auth: &mut Option<(Origin, Credentials)>,
+    ) -> io::Result<Response> {
+        let mut replays = 0;
+        loop {
+            debug!("request to {}", url);
+            h.url(url.as_str())?;
+            let credentials = match auth {
+                Some((origin, credentials)) if *origin == url.origin() => Some(&*credentials),
+                _ => None,
+            };
+            let response = self.perform(h, url, data, opts, credentials)?;
+            if !(300..400).contains(&response.code) && response.code != 401 {
+                return Ok(response);
+            }
+            replays += 1;
+            if replays > MAX_REPLAYS {
+                return Err(self.err("too many redirects or authentication replays"));
+            }
+            if response.code == 401 {
+                // Ask for credentials until the server accepts them, like
+                // libgit2 does.
+                *auth = Some((url.origin(), self.credentials(url, &response.auth_schemes)?));
+                continue;
+            }
+            let location = match response.location {
+                Some(ref location) => location,
+                None => return Err(self.err("redirect without location")),
             };
-            *self.base_url.lock().unwrap() = new_base.to_string();
+            let target = url
+                .join(location)
+                .map_err(|_| self.err("invalid redirect location"))?;
+            if target.scheme() != url.scheme() && target.scheme() != "https" {
+                return Err(self.err(format!(
+                    "cannot redirect from '{}' to '{}'",
+                    url.scheme(),
+                    target.scheme()
+                )));
+            }
+            let host = url.host_str().unwrap_or("");
+            let target_host = target.host_str().unwrap_or("");
+            if !offsite && !host.eq_ignore_ascii_case(target_host) {
+                return Err(self.err(format!(
+                    "cannot redirect from '{}' to '{}'",
+                    host, target_host
+                )));
+            }
+            *url = target;
         }
+    }
 
-        Ok(())
+    fn perform(
+        &self,
+        h: &mut Handle,
+        url: &Url,
+        data: &[u8],
+        opts: Option<&ConnectOptions>,
+        auth: Option<&Credentials>,
+    ) -> io::Result<Response> {
+        let mut headers = Vec::new();
+        headers.push(format!("Host: {}", url.host_str().unwrap()));
+        if !data.is_empty() {
+            headers.push(format!("Accept: application/x-git-{}-result", self.service));
+            headers.push(format!(
+                "Content-Type: \
+                 application/x-git-{}-request",
+                self.service
+            ));
+        } else {
+            headers.push("Accept: */*".to_string());
+        }
+        headers.push("Expect:".to_string());
+        if self.service == "upload-pack" {
+            headers.push(v2::HEADER.to_string());
+        }
+        match auth {
+            Some(Credentials::Basic { username, password }) => {
+                h.basic_auth(Some((username, password)))?;
+            }
+            Some(Credentials::Bearer(token)) => {
+                h.basic_auth(None)?;
+                headers.push(format!("Authorization: Bearer {}", token));
+            }
+            None => h.basic_auth(None)?,
+        }
+        for header in opts.map(|opts| opts.custom_headers()).unwrap_or_default() {
+            headers.push(header.to_string());
+        }
+        h.headers(&headers)?;
+
+        // Send the request, collecting the response in-memory
+        let (code, transfer) = h.perform()?;
+
+        // Look for the Content-Type, Location and WWW-Authenticate headers
+        let mut content_type = None;
+        let mut location = None;
+        let mut auth_schemes = Vec::new();
+        for header in transfer.headers.iter() {
+            let header = match str::from_utf8(header) {
+                Ok(s) => s,
+                Err(..) => continue,
+            };
+            let mut parts = header.splitn(2, ": ");
+            let name = parts.next().unwrap();
+            let value = match parts.next() {
+                Some(value) => value,
+                None => continue,
+            };
+            if name.eq_ignore_ascii_case("Content-Type") {
+                content_type = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("Location") {
+                location = Some(value.trim().to_string());
+            } else if name.eq_ignore_ascii_case("WWW-Authenticate") {
+                let scheme = value.trim().split(' ').next().unwrap();
+                auth_schemes.push(scheme.to_ascii_lowercase());
+            }
+        }
+
+        Ok(Response {
+            code,
+            content_type,
+            location,
+            auth_schemes,
+            data: transfer.data,
+        })
+    }
+
+    /// Ask the credentials callback for a username and password to retry
+    /// the request with.
+    fn credentials(&self, url: &Url, schemes: &[String]) -> io::Result<Credentials> {
+        let owner = match self.owner {
+            Some(ref owner) => owner,
+            None => return Err(self.err("server requires authentication")),
+        };
+        let username = Some(url.username()).filter(|s| !s.is_empty());
+        let cred = owner
+            .credentials(username, CredentialType::USER_PASS_PLAINTEXT)
+            .map_err(|e| self.err(e))?;
+        let (username, password) = match cred.userpass() {
+            Some(pair) => pair,
+            None => return Err(self.err("credentials callback returned invalid credentials")),
+        };
+        // Servers which don't say which scheme they want get basic
+        // authentication, the bearer scheme is only used when it's the only
+        // one on offer.
+        if schemes.iter().any(|s| s == "bearer") && !schemes.iter().any(|s| s == "basic") {
+            Ok(Credentials::Bearer(password.to_string()))
+        } else {
+            Ok(Credentials::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            })
+        }
     }
 }
 
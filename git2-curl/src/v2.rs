@@ -0,0 +1,437 @@
+//! Support for talking the smart protocol version 2 to servers.
+//!
+//! libgit2 itself only speaks version 0 of the protocol, so requests are
+//! translated on the way out and responses on the way back in: the refs
+//! listed by `ls-refs` are turned into a version 0 advertisement, and the
+//! requests and responses of the negotiation into those of a `fetch` command.
+
+use std::io;
+use std::str;
+
+/// The capabilities advertised by a server speaking protocol version 2.
+pub struct Capabilities {
+    agent: Option<String>,
+    object_format: Option<String>,
+    /// Whether the `fetch` command supports `shallow` and the `deepen`
+    /// arguments.
+    shallow: bool,
+    /// Whether the `fetch` command supports `filter`.
+    filter: bool,
+}
+
+/// The state of a fetch from a server speaking protocol version 2.
+pub struct Session {
+    caps: Capabilities,
+    /// The response to the final request of the negotiation, if the server
+    /// already sent the pack.
+    ///
+    /// This holds the `shallow` and `unshallow` lines and the packets of the
+    /// pack, up to and including the final flush.
+    pending: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// The negotiation request of a version 0 client.
+struct FetchRequest {
+    /// The capabilities asked for which carry over to a `fetch` command.
+    features: Vec<String>,
+    wants: Vec<String>,
+    shallow: Vec<String>,
+    /// The `deepen`, `deepen-since` and `deepen-not` lines.
+    deepen: Vec<String>,
+    filter: Option<String>,
+    haves: Vec<String>,
+    done: bool,
+}
+
+/// The response to a `fetch` command.
+struct FetchResponse<'a> {
+    acks: Vec<&'a str>,
+    shallow_info: Vec<u8>,
+    pack: Option<&'a [u8]>,
+}
+
+enum Pkt<'a> {
+    Flush,
+    Delim,
+    Data(&'a [u8]),
+}
+
+const FLUSH: &[u8] = b"0000";
+const DELIM: &[u8] = b"0001";
+const ZERO_ID: &str = "0000000000000000000000000000000000000000";
+
+/// The value of the `Git-Protocol` header asking for version 2.
+pub const HEADER: &str = "Git-Protocol: version=2";
+
+/// Compute the `ref-prefix` arguments of `ls-refs` from the fetch refspecs
+/// of a remote.
+///
+/// Refspecs which don't name a full ref are expanded the way git resolves
+/// them. `HEAD` and tags are always listed. Without refspecs nothing is
+/// filtered, as the refs to fetch are only known later on.
+pub fn ref_prefixes<'a>(refspecs: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let mut prefixes = Vec::new();
+    for refspec in refspecs {
+        if refspec.starts_with('^') {
+            continue;
+        }
+        let refspec = refspec.trim_start_matches('+');
+        let src = refspec.split(':').next().unwrap();
+        if src.is_empty() {
+            continue;
+        }
+        if let Some(i) = src.find('*') {
+            prefixes.push(src[..i].to_string());
+        } else if src.starts_with("refs/") {
+            prefixes.push(src.to_string());
+        } else {
+            prefixes.push(src.to_string());
+            for dir in ["refs/", "refs/tags/", "refs/heads/", "refs/remotes/"] {
+                prefixes.push(format!("{}{}", dir, src));
+            }
+            prefixes.push(format!("refs/remotes/{}/HEAD", src));
+        }
+    }
+    if !prefixes.is_empty() {
+        prefixes.push("HEAD".to_string());
+        prefixes.push("refs/tags/".to_string());
+    }
+    prefixes
+}
+
+/// Parse the response to the initial request, returning `None` if the server
+/// does not speak version 2.
+pub fn capabilities(data: &[u8]) -> io::Result<Option<Capabilities>> {
+    let pkts = parse(data)?;
+    let mut lines = pkts.iter().filter_map(|pkt| match *pkt {
+        Pkt::Data(line) => Some(text(line)),
+        _ => None,
+    });
+    if lines.next() != Some("version 2") {
+        return Ok(None);
+    }
+    let mut caps = Capabilities {
+        agent: None,
+        object_format: None,
+        shallow: false,
+        filter: false,
+    };
+    for line in lines {
+        let (key, value) = line.split_once('=').unwrap_or((line, ""));
+        match key {
+            "agent" => caps.agent = Some(value.to_string()),
+            "object-format" => caps.object_format = Some(value.to_string()),
+            "fetch" => {
+                caps.shallow = value.split(' ').any(|f| f == "shallow");
+                caps.filter = value.split(' ').any(|f| f == "filter");
+            }
+            _ => {}
+        }
+    }
+    Ok(Some(caps))
+}
+
+impl Capabilities {
+    /// Build an `ls-refs` request listing the refs matching `prefixes`.
+    pub fn ls_refs(&self, prefixes: &[String]) -> Vec<u8> {
+        let mut out = self.command("ls-refs");
+        pkt(&mut out, b"peel\n");
+        pkt(&mut out, b"symrefs\n");
+        for prefix in prefixes {
+            pkt(&mut out, format!("ref-prefix {}\n", prefix).as_bytes());
+        }
+        out.extend_from_slice(FLUSH);
+        out
+    }
+
+    /// Turn the response to `ls-refs` into a version 0 advertisement.
+    pub fn advertisement(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut refs = Vec::new();
+        let mut symrefs = Vec::new();
+        for pkt in parse(data)? {
+            let line = match pkt {
+                Pkt::Data(line) => text(line),
+                _ => continue,
+            };
+            let mut parts = line.split(' ');
+            let (id, name) = match (parts.next(), parts.next()) {
+                (Some(id), Some(name)) => (id, name),
+                _ => return Err(invalid("invalid ls-refs response")),
+            };
+            refs.push(format!("{} {}", id, name));
+            for attr in parts {
+                if let Some(target) = attr.strip_prefix("symref-target:") {
+                    symrefs.push(format!("symref={}:{}", name, target));
+                } else if let Some(peeled) = attr.strip_prefix("peeled:") {
+                    refs.push(format!("{} {}^{{}}", peeled, name));
+                }
+            }
+        }
+
+        // Any object can be asked for with version 2.
+        let mut caps = "multi_ack_detailed side-band-64k ofs-delta thin-pack include-tag \
+                        allow-tip-sha1-in-want allow-reachable-sha1-in-want"
+            .to_string();
+        if self.shallow {
+            caps.push_str(" shallow deepen-since deepen-not");
+        }
+        if self.filter {
+            caps.push_str(" filter");
+        }
+        for symref in symrefs {
+            caps.push(' ');
+            caps.push_str(&symref);
+        }
+        if let Some(ref agent) = self.agent {
+            caps.push_str(" agent=");
+            caps.push_str(agent);
+        }
+
+        let mut out = Vec::new();
+        pkt(&mut out, b"# service=git-upload-pack\n");
+        out.extend_from_slice(FLUSH);
+        if refs.is_empty() {
+            refs.push(format!("{} capabilities^{{}}", ZERO_ID));
+        }
+        for (i, line) in refs.iter().enumerate() {
+            if i == 0 {
+                pkt(&mut out, format!("{}\0{}\n", line, caps).as_bytes());
+            } else {
+                pkt(&mut out, format!("{}\n", line).as_bytes());
+            }
+        }
+        out.extend_from_slice(FLUSH);
+        Ok(out)
+    }
+
+    fn command(&self, name: &str) -> Vec<u8> {
+        let mut out = Vec::new();
+        pkt(&mut out, format!("command={}\n", name).as_bytes());
+        if self.agent.is_some() {
+            let agent = format!("agent=git2-curl/{}\n", env!("CARGO_PKG_VERSION"));
+            pkt(&mut out, agent.as_bytes());
+        }
+        if let Some(ref format) = self.object_format {
+            pkt(&mut out, format!("object-format={}\n", format).as_bytes());
+        }
+        out.extend_from_slice(DELIM);
+        out
+    }
+}
+
+impl Session {
+    /// Start a fetch from a server with the given capabilities.
+    pub fn new(caps: Capabilities) -> Session {
+        Session {
+            caps,
+            pending: None,
+        }
+    }
+
+    /// Answer a negotiation request of libgit2, using `send` to send a
+    /// `fetch` command to the server when needed.
+    ///
+    /// Once the server has sent the pack, the remaining requests of the
+    /// negotiation are answered without asking the server again.
+    pub fn fetch<F>(&mut self, request: &[u8], send: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> io::Result<Vec<u8>>,
+    {
+        let request = FetchRequest::parse(request)?;
+        // When asked for a shallow clone, libgit2 first asks for the shallow
+        // boundary on its own, which a `fetch` command only reports along
+        // with the pack.
+        let deepen = !request.deepen.is_empty() && request.haves.is_empty() && !request.done;
+        let mut out = Vec::new();
+        if self.pending.is_none() {
+            let data = send(&request.command(&self.caps, request.done || deepen))?;
+            let response = FetchResponse::parse(&data)?;
+            match response.pack {
+                Some(pack) => self.pending = Some((response.shallow_info, pack.to_vec())),
+                None => {
+                    for ack in response.acks {
+                        pkt(&mut out, format!("ACK {} common\n", ack).as_bytes());
+                    }
+                    pkt(&mut out, b"NAK\n");
+                    return Ok(out);
+                }
+            }
+        }
+
+        if deepen {
+            out.extend_from_slice(&self.pending.as_ref().unwrap().0);
+            out.extend_from_slice(FLUSH);
+        } else if request.done {
+            let (_, pack) = self.pending.take().unwrap();
+            pkt(&mut out, b"NAK\n");
+            out.extend_from_slice(&pack);
+        } else {
+            // Acknowledge a have so libgit2 stops negotiating and asks for
+            // the pack.
+            if let Some(have) = request.haves.first() {
+                pkt(&mut out, format!("ACK {} common\n", have).as_bytes());
+            }
+            pkt(&mut out, b"NAK\n");
+        }
+        Ok(out)
+    }
+}
+
+impl FetchRequest {
+    fn parse(data: &[u8]) -> io::Result<FetchRequest> {
+        let mut request = FetchRequest {
+            features: Vec::new(),
+            wants: Vec::new(),
+            shallow: Vec::new(),
+            deepen: Vec::new(),
+            filter: None,
+            haves: Vec::new(),
+            done: false,
+        };
+        for pkt in parse(data)? {
+            let line = match pkt {
+                Pkt::Data(line) => text(line),
+                _ => continue,
+            };
+            let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+            match command {
+                "want" => {
+                    let mut args = arg.split(' ');
+                    request.wants.push(args.next().unwrap().to_string());
+                    request.features.extend(
+                        args.filter(|cap| {
+                            matches!(*cap, "ofs-delta" | "thin-pack" | "include-tag")
+                        })
+                        .map(|cap| cap.to_string()),
+                    );
+                }
+                "shallow" => request.shallow.push(arg.to_string()),
+                "deepen" | "deepen-since" | "deepen-not" => request.deepen.push(line.to_string()),
+                "filter" => request.filter = Some(arg.to_string()),
+                "have" => request.haves.push(arg.to_string()),
+                "done" => request.done = true,
+                _ => return Err(invalid("unexpected negotiation request")),
+            }
+        }
+        Ok(request)
+    }
+
+    fn command(&self, caps: &Capabilities, done: bool) -> Vec<u8> {
+        let mut out = caps.command("fetch");
+        for feature in &self.features {
+            pkt(&mut out, format!("{}\n", feature).as_bytes());
+        }
+        for want in &self.wants {
+            pkt(&mut out, format!("want {}\n", want).as_bytes());
+        }
+        for shallow in &self.shallow {
+            pkt(&mut out, format!("shallow {}\n", shallow).as_bytes());
+        }
+        for deepen in &self.deepen {
+            pkt(&mut out, format!("{}\n", deepen).as_bytes());
+        }
+        if let Some(ref filter) = self.filter {
+            pkt(&mut out, format!("filter {}\n", filter).as_bytes());
+        }
+        for have in &self.haves {
+            pkt(&mut out, format!("have {}\n", have).as_bytes());
+        }
+        if done {
+            pkt(&mut out, b"done\n");
+        }
+        out.extend_from_slice(FLUSH);
+        out
+    }
+}
+
+impl<'a> FetchResponse<'a> {
+    fn parse(mut data: &'a [u8]) -> io::Result<FetchResponse<'a>> {
+        let mut response = FetchResponse {
+            acks: Vec::new(),
+            shallow_info: Vec::new(),
+            pack: None,
+        };
+        let mut section = "";
+        while let Some((packet, rest)) = next(data)? {
+            data = rest;
+            let line = match packet {
+                Pkt::Data(line) => text(line),
+                Pkt::Flush | Pkt::Delim => {
+                    section = "";
+                    continue;
+                }
+            };
+            if let Some(msg) = line.strip_prefix("ERR ") {
+                return Err(invalid(&format!("remote error: {}", msg)));
+            }
+            match section {
+                "" if line == "packfile" => {
+                    response.pack = Some(data);
+                    break;
+                }
+                "" => section = section_name(line)?,
+                "acknowledgments" => {
+                    if let Some(id) = line.strip_prefix("ACK ") {
+                        response.acks.push(id);
+                    }
+                }
+                "shallow-info" => {
+                    // libgit2 wants these without a trailing newline.
+                    pkt(&mut response.shallow_info, line.as_bytes());
+                }
+                _ => {}
+            }
+        }
+        Ok(response)
+    }
+}
+
+fn section_name(line: &str) -> io::Result<&'static str> {
+    match line {
+        "acknowledgments" => Ok("acknowledgments"),
+        "shallow-info" => Ok("shallow-info"),
+        "wanted-refs" => Ok("wanted-refs"),
+        "packfile-uris" => Ok("packfile-uris"),
+        _ => Err(invalid(&format!("unexpected section `{}`", line))),
+    }
+}
+
+fn parse(mut data: &[u8]) -> io::Result<Vec<Pkt<'_>>> {
+    let mut pkts = Vec::new();
+    while let Some((pkt, rest)) = next(data)? {
+        pkts.push(pkt);
+        data = rest;
+    }
+    Ok(pkts)
+}
+
+fn next(data: &[u8]) -> io::Result<Option<(Pkt<'_>, &[u8])>> {
+    if data.is_empty() {
+        return Ok(None);
+    }
+    let len = data
+        .get(..4)
+        .and_then(|len| str::from_utf8(len).ok())
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or_else(|| invalid("invalid packet line"))?;
+    match len {
+        0 => Ok(Some((Pkt::Flush, &data[4..]))),
+        1 => Ok(Some((Pkt::Delim, &data[4..]))),
+        n if n < 4 || n > data.len() => Err(invalid("invalid packet line")),
+        n => Ok(Some((Pkt::Data(&data[4..n]), &data[n..]))),
+    }
+}
+
+fn pkt(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(format!("{:04x}", data.len() + 4).as_bytes());
+    out.extend_from_slice(data);
+}
+
+fn text(line: &[u8]) -> &str {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    str::from_utf8(line).unwrap_or("")
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
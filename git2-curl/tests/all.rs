@@ -1,16 +1,38 @@
 //! A simple test to verify that git2-curl can communicate to git over HTTP.
 
+use std::cell::Cell;
 use std::fs::File;
 use std::io::{BufRead, BufReader, Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::path::Path;
 use std::process::{Command, Stdio};
+use std::sync::Mutex;
 use tempfile::TempDir;
 
 const PORT: u16 = 7848;
+/// A server which wants a username and password for basic authentication.
+const BASIC_PORT: u16 = 7849;
+/// A server which wants a bearer token.
+const BEARER_PORT: u16 = 7850;
+
+/// The `ls-refs` requests made to the servers.
+static LS_REFS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// The hosts of the requests made to the servers, and whether they had an
+/// `Authorization` header.
+static HOSTS: Mutex<Vec<(String, bool)>> = Mutex::new(Vec::new());
 
 /// This is a very bare-bones HTTP server, enough to run git-http-backend as a CGI.
-fn handle_client(stream: TcpStream, working_dir: &Path) {
+///
+/// If `auth` is given, requests without it as their `Authorization` header
+/// or without the `X-Git2-Curl: test` header are refused. Protocol v2 is
+/// only spoken if `v2` is set.
+///
+/// Requests to paths starting with `/moved` are redirected to the same host,
+/// those starting with `/elsewhere` to another host, and POST requests to
+/// paths starting with `/post-elsewhere` to another host as well. Requests
+/// to paths starting with `/auth-elsewhere` are redirected to another host
+/// once authenticated.
+fn handle_client(stream: TcpStream, working_dir: &Path, auth: Option<&str>, v2: bool) {
     let mut buf = BufReader::new(stream);
     let mut line = String::new();
     if buf.read_line(&mut line).unwrap() == 0 {
@@ -19,10 +41,18 @@ fn handle_client(stream: TcpStream, working_dir: &Path) {
     // Read the "METHOD path HTTP/1.1" line.
     let mut parts = line.split_ascii_whitespace();
     let method = parts.next().unwrap();
-    let path = parts.next().unwrap();
+    let mut path = parts.next().unwrap();
+    // Requests sent to a proxy contain the whole URL.
+    if let Some(rest) = path.strip_prefix("http://") {
+        path = &rest[rest.find('/').unwrap()..];
+    }
     let (path, query) = path.split_once('?').unwrap_or_else(|| (path, ""));
     let mut content_length = 0;
     let mut content_type = String::new();
+    let mut authorization = None;
+    let mut host = String::new();
+    let mut custom = false;
+    let mut protocol = String::new();
     // Read headers.
     loop {
         let mut header = String::new();
@@ -37,6 +67,10 @@ fn handle_client(stream: TcpStream, working_dir: &Path) {
         match name.as_str() {
             "content-length" => content_length = value.trim().parse().unwrap_or(0),
             "content-type" => content_type = value.trim().to_owned(),
+            "authorization" => authorization = Some(value.trim().to_owned()),
+            "host" => host = value.trim().to_owned(),
+            "x-git2-curl" => custom = value.trim() == "test",
+            "git-protocol" if v2 => protocol = value.trim().to_owned(),
             _ => {}
         }
     }
@@ -45,6 +79,50 @@ fn handle_client(stream: TcpStream, working_dir: &Path) {
         buf.read_exact(&mut body).unwrap();
     }
 
+    let mut stream = buf.into_inner();
+    let port = stream.local_addr().unwrap().port();
+    let redirect = if let Some(rest) = path.strip_prefix("/moved") {
+        Some(rest.to_string())
+    } else if let Some(rest) = path.strip_prefix("/elsewhere") {
+        Some(format!("http://127.0.0.1:{port}{rest}"))
+    } else if let Some(rest) = path.strip_prefix("/post-elsewhere") {
+        Some(format!("http://127.0.0.1:{port}{rest}")).filter(|_| method == "POST")
+    } else {
+        None
+    };
+    if let Some(location) = redirect {
+        return redirect_to(stream, &location, query);
+    }
+    let path = path.strip_prefix("/post-elsewhere").unwrap_or(path);
+    if body.windows(15).any(|w| w == b"command=ls-refs") {
+        LS_REFS
+            .lock()
+            .unwrap()
+            .push(String::from_utf8_lossy(&body).into_owned());
+    }
+
+    HOSTS.lock().unwrap().push((host, authorization.is_some()));
+    if let Some(auth) = auth {
+        if authorization.as_deref() != Some(auth) || !custom {
+            let scheme = auth.split(' ').next().unwrap();
+            stream
+                .write_all(
+                    format!(
+                        "HTTP/1.1 401 Unauthorized\r\n\
+                         Connection: close\r\n\
+                         Content-Length: 0\r\n\
+                         WWW-Authenticate: {scheme} realm=\"git\"\r\n\r\n"
+                    )
+                    .as_bytes(),
+                )
+                .unwrap();
+            return;
+        }
+    }
+    if let Some(rest) = path.strip_prefix("/auth-elsewhere") {
+        return redirect_to(stream, &format!("http://127.0.0.1:{port}{rest}"), query);
+    }
+
     let mut cgi_env = vec![
         ("GIT_PROJECT_ROOT", "."),
         ("GIT_HTTP_EXPORT_ALL", "1"),
@@ -62,6 +140,9 @@ fn handle_client(stream: TcpStream, working_dir: &Path) {
 
     let cl = content_length.to_string();
     cgi_env.push(("CONTENT_LENGTH", cl.as_str()));
+    if !protocol.is_empty() {
+        cgi_env.push(("HTTP_GIT_PROTOCOL", protocol.as_str()));
+    }
 
     // Spawn git-http-backend
     let mut cmd = Command::new("git");
@@ -95,7 +176,6 @@ fn handle_client(stream: TcpStream, working_dir: &Path) {
     let content_length = body.len();
 
     // Write HTTP response
-    let mut stream = buf.into_inner();
     stream
         .write_all(
             &format!(
@@ -112,25 +192,33 @@ fn handle_client(stream: TcpStream, working_dir: &Path) {
     stream.flush().unwrap();
 }
 
+fn redirect_to(mut stream: TcpStream, location: &str, query: &str) {
+    let query = if query.is_empty() {
+        String::new()
+    } else {
+        format!("?{query}")
+    };
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 307 Temporary Redirect\r\n\
+                 Connection: close\r\n\
+                 Content-Length: 0\r\n\
+                 Location: {location}{query}\r\n\r\n"
+            )
+            .as_bytes(),
+        )
+        .unwrap();
+}
+
 fn main() {
     let td = TempDir::new().unwrap();
-    let td_path = td.path().to_owned();
 
-    // Spin up a server for git-http-backend
-    std::thread::spawn(move || {
-        let listener = TcpListener::bind(("localhost", PORT)).unwrap();
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    let td_path = td_path.clone();
-                    std::thread::spawn(move || handle_client(stream, &td_path));
-                }
-                Err(e) => {
-                    panic!("Connection failed: {}", e);
-                }
-            }
-        }
-    });
+    // Spin up servers for git-http-backend
+    serve(PORT, td.path(), None, true);
+    // "user:pass"
+    serve(BASIC_PORT, td.path(), Some("Basic dXNlcjpwYXNz"), false);
+    serve(BEARER_PORT, td.path(), Some("Bearer token"), false);
 
     unsafe {
         git2_curl::register(curl::easy::Easy::new());
@@ -190,4 +278,225 @@ fn main() {
     r.reset(&obj, git2::ResetType::Hard, None).unwrap();
 
     assert!(File::open(&td2.path().join("bar")).is_ok());
+
+    // The refs were listed with protocol v2, asking only for those matched
+    // by the refspecs of the remote.
+    {
+        let ls_refs = LS_REFS.lock().unwrap();
+        assert_eq!(ls_refs.len(), 2);
+        assert!(ls_refs[0].contains("ref-prefix refs/heads/\n"));
+        assert!(ls_refs[0].contains("ref-prefix HEAD\n"));
+    }
+
+    // Refs outside of the refspecs of the remote are listed when fetched
+    // explicitly, and all of them when listing after connecting.
+    r1.reference("refs/pull/1/head", id, false, "pull").unwrap();
+    remote
+        .fetch(&["refs/pull/1/head:refs/pull/1/head"], None, None)
+        .unwrap();
+    assert_eq!(
+        r.find_reference("refs/pull/1/head").unwrap().target(),
+        Some(id)
+    );
+    assert!(LS_REFS.lock().unwrap()[2].contains("ref-prefix refs/pull/1/head\n"));
+    {
+        let connection = remote
+            .connect_auth(git2::Direction::Fetch, None, None)
+            .unwrap();
+        let heads = connection.list().unwrap();
+        assert!(heads.iter().any(|head| head.name() == "refs/pull/1/head"));
+        assert!(!LS_REFS.lock().unwrap()[3].contains("ref-prefix"));
+    }
+
+    // Shallow clones over protocol v2
+    let td7 = TempDir::new().unwrap();
+    let mut fo = git2::FetchOptions::new();
+    fo.depth(1);
+    let shallow = git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&format!("http://localhost:{}", PORT), td7.path())
+        .unwrap();
+    assert!(shallow.is_shallow());
+    let head = shallow.head().unwrap().peel_to_commit().unwrap();
+    assert_eq!(head.parent_count(), 0);
+    let mut fo = git2::FetchOptions::new();
+    fo.shallow_since(git2::Time::new(0, 0));
+    shallow
+        .find_remote("origin")
+        .unwrap()
+        .fetch(&[] as &[&str], Some(&mut fo), None)
+        .unwrap();
+    assert!(!git2::Repository::open(td7.path()).unwrap().is_shallow());
+
+    // Partial clones over protocol v2, once the server allows filters
+    let td10 = TempDir::new().unwrap();
+    let mut fo = git2::FetchOptions::new();
+    fo.filter(git2::ObjectFilter::BlobNone);
+    let url = format!("http://localhost:{}", PORT);
+    assert!(git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&url, td10.path())
+        .is_err());
+    r1.config()
+        .unwrap()
+        .set_bool("uploadpack.allowFilter", true)
+        .unwrap();
+    let mut fo = git2::FetchOptions::new();
+    fo.filter(git2::ObjectFilter::BlobNone);
+    let partial = git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&url, td10.path())
+        .unwrap();
+    assert!(partial
+        .config()
+        .unwrap()
+        .get_bool("remote.origin.promisor")
+        .unwrap());
+    assert!(File::open(td10.path().join("bar")).is_ok());
+
+    // Redirects to the same host are always followed, to other hosts only
+    // if allowed.
+    let clone_with = |path: &str, redirect: Option<git2::RemoteRedirect>| {
+        let td = TempDir::new().unwrap();
+        let mut fo = git2::FetchOptions::new();
+        if let Some(redirect) = redirect {
+            fo.follow_redirects(redirect);
+        }
+        git2::build::RepoBuilder::new()
+            .fetch_options(fo)
+            .clone(&format!("http://localhost:{}{}", PORT, path), td.path())
+            .map(|_| ())
+    };
+    clone_with("/moved", Some(git2::RemoteRedirect::None)).unwrap();
+    clone_with("/elsewhere", None).unwrap();
+    assert!(clone_with("/elsewhere", Some(git2::RemoteRedirect::None)).is_err());
+    assert!(clone_with("/post-elsewhere", None).is_err());
+    clone_with("/post-elsewhere", Some(git2::RemoteRedirect::All)).unwrap();
+
+    // Authenticate with the credentials callback
+    let td3 = TempDir::new().unwrap();
+    let url = format!("http://localhost:{}", BASIC_PORT);
+    assert!(git2::Repository::clone(&url, td3.path()).is_err());
+    let calls = Cell::new(0);
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        calls.set(calls.get() + 1);
+        assert_eq!(url, format!("http://localhost:{}", BASIC_PORT));
+        assert_eq!(username, None);
+        assert!(allowed.is_user_pass_plaintext());
+        git2::Cred::userpass_plaintext("user", "pass")
+    });
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(callbacks)
+        .custom_headers(&["X-Git2-Curl: test"]);
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&url, td3.path())
+        .unwrap();
+    assert!(File::open(td3.path().join("bar")).is_ok());
+    // The credentials are asked for once and reused for later requests.
+    assert_eq!(calls.get(), 1);
+
+    // Wrong credentials are asked for again, as many times as libgit2 does.
+    let td9 = TempDir::new().unwrap();
+    let calls = Cell::new(0);
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(|_, _, _| {
+        calls.set(calls.get() + 1);
+        git2::Cred::userpass_plaintext("user", "wrong")
+    });
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(callbacks)
+        .custom_headers(&["X-Git2-Curl: test"]);
+    assert!(git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&url, td9.path())
+        .is_err());
+    assert_eq!(calls.get(), 15);
+
+    // Credentials aren't sent along when redirected to another host, which
+    // asks for them again.
+    HOSTS.lock().unwrap().clear();
+    let td11 = TempDir::new().unwrap();
+    let calls = Cell::new(0);
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(|_, _, _| {
+        calls.set(calls.get() + 1);
+        git2::Cred::userpass_plaintext("user", "pass")
+    });
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(callbacks)
+        .custom_headers(&["X-Git2-Curl: test"])
+        .follow_redirects(git2::RemoteRedirect::All);
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&format!("{}/auth-elsewhere", url), td11.path())
+        .unwrap();
+    assert_eq!(calls.get(), 2);
+    let hosts = HOSTS.lock().unwrap().clone();
+    let elsewhere = hosts.iter().position(|(host, _)| host == "127.0.0.1");
+    assert_eq!(hosts[1], ("localhost".to_string(), true));
+    assert!(!hosts[elsewhere.unwrap()].1);
+
+    // Authenticate with a bearer token
+    let td4 = TempDir::new().unwrap();
+    let mut callbacks = git2::RemoteCallbacks::new();
+    callbacks.credentials(|_, _, _| git2::Cred::userpass_plaintext("", "token"));
+    let mut fo = git2::FetchOptions::new();
+    fo.remote_callbacks(callbacks)
+        .custom_headers(&["X-Git2-Curl: test"]);
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone(&format!("http://localhost:{}", BEARER_PORT), td4.path())
+        .unwrap();
+    assert!(File::open(td4.path().join("bar")).is_ok());
+
+    // Connect through a proxy; the server accepts requests for any host.
+    let td5 = TempDir::new().unwrap();
+    let mut proxy = git2::ProxyOptions::new();
+    proxy.url(&format!("http://localhost:{}", PORT));
+    let mut fo = git2::FetchOptions::new();
+    fo.proxy_options(proxy);
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone("http://git2-curl.invalid", td5.path())
+        .unwrap();
+    assert!(File::open(td5.path().join("bar")).is_ok());
+
+    // Remotes without a proxy of their own don't use it, but get the
+    // default proxy of curl back.
+    let td6 = TempDir::new().unwrap();
+    assert!(git2::Repository::clone("http://git2-curl.invalid", td6.path()).is_err());
+    std::env::set_var("http_proxy", format!("http://localhost:{}", PORT));
+    git2::Repository::clone("http://git2-curl.invalid", td6.path()).unwrap();
+
+    // Automatic proxy detection uses the proxy of the handle.
+    let td8 = TempDir::new().unwrap();
+    let mut proxy = git2::ProxyOptions::new();
+    proxy.auto();
+    let mut fo = git2::FetchOptions::new();
+    fo.proxy_options(proxy);
+    git2::build::RepoBuilder::new()
+        .fetch_options(fo)
+        .clone("http://git2-curl.invalid", td8.path())
+        .unwrap();
+    assert!(File::open(td8.path().join("bar")).is_ok());
+}
+
+fn serve(port: u16, working_dir: &Path, auth: Option<&'static str>, v2: bool) {
+    let listener = TcpListener::bind(("localhost", port)).unwrap();
+    let working_dir = working_dir.to_owned();
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let working_dir = working_dir.clone();
+                    std::thread::spawn(move || handle_client(stream, &working_dir, auth, v2));
+                }
+                Err(e) => {
+                    panic!("Connection failed: {}", e);
+                }
+            }
+        }
+    });
 }
@@ -4,7 +4,7 @@
 // This is required to link libz when libssh2-sys is not included.
 extern crate libz_sys as libz;
 
-use libc::{c_char, c_int, c_uchar, c_uint, c_ushort, c_void, size_t};
+use libc::{c_char, c_int, c_uchar, c_uint, c_ushort, c_void, size_t, ssize_t};
 #[cfg(feature = "ssh")]
 use libssh2_sys as libssh2;
 use std::ffi::CStr;
@@ -22,6 +22,13 @@ pub const GIT_PROXY_OPTIONS_VERSION: c_uint = 1;
 pub const GIT_SUBMODULE_UPDATE_OPTIONS_VERSION: c_uint = 1;
 pub const GIT_ODB_BACKEND_VERSION: c_uint = 1;
 pub const GIT_REFDB_BACKEND_VERSION: c_uint = 1;
+pub const GIT_CONFIG_BACKEND_VERSION: c_uint = 1;
+pub const GIT_CONFIG_BACKEND_MEMORY_OPTIONS_VERSION: c_uint = 1;
+pub const GIT_FILTER_VERSION: c_uint = 1;
+pub const GIT_FILTER_OPTIONS_VERSION: c_uint = 1;
+pub const GIT_BLOB_FILTER_OPTIONS_VERSION: c_uint = 1;
+pub const GIT_MERGE_DRIVER_VERSION: c_uint = 1;
+pub const GIT_STREAM_VERSION: c_int = 1;
 pub const GIT_CHERRYPICK_OPTIONS_VERSION: c_uint = 1;
 pub const GIT_APPLY_OPTIONS_VERSION: c_uint = 1;
 pub const GIT_REVERT_OPTIONS_VERSION: c_uint = 1;
@@ -55,12 +62,13 @@ pub enum git_branch_iterator {}
 pub enum git_blame {}
 pub enum git_commit {}
 pub enum git_config {}
-pub enum git_config_iterator {}
+pub enum git_filter_source {}
+pub enum git_filter_list {}
+pub enum git_merge_driver_source {}
 pub enum git_index {}
 pub enum git_index_conflict_iterator {}
 pub enum git_object {}
 pub enum git_reference {}
-pub enum git_reference_iterator {}
 pub enum git_annotated_commit {}
 pub enum git_refdb {}
 pub enum git_refspec {}
@@ -897,6 +905,65 @@ git_enum! {
     }
 }
 
+#[repr(C)]
+pub struct git_config_backend_entry {
+    pub entry: git_config_entry,
+    pub free: Option<extern "C" fn(*mut git_config_backend_entry)>,
+}
+
+#[repr(C)]
+pub struct git_config_iterator {
+    pub backend: *mut git_config_backend,
+    pub flags: c_uint,
+    pub next: Option<
+        extern "C" fn(*mut *mut git_config_backend_entry, *mut git_config_iterator) -> c_int,
+    >,
+    pub free: Option<extern "C" fn(*mut git_config_iterator)>,
+}
+
+#[repr(C)]
+pub struct git_config_backend {
+    pub version: c_uint,
+    pub readonly: c_int,
+    pub cfg: *mut git_config,
+    pub open: Option<
+        extern "C" fn(*mut git_config_backend, git_config_level_t, *const git_repository) -> c_int,
+    >,
+    pub get: Option<
+        extern "C" fn(
+            *mut git_config_backend,
+            *const c_char,
+            *mut *mut git_config_backend_entry,
+        ) -> c_int,
+    >,
+    pub set: Option<extern "C" fn(*mut git_config_backend, *const c_char, *const c_char) -> c_int>,
+    pub set_multivar: Option<
+        extern "C" fn(
+            *mut git_config_backend,
+            *const c_char,
+            *const c_char,
+            *const c_char,
+        ) -> c_int,
+    >,
+    pub del: Option<extern "C" fn(*mut git_config_backend, *const c_char) -> c_int>,
+    pub del_multivar:
+        Option<extern "C" fn(*mut git_config_backend, *const c_char, *const c_char) -> c_int>,
+    pub iterator:
+        Option<extern "C" fn(*mut *mut git_config_iterator, *mut git_config_backend) -> c_int>,
+    pub snapshot:
+        Option<extern "C" fn(*mut *mut git_config_backend, *mut git_config_backend) -> c_int>,
+    pub lock: Option<extern "C" fn(*mut git_config_backend) -> c_int>,
+    pub unlock: Option<extern "C" fn(*mut git_config_backend, c_int) -> c_int>,
+    pub free: Option<extern "C" fn(*mut git_config_backend)>,
+}
+
+#[repr(C)]
+pub struct git_config_backend_memory_options {
+    pub version: c_uint,
+    pub backend_type: *const c_char,
+    pub origin_path: *const c_char,
+}
+
 git_enum! {
     pub enum git_submodule_update_t {
         GIT_SUBMODULE_UPDATE_CHECKOUT = 1,
@@ -945,6 +1012,91 @@ git_enum! {
     }
 }
 
+git_enum! {
+    pub enum git_filter_mode_t {
+        GIT_FILTER_TO_WORKTREE = 0,
+        GIT_FILTER_TO_ODB = 1,
+    }
+}
+
+pub const GIT_FILTER_SMUDGE: git_filter_mode_t = GIT_FILTER_TO_WORKTREE;
+pub const GIT_FILTER_CLEAN: git_filter_mode_t = GIT_FILTER_TO_ODB;
+
+git_enum! {
+    pub enum git_filter_flag_t: u32 {
+        GIT_FILTER_DEFAULT = 0,
+        GIT_FILTER_ALLOW_UNSAFE = 1 << 0,
+        GIT_FILTER_NO_SYSTEM_ATTRIBUTES = 1 << 1,
+        GIT_FILTER_ATTRIBUTES_FROM_HEAD = 1 << 2,
+        GIT_FILTER_ATTRIBUTES_FROM_COMMIT = 1 << 3,
+    }
+}
+
+pub type git_filter_init_fn = Option<extern "C" fn(*mut git_filter) -> c_int>;
+pub type git_filter_shutdown_fn = Option<extern "C" fn(*mut git_filter)>;
+pub type git_filter_check_fn = Option<
+    extern "C" fn(
+        *mut git_filter,
+        *mut *mut c_void,
+        *const git_filter_source,
+        *mut *const c_char,
+    ) -> c_int,
+>;
+pub type git_filter_apply_fn = Option<
+    extern "C" fn(
+        *mut git_filter,
+        *mut *mut c_void,
+        *mut git_buf,
+        *const git_buf,
+        *const git_filter_source,
+    ) -> c_int,
+>;
+pub type git_filter_stream_fn = Option<
+    extern "C" fn(
+        *mut *mut git_writestream,
+        *mut git_filter,
+        *mut *mut c_void,
+        *const git_filter_source,
+        *mut git_writestream,
+    ) -> c_int,
+>;
+pub type git_filter_cleanup_fn = Option<extern "C" fn(*mut git_filter, *mut c_void)>;
+
+#[repr(C)]
+pub struct git_filter {
+    pub version: c_uint,
+    pub attributes: *const c_char,
+    pub initialize: git_filter_init_fn,
+    pub shutdown: git_filter_shutdown_fn,
+    pub check: git_filter_check_fn,
+    pub apply: git_filter_apply_fn,
+    pub stream: git_filter_stream_fn,
+    pub cleanup: git_filter_cleanup_fn,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct git_filter_options {
+    pub version: c_uint,
+    pub flags: u32,
+    pub commit_id: *mut git_oid,
+    pub attr_commit_id: git_oid,
+}
+
+pub const GIT_BLOB_FILTER_CHECK_FOR_BINARY: u32 = 1 << 0;
+pub const GIT_BLOB_FILTER_NO_SYSTEM_ATTRIBUTES: u32 = 1 << 1;
+pub const GIT_BLOB_FILTER_ATTRIBUTES_FROM_HEAD: u32 = 1 << 2;
+pub const GIT_BLOB_FILTER_ATTRIBUTES_FROM_COMMIT: u32 = 1 << 3;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct git_blob_filter_options {
+    pub version: c_int,
+    pub flags: u32,
+    pub commit_id: *mut git_oid,
+    pub attr_commit_id: git_oid,
+}
+
 pub const GIT_ATTR_CHECK_FILE_THEN_INDEX: u32 = 0;
 pub const GIT_ATTR_CHECK_INDEX_THEN_FILE: u32 = 1;
 pub const GIT_ATTR_CHECK_INDEX_ONLY: u32 = 2;
@@ -957,6 +1109,31 @@ pub struct git_cred {
     pub free: Option<extern "C" fn(*mut git_cred)>,
 }
 
+#[repr(C)]
+pub struct git_cred_userpass_plaintext {
+    pub parent: git_cred,
+    pub username: *mut c_char,
+    pub password: *mut c_char,
+}
+
+#[repr(C)]
+pub struct git_cred_ssh_interactive {
+    pub parent: git_cred,
+    pub username: *mut c_char,
+    pub prompt_callback: git_cred_ssh_interactive_callback,
+    pub payload: *mut c_void,
+}
+
+#[repr(C)]
+pub struct git_cred_ssh_custom {
+    pub parent: git_cred,
+    pub username: *mut c_char,
+    pub publickey: *mut c_char,
+    pub publickey_len: size_t,
+    pub sign_callback: git_cred_sign_callback,
+    pub payload: *mut c_void,
+}
+
 git_enum! {
     pub enum git_credtype_t {
         GIT_CREDTYPE_USERPASS_PLAINTEXT = 1 << 0,
@@ -990,7 +1167,7 @@ pub type git_cred_sign_callback = Option<
         data: *const c_uchar,
         data_len: size_t,
         abstrakt: *mut *mut c_void,
-    ),
+    ) -> c_int,
 >;
 
 pub enum LIBSSH2_SESSION {}
@@ -1372,6 +1549,27 @@ pub struct git_merge_file_options {
     pub marker_size: c_ushort,
 }
 
+pub type git_merge_driver_init_fn = Option<extern "C" fn(*mut git_merge_driver) -> c_int>;
+pub type git_merge_driver_shutdown_fn = Option<extern "C" fn(*mut git_merge_driver)>;
+pub type git_merge_driver_apply_fn = Option<
+    extern "C" fn(
+        *mut git_merge_driver,
+        *mut *const c_char,
+        *mut u32,
+        *mut git_buf,
+        *const c_char,
+        *const git_merge_driver_source,
+    ) -> c_int,
+>;
+
+#[repr(C)]
+pub struct git_merge_driver {
+    pub version: c_uint,
+    pub initialize: git_merge_driver_init_fn,
+    pub shutdown: git_merge_driver_shutdown_fn,
+    pub apply: git_merge_driver_apply_fn,
+}
+
 #[repr(C)]
 pub struct git_merge_file_result {
     pub automergeable: c_uint,
@@ -1490,6 +1688,9 @@ pub struct git_transport {
     pub free: Option<extern "C" fn(transport: *mut git_transport)>,
 }
 
+pub const GIT_FETCH_DEPTH_FULL: c_int = 0;
+pub const GIT_FETCH_DEPTH_UNSHALLOW: c_int = 2147483647;
+
 #[repr(C)]
 pub struct git_remote_connect_options {
     pub version: c_uint,
@@ -1622,6 +1823,14 @@ pub struct git_odb_writepack {
     pub free: Option<unsafe extern "C" fn(*mut git_odb_writepack)>,
 }
 
+#[repr(C)]
+pub struct git_reference_iterator {
+    pub db: *mut git_refdb,
+    pub next: Option<extern "C" fn(*mut *mut git_reference, *mut git_reference_iterator) -> c_int>,
+    pub next_name: Option<extern "C" fn(*mut *const c_char, *mut git_reference_iterator) -> c_int>,
+    pub free: Option<extern "C" fn(*mut git_reference_iterator)>,
+}
+
 #[repr(C)]
 pub struct git_refdb_backend {
     pub version: c_uint,
@@ -1709,6 +1918,36 @@ git_enum! {
     }
 }
 
+#[repr(C)]
+pub struct git_stream {
+    pub version: c_int,
+    // `encrypted` and `proxy_support` bitfields
+    pub flags: c_uint,
+    pub timeout: c_int,
+    pub connect_timeout: c_int,
+    pub connect: Option<extern "C" fn(*mut git_stream) -> c_int>,
+    pub certificate: Option<extern "C" fn(*mut *mut git_cert, *mut git_stream) -> c_int>,
+    pub set_proxy: Option<extern "C" fn(*mut git_stream, *const git_proxy_options) -> c_int>,
+    pub read: Option<extern "C" fn(*mut git_stream, *mut c_void, size_t) -> ssize_t>,
+    pub write: Option<extern "C" fn(*mut git_stream, *const c_char, size_t, c_int) -> ssize_t>,
+    pub close: Option<extern "C" fn(*mut git_stream) -> c_int>,
+    pub free: Option<extern "C" fn(*mut git_stream)>,
+}
+
+#[repr(C)]
+pub struct git_stream_registration {
+    pub version: c_int,
+    pub init: Option<extern "C" fn(*mut *mut git_stream, *const c_char, *const c_char) -> c_int>,
+    pub wrap: Option<extern "C" fn(*mut *mut git_stream, *mut git_stream, *const c_char) -> c_int>,
+}
+
+git_enum! {
+    pub enum git_stream_t {
+        GIT_STREAM_STANDARD = 1,
+        GIT_STREAM_TLS = 2,
+    }
+}
+
 git_enum! {
     pub enum git_smart_service_t {
         GIT_SERVICE_UPLOADPACK_LS = 1,
@@ -2325,6 +2564,7 @@ extern "C" {
     pub fn git_remote_pushurl(remote: *const git_remote) -> *const c_char;
     pub fn git_remote_refspec_count(remote: *const git_remote) -> size_t;
     pub fn git_remote_url(remote: *const git_remote) -> *const c_char;
+    pub fn git_remote_connect_options_dispose(opts: *mut git_remote_connect_options);
     pub fn git_remote_connect(
         remote: *mut git_remote,
         dir: git_direction,
@@ -2332,7 +2572,13 @@ extern "C" {
         proxy_opts: *const git_proxy_options,
         custom_headers: *const git_strarray,
     ) -> c_int;
+    pub fn git_remote_connect_ext(
+        remote: *mut git_remote,
+        dir: git_direction,
+        opts: *const git_remote_connect_options,
+    ) -> c_int;
     pub fn git_remote_connected(remote: *const git_remote) -> c_int;
+    pub fn git_remote_owner(remote: *const git_remote) -> *mut git_repository;
     pub fn git_remote_disconnect(remote: *mut git_remote) -> c_int;
     pub fn git_remote_add_fetch(
         repo: *mut git_repository,
@@ -2417,6 +2663,12 @@ extern "C" {
     pub fn git_remote_default_branch(out: *mut git_buf, remote: *mut git_remote) -> c_int;
 
     // refspec
+    pub fn git_refspec_parse(
+        refspec: *mut *mut git_refspec,
+        input: *const c_char,
+        is_fetch: c_int,
+    ) -> c_int;
+    pub fn git_refspec_free(refspec: *mut git_refspec);
     pub fn git_refspec_direction(spec: *const git_refspec) -> git_direction;
     pub fn git_refspec_dst(spec: *const git_refspec) -> *const c_char;
     pub fn git_refspec_dst_matches(spec: *const git_refspec, refname: *const c_char) -> c_int;
@@ -2570,6 +2822,15 @@ extern "C" {
         glob: *const c_char,
     ) -> c_int;
     pub fn git_reference_iterator_free(iter: *mut git_reference_iterator);
+    pub fn git_reference__alloc(
+        name: *const c_char,
+        oid: *const git_oid,
+        peel: *const git_oid,
+    ) -> *mut git_reference;
+    pub fn git_reference__alloc_symbolic(
+        name: *const c_char,
+        target: *const c_char,
+    ) -> *mut git_reference;
     pub fn git_reference_next(
         out: *mut *mut git_reference,
         iter: *mut git_reference_iterator,
@@ -2790,6 +3051,17 @@ extern "C" {
         id: *mut git_oid,
         stream: *mut git_writestream,
     ) -> c_int;
+    pub fn git_blob_owner(blob: *const git_blob) -> *mut git_repository;
+    pub fn git_blob_filter_options_init(
+        opts: *mut git_blob_filter_options,
+        version: c_uint,
+    ) -> c_int;
+    pub fn git_blob_filter(
+        out: *mut git_buf,
+        blob: *mut git_blob,
+        as_path: *const c_char,
+        opts: *mut git_blob_filter_options,
+    ) -> c_int;
 
     // tree
     pub fn git_tree_entry_byid(tree: *const git_tree, id: *const git_oid) -> *const git_tree_entry;
@@ -3241,6 +3513,71 @@ extern "C" {
         name: *const c_char,
         regexp: *const c_char,
     ) -> c_int;
+    pub fn git_config_init_backend(backend: *mut git_config_backend, version: c_uint) -> c_int;
+    pub fn git_config_add_backend(
+        cfg: *mut git_config,
+        backend: *mut git_config_backend,
+        level: git_config_level_t,
+        repo: *const git_repository,
+        force: c_int,
+    ) -> c_int;
+    pub fn git_config_backend_from_string(
+        out: *mut *mut git_config_backend,
+        cfg: *const c_char,
+        len: size_t,
+        opts: *mut git_config_backend_memory_options,
+    ) -> c_int;
+
+    // filter
+    pub fn git_filter_init(filter: *mut git_filter, version: c_uint) -> c_int;
+    pub fn git_filter_register(
+        name: *const c_char,
+        filter: *mut git_filter,
+        priority: c_int,
+    ) -> c_int;
+    pub fn git_filter_unregister(name: *const c_char) -> c_int;
+    pub fn git_filter_lookup(name: *const c_char) -> *mut git_filter;
+    pub fn git_filter_source_repo(src: *const git_filter_source) -> *mut git_repository;
+    pub fn git_filter_source_path(src: *const git_filter_source) -> *const c_char;
+    pub fn git_filter_source_filemode(src: *const git_filter_source) -> u16;
+    pub fn git_filter_source_id(src: *const git_filter_source) -> *const git_oid;
+    pub fn git_filter_source_mode(src: *const git_filter_source) -> git_filter_mode_t;
+    pub fn git_filter_source_flags(src: *const git_filter_source) -> u32;
+    pub fn git_filter_list_load(
+        filters: *mut *mut git_filter_list,
+        repo: *mut git_repository,
+        blob: *mut git_blob,
+        path: *const c_char,
+        mode: git_filter_mode_t,
+        flags: u32,
+    ) -> c_int;
+    pub fn git_filter_list_load_ext(
+        filters: *mut *mut git_filter_list,
+        repo: *mut git_repository,
+        blob: *mut git_blob,
+        path: *const c_char,
+        mode: git_filter_mode_t,
+        opts: *mut git_filter_options,
+    ) -> c_int;
+    pub fn git_filter_list_contains(filters: *mut git_filter_list, name: *const c_char) -> c_int;
+    pub fn git_filter_list_apply_to_buffer(
+        out: *mut git_buf,
+        filters: *mut git_filter_list,
+        data: *const c_char,
+        len: size_t,
+    ) -> c_int;
+    pub fn git_filter_list_apply_to_file(
+        out: *mut git_buf,
+        filters: *mut git_filter_list,
+        repo: *mut git_repository,
+        path: *const c_char,
+    ) -> c_int;
+    pub fn git_filter_list_apply_to_blob(
+        out: *mut git_buf,
+        filters: *mut git_filter_list,
+        blob: *mut git_blob,
+    ) -> c_int;
+    pub fn git_filter_list_free(filters: *mut git_filter_list);
 
     // attr
     pub fn git_attr_get(
@@ -3421,6 +3758,25 @@ extern "C" {
         -> c_int;
     pub fn git_repository_state_cleanup(repo: *mut git_repository) -> c_int;
 
+    // merge driver
+    pub fn git_merge_driver_lookup(name: *const c_char) -> *mut git_merge_driver;
+    pub fn git_merge_driver_register(name: *const c_char, driver: *mut git_merge_driver) -> c_int;
+    pub fn git_merge_driver_unregister(name: *const c_char) -> c_int;
+    pub fn git_merge_driver_source_repo(src: *const git_merge_driver_source)
+        -> *mut git_repository;
+    pub fn git_merge_driver_source_ancestor(
+        src: *const git_merge_driver_source,
+    ) -> *const git_index_entry;
+    pub fn git_merge_driver_source_ours(
+        src: *const git_merge_driver_source,
+    ) -> *const git_index_entry;
+    pub fn git_merge_driver_source_theirs(
+        src: *const git_merge_driver_source,
+    ) -> *const git_index_entry;
+    pub fn git_merge_driver_source_file_options(
+        src: *const git_merge_driver_source,
+    ) -> *const git_merge_file_options;
+
     // merge analysis
 
     pub fn git_merge_analysis(
@@ -3890,11 +4246,25 @@ extern "C" {
         param: *mut c_void,
     ) -> c_int;
     pub fn git_transport_unregister(prefix: *const c_char) -> c_int;
+    pub fn git_stream_register(
+        kind: git_stream_t,
+        registration: *mut git_stream_registration,
+    ) -> c_int;
     pub fn git_transport_smart(
         out: *mut *mut git_transport,
         owner: *mut git_remote,
         payload: *mut c_void,
     ) -> c_int;
+    pub fn git_transport_smart_credentials(
+        out: *mut *mut git_cred,
+        transport: *mut git_transport,
+        user: *const c_char,
+        methods: c_int,
+    ) -> c_int;
+    pub fn git_transport_remote_connect_options(
+        out: *mut git_remote_connect_options,
+        transport: *mut git_transport,
+    ) -> c_int;
 
     // describe
     pub fn git_describe_commit(
@@ -1,10 +1,12 @@
 use std::io;
 use std::marker;
 use std::mem;
+use std::path::Path;
+use std::ptr;
 use std::slice;
 
-use crate::util::Binding;
-use crate::{raw, Error, Object, Oid};
+use crate::util::{path_to_repo_path, Binding};
+use crate::{raw, Buf, Error, FilterList, FilterMode, Object, Oid};
 
 /// A structure to represent a git [blob][1]
 ///
@@ -39,6 +41,66 @@ impl<'repo> Blob<'repo> {
         unsafe { raw::git_blob_rawsize(&*self.raw) as usize }
     }
 
+    /// Get the content of this blob as it would be produced by applying the
+    /// filters configured for `as_path`.
+    ///
+    /// With the default options this is what a checkout writes to the
+    /// working directory: gitattributes such as `eol`, `ident` or
+    /// `working-tree-encoding` (and core.autocrlf) are taken into account.
+    /// The path does not need to exist, it is only used to look up
+    /// attributes.
+    pub fn filter(&self, as_path: &Path, opts: Option<&BlobFilterOptions>) -> Result<Buf, Error> {
+        let path = path_to_repo_path(as_path)?;
+        let default = BlobFilterOptions::new();
+        let opts = opts.unwrap_or(&default);
+        let buf = Buf::new();
+
+        // libgit2 hands back an empty buffer for binary blobs, return the
+        // unfiltered content instead.
+        if opts.raw.flags & raw::GIT_BLOB_FILTER_CHECK_FOR_BINARY != 0 && self.is_binary() {
+            let content = self.content();
+            unsafe {
+                try_call!(raw::git_buf_set(
+                    buf.raw(),
+                    content.as_ptr() as *const _,
+                    content.len()
+                ));
+            }
+            return Ok(buf);
+        }
+
+        match opts.mode {
+            FilterMode::ToWorktree => {
+                let mut raw_opts = opts.raw;
+                unsafe {
+                    try_call!(raw::git_blob_filter(
+                        buf.raw(),
+                        self.raw,
+                        path,
+                        &mut raw_opts
+                    ));
+                }
+                Ok(buf)
+            }
+            FilterMode::ToOdb => {
+                let mut filter_opts = opts.filter_options();
+                let mut list = ptr::null_mut();
+                unsafe {
+                    try_call!(raw::git_filter_list_load_ext(
+                        &mut list,
+                        raw::git_blob_owner(self.raw),
+                        self.raw,
+                        path,
+                        raw::GIT_FILTER_TO_ODB,
+                        &mut filter_opts
+                    ));
+                    let list: FilterList<'_> = Binding::from_raw(list);
+                    list.apply_to_blob(self)
+                }
+            }
+        }
+    }
+
     /// Casts this Blob to be usable as an `Object`
     pub fn as_object(&self) -> &Object<'repo> {
         unsafe { &*(self as *const _ as *const Object<'repo>) }
@@ -83,6 +145,97 @@ impl<'repo> Drop for Blob<'repo> {
     }
 }
 
+/// Options for [`Blob::filter`].
+pub struct BlobFilterOptions {
+    raw: raw::git_blob_filter_options,
+    mode: FilterMode,
+}
+
+impl Default for BlobFilterOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BlobFilterOptions {
+    /// Creates a default set of options, filtering towards the working
+    /// directory and leaving binary blobs alone.
+    pub fn new() -> BlobFilterOptions {
+        unsafe {
+            let mut raw: raw::git_blob_filter_options = mem::zeroed();
+            assert_eq!(
+                raw::git_blob_filter_options_init(&mut raw, raw::GIT_BLOB_FILTER_OPTIONS_VERSION),
+                0
+            );
+            BlobFilterOptions {
+                raw,
+                mode: FilterMode::ToWorktree,
+            }
+        }
+    }
+
+    fn flag(&mut self, opt: u32, val: bool) -> &mut BlobFilterOptions {
+        if val {
+            self.raw.flags |= opt;
+        } else {
+            self.raw.flags &= !opt;
+        }
+        self
+    }
+
+    /// The direction to filter in.
+    ///
+    /// [`FilterMode::ToWorktree`] (the default) produces the content a
+    /// checkout would write, [`FilterMode::ToOdb`] the content `git add`
+    /// would store for the blob's data.
+    pub fn mode(&mut self, mode: FilterMode) -> &mut BlobFilterOptions {
+        self.mode = mode;
+        self
+    }
+
+    /// Do not apply filters to binary blobs (enabled by default).
+    pub fn check_for_binary(&mut self, check: bool) -> &mut BlobFilterOptions {
+        self.flag(raw::GIT_BLOB_FILTER_CHECK_FOR_BINARY, check)
+    }
+
+    /// Do not load `/etc/gitattributes`.
+    pub fn no_system_attributes(&mut self, skip: bool) -> &mut BlobFilterOptions {
+        self.flag(raw::GIT_BLOB_FILTER_NO_SYSTEM_ATTRIBUTES, skip)
+    }
+
+    /// Load attributes from the `.gitattributes` file in the HEAD commit.
+    pub fn attributes_from_head(&mut self, enable: bool) -> &mut BlobFilterOptions {
+        self.flag(raw::GIT_BLOB_FILTER_ATTRIBUTES_FROM_HEAD, enable)
+    }
+
+    /// Load attributes from the `.gitattributes` file in the given commit.
+    pub fn attributes_from_commit(&mut self, commit: Oid) -> &mut BlobFilterOptions {
+        unsafe {
+            self.raw.attr_commit_id = *commit.raw();
+        }
+        self.flag(raw::GIT_BLOB_FILTER_ATTRIBUTES_FROM_COMMIT, true)
+    }
+
+    fn filter_options(&self) -> raw::git_filter_options {
+        let mut flags = 0;
+        if self.raw.flags & raw::GIT_BLOB_FILTER_NO_SYSTEM_ATTRIBUTES != 0 {
+            flags |= raw::GIT_FILTER_NO_SYSTEM_ATTRIBUTES;
+        }
+        if self.raw.flags & raw::GIT_BLOB_FILTER_ATTRIBUTES_FROM_HEAD != 0 {
+            flags |= raw::GIT_FILTER_ATTRIBUTES_FROM_HEAD;
+        }
+        if self.raw.flags & raw::GIT_BLOB_FILTER_ATTRIBUTES_FROM_COMMIT != 0 {
+            flags |= raw::GIT_FILTER_ATTRIBUTES_FROM_COMMIT;
+        }
+        raw::git_filter_options {
+            version: raw::GIT_FILTER_OPTIONS_VERSION,
+            flags,
+            commit_id: ptr::null_mut(),
+            attr_commit_id: self.raw.attr_commit_id,
+        }
+    }
+}
+
 /// A structure to represent a git writestream for blobs
 pub struct BlobWriter<'repo> {
     raw: *mut raw::git_writestream,
@@ -155,8 +308,8 @@ impl<'repo> io::Write for BlobWriter<'repo> {
 
 #[cfg(test)]
 mod tests {
-    use crate::Repository;
-    use std::fs::File;
+    use crate::{BlobFilterOptions, FilterMode, Repository};
+    use std::fs::{self, File};
     use std::io::prelude::*;
     use std::path::Path;
     use tempfile::TempDir;
@@ -205,4 +358,37 @@ mod tests {
         assert_eq!(blob.content(), [10, 11, 12]);
         blob.into_object();
     }
+
+    #[test]
+    fn filter() {
+        let (td, repo) = crate::test::repo_init();
+        t!(fs::write(
+            td.path().join(".gitattributes"),
+            "*.txt text eol=crlf\n*.c ident\n"
+        ));
+
+        let id = t!(repo.blob(b"a\nb\n"));
+        let blob = t!(repo.find_blob(id));
+        let out = t!(blob.filter(Path::new("foo.txt"), None));
+        assert_eq!(&*out, b"a\r\nb\r\n");
+        let out = t!(blob.filter(Path::new("foo.bin"), None));
+        assert_eq!(&*out, b"a\nb\n");
+
+        let id = t!(repo.blob(b"x\r\ny\r\n"));
+        let blob = t!(repo.find_blob(id));
+        let mut opts = BlobFilterOptions::new();
+        opts.mode(FilterMode::ToOdb);
+        let out = t!(blob.filter(Path::new("foo.txt"), Some(&opts)));
+        assert_eq!(&*out, b"x\ny\n");
+
+        let id = t!(repo.blob(b"/* $Id$ */\n"));
+        let blob = t!(repo.find_blob(id));
+        let out = t!(blob.filter(Path::new("foo.c"), None));
+        assert_eq!(out.as_str(), Some(&*format!("/* $Id: {} $ */\n", id)));
+
+        let id = t!(repo.blob(&[0, 1, b'\n', 2]));
+        let blob = t!(repo.find_blob(id));
+        let out = t!(blob.filter(Path::new("foo.txt"), None));
+        assert_eq!(&*out, [0, 1, b'\n', 2]);
+    }
 }
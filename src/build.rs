@@ -1,11 +1,13 @@
 //! Builder-pattern objects for configuration various git operations.
 
 use libc::{c_char, c_int, c_uint, c_void, size_t};
+use std::cell::RefCell;
 use std::ffi::{CStr, CString};
 use std::mem;
 use std::path::Path;
 use std::ptr;
 
+use crate::transport::Operation;
 use crate::util::{self, Binding};
 use crate::{panic, raw, Error, FetchOptions, IntoCString, Oid, Repository, Tree};
 use crate::{CheckoutNotificationType, DiffFile, FileMode, Remote};
@@ -241,6 +243,15 @@ impl<'cb> RepoBuilder<'cb> {
     /// This will use the options configured so far to clone the specified URL
     /// into the specified local path.
     pub fn clone(&mut self, url: &str, into: &Path) -> Result<Repository, Error> {
+        if self
+            .fetch_opts
+            .as_ref()
+            .is_some_and(|o| o.has_shallow_limits())
+        {
+            return Err(Error::from_str(
+                "shallow_since and shallow_exclude can't be used to clone",
+            ));
+        }
         let mut opts: raw::git_clone_options = unsafe { mem::zeroed() };
         unsafe {
             try_call!(raw::git_clone_init_options(
@@ -265,9 +276,9 @@ impl<'cb> RepoBuilder<'cb> {
             };
         }
 
-        if let Some(ref mut cbs) = self.fetch_opts {
-            opts.fetch_opts = cbs.raw();
-        }
+        let fetch_opts = self.fetch_opts.get_or_insert_with(FetchOptions::new);
+        let callbacks = fetch_opts.payload();
+        opts.fetch_opts = fetch_opts.raw();
 
         if let Some(ref mut c) = self.checkout {
             unsafe {
@@ -275,19 +286,55 @@ impl<'cb> RepoBuilder<'cb> {
             }
         }
 
-        if let Some(ref mut callback) = self.remote_create {
+        // The objects left out of partial clones are fetched lazily as soon
+        // as the remote is created, so that the checkout can read them. The
+        // remote is only made the promisor of the clone once the filter was
+        // sent.
+        let filter = self.fetch_opts.as_ref().and_then(|o| o.object_filter());
+        let promisor = RefCell::new(None);
+        let mut partial_clone: Box<RemoteCreate<'_>>;
+        let payload = match (filter, self.remote_create.as_mut()) {
+            (Some(_), mut user_callback) => {
+                let promisor = &promisor;
+                partial_clone = Box::new(move |repo, name, url| {
+                    let remote = match user_callback.as_mut() {
+                        Some(callback) => callback(repo, name, url)?,
+                        None => repo.remote(name, url)?,
+                    };
+                    if let Some(name) = remote.name() {
+                        *promisor.borrow_mut() = Some(name.to_string());
+                        let backend =
+                            crate::partial_clone::LazyFetch::new(repo, name, FetchOptions::new);
+                        repo.odb()?.add_backend(backend, 0)?;
+                    }
+                    Ok(remote)
+                });
+                Some(&mut partial_clone as *mut _ as *mut c_void)
+            }
+            (None, Some(callback)) => Some(callback as *mut _ as *mut c_void),
+            (None, None) => None,
+        };
+        if let Some(payload) = payload {
             opts.remote_cb = Some(remote_create_cb);
-            opts.remote_cb_payload = callback as *mut _ as *mut _;
+            opts.remote_cb_payload = payload;
         }
 
         let url = CString::new(url)?;
         // Normal file path OK (does not need Windows conversion).
         let into = into.into_c_string()?;
         let mut raw = ptr::null_mut();
-        unsafe {
-            try_call!(raw::git_clone(&mut raw, url, into, &opts));
-            Ok(Binding::from_raw(raw))
+        let operation = Operation::fetch(Vec::new(), filter.map(|f| f.to_string()), Vec::new());
+        let repo = operation
+            .clone()
+            .run(url.to_str().unwrap(), callbacks, || unsafe {
+                try_call!(raw::git_clone(&mut raw, url, into, &opts));
+                Ok(Binding::from_raw(raw))
+            })?;
+        let promisor = promisor.borrow_mut().take();
+        if let (Some(filter), Some(remote), true) = (filter, promisor, operation.filter_sent()) {
+            crate::partial_clone::configure(&repo, &remote, &filter)?;
         }
+        Ok(repo)
     }
 }
 
@@ -0,0 +1,501 @@
+//! Reading and writing git bundles
+//!
+//! A bundle is a file holding a list of references and a pack with the
+//! objects they need, the same as `git bundle` creates. Bundles move history
+//! between repositories that can't reach each other over the network: a
+//! [`BundleBuilder`] writes one, and the receiving side can
+//! [verify](Bundle::verify) it, [list its heads](Bundle::heads) and
+//! [unbundle](Bundle::unbundle) it, or fetch from it with
+//! [`transport::bundle`](crate::transport::bundle).
+//!
+//! A bundle may contain only the history since some commits, its
+//! prerequisites, which the repository reading it must already have.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, prelude::*, BufReader, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str;
+
+use crate::{Error, ErrorClass, ErrorCode, ObjectType, Oid, Repository, Revwalk};
+
+const V2_SIGNATURE: &str = "# v2 git bundle\n";
+const V3_SIGNATURE: &str = "# v3 git bundle\n";
+
+/// Versions of the bundle format.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum BundleVersion {
+    /// The version written by git by default.
+    V2,
+    /// Adds capabilities to the header, such as the hash algorithm used.
+    V3,
+}
+
+/// A bundle file, as read from its header.
+pub struct Bundle {
+    path: PathBuf,
+    version: BundleVersion,
+    filter: Option<String>,
+    prerequisites: Vec<Oid>,
+    heads: Vec<BundleRef>,
+    pack_offset: u64,
+}
+
+/// A reference contained in a bundle.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BundleRef {
+    id: Oid,
+    name: String,
+}
+
+/// A builder for writing bundles, the equivalent of `git bundle create`.
+pub struct BundleBuilder<'repo> {
+    repo: &'repo Repository,
+    version: BundleVersion,
+    refs: Vec<String>,
+}
+
+impl Bundle {
+    /// Open the bundle at `path` and read its header.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Bundle, Error> {
+        let path = path.as_ref();
+        let file = File::open(path).map_err(io_error)?;
+        let mut reader = BufReader::new(file);
+        let mut line = Vec::new();
+        let mut offset = read_line(&mut reader, &mut line)?;
+        let version = match str::from_utf8(&line) {
+            Ok(V2_SIGNATURE) => BundleVersion::V2,
+            Ok(V3_SIGNATURE) => BundleVersion::V3,
+            _ => return Err(invalid(&format!("'{}' is not a bundle", path.display()))),
+        };
+
+        let mut bundle = Bundle {
+            path: path.to_path_buf(),
+            version,
+            filter: None,
+            prerequisites: Vec::new(),
+            heads: Vec::new(),
+            pack_offset: 0,
+        };
+        loop {
+            line.clear();
+            let n = read_line(&mut reader, &mut line)?;
+            offset += n;
+            if n == 0 || !line.ends_with(b"\n") {
+                return Err(invalid("unexpected end of bundle header"));
+            }
+            let line = str::from_utf8(&line[..n - 1])
+                .map_err(|_| invalid("bundle header is not valid UTF-8"))?;
+            if line.is_empty() {
+                break;
+            }
+            if let Some(capability) = line.strip_prefix('@') {
+                if version == BundleVersion::V2 {
+                    return Err(invalid("capabilities are not allowed in a v2 bundle"));
+                }
+                match capability.split_once('=') {
+                    Some(("object-format", "sha1")) => {}
+                    Some(("object-format", format)) => {
+                        return Err(invalid(&format!(
+                            "unsupported object format '{}' in bundle",
+                            format
+                        )))
+                    }
+                    Some(("filter", filter)) => bundle.filter = Some(filter.to_string()),
+                    _ => {
+                        return Err(invalid(&format!(
+                            "unknown bundle capability '{}'",
+                            capability
+                        )))
+                    }
+                }
+            } else if let Some(prerequisite) = line.strip_prefix('-') {
+                // The object id may be followed by the commit's subject.
+                let id = prerequisite.split(' ').next().unwrap_or("");
+                bundle.prerequisites.push(parse_oid(id)?);
+            } else {
+                let (id, name) = line
+                    .split_once(' ')
+                    .ok_or_else(|| invalid(&format!("invalid bundle reference '{}'", line)))?;
+                bundle.heads.push(BundleRef {
+                    id: parse_oid(id)?,
+                    name: name.to_string(),
+                });
+            }
+        }
+        bundle.pack_offset = offset as u64;
+        Ok(bundle)
+    }
+
+    /// Get the path of the bundle file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get the version of the format of the bundle.
+    pub fn version(&self) -> BundleVersion {
+        self.version
+    }
+
+    /// Get the object filter the pack of the bundle was created with, if it
+    /// only contains some of the objects of its history.
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_deref()
+    }
+
+    /// Get the commits a repository must have to read the bundle.
+    pub fn prerequisites(&self) -> &[Oid] {
+        &self.prerequisites
+    }
+
+    /// Get the references contained in the bundle, the equivalent of
+    /// `git bundle list-heads`.
+    pub fn heads(&self) -> &[BundleRef] {
+        &self.heads
+    }
+
+    /// Check that `repo` has all the prerequisites of the bundle, the
+    /// equivalent of `git bundle verify`.
+    pub fn verify(&self, repo: &Repository) -> Result<(), Error> {
+        let missing = self
+            .prerequisites
+            .iter()
+            .filter(|id| repo.find_commit(**id).is_err())
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(Error::new(
+            ErrorCode::NotFound,
+            ErrorClass::Object,
+            format!(
+                "repository lacks these prerequisite commits: {}",
+                missing.join(", ")
+            ),
+        ))
+    }
+
+    /// Add the objects in the bundle to `repo`, the equivalent of
+    /// `git bundle unbundle`.
+    ///
+    /// The prerequisites of the bundle are verified first. No reference is
+    /// updated, the heads of the bundle point to the objects added.
+    pub fn unbundle(&self, repo: &Repository) -> Result<(), Error> {
+        self.verify(repo)?;
+        // The object database knows where its packs go, which for a
+        // worktree is in the common directory.
+        let odb = repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        let mut pack = self.pack()?;
+        io::copy(&mut pack, &mut writer).map_err(io_error)?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Open the bundle file, positioned at the start of its pack.
+    pub(crate) fn pack(&self) -> Result<File, Error> {
+        let mut file = File::open(&self.path).map_err(io_error)?;
+        file.seek(SeekFrom::Start(self.pack_offset))
+            .map_err(io_error)?;
+        Ok(file)
+    }
+}
+
+impl BundleRef {
+    /// Get the object the reference points to.
+    pub fn id(&self) -> Oid {
+        self.id
+    }
+
+    /// Get the full name of the reference, such as `refs/heads/main`.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<'repo> BundleBuilder<'repo> {
+    /// Create a builder writing bundles of objects in `repo`.
+    pub fn new(repo: &'repo Repository) -> BundleBuilder<'repo> {
+        BundleBuilder {
+            repo,
+            version: BundleVersion::V2,
+            refs: Vec::new(),
+        }
+    }
+
+    /// Set the version of the format to write.
+    ///
+    /// Defaults to [`BundleVersion::V2`].
+    pub fn version(&mut self, version: BundleVersion) -> &mut BundleBuilder<'repo> {
+        self.version = version;
+        self
+    }
+
+    /// Add a reference to the heads of the bundle.
+    ///
+    /// The name may be shortened, as in `main` for `refs/heads/main`, and
+    /// `HEAD` is allowed. The bundle records the full name of the reference.
+    pub fn reference(&mut self, name: &str) -> &mut BundleBuilder<'repo> {
+        self.refs.push(name.to_string());
+        self
+    }
+
+    /// Write a bundle of the commits in `walk` to `out`.
+    ///
+    /// The commits the walk was told to [hide](Revwalk::hide) which are
+    /// parents of the commits in the walk become the prerequisites of the
+    /// bundle. The heads of the bundle are the references added with
+    /// [`reference`](BundleBuilder::reference), of which there must be at
+    /// least one. Like `git bundle create`, this fails if a reference points
+    /// to a commit which is neither in the walk nor a prerequisite, as the
+    /// bundle wouldn't have the objects it needs.
+    pub fn write<W: Write>(&self, walk: &mut Revwalk<'_>, mut out: W) -> Result<(), Error> {
+        if self.refs.is_empty() {
+            return Err(Error::from_str("refusing to create an empty bundle"));
+        }
+        let mut heads = Vec::new();
+        for name in &self.refs {
+            let reference = match self.repo.find_reference(name) {
+                Ok(reference) => reference,
+                Err(_) => self.repo.resolve_reference_from_short_name(name)?,
+            };
+            let id = reference.resolve()?.target().unwrap();
+            let name = reference
+                .name()
+                .ok_or_else(|| Error::from_str("reference name is not valid UTF-8"))?;
+            heads.push((id, name.to_string()));
+        }
+
+        let mut commits = Vec::new();
+        for id in walk {
+            commits.push(id?);
+        }
+        let included = commits.iter().collect::<HashSet<_>>();
+        let mut prerequisites = Vec::new();
+        for id in &commits {
+            for parent in self.repo.find_commit(*id)?.parent_ids() {
+                if !included.contains(&parent) && !prerequisites.contains(&parent) {
+                    prerequisites.push(parent);
+                }
+            }
+        }
+
+        for (id, name) in &heads {
+            let commit = self.repo.find_object(*id, None)?.peel(ObjectType::Commit)?;
+            let commit = commit.id();
+            if !included.contains(&commit) && !prerequisites.contains(&commit) {
+                return Err(Error::new(
+                    ErrorCode::InvalidSpec,
+                    ErrorClass::Invalid,
+                    format!("ref '{}' is excluded by the revision walk", name),
+                ));
+            }
+        }
+
+        let mut header = String::from(match self.version {
+            BundleVersion::V2 => V2_SIGNATURE,
+            BundleVersion::V3 => V3_SIGNATURE,
+        });
+        if self.version == BundleVersion::V3 {
+            header.push_str("@object-format=sha1\n");
+        }
+        for id in &prerequisites {
+            let commit = self.repo.find_commit(*id)?;
+            header.push_str(&format!("-{} {}\n", id, commit.summary().unwrap_or("")));
+        }
+        for (id, name) in &heads {
+            header.push_str(&format!("{} {}\n", id, name));
+        }
+        header.push('\n');
+        out.write_all(header.as_bytes()).map_err(io_error)?;
+
+        // Hiding the prerequisites leaves out the trees and blobs the reader
+        // already has.
+        let mut pb = self.repo.packbuilder()?;
+        let mut pack_walk = self.repo.revwalk()?;
+        for id in &commits {
+            pack_walk.push(*id)?;
+        }
+        for id in &prerequisites {
+            pack_walk.hide(*id)?;
+        }
+        pb.insert_walk(&mut pack_walk)?;
+        for (id, _) in &heads {
+            if self.repo.find_object(*id, None)?.kind() == Some(ObjectType::Tag) {
+                pb.insert_object(*id, None)?;
+            }
+        }
+        let mut err = None;
+        pb.foreach(|chunk| match out.write_all(chunk) {
+            Ok(()) => true,
+            Err(e) => {
+                err = Some(io_error(e));
+                false
+            }
+        })
+        .map_err(|e| err.take().unwrap_or(e))?;
+        out.flush().map_err(io_error)
+    }
+
+    /// Write a bundle of the commits in `walk` to a new file at `path`.
+    ///
+    /// See [`write`](BundleBuilder::write) for what the bundle contains.
+    pub fn write_to_path<P: AsRef<Path>>(
+        &self,
+        walk: &mut Revwalk<'_>,
+        path: P,
+    ) -> Result<(), Error> {
+        let file = File::create(path).map_err(io_error)?;
+        self.write(walk, io::BufWriter::new(file))
+    }
+}
+
+/// Read a line of the header, returning the number of bytes read.
+fn read_line<R: BufRead>(reader: &mut R, line: &mut Vec<u8>) -> Result<usize, Error> {
+    reader.read_until(b'\n', line).map_err(io_error)
+}
+
+fn parse_oid(s: &str) -> Result<Oid, Error> {
+    if s.len() != 40 {
+        return Err(invalid(&format!("invalid object id '{}' in bundle", s)));
+    }
+    Oid::from_str(s)
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorCode::Invalid, ErrorClass::Invalid, msg)
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::new(ErrorCode::GenericError, ErrorClass::Os, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn create_and_unbundle() {
+        let (_td, repo) = crate::test::repo_init();
+        let base = t!(repo.head()).target().unwrap();
+        let clone_td = TempDir::new().unwrap();
+        let url = crate::test::path2url(repo.path());
+        let clone = t!(crate::build::RepoBuilder::new().clone(&url, clone_td.path()));
+        let (tip, _) = crate::test::commit(&repo);
+        let sig = t!(repo.signature());
+        let tag = t!(repo.tag("v1", &t!(repo.find_object(tip, None)), &sig, "v1", false));
+
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("repo.bundle");
+        let mut walk = t!(repo.revwalk());
+        t!(walk.push(tip));
+        t!(walk.hide(base));
+        t!(BundleBuilder::new(&repo)
+            .version(BundleVersion::V3)
+            .reference("main")
+            .reference("HEAD")
+            .reference("v1")
+            .write_to_path(&mut walk, &path));
+
+        let contents = t!(fs::read(&path));
+        let header = format!(
+            "# v3 git bundle\n@object-format=sha1\n-{} initial\n{} refs/heads/main\n{} HEAD\n{} refs/tags/v1\n\nPACK",
+            base, tip, tip, tag
+        );
+        assert!(contents.starts_with(header.as_bytes()));
+
+        let bundle = t!(Bundle::open(&path));
+        assert_eq!(bundle.version(), BundleVersion::V3);
+        assert_eq!(bundle.filter(), None);
+        assert_eq!(bundle.prerequisites(), [base]);
+        let heads = bundle
+            .heads()
+            .iter()
+            .map(|r| (r.id(), r.name()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            heads,
+            [
+                (tip, "refs/heads/main"),
+                (tip, "HEAD"),
+                (tag, "refs/tags/v1")
+            ]
+        );
+
+        let other_td = TempDir::new().unwrap();
+        let other = t!(Repository::init(other_td.path()));
+        let err = bundle.verify(&other).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(err.message().contains(&base.to_string()));
+        assert!(bundle.unbundle(&other).is_err());
+
+        // The clone has the prerequisites but not the new commit.
+        assert!(clone.find_commit(tip).is_err());
+        t!(bundle.verify(&clone));
+        // The objects of a worktree go to the common directory.
+        let wt_td = TempDir::new().unwrap();
+        let wt = t!(clone.worktree("wt", &wt_td.path().join("wt"), None));
+        let wt = t!(Repository::open_from_worktree(&wt));
+        t!(bundle.unbundle(&wt));
+        assert!(clone.find_commit(tip).is_ok());
+        assert!(clone.find_tag(tag).is_ok());
+    }
+
+    #[test]
+    fn excluded_heads() {
+        let (_td, repo) = crate::test::repo_init();
+        let base = t!(repo.head()).target().unwrap();
+        let (tip, _) = crate::test::commit(&repo);
+        t!(repo.reference("refs/heads/base", base, false, "base"));
+
+        let mut walk = t!(repo.revwalk());
+        t!(walk.push(base));
+        let err = BundleBuilder::new(&repo)
+            .reference("main")
+            .write(&mut walk, Vec::new())
+            .unwrap_err();
+        assert_eq!(err.code(), ErrorCode::InvalidSpec);
+        assert!(err.message().contains("refs/heads/main"));
+
+        // Heads may be prerequisites.
+        let mut walk = t!(repo.revwalk());
+        t!(walk.push(tip));
+        t!(walk.hide(base));
+        t!(BundleBuilder::new(&repo)
+            .reference("main")
+            .reference("base")
+            .write(&mut walk, Vec::new()));
+    }
+
+    #[test]
+    fn invalid_bundles() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("bad.bundle");
+        let id = "0123456789012345678901234567890123456789";
+        let cases = [
+            "not a bundle\n".to_string(),
+            format!("# v2 git bundle\n{} refs/heads/main\n", id),
+            format!("# v2 git bundle\n@object-format=sha1\n{} HEAD\n\n", id),
+            format!("# v3 git bundle\n@object-format=sha256\n{} HEAD\n\n", id),
+            "# v2 git bundle\n0123 HEAD\n\n".to_string(),
+        ];
+        for case in &cases {
+            t!(fs::write(&path, case));
+            assert!(Bundle::open(&path).is_err(), "{:?}", case);
+        }
+
+        t!(fs::write(
+            &path,
+            format!(
+                "# v3 git bundle\n@filter=blob:none\n-{} subject\n{} HEAD\n\n",
+                id, id
+            )
+        ));
+        let bundle = t!(Bundle::open(&path));
+        assert_eq!(bundle.filter(), Some("blob:none"));
+        assert_eq!(bundle.prerequisites(), [t!(Oid::from_str(id))]);
+    }
+}
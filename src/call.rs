@@ -54,7 +54,8 @@ mod impls {
     use crate::call::Convert;
     use crate::{raw, BranchType, ConfigLevel, Direction, ObjectType, ResetType};
     use crate::{
-        AutotagOption, DiffFormat, FetchPrune, FileFavor, SubmoduleIgnore, SubmoduleUpdate,
+        AutotagOption, DiffFormat, FetchPrune, FileFavor, FilterMode, SubmoduleIgnore,
+        SubmoduleUpdate,
     };
 
     impl<T: Copy> Convert<T> for T {
@@ -195,6 +196,15 @@ mod impls {
         }
     }
 
+    impl Convert<raw::git_filter_mode_t> for FilterMode {
+        fn convert(&self) -> raw::git_filter_mode_t {
+            match *self {
+                FilterMode::ToWorktree => raw::GIT_FILTER_TO_WORKTREE,
+                FilterMode::ToOdb => raw::GIT_FILTER_TO_ODB,
+            }
+        }
+    }
+
     impl Convert<raw::git_submodule_ignore_t> for SubmoduleIgnore {
         fn convert(&self) -> raw::git_submodule_ignore_t {
             match *self {
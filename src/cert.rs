@@ -1,12 +1,59 @@
 //! Certificate types which are passed to `CertificateCheck` in
 //! `RemoteCallbacks`.
 
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+use libc::c_long;
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    any(feature = "ssh", feature = "https")
+))]
+use libc::{c_int, c_void};
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+use std::ffi::{CStr, CString};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
 use std::marker;
 use std::mem;
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    any(feature = "ssh", feature = "https")
+))]
+use std::ptr;
 use std::slice;
+use std::str;
 
 use crate::raw;
-use crate::util::Binding;
+use crate::util::{base64, unbase64, Binding};
+use crate::Error;
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+use crate::{ErrorClass, ErrorCode};
 
 /// A certificate for a remote connection, viewable as one of `CertHostkey` or
 /// `CertX509` currently.
@@ -27,6 +74,136 @@ pub struct CertX509<'a> {
     _marker: marker::PhantomData<&'a raw::git_cert>,
 }
 
+/// The result of checking a host key with [`KnownHosts`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HostKeyStatus {
+    /// The host is known with this key.
+    Accept,
+    /// The key is revoked, or the host is known with another key of the same
+    /// type, which might mean that someone impersonates the host.
+    Reject,
+    /// The host isn't known with a key of this type.
+    Unknown,
+}
+
+/// The host keys of an OpenSSH `known_hosts` file, to check the host keys
+/// passed to [`RemoteCallbacks::certificate_check`].
+///
+/// Hashed host names, wildcards, negated patterns, non-default ports and
+/// the `@revoked` marker are supported. libssh2 doesn't support host
+/// certificates, so the keys of hosts matched by a `@cert-authority` line
+/// are rejected unless another line lists them.
+///
+/// Hashed host names are matched with OpenSSL, so they can only be matched
+/// when the `ssh` or `https` feature is enabled on Unix platforms other than
+/// macOS. Elsewhere a hashed line may be the one for the host being checked,
+/// so keys it could apply to are rejected rather than reported as unknown.
+///
+/// ```no_run
+/// use git2::cert::{HostKeyStatus, KnownHosts};
+/// use git2::{CertificateCheckStatus, Error, RemoteCallbacks};
+/// use std::path::Path;
+///
+/// let mut known_hosts = KnownHosts::from_file(Path::new("/home/me/.ssh/known_hosts"))?;
+/// let mut callbacks = RemoteCallbacks::new();
+/// callbacks.certificate_check(move |cert, host| {
+///     let hostkey = match cert.as_hostkey() {
+///         Some(hostkey) => hostkey,
+///         None => return Ok(CertificateCheckStatus::CertificatePassthrough),
+///     };
+///     match known_hosts.check(host, 22, hostkey) {
+///         HostKeyStatus::Accept => Ok(CertificateCheckStatus::CertificateOk),
+///         HostKeyStatus::Unknown => match hostkey.hostkey() {
+///             // Trust hosts on first use, when there is a key to remember.
+///             Some(key) => {
+///                 known_hosts.add(host, 22, key)?;
+///                 Ok(CertificateCheckStatus::CertificateOk)
+///             }
+///             None => Err(Error::from_str("unknown host key")),
+///         },
+///         HostKeyStatus::Reject => Err(Error::from_str("host key verification failed")),
+///     }
+/// });
+/// # Ok::<(), Error>(())
+/// ```
+///
+/// [`RemoteCallbacks::certificate_check`]: crate::RemoteCallbacks::certificate_check
+#[derive(Default)]
+pub struct KnownHosts {
+    entries: Vec<KnownHost>,
+    path: Option<PathBuf>,
+}
+
+/// A line of a `known_hosts` file.
+struct KnownHost {
+    marker: Option<Marker>,
+    patterns: String,
+    key: Vec<u8>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Marker {
+    CertAuthority,
+    Revoked,
+}
+
+/// A set of trust anchors for the X.509 certificates of HTTPS remotes, used
+/// by [`RemoteCallbacks::verify_x509_with`] instead of the system's trust
+/// store.
+///
+/// A certificate is trusted if it's valid for the host and either the hash
+/// of its public key is pinned, or it's signed by one of the roots and may
+/// be used by a TLS server. libgit2 only passes the server's own certificate
+/// to the callback, so intermediate certificates have to be added as roots,
+/// too.
+///
+/// This is only available when libgit2 uses OpenSSL.
+///
+/// ```no_run
+/// use git2::cert::X509Roots;
+/// use git2::RemoteCallbacks;
+/// use std::fs;
+///
+/// let mut roots = X509Roots::new();
+/// roots.add_pem(&fs::read("/etc/customers/acme/ca.pem").unwrap())?;
+/// let mut callbacks = RemoteCallbacks::new();
+/// callbacks.verify_x509_with(roots);
+/// # Ok::<(), git2::Error>(())
+/// ```
+///
+/// [`RemoteCallbacks::verify_x509_with`]: crate::RemoteCallbacks::verify_x509_with
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+#[derive(Clone, Default)]
+pub struct X509Roots {
+    roots: Vec<Vec<u8>>,
+    pins: Vec<[u8; 32]>,
+}
+
+/// An owned OpenSSL certificate.
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+struct X509(*mut openssl_sys::X509);
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+extern "C" {
+    // Not bound by openssl-sys.
+    fn X509_STORE_CTX_set_purpose(ctx: *mut openssl_sys::X509_STORE_CTX, purpose: c_int) -> c_int;
+}
+
 /// The SSH host key type.
 #[derive(Copy, Clone, Debug)]
 #[non_exhaustive]
@@ -170,6 +347,595 @@ impl<'a> CertHostkey<'a> {
     }
 }
 
+impl KnownHosts {
+    /// Create an empty set of known hosts.
+    pub fn new() -> KnownHosts {
+        KnownHosts::default()
+    }
+
+    /// Parse the contents of a `known_hosts` file.
+    ///
+    /// Lines which can't be parsed are ignored, like OpenSSH does.
+    pub fn parse(contents: &str) -> KnownHosts {
+        KnownHosts {
+            entries: contents.lines().filter_map(KnownHost::parse).collect(),
+            path: None,
+        }
+    }
+
+    /// Read the `known_hosts` file at `path`, which [`KnownHosts::add`]
+    /// appends new hosts to.
+    ///
+    /// A missing file is treated as an empty one.
+    pub fn from_file(path: &Path) -> Result<KnownHosts, Error> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(io_error(path, e)),
+        };
+        let mut ret = KnownHosts::parse(&contents);
+        ret.path = Some(path.to_path_buf());
+        Ok(ret)
+    }
+
+    /// Check the host key of a certificate for `host` on `port`.
+    ///
+    /// `host` is the host name passed to the certificate callback, and
+    /// `port` the port of the URL, 22 if it has none. If libssh2 didn't
+    /// provide the raw key, keys are compared by their SHA-1 hash with
+    /// OpenSSL. As the type of the key isn't known then, any other key
+    /// listed for the host makes it rejected, and so does any key listed
+    /// for it without OpenSSL.
+    pub fn check(&self, host: &str, port: u16, cert: &CertHostkey<'_>) -> HostKeyStatus {
+        if let Some(key) = cert.hostkey() {
+            return self.check_key(host, port, key);
+        }
+        let hash = match cert.hash_sha1() {
+            Some(hash) => hash,
+            None => return HostKeyStatus::Unknown,
+        };
+        let name = host_name(host, port);
+        let mut found = false;
+        let mut changed = false;
+        for entry in &self.entries {
+            let matches = match entry.matches(&name) {
+                Some(false) => continue,
+                matches => matches,
+            };
+            let same = match sha1(&entry.key) {
+                Some(entry_hash) => entry_hash == *hash,
+                None => return HostKeyStatus::Reject,
+            };
+            match entry.marker {
+                Some(Marker::Revoked) if same => return HostKeyStatus::Reject,
+                Some(Marker::Revoked) => {}
+                Some(Marker::CertAuthority) => changed = true,
+                None if same && matches.is_some() => found = true,
+                None => changed = true,
+            }
+        }
+        status(found, changed)
+    }
+
+    /// Check a host key for `host` on `port`, given in the ssh wire format
+    /// like [`CertHostkey::hostkey`] returns it.
+    pub fn check_key(&self, host: &str, port: u16, key: &[u8]) -> HostKeyStatus {
+        let name = host_name(host, port);
+        let key_type = key_type(key);
+        let mut found = false;
+        let mut changed = false;
+        for entry in &self.entries {
+            // Lines which may be for the host count as if they were, except
+            // to accept its key.
+            let matches = match entry.matches(&name) {
+                Some(false) => continue,
+                matches => matches,
+            };
+            match entry.marker {
+                Some(Marker::Revoked) if entry.key == key => return HostKeyStatus::Reject,
+                Some(Marker::Revoked) => {}
+                Some(Marker::CertAuthority) => changed = true,
+                None if entry.key == key && matches.is_some() => found = true,
+                None if entry.key == key => changed = true,
+                None => changed |= key_type.is_some() && key_type == self::key_type(&entry.key),
+            }
+        }
+        status(found, changed)
+    }
+
+    /// Add a host key for `host` on `port`, appending it to the file this
+    /// was read from if any.
+    ///
+    /// The key is given in the ssh wire format like [`CertHostkey::hostkey`]
+    /// returns it. The host name is written as is, not hashed.
+    pub fn add(&mut self, host: &str, port: u16, key: &[u8]) -> Result<(), Error> {
+        let key_type = key_type(key).ok_or_else(|| Error::from_str("invalid host key"))?;
+        let line = format!("{} {} {}", host_name(host, port), key_type, base64(key));
+        if let Some(path) = &self.path {
+            let append = || -> io::Result<()> {
+                let newline = match fs::read(path) {
+                    Ok(contents) => !contents.is_empty() && !contents.ends_with(b"\n"),
+                    Err(_) => false,
+                };
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                if newline {
+                    file.write_all(b"\n")?;
+                }
+                writeln!(file, "{}", line)
+            };
+            append().map_err(|e| io_error(path, e))?;
+        }
+        self.entries.extend(KnownHost::parse(&line));
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+impl X509Roots {
+    /// Create a set of roots which trusts nothing.
+    pub fn new() -> X509Roots {
+        X509Roots::default()
+    }
+
+    /// Add the certificates of a PEM bundle as roots.
+    ///
+    /// libgit2 only passes the server's own certificate to the callback, not
+    /// the chain the server sent, so the certificate which issued it has to
+    /// be among the roots: when the server's certificate is issued by an
+    /// intermediate CA, add that intermediate, not only the root CA above
+    /// it. Any certificate added is trusted as an anchor on its own.
+    ///
+    /// Returns an error if the bundle contains no certificates or one which
+    /// can't be parsed, in which case none are added.
+    pub fn add_pem(&mut self, pem: &[u8]) -> Result<&mut X509Roots, Error> {
+        let len = c_int::try_from(pem.len()).map_err(|_| Error::from_str("invalid PEM data"))?;
+        openssl_sys::init();
+        let mut certs = Vec::new();
+        unsafe {
+            let bio = openssl_sys::BIO_new_mem_buf(pem.as_ptr() as *mut c_void, len);
+            if bio.is_null() {
+                return Err(Error::from_str("out of memory"));
+            }
+            let ret = loop {
+                let x509 =
+                    openssl_sys::PEM_read_bio_X509(bio, ptr::null_mut(), None, ptr::null_mut());
+                if x509.is_null() {
+                    // Reading stops at the end of the data for want of
+                    // another start line.
+                    let err = openssl_sys::ERR_peek_last_error();
+                    let end = openssl_sys::ERR_GET_LIB(err) == openssl_sys::ERR_LIB_PEM
+                        && openssl_sys::ERR_GET_REASON(err) == openssl_sys::PEM_R_NO_START_LINE;
+                    openssl_sys::ERR_clear_error();
+                    break match end {
+                        true => Ok(()),
+                        false => Err(Error::from_str("invalid PEM data")),
+                    };
+                }
+                match X509(x509).to_der() {
+                    Ok(der) => certs.push(der),
+                    Err(e) => break Err(e),
+                }
+            };
+            openssl_sys::BIO_free_all(bio);
+            ret?;
+        }
+        if certs.is_empty() {
+            return Err(Error::from_str("no certificates found in PEM data"));
+        }
+        self.roots.extend(certs);
+        Ok(self)
+    }
+
+    /// Add a DER encoded certificate as a root.
+    ///
+    /// As with [`add_pem`](X509Roots::add_pem), the certificate which issued
+    /// the server's certificate has to be added, which is an intermediate CA
+    /// rather than the root CA above it when there is one.
+    pub fn add_der(&mut self, der: &[u8]) -> Result<&mut X509Roots, Error> {
+        X509::from_der(der)?;
+        self.roots.push(der.to_vec());
+        Ok(self)
+    }
+
+    /// Trust certificates whose public key has this hash, the SHA-256 hash
+    /// of the DER encoded SubjectPublicKeyInfo as used for HTTP public key
+    /// pinning.
+    ///
+    /// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform der |
+    /// openssl dgst -sha256` prints it for a PEM certificate.
+    pub fn pin_spki_sha256(&mut self, hash: [u8; 32]) -> &mut X509Roots {
+        self.pins.push(hash);
+        self
+    }
+
+    /// Check that `cert` is valid for `host` and trusted by these roots.
+    ///
+    /// The error has the code [`ErrorCode::Certificate`] if the certificate
+    /// isn't trusted.
+    ///
+    /// [`ErrorCode::Certificate`]: crate::ErrorCode::Certificate
+    pub fn verify(&self, cert: &CertX509<'_>, host: &str) -> Result<(), Error> {
+        let leaf = X509::from_der(cert.data())?;
+        if !leaf.matches_host(host) {
+            return Err(certificate_error(&format!(
+                "certificate is not valid for '{}'",
+                host
+            )));
+        }
+        if !self.pins.is_empty() && self.pins.contains(&leaf.spki_sha256()?) {
+            return Ok(());
+        }
+        if self.roots.is_empty() {
+            return Err(certificate_error("certificate is not trusted"));
+        }
+        let roots = self
+            .roots
+            .iter()
+            .map(|der| X509::from_der(der))
+            .collect::<Result<Vec<_>, _>>()?;
+        unsafe {
+            let store = openssl_sys::X509_STORE_new();
+            let ctx = openssl_sys::X509_STORE_CTX_new();
+            let ret = if store.is_null() || ctx.is_null() {
+                Err(Error::from_str("out of memory"))
+            } else {
+                for root in roots.iter() {
+                    openssl_sys::X509_STORE_add_cert(store, root.0);
+                }
+                // The chain only consists of the server's certificate, so
+                // any root has to be accepted as an anchor, not only
+                // self-signed ones.
+                openssl_sys::X509_STORE_set_flags(store, openssl_sys::X509_V_FLAG_PARTIAL_CHAIN);
+                if openssl_sys::X509_STORE_CTX_init(ctx, store, leaf.0, ptr::null_mut()) != 1
+                    || X509_STORE_CTX_set_purpose(ctx, openssl_sys::X509_PURPOSE_SSL_SERVER) != 1
+                {
+                    Err(Error::from_str(
+                        "failed to initialize certificate verification",
+                    ))
+                } else if openssl_sys::X509_verify_cert(ctx) == 1 {
+                    Ok(())
+                } else {
+                    let code = openssl_sys::X509_STORE_CTX_get_error(ctx);
+                    let msg =
+                        CStr::from_ptr(openssl_sys::X509_verify_cert_error_string(code as c_long));
+                    Err(certificate_error(&format!(
+                        "certificate verification failed: {}",
+                        msg.to_string_lossy()
+                    )))
+                }
+            };
+            openssl_sys::X509_STORE_CTX_free(ctx);
+            openssl_sys::X509_STORE_free(store);
+            openssl_sys::ERR_clear_error();
+            ret
+        }
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+impl X509 {
+    fn from_der(der: &[u8]) -> Result<X509, Error> {
+        openssl_sys::init();
+        let mut p = der.as_ptr();
+        unsafe {
+            let x509 = openssl_sys::d2i_X509(ptr::null_mut(), &mut p, der.len() as c_long);
+            if x509.is_null() {
+                openssl_sys::ERR_clear_error();
+                return Err(Error::from_str("failed to parse X.509 certificate"));
+            }
+            Ok(X509(x509))
+        }
+    }
+
+    fn matches_host(&self, host: &str) -> bool {
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+        let is_ip = host.parse::<IpAddr>().is_ok();
+        let host = match CString::new(host) {
+            Ok(host) => host,
+            Err(_) => return false,
+        };
+        unsafe {
+            let ret = if is_ip {
+                openssl_sys::X509_check_ip_asc(self.0, host.as_ptr(), 0)
+            } else {
+                let len = host.as_bytes().len();
+                openssl_sys::X509_check_host(self.0, host.as_ptr(), len, 0, ptr::null_mut())
+            };
+            ret == 1
+        }
+    }
+
+    fn spki_sha256(&self) -> Result<[u8; 32], Error> {
+        unsafe {
+            let key = openssl_sys::X509_get_pubkey(self.0);
+            if key.is_null() {
+                openssl_sys::ERR_clear_error();
+                return Err(Error::from_str(
+                    "failed to read the certificate's public key",
+                ));
+            }
+            let len = openssl_sys::i2d_PUBKEY(key, ptr::null_mut());
+            let mut der = vec![0; len.max(0) as usize];
+            let mut p = der.as_mut_ptr();
+            let written = openssl_sys::i2d_PUBKEY(key, &mut p);
+            openssl_sys::EVP_PKEY_free(key);
+            if len <= 0 || written != len {
+                openssl_sys::ERR_clear_error();
+                return Err(Error::from_str(
+                    "failed to read the certificate's public key",
+                ));
+            }
+            sha256(&der)
+        }
+    }
+
+    fn to_der(&self) -> Result<Vec<u8>, Error> {
+        unsafe {
+            let len = openssl_sys::i2d_X509(self.0, ptr::null_mut());
+            let mut der = vec![0; len.max(0) as usize];
+            let mut p = der.as_mut_ptr();
+            if len <= 0 || openssl_sys::i2d_X509(self.0, &mut p) != len {
+                openssl_sys::ERR_clear_error();
+                return Err(Error::from_str("failed to encode X.509 certificate"));
+            }
+            Ok(der)
+        }
+    }
+}
+
+/// Compute the SHA-256 hash of `data`.
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+fn sha256(data: &[u8]) -> Result<[u8; 32], Error> {
+    let mut hash = [0; 32];
+    unsafe {
+        let ctx = openssl_sys::EVP_MD_CTX_new();
+        if ctx.is_null() {
+            return Err(Error::from_str("out of memory"));
+        }
+        let ok = openssl_sys::EVP_DigestInit_ex(ctx, openssl_sys::EVP_sha256(), ptr::null_mut())
+            == 1
+            && openssl_sys::EVP_DigestUpdate(ctx, data.as_ptr() as *const c_void, data.len()) == 1
+            && openssl_sys::EVP_DigestFinal_ex(ctx, hash.as_mut_ptr(), ptr::null_mut()) == 1;
+        openssl_sys::EVP_MD_CTX_free(ctx);
+        if !ok {
+            openssl_sys::ERR_clear_error();
+            return Err(Error::from_str(
+                "failed to hash the certificate's public key",
+            ));
+        }
+    }
+    Ok(hash)
+}
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+impl Drop for X509 {
+    fn drop(&mut self) {
+        unsafe { openssl_sys::X509_free(self.0) }
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+fn certificate_error(msg: &str) -> Error {
+    Error::new(ErrorCode::Certificate, ErrorClass::Ssl, msg)
+}
+
+impl KnownHost {
+    fn parse(line: &str) -> Option<KnownHost> {
+        let mut fields = line.split_whitespace();
+        let mut patterns = fields.next()?;
+        let marker = match patterns {
+            "@cert-authority" => Some(Marker::CertAuthority),
+            "@revoked" => Some(Marker::Revoked),
+            s if s.starts_with('#') => return None,
+            s if s.starts_with('@') => return None,
+            _ => None,
+        };
+        if marker.is_some() {
+            patterns = fields.next()?;
+        }
+        let key_type = fields.next()?;
+        let key = unbase64(fields.next()?)?;
+        if self::key_type(&key) != Some(key_type) {
+            return None;
+        }
+        Some(KnownHost {
+            marker,
+            patterns: patterns.to_string(),
+            key,
+        })
+    }
+
+    // Whether the patterns match the host `name`, a negated pattern which
+    // matches overrides the others.
+    /// Whether the line is for `name`, `None` if that can't be told because
+    /// a hashed name can't be checked without OpenSSL.
+    fn matches(&self, name: &str) -> Option<bool> {
+        let mut matched = false;
+        let mut maybe = false;
+        let mut maybe_negated = false;
+        for pattern in self.patterns.split(',') {
+            let (negated, pattern) = match pattern.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            let matches = match pattern.strip_prefix("|1|") {
+                Some(hashed) => hashed_matches(hashed, name),
+                None => Some(wildcard_matches(pattern.as_bytes(), name.as_bytes())),
+            };
+            match (matches, negated) {
+                (Some(true), true) => return Some(false),
+                (Some(true), false) => matched = true,
+                (Some(false), _) => {}
+                (None, true) => maybe_negated = true,
+                (None, false) => maybe = true,
+            }
+        }
+        if matched && !maybe_negated {
+            Some(true)
+        } else if matched || maybe {
+            None
+        } else {
+            Some(false)
+        }
+    }
+}
+
+// The status of a key which was listed for the host if `found`, and which
+// another key or a certificate authority was listed for if `changed`.
+fn status(found: bool, changed: bool) -> HostKeyStatus {
+    if found {
+        HostKeyStatus::Accept
+    } else if changed {
+        HostKeyStatus::Reject
+    } else {
+        HostKeyStatus::Unknown
+    }
+}
+
+// The name of a host in `known_hosts` files.
+fn host_name(host: &str, port: u16) -> String {
+    if port == 22 {
+        host.to_string()
+    } else {
+        format!("[{}]:{}", host, port)
+    }
+}
+
+// The type of a key in the ssh wire format, the string it starts with.
+fn key_type(key: &[u8]) -> Option<&str> {
+    let len = u32::from_be_bytes(key.get(..4)?.try_into().ok()?) as usize;
+    str::from_utf8(key.get(4..4 + len)?).ok()
+}
+
+// Match a `|1|salt|hash` pattern, the HMAC-SHA1 of the name keyed by the salt,
+// `None` if it can't be computed.
+fn hashed_matches(hashed: &str, name: &str) -> Option<bool> {
+    let (salt, hash) = match hashed.split_once('|') {
+        Some((salt, hash)) => (unbase64(salt), unbase64(hash)),
+        None => return Some(false),
+    };
+    match (salt, hash) {
+        (Some(salt), Some(hash)) => hmac_sha1(&salt, name.as_bytes()).map(|h| h[..] == hash[..]),
+        _ => Some(false),
+    }
+}
+
+// Match a pattern with `*` and `?` wildcards, ignoring case.
+fn wildcard_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => (0..=name.len()).any(|i| wildcard_matches(rest, &name[i..])),
+        Some((b'?', rest)) => !name.is_empty() && wildcard_matches(rest, &name[1..]),
+        Some((c, rest)) => match name.split_first() {
+            Some((n, name)) => c.eq_ignore_ascii_case(n) && wildcard_matches(rest, name),
+            None => false,
+        },
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    any(feature = "ssh", feature = "https")
+))]
+fn sha1(data: &[u8]) -> Option<[u8; 20]> {
+    openssl_sys::init();
+    let mut hash = [0; 20];
+    unsafe {
+        let ctx = openssl_sys::EVP_MD_CTX_new();
+        if ctx.is_null() {
+            return None;
+        }
+        let ok = openssl_sys::EVP_DigestInit_ex(ctx, openssl_sys::EVP_sha1(), ptr::null_mut()) == 1
+            && openssl_sys::EVP_DigestUpdate(ctx, data.as_ptr() as *const c_void, data.len()) == 1
+            && openssl_sys::EVP_DigestFinal_ex(ctx, hash.as_mut_ptr(), ptr::null_mut()) == 1;
+        openssl_sys::EVP_MD_CTX_free(ctx);
+        openssl_sys::ERR_clear_error();
+        ok.then_some(hash)
+    }
+}
+
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    any(feature = "ssh", feature = "https")
+))]
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Option<[u8; 20]> {
+    openssl_sys::init();
+    let len = c_int::try_from(key.len()).ok()?;
+    let mut hash = [0; 20];
+    unsafe {
+        let ctx = openssl_sys::HMAC_CTX_new();
+        if ctx.is_null() {
+            return None;
+        }
+        let md = openssl_sys::EVP_sha1();
+        let key = key.as_ptr() as *const c_void;
+        let ok = openssl_sys::HMAC_Init_ex(ctx, key, len, md, ptr::null_mut()) == 1
+            && openssl_sys::HMAC_Update(ctx, data.as_ptr(), data.len()) == 1
+            && openssl_sys::HMAC_Final(ctx, hash.as_mut_ptr(), ptr::null_mut()) == 1;
+        openssl_sys::HMAC_CTX_free(ctx);
+        openssl_sys::ERR_clear_error();
+        ok.then_some(hash)
+    }
+}
+
+// Without OpenSSL there is no SHA-1 to check hashes with, so keys given by
+// their hash and hashed host names can't be checked.
+#[cfg(not(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    any(feature = "ssh", feature = "https")
+)))]
+fn sha1(_data: &[u8]) -> Option<[u8; 20]> {
+    None
+}
+
+#[cfg(not(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    any(feature = "ssh", feature = "https")
+)))]
+fn hmac_sha1(_key: &[u8], _data: &[u8]) -> Option<[u8; 20]> {
+    None
+}
+
+fn io_error(path: &Path, e: io::Error) -> Error {
+    Error::from_str(&format!("failed to access '{}': {}", path.display(), e))
+}
+
 impl<'a> CertX509<'a> {
     /// Return the X.509 certificate data as a byte slice
     pub fn data(&self) -> &[u8] {
@@ -189,3 +955,304 @@ impl<'a> Binding for Cert<'a> {
         self.raw
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::mem;
+
+    use tempfile::TempDir;
+
+    use super::{Cert, HostKeyStatus, KnownHosts};
+    use crate::raw;
+    use crate::util::{unbase64, Binding, Sha1};
+
+    const KEY_A: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIJf9OQa00Gm6jXgeT4Ge0fIPpKM20srq7kOfP7zF7XPM";
+    const KEY_B: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIAe76WO5QahMUVHJFnYEYTsEkmhQBCm//eiJ361/DnZg";
+    const KEY_C: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIPNwN8u4UTQhHMqbVejuH7v5dsoQvsXbINQOvJKLHWag";
+    const KEY_D: &str = "AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBPxAUn3lox1kKT8y\
+                         4/3urW0glMRPY0LXOQg3VWFJUIN++ixVDl6kVhpaRiTrnPdv4RLSq7n2K+5E75VBVOrT1Ew=";
+
+    fn known_hosts() -> KnownHosts {
+        KnownHosts::parse(&format!(
+            "# comment\n\
+             example.com,192.0.2.1 ssh-ed25519 {a}\n\
+             @revoked * ssh-ed25519 {b}\n\
+             |1|AAECAwQFBgcICQoLDA0ODxAREhM=|Wgcx+Fm+LmaWwC7rQ80eIf2uHe0= ssh-ed25519 {c}\n\
+             *.example.org,!bad.example.org ecdsa-sha2-nistp256 {d}\n\
+             @cert-authority *.example.net ssh-ed25519 {a}\n\
+             example.net ssh-rsa {a}\n\
+             garbage\n",
+            a = KEY_A,
+            b = KEY_B,
+            c = KEY_C,
+            d = KEY_D,
+        ))
+    }
+
+    const OPENSSL: bool = cfg!(all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        any(feature = "ssh", feature = "https")
+    ));
+
+    fn key(key: &str) -> Vec<u8> {
+        unbase64(key).unwrap()
+    }
+
+    #[test]
+    fn check_key() {
+        let hosts = known_hosts();
+        let check = |host: &str, port: u16, k: &str| hosts.check_key(host, port, &key(k));
+        assert_eq!(check("example.com", 22, KEY_A), HostKeyStatus::Accept);
+        assert_eq!(check("EXAMPLE.com", 22, KEY_A), HostKeyStatus::Accept);
+        assert_eq!(check("192.0.2.1", 22, KEY_A), HostKeyStatus::Accept);
+        // Another key of the same type is rejected, not of another type.
+        assert_eq!(check("example.com", 22, KEY_C), HostKeyStatus::Reject);
+        assert_eq!(check("example.com", 22, KEY_D), HostKeyStatus::Unknown);
+
+        // Hashed host names can only be matched with OpenSSL. Without it,
+        // the line may be for any host: its key is never accepted, and the
+        // other keys of its type are rejected.
+        let (accept, unknown) = match OPENSSL {
+            true => (HostKeyStatus::Accept, HostKeyStatus::Unknown),
+            false => (HostKeyStatus::Reject, HostKeyStatus::Reject),
+        };
+        assert_eq!(check("example.com", 2222, KEY_C), accept);
+        assert_eq!(check("example.com", 2222, KEY_A), HostKeyStatus::Reject);
+        assert_eq!(check("example.com", 2200, KEY_C), unknown);
+
+        assert_eq!(check("example.com", 22, KEY_B), HostKeyStatus::Reject);
+        assert_eq!(check("example.org", 2222, KEY_B), HostKeyStatus::Reject);
+
+        assert_eq!(check("git.example.org", 22, KEY_D), HostKeyStatus::Accept);
+        assert_eq!(check("bad.example.org", 22, KEY_D), HostKeyStatus::Unknown);
+        assert_eq!(check("example.org", 22, KEY_D), HostKeyStatus::Unknown);
+
+        // Hosts with a certificate authority are expected to present host
+        // certificates, which can't be checked. Lines with the wrong key
+        // type are ignored.
+        assert_eq!(check("git.example.net", 22, KEY_A), HostKeyStatus::Reject);
+        assert_eq!(check("git.example.net", 22, KEY_D), HostKeyStatus::Reject);
+        assert_eq!(check("example.net", 22, KEY_A), unknown);
+    }
+
+    #[test]
+    fn check_cert() {
+        let hosts = known_hosts();
+        let hostkey = key(KEY_A);
+        let mut sha1 = Sha1::default();
+        sha1.update(&hostkey);
+        let mut raw: raw::git_cert_hostkey = unsafe { mem::zeroed() };
+        raw.parent.cert_type = raw::GIT_CERT_HOSTKEY_LIBSSH2;
+        raw.kind = raw::GIT_CERT_SSH_RAW;
+        raw.raw_type = raw::GIT_CERT_SSH_RAW_TYPE_KEY_ED25519;
+        raw.hostkey = hostkey.as_ptr() as *const _;
+        raw.hostkey_len = hostkey.len();
+        raw.hash_sha1 = sha1.finish();
+
+        let cert = unsafe { Cert::from_raw(&mut raw as *mut _ as *mut raw::git_cert) };
+        let cert = cert.as_hostkey().unwrap();
+        assert_eq!(hosts.check("example.com", 22, cert), HostKeyStatus::Accept);
+        assert_eq!(
+            hosts.check("example.com", 2222, cert),
+            HostKeyStatus::Reject
+        );
+
+        // Without the raw key, only the hashes can be compared, with
+        // OpenSSL. Any other key listed for the host rejects it, and so does
+        // any key at all without OpenSSL.
+        raw.kind = raw::GIT_CERT_SSH_SHA1;
+        let cert = unsafe { Cert::from_raw(&mut raw as *mut _ as *mut raw::git_cert) };
+        let cert = cert.as_hostkey().unwrap();
+        let (accept, unknown) = match OPENSSL {
+            true => (HostKeyStatus::Accept, HostKeyStatus::Unknown),
+            false => (HostKeyStatus::Reject, HostKeyStatus::Reject),
+        };
+        assert_eq!(hosts.check("example.com", 22, cert), accept);
+        assert_eq!(
+            hosts.check("git.example.net", 22, cert),
+            HostKeyStatus::Reject
+        );
+        assert_eq!(
+            hosts.check("example.com", 2222, cert),
+            HostKeyStatus::Reject
+        );
+        assert_eq!(hosts.check("example.com", 2200, cert), unknown);
+    }
+
+    #[test]
+    fn add() {
+        let td = TempDir::new().unwrap();
+        let path = td.path().join("known_hosts");
+        let mut hosts = KnownHosts::from_file(&path).unwrap();
+        assert_eq!(
+            hosts.check_key("example.com", 22, &key(KEY_A)),
+            HostKeyStatus::Unknown
+        );
+        hosts.add("example.com", 22, &key(KEY_A)).unwrap();
+        assert_eq!(
+            hosts.check_key("example.com", 22, &key(KEY_A)),
+            HostKeyStatus::Accept
+        );
+        assert!(hosts.add("example.com", 22, b"bad").is_err());
+
+        fs::write(&path, format!("example.com ssh-ed25519 {}", KEY_A)).unwrap();
+        let mut hosts = KnownHosts::from_file(&path).unwrap();
+        hosts.add("example.org", 2222, &key(KEY_D)).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!(
+                "example.com ssh-ed25519 {}\n[example.org]:2222 ecdsa-sha2-nistp256 {}\n",
+                KEY_A, KEY_D
+            )
+        );
+        let hosts = KnownHosts::from_file(&path).unwrap();
+        assert_eq!(
+            hosts.check_key("example.org", 2222, &key(KEY_D)),
+            HostKeyStatus::Accept
+        );
+    }
+
+    #[test]
+    #[cfg(all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        feature = "https"
+    ))]
+    fn verify_x509() {
+        use super::X509Roots;
+        use crate::ErrorCode;
+
+        const LEAF: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBnjCCAUWgAwIBAgIUU3Va2wptxg/fGhp4alqY/RjyR0EwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTcwMjA5MzhaGA8yMTI2MDkyMzAy
+MDkzOFowGjEYMBYGA1UEAwwPZ2l0LmV4YW1wbGUuY29tMFkwEwYHKoZIzj0CAQYI
+KoZIzj0DAQcDQgAE4RxSGOCt1Z1FnsCo5TMMmYnsEHeXK3EBcVXI53E53l9zaiYU
+iBXy6P+4Eyg2ogX4/QVEl4vb112K5p3b8GWl5KNvMG0wIAYDVR0RBBkwF4IPZ2l0
+LmV4YW1wbGUuY29thwTAAAIBMAkGA1UdEwQCMAAwHQYDVR0OBBYEFJQ1dn9MVpTB
+aq1tAVtch0p9aexwMB8GA1UdIwQYMBaAFJTkIit2C6qB/u8IL0dODVZoWb4IMAoG
+CCqGSM49BAMCA0cAMEQCICxEWJvaxEZfOzx9KhNJAYpBj/lGFvAogFBTNobRQKWb
+AiBJY7C0Vns+25RMtIQh4ccAam3i9cJTwjl8SVJXfAZsqg==
+-----END CERTIFICATE-----
+";
+        const CA: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBezCCASGgAwIBAgIUSquiFHcGMPNbK8+gqQ0ClfCWsfwwCgYIKoZIzj0EAwIw
+EjEQMA4GA1UEAwwHVGVzdCBDQTAgFw0yNjEwMTcwMjA5MzhaGA8yMTI2MDkyMzAy
+MDkzOFowEjEQMA4GA1UEAwwHVGVzdCBDQTBZMBMGByqGSM49AgEGCCqGSM49AwEH
+A0IABG6KnS3favGy4uY6ojwY4EGHq/WSg3xsoOPZ5vDPk5Pu/2GxeVIHUmBAr1B5
+iTgv1+vJH+ooT/2xwGrOQpD3UGCjUzBRMB0GA1UdDgQWBBSU5CIrdguqgf7vCC9H
+Tg1WaFm+CDAfBgNVHSMEGDAWgBSU5CIrdguqgf7vCC9HTg1WaFm+CDAPBgNVHRMB
+Af8EBTADAQH/MAoGCCqGSM49BAMCA0gAMEUCIQC7iOT+XdD4vZdRkOntQOJu0cbS
+uDMvI5V9NhZCLT8OEAIgMsIgmtufVWgPquMSB8xaPYjcUz4N1965GC952SjmY94=
+-----END CERTIFICATE-----
+";
+        const OTHER_CA: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBfTCCASOgAwIBAgIUQAM5vYFHAkzEMPRJhwGZvUFPbbIwCgYIKoZIzj0EAwIw
+EzERMA8GA1UEAwwIT3RoZXIgQ0EwIBcNMjYxMDE3MDIwOTM4WhgPMjEyNjA5MjMw
+MjA5MzhaMBMxETAPBgNVBAMMCE90aGVyIENBMFkwEwYHKoZIzj0CAQYIKoZIzj0D
+AQcDQgAE1k3q7sSXu5mtBePptjGj/aPmrMQfQ1ouM0Bl7yDZMmZU7Db07c2cTTp7
+hbLcqK3TbyxEEvJDoA4qJDg5Xwk266NTMFEwHQYDVR0OBBYEFO1gTu0ivRz2mh8f
+6n/spPpAXz50MB8GA1UdIwQYMBaAFO1gTu0ivRz2mh8f6n/spPpAXz50MA8GA1Ud
+EwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSAAwRQIgMQJA2EYLnom1jWpRNjcPpbwc
+43E5JR9wtu25kHRZVZcCIQDVGu0AoxlibxFSm8XOKacWWHA4qA7N+rgYVkOaLr63
+iw==
+-----END CERTIFICATE-----
+";
+        const CLIENT_CA: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBfzCCASWgAwIBAgIUOUFKHt7LbdM6CWhLEaryfdW6/4QwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJQ2xpZW50IENBMCAXDTI2MTAxNzAzNDEzMloYDzIxMjYwOTIz
+MDM0MTMyWjAUMRIwEAYDVQQDDAlDbGllbnQgQ0EwWTATBgcqhkjOPQIBBggqhkjO
+PQMBBwNCAARPiPT1QbSlGB4ekqZJ4yxmH43LG00Oz8xnu7vNQ3dXp5vrqPIj484N
+cP2dpS6x+zczdYMwsjHDpMk0WodI8gj5o1MwUTAdBgNVHQ4EFgQU6NXOEpxBpmaq
+dWFtUP3yEGRmHQAwHwYDVR0jBBgwFoAU6NXOEpxBpmaqdWFtUP3yEGRmHQAwDwYD
+VR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiEAiX9U38oYqADxHnO8OSix
+DRx50bd2VaXbeApKbRva7voCIEG4O/8+bnq4nVUeS081c6aiHBUYyhsl8vml95LP
+Whbn
+-----END CERTIFICATE-----
+";
+        // Issued by CLIENT_CA for git.example.com, for client authentication
+        // only.
+        const CLIENT_LEAF: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBsDCCAVagAwIBAgIUe63E0hsbfQRCizPxHzwTyGl6sMIwCgYIKoZIzj0EAwIw
+FDESMBAGA1UEAwwJQ2xpZW50IENBMCAXDTI2MTAxNzAzNDEzMloYDzIxMjYwOTIz
+MDM0MTMyWjAaMRgwFgYDVQQDDA9naXQuZXhhbXBsZS5jb20wWTATBgcqhkjOPQIB
+BggqhkjOPQMBBwNCAASjE5CYBcdBu3gYf585br0hu7R7zkBJE0xNnFLFmiUTToEp
+O5Cagkgj0iTabRROLp0MTd3/GsPxVCbO3aHh6OrWo34wfDAaBgNVHREEEzARgg9n
+aXQuZXhhbXBsZS5jb20wCQYDVR0TBAIwADATBgNVHSUEDDAKBggrBgEFBQcDAjAd
+BgNVHQ4EFgQUgQElJWS+W1Xf0UiNmGFEFynq3mkwHwYDVR0jBBgwFoAU6NXOEpxB
+pmaqdWFtUP3yEGRmHQAwCgYIKoZIzj0EAwIDSAAwRQIhAKzdmWpteUPVr5TTt55E
+QmP97IxSuQJjPfHM4DtrOfeOAiAH74rndDBcAi5MN9D/hFjEV3xJzeuKBh0Jp36u
+LIv2lw==
+-----END CERTIFICATE-----
+";
+        const LEAF_SPKI: [u8; 32] = [
+            0xe0, 0xf3, 0xf9, 0x28, 0xb6, 0x34, 0xfa, 0x62, 0xc6, 0xf7, 0x59, 0xb9, 0x27, 0x7c,
+            0x67, 0xf9, 0x76, 0x88, 0x50, 0x50, 0xe7, 0x2e, 0x76, 0x76, 0x88, 0x1b, 0xf2, 0xd7,
+            0xb6, 0xcd, 0x8c, 0x09,
+        ];
+
+        let to_der = |pem: &str| {
+            let body: String = pem.lines().filter(|l| !l.starts_with("-----")).collect();
+            unbase64(&body).unwrap()
+        };
+        let to_raw = |der: &[u8]| {
+            let mut raw: raw::git_cert_x509 = unsafe { mem::zeroed() };
+            raw.parent.cert_type = raw::GIT_CERT_X509;
+            raw.data = der.as_ptr() as *mut _;
+            raw.len = der.len();
+            raw
+        };
+        let der = to_der(LEAF);
+        let mut raw = to_raw(&der);
+        let cert = unsafe { Cert::from_raw(&mut raw as *mut _ as *mut raw::git_cert) };
+        let cert = cert.as_x509().unwrap();
+
+        let mut roots = X509Roots::new();
+        let err = roots.verify(cert, "git.example.com").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Certificate);
+
+        roots.add_pem(CA.as_bytes()).unwrap();
+        roots.verify(cert, "git.example.com").unwrap();
+        roots.verify(cert, "192.0.2.1").unwrap();
+        let err = roots.verify(cert, "other.example.com").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Certificate);
+
+        let mut other = X509Roots::new();
+        other.add_pem(OTHER_CA.as_bytes()).unwrap();
+        let err = other.verify(cert, "git.example.com").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Certificate);
+        other.pin_spki_sha256(LEAF_SPKI);
+        other.verify(cert, "git.example.com").unwrap();
+        assert!(other.verify(cert, "other.example.com").is_err());
+
+        // The server's certificate can be trusted directly, too.
+        let mut leaf = X509Roots::new();
+        leaf.add_der(&der).unwrap();
+        leaf.verify(cert, "git.example.com").unwrap();
+
+        // Certificates which may not be used by servers aren't trusted.
+        let client_der = to_der(CLIENT_LEAF);
+        let mut client_raw = to_raw(&client_der);
+        let client = unsafe { Cert::from_raw(&mut client_raw as *mut _ as *mut raw::git_cert) };
+        let client = client.as_x509().unwrap();
+        let mut client_roots = X509Roots::new();
+        client_roots.add_pem(CLIENT_CA.as_bytes()).unwrap();
+        let err = client_roots.verify(client, "git.example.com").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Certificate);
+        roots.add_pem(CLIENT_CA.as_bytes()).unwrap();
+        roots.verify(cert, "git.example.com").unwrap();
+
+        assert!(X509Roots::new().add_pem(b"garbage").is_err());
+        let bundle = format!("{}{}", CA, OTHER_CA.replace("MIIB", "MIIC"));
+        assert!(roots.add_pem(bundle.as_bytes()).is_err());
+        assert!(X509Roots::new().add_der(b"garbage").is_err());
+    }
+}
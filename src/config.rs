@@ -4,8 +4,9 @@ use std::path::{Path, PathBuf};
 use std::ptr;
 use std::str;
 
+use crate::config_backend::{self, ConfigBackend, MemoryConfig};
 use crate::util::{self, Binding};
-use crate::{raw, Buf, ConfigLevel, Error, IntoCString};
+use crate::{call, raw, Buf, ConfigLevel, Error, IntoCString};
 
 /// A structure representing a git configuration key/value store
 pub struct Config {
@@ -72,6 +73,18 @@ impl Config {
         }
     }
 
+    /// Allocate a new configuration object backed by memory only
+    ///
+    /// The config starts out empty and values set on it are kept in memory
+    /// at the [`ConfigLevel::App`] level. More in-memory or on-disk sources
+    /// can be layered on top with [`Config::add_string`],
+    /// [`Config::add_values`] or [`Config::add_file`].
+    pub fn new_in_memory() -> Result<Config, Error> {
+        let mut cfg = Config::new()?;
+        cfg.add_backend(MemoryConfig::default(), ConfigLevel::App, false)?;
+        Ok(cfg)
+    }
+
     /// Create a new config instance containing a single on-disk file
     pub fn open(path: &Path) -> Result<Config, Error> {
         crate::init();
@@ -167,6 +180,92 @@ impl Config {
         }
     }
 
+    /// Add a read-only config file held in memory to an existing config
+    ///
+    /// `contents` is parsed like an on-disk file, following the default Git
+    /// config syntax (see man git-config), except that include directives
+    /// are not followed. The entries are added at the given level, just like
+    /// with [`Config::add_file`], without writing anything to disk.
+    pub fn add_string(
+        &mut self,
+        contents: &str,
+        level: ConfigLevel,
+        force: bool,
+    ) -> Result<(), Error> {
+        let entries = config_backend::parse(contents)?;
+        self.add_memory(entries, level, force)
+    }
+
+    /// Add a read-only set of config values held in memory to an existing
+    /// config
+    ///
+    /// Each value is given as a pair of its full name (e.g.
+    /// `core.autocrlf`) and its value; a name may appear several times to
+    /// create a multivar. The values are added at the given level, just like
+    /// with [`Config::add_file`].
+    pub fn add_values(
+        &mut self,
+        values: &[(&str, &str)],
+        level: ConfigLevel,
+        force: bool,
+    ) -> Result<(), Error> {
+        let entries = values
+            .iter()
+            .map(|(name, value)| Ok((config_backend::normalize_name(name)?, value.to_string())))
+            .collect::<Result<Vec<_>, Error>>()?;
+        self.add_memory(entries, level, force)
+    }
+
+    fn add_memory(
+        &mut self,
+        entries: Vec<(String, String)>,
+        level: ConfigLevel,
+        force: bool,
+    ) -> Result<(), Error> {
+        let backend = config_backend::boxed(Box::new(MemoryConfig::new(entries)), true)?;
+        unsafe { self.add_raw_backend(backend, level, force) }
+    }
+
+    /// Add a custom backend to an existing config.
+    ///
+    /// Like with [`Config::add_file`], backends with a higher priority level
+    /// will be accessed first. Adding a backend at a level which is already
+    /// in use fails, unless `force` is set, in which case the existing
+    /// backend is replaced.
+    pub fn add_backend<B>(
+        &mut self,
+        backend: B,
+        level: ConfigLevel,
+        force: bool,
+    ) -> Result<(), Error>
+    where
+        B: ConfigBackend,
+    {
+        let backend = config_backend::boxed(Box::new(backend), false)?;
+        unsafe { self.add_raw_backend(backend, level, force) }
+    }
+
+    // Takes ownership of `backend`, freeing it if it can't be added.
+    pub(crate) unsafe fn add_raw_backend(
+        &mut self,
+        backend: *mut raw::git_config_backend,
+        level: ConfigLevel,
+        force: bool,
+    ) -> Result<(), Error> {
+        let rc = raw::git_config_add_backend(
+            self.raw,
+            backend,
+            call::convert(&level),
+            ptr::null(),
+            force as libc::c_int,
+        );
+        if rc < 0 {
+            (*backend).free.unwrap()(backend);
+            return Err(Error::last_error(rc));
+        }
+        Ok(())
+    }
+
     /// Delete a config variable from the config file with the highest level
     /// (usually the local one).
     pub fn remove(&mut self, name: &str) -> Result<(), Error> {
@@ -649,7 +748,7 @@ mod tests {
     use std::fs::File;
     use tempfile::TempDir;
 
-    use crate::Config;
+    use crate::{Config, ConfigLevel};
 
     #[test]
     fn smoke() {
@@ -740,6 +839,56 @@ mod tests {
         assert_eq!(count(cfg.multivar("foo.bar", None).unwrap()), 0);
     }
 
+    #[test]
+    fn in_memory() {
+        let mut cfg = Config::new_in_memory().unwrap();
+        cfg.set_str("user.name", "foo").unwrap();
+        assert_eq!(cfg.get_string("user.name").unwrap(), "foo");
+
+        cfg.add_string(
+            "[core]\n\tautocrlf = true\n\tbare\n[user]\n\tname = bar\n",
+            ConfigLevel::Global,
+            false,
+        )
+        .unwrap();
+        cfg.add_values(
+            &[
+                ("safe.directory", "/a"),
+                ("Safe.Directory", "/b"),
+                ("core.autoCRLF", "input"),
+            ],
+            ConfigLevel::System,
+            false,
+        )
+        .unwrap();
+        assert_eq!(cfg.get_string("user.name").unwrap(), "foo");
+        assert_eq!(cfg.get_string("core.autocrlf").unwrap(), "true");
+        assert!(cfg.get_bool("core.bare").unwrap());
+        assert_eq!(
+            cfg.get_entry("core.autocrlf").unwrap().level(),
+            ConfigLevel::Global
+        );
+        let mut dirs = Vec::new();
+        cfg.multivar("safe.directory", None)
+            .unwrap()
+            .for_each(|e| dirs.push(e.value().unwrap().to_string()))
+            .unwrap();
+        assert_eq!(dirs, ["/a", "/b"]);
+
+        let global = cfg.open_level(ConfigLevel::Global).unwrap();
+        assert_eq!(global.get_string("user.name").unwrap(), "bar");
+        assert!(cfg
+            .add_values(&[("user.name", "baz")], ConfigLevel::Global, false)
+            .is_err());
+        cfg.add_values(&[("user.name", "baz")], ConfigLevel::App, true)
+            .unwrap();
+        assert_eq!(cfg.get_string("user.name").unwrap(), "baz");
+        assert!(cfg.set_str("user.name", "qux").is_err());
+        assert!(cfg
+            .add_values(&[("name", "baz")], ConfigLevel::Local, false)
+            .is_err());
+    }
+
     #[test]
     fn parse() {
         assert_eq!(Config::parse_bool("").unwrap(), false);
@@ -0,0 +1,708 @@
+use std::collections::HashSet;
+use std::ffi::{CStr, CString};
+use std::mem;
+use std::ptr;
+
+use libc::{c_char, c_int};
+
+use crate::{panic, raw, Config, ConfigLevel, Error, ErrorClass, ErrorCode};
+
+/// A custom configuration backend, which can be added to a [`Config`] with
+/// [`Config::add_backend`].
+///
+/// Names handed to a backend are already normalized, with the section and
+/// variable name lowercased, so backends only need to store and return them
+/// as they are. All of the semantics of single and multivar updates, such as
+/// matching values against a regular expression, are implemented on top of
+/// these two methods.
+pub trait ConfigBackend: Send + 'static {
+    /// Returns every entry in the backend, in the order they were added.
+    ///
+    /// A variable with several values (a multivar) is returned as one entry
+    /// per value.
+    fn entries(&self) -> Result<Vec<(String, String)>, Error>;
+
+    /// Replaces all values of the variable `name` with `values`.
+    ///
+    /// An empty list of values removes the variable. The default
+    /// implementation fails, making the backend read-only.
+    fn set_values(&mut self, _name: &str, _values: &[String]) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Config,
+            "this backend is read-only",
+        ))
+    }
+}
+
+/// A configuration backend which keeps its entries in memory.
+#[derive(Clone, Default)]
+pub(crate) struct MemoryConfig {
+    entries: Vec<(String, String)>,
+}
+
+impl MemoryConfig {
+    pub(crate) fn new(entries: Vec<(String, String)>) -> MemoryConfig {
+        MemoryConfig { entries }
+    }
+}
+
+impl ConfigBackend for MemoryConfig {
+    fn entries(&self) -> Result<Vec<(String, String)>, Error> {
+        Ok(self.entries.clone())
+    }
+
+    fn set_values(&mut self, name: &str, values: &[String]) -> Result<(), Error> {
+        let pos = self.entries.iter().position(|(n, _)| n == name);
+        self.entries.retain(|(n, _)| n != name);
+        let pos = pos.unwrap_or(self.entries.len());
+        let new = values.iter().map(|v| (name.to_string(), v.clone()));
+        self.entries.splice(pos..pos, new);
+        Ok(())
+    }
+}
+
+/// Instance of a `git_config_backend`, must use `#[repr(C)]` to ensure that
+/// the C fields come first.
+#[repr(C)]
+struct RawConfigBackend {
+    raw: raw::git_config_backend,
+    level: raw::git_config_level_t,
+    obj: Box<dyn ConfigBackend>,
+    // Callers of `get` on a read-only backend may keep using the returned
+    // entry after freeing it, as libgit2's own snapshots keep their entries
+    // alive, so read-only backends hand out entries from this list instead.
+    frozen: Vec<RawConfigEntry>,
+}
+
+/// Instance of a `git_config_backend_entry`, must use `#[repr(C)]` to ensure
+/// that the C fields come first.
+#[repr(C)]
+struct RawConfigEntry {
+    raw: raw::git_config_backend_entry,
+    name: CString,
+    value: CString,
+}
+
+/// Instance of a `git_config_iterator`, must use `#[repr(C)]` to ensure that
+/// the C fields come first.
+#[repr(C)]
+struct RawConfigIterator {
+    raw: raw::git_config_iterator,
+    // entries handed out by the iterator are owned by it, not by the caller
+    entries: Vec<RawConfigEntry>,
+    pos: usize,
+}
+
+const BACKEND_TYPE: &[u8] = b"in-memory\0";
+
+/// Wraps `backend` into a newly allocated `git_config_backend`.
+///
+/// The returned backend is freed through its own `free` callback, either by
+/// the config it gets added to or by the caller if adding it fails.
+pub(crate) fn boxed(
+    backend: Box<dyn ConfigBackend>,
+    readonly: bool,
+) -> Result<*mut raw::git_config_backend, Error> {
+    unsafe {
+        let mut raw = Box::new(RawConfigBackend {
+            raw: mem::zeroed(),
+            level: raw::GIT_CONFIG_LEVEL_APP,
+            obj: backend,
+            frozen: Vec::new(),
+        });
+        try_call!(raw::git_config_init_backend(
+            &mut raw.raw,
+            raw::GIT_CONFIG_BACKEND_VERSION
+        ));
+        raw.raw.readonly = readonly as c_int;
+        raw.raw.open = Some(backend_open);
+        raw.raw.get = Some(backend_get);
+        raw.raw.set = Some(backend_set);
+        raw.raw.set_multivar = Some(backend_set_multivar);
+        raw.raw.del = Some(backend_del);
+        raw.raw.del_multivar = Some(backend_del_multivar);
+        raw.raw.iterator = Some(backend_iterator);
+        raw.raw.snapshot = Some(backend_snapshot);
+        raw.raw.lock = Some(backend_lock);
+        raw.raw.unlock = Some(backend_unlock);
+        raw.raw.free = Some(backend_free);
+        Ok(Box::into_raw(raw) as *mut raw::git_config_backend)
+    }
+}
+
+fn str_arg<'a>(s: *const c_char) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(Error::new(
+            ErrorCode::Invalid,
+            ErrorClass::Config,
+            "missing config argument",
+        ));
+    }
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|_| {
+        Error::new(
+            ErrorCode::Invalid,
+            ErrorClass::Config,
+            "config argument is not valid utf-8",
+        )
+    })
+}
+
+// Validates `name` and lowercases its section and variable name, in the same
+// way as libgit2 does before looking up or storing a variable.
+pub(crate) fn normalize_name(name: &str) -> Result<String, Error> {
+    fn section(s: &str) -> bool {
+        !s.is_empty()
+            && !s.starts_with('-')
+            && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    }
+    match (name.find('.'), name.rfind('.')) {
+        (Some(first), Some(last))
+            if section(&name[..first])
+                && section(&name[last + 1..])
+                && !name[first..last].contains('\n') =>
+        {
+            Ok(format!(
+                "{}{}{}",
+                name[..first].to_ascii_lowercase(),
+                &name[first..=last],
+                name[last + 1..].to_ascii_lowercase()
+            ))
+        }
+        _ => Err(Error::new(
+            ErrorCode::InvalidSpec,
+            ErrorClass::Config,
+            format!("invalid config item name '{}'", name),
+        )),
+    }
+}
+
+// Parses `contents` as a config file, returning its entries.
+//
+// libgit2's own in-memory backend releases its entries on every lookup
+// without holding a reference for them, so it is only used for parsing here
+// and its entries are copied out through an iterator.
+pub(crate) fn parse(contents: &str) -> Result<Vec<(String, String)>, Error> {
+    let mut cfg = Config::new()?;
+    unsafe {
+        let mut backend = ptr::null_mut();
+        try_call!(raw::git_config_backend_from_string(
+            &mut backend,
+            contents.as_ptr() as *const c_char,
+            contents.len(),
+            ptr::null_mut()
+        ));
+        cfg.add_raw_backend(backend, ConfigLevel::App, false)?;
+    }
+    let mut entries = Vec::new();
+    let mut iter = cfg.entries(None)?;
+    while let Some(entry) = iter.next() {
+        let entry = entry?;
+        let name = String::from_utf8_lossy(entry.name_bytes()).into_owned();
+        // A variable without a value is a short-hand for a boolean `true`.
+        let value = if entry.has_value() {
+            String::from_utf8_lossy(entry.value_bytes()).into_owned()
+        } else {
+            "true".to_string()
+        };
+        entries.push((name, value));
+    }
+    Ok(entries)
+}
+
+fn values_of(backend: &dyn ConfigBackend, name: &str) -> Result<Vec<String>, Error> {
+    Ok(backend
+        .entries()?
+        .into_iter()
+        .filter(|(n, _)| n == name)
+        .map(|(_, v)| v)
+        .collect())
+}
+
+fn readonly(b: &RawConfigBackend) -> Result<(), Error> {
+    if b.raw.readonly != 0 {
+        Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Config,
+            "this backend is read-only",
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+fn multivar(name: &str) -> Error {
+    Error::new(
+        ErrorCode::GenericError,
+        ErrorClass::Config,
+        format!("entry '{}' is not unique due to being a multivar", name),
+    )
+}
+
+fn not_found(name: &str) -> Error {
+    Error::new(
+        ErrorCode::NotFound,
+        ErrorClass::Config,
+        format!("could not find key '{}' to delete", name),
+    )
+}
+
+// Returns which of `values` match `regexp`. The regular expression is
+// evaluated by libgit2 itself, by running a multivar iterator over a
+// throwaway config holding just these values, so that its syntax is exactly
+// the one used for on-disk configs.
+fn matches(values: &[String], regexp: &str) -> Result<Vec<bool>, Error> {
+    const NAME: &str = "match.value";
+    let store = MemoryConfig::new(
+        values
+            .iter()
+            .map(|v| (NAME.to_string(), v.clone()))
+            .collect(),
+    );
+    let mut cfg = Config::new()?;
+    cfg.add_backend(store, ConfigLevel::App, false)?;
+    let mut matched = HashSet::new();
+    let mut iter = cfg.multivar(NAME, Some(regexp))?;
+    while let Some(entry) = iter.next() {
+        matched.insert(entry?.value_bytes().to_vec());
+    }
+    Ok(values
+        .iter()
+        .map(|v| matched.contains(v.as_bytes()))
+        .collect())
+}
+
+fn new_entry(
+    name: &str,
+    value: &str,
+    level: raw::git_config_level_t,
+) -> Result<RawConfigEntry, Error> {
+    let mut entry = RawConfigEntry {
+        raw: unsafe { mem::zeroed() },
+        name: CString::new(name)?,
+        value: CString::new(value)?,
+    };
+    entry.raw.entry.name = entry.name.as_ptr();
+    entry.raw.entry.value = entry.value.as_ptr();
+    entry.raw.entry.backend_type = BACKEND_TYPE.as_ptr() as *const c_char;
+    entry.raw.entry.origin_path = ptr::null();
+    entry.raw.entry.include_depth = 0;
+    entry.raw.entry.level = level;
+    entry.raw.free = Some(entry_free);
+    Ok(entry)
+}
+
+// Creates an entry which stays owned by the backend or iterator returning it.
+fn owned_entry(
+    name: &str,
+    value: &str,
+    level: raw::git_config_level_t,
+) -> Result<RawConfigEntry, Error> {
+    let mut entry = new_entry(name, value, level)?;
+    entry.raw.free = Some(entry_free_owned);
+    Ok(entry)
+}
+
+fn result(r: Result<(), Error>) -> c_int {
+    match r {
+        Ok(()) => 0,
+        Err(e) => unsafe { e.raw_set_git_error() },
+    }
+}
+
+extern "C" fn backend_open(
+    backend: *mut raw::git_config_backend,
+    level: raw::git_config_level_t,
+    _repo: *const raw::git_repository,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &mut *(backend as *mut RawConfigBackend);
+        b.level = level;
+        if b.raw.readonly == 0 {
+            return 0;
+        }
+        let frozen = b.obj.entries().and_then(|entries| {
+            entries
+                .iter()
+                .map(|(name, value)| owned_entry(name, value, level))
+                .collect()
+        });
+        match frozen {
+            Ok(frozen) => {
+                b.frozen = frozen;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_get(
+    backend: *mut raw::git_config_backend,
+    key: *const c_char,
+    out: *mut *mut raw::git_config_backend_entry,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        // libgit2 looks at the entry even when this fails.
+        *out = ptr::null_mut();
+        let b = &mut *(backend as *mut RawConfigBackend);
+        let key = match str_arg(key) {
+            Ok(key) => key,
+            Err(e) => return e.raw_set_git_error(),
+        };
+        if b.raw.readonly != 0 {
+            let found = b
+                .frozen
+                .iter_mut()
+                .rev()
+                .find(|e| e.name.to_bytes() == key.as_bytes());
+            return match found {
+                Some(entry) => {
+                    *out = &mut entry.raw;
+                    0
+                }
+                None => raw::GIT_ENOTFOUND,
+            };
+        }
+        let entry = values_of(&*b.obj, key).and_then(|mut values| {
+            values
+                .pop()
+                .map(|v| new_entry(key, &v, b.level))
+                .transpose()
+        });
+        match entry {
+            Ok(Some(entry)) => {
+                *out = Box::into_raw(Box::new(entry)) as *mut raw::git_config_backend_entry;
+                0
+            }
+            Ok(None) => raw::GIT_ENOTFOUND,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_set(
+    backend: *mut raw::git_config_backend,
+    key: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &mut *(backend as *mut RawConfigBackend);
+        result(set(b, key, value))
+    })
+    .unwrap_or(-1)
+}
+
+fn set(b: &mut RawConfigBackend, key: *const c_char, value: *const c_char) -> Result<(), Error> {
+    readonly(b)?;
+    let key = &normalize_name(str_arg(key)?)?;
+    let value = str_arg(value)?;
+    if values_of(&*b.obj, key)?.len() > 1 {
+        return Err(multivar(key));
+    }
+    b.obj.set_values(key, &[value.to_string()])
+}
+
+extern "C" fn backend_set_multivar(
+    backend: *mut raw::git_config_backend,
+    name: *const c_char,
+    regexp: *const c_char,
+    value: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &mut *(backend as *mut RawConfigBackend);
+        result(set_multivar(b, name, regexp, value))
+    })
+    .unwrap_or(-1)
+}
+
+fn set_multivar(
+    b: &mut RawConfigBackend,
+    name: *const c_char,
+    regexp: *const c_char,
+    value: *const c_char,
+) -> Result<(), Error> {
+    readonly(b)?;
+    let name = &normalize_name(str_arg(name)?)?;
+    let value = str_arg(value)?;
+    let values = values_of(&*b.obj, name)?;
+    let matched = matches(&values, str_arg(regexp)?)?;
+    // Like the file backend, the first matching value is replaced and all
+    // other matches are removed. If nothing matched the value is appended.
+    let mut new = Vec::with_capacity(values.len() + 1);
+    let mut written = false;
+    for (v, m) in values.into_iter().zip(matched) {
+        if !m {
+            new.push(v);
+        } else if !written {
+            new.push(value.to_string());
+            written = true;
+        }
+    }
+    if !written {
+        new.push(value.to_string());
+    }
+    b.obj.set_values(name, &new)
+}
+
+extern "C" fn backend_del(backend: *mut raw::git_config_backend, key: *const c_char) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &mut *(backend as *mut RawConfigBackend);
+        result(del(b, key))
+    })
+    .unwrap_or(-1)
+}
+
+fn del(b: &mut RawConfigBackend, key: *const c_char) -> Result<(), Error> {
+    readonly(b)?;
+    let key = &normalize_name(str_arg(key)?)?;
+    match values_of(&*b.obj, key)?.len() {
+        0 => Err(not_found(key)),
+        1 => b.obj.set_values(key, &[]),
+        _ => Err(multivar(key)),
+    }
+}
+
+extern "C" fn backend_del_multivar(
+    backend: *mut raw::git_config_backend,
+    name: *const c_char,
+    regexp: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &mut *(backend as *mut RawConfigBackend);
+        result(del_multivar(b, name, regexp))
+    })
+    .unwrap_or(-1)
+}
+
+fn del_multivar(
+    b: &mut RawConfigBackend,
+    name: *const c_char,
+    regexp: *const c_char,
+) -> Result<(), Error> {
+    readonly(b)?;
+    let name = &normalize_name(str_arg(name)?)?;
+    let values = values_of(&*b.obj, name)?;
+    let matched = matches(&values, str_arg(regexp)?)?;
+    if !matched.iter().any(|m| *m) {
+        return Err(not_found(name));
+    }
+    let new = values
+        .into_iter()
+        .zip(matched)
+        .filter(|(_, m)| !m)
+        .map(|(v, _)| v)
+        .collect::<Vec<_>>();
+    b.obj.set_values(name, &new)
+}
+
+extern "C" fn backend_iterator(
+    out: *mut *mut raw::git_config_iterator,
+    backend: *mut raw::git_config_backend,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawConfigBackend);
+        let entries = b.obj.entries().and_then(|entries| {
+            entries
+                .iter()
+                .map(|(name, value)| owned_entry(name, value, b.level))
+                .collect()
+        });
+        match entries {
+            Ok(entries) => {
+                let iter = Box::new(RawConfigIterator {
+                    raw: raw::git_config_iterator {
+                        backend,
+                        flags: 0,
+                        next: Some(iterator_next),
+                        free: Some(iterator_free),
+                    },
+                    entries,
+                    pos: 0,
+                });
+                *out = Box::into_raw(iter) as *mut raw::git_config_iterator;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_snapshot(
+    out: *mut *mut raw::git_config_backend,
+    backend: *mut raw::git_config_backend,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawConfigBackend);
+        let snapshot = b
+            .obj
+            .entries()
+            .and_then(|entries| boxed(Box::new(MemoryConfig::new(entries)), true));
+        match snapshot {
+            Ok(snapshot) => {
+                *out = snapshot;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_lock(_backend: *mut raw::git_config_backend) -> c_int {
+    0
+}
+
+extern "C" fn backend_unlock(_backend: *mut raw::git_config_backend, _success: c_int) -> c_int {
+    0
+}
+
+extern "C" fn backend_free(backend: *mut raw::git_config_backend) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(backend as *mut RawConfigBackend));
+    });
+}
+
+extern "C" fn entry_free(entry: *mut raw::git_config_backend_entry) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(entry as *mut RawConfigEntry));
+    });
+}
+
+// Entries owned by a backend or iterator are freed together with it.
+extern "C" fn entry_free_owned(_entry: *mut raw::git_config_backend_entry) {}
+
+extern "C" fn iterator_next(
+    out: *mut *mut raw::git_config_backend_entry,
+    iter: *mut raw::git_config_iterator,
+) -> c_int {
+    unsafe {
+        let iter = &mut *(iter as *mut RawConfigIterator);
+        match iter.entries.get_mut(iter.pos) {
+            Some(entry) => {
+                iter.pos += 1;
+                *out = &mut entry.raw;
+                0
+            }
+            None => raw::GIT_ITEROVER,
+        }
+    }
+}
+
+extern "C" fn iterator_free(iter: *mut raw::git_config_iterator) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(iter as *mut RawConfigIterator));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryConfig;
+    use crate::{Config, ConfigBackend, ConfigLevel, Error, ErrorCode};
+
+    struct Tenant(&'static str);
+
+    impl ConfigBackend for Tenant {
+        fn entries(&self) -> Result<Vec<(String, String)>, Error> {
+            Ok(vec![
+                ("user.name".to_string(), self.0.to_string()),
+                ("core.autocrlf".to_string(), "input".to_string()),
+            ])
+        }
+    }
+
+    fn values(cfg: &Config, name: &str) -> Vec<String> {
+        let mut values = Vec::new();
+        cfg.multivar(name, None)
+            .unwrap()
+            .for_each(|e| values.push(e.value().unwrap().to_string()))
+            .unwrap();
+        values
+    }
+
+    #[test]
+    fn smoke() {
+        let mut cfg = Config::new().unwrap();
+        cfg.add_backend(MemoryConfig::default(), ConfigLevel::App, false)
+            .unwrap();
+
+        cfg.set_str("foo.bar", "baz").unwrap();
+        cfg.set_bool("Foo.Enabled", true).unwrap();
+        cfg.set_i32("remote.Origin.count", 3).unwrap();
+        assert_eq!(cfg.get_string("foo.bar").unwrap(), "baz");
+        assert!(cfg.get_bool("foo.enabled").unwrap());
+        assert_eq!(cfg.get_i32("remote.Origin.count").unwrap(), 3);
+        assert_eq!(cfg.get_entry("foo.bar").unwrap().level(), ConfigLevel::App);
+
+        let mut names = Vec::new();
+        cfg.entries(None)
+            .unwrap()
+            .for_each(|e| names.push(e.name().unwrap().to_string()))
+            .unwrap();
+        assert_eq!(names, ["foo.bar", "foo.enabled", "remote.Origin.count"]);
+
+        cfg.remove("foo.bar").unwrap();
+        assert!(cfg.get_string("foo.bar").is_err());
+        let err = cfg.remove("foo.bar").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+
+        let mut snapshot = cfg.snapshot().unwrap();
+        cfg.set_str("foo.enabled", "false").unwrap();
+        assert!(snapshot.get_bool("foo.enabled").unwrap());
+        assert_eq!(snapshot.get_str("remote.Origin.count").unwrap(), "3");
+        assert!(!cfg.get_bool("foo.enabled").unwrap());
+        assert!(snapshot.set_str("foo.bar", "baz").is_err());
+    }
+
+    #[test]
+    fn multivar() {
+        let mut cfg = Config::new().unwrap();
+        cfg.add_backend(MemoryConfig::default(), ConfigLevel::Local, false)
+            .unwrap();
+
+        cfg.set_multivar("foo.bar", "^$", "a").unwrap();
+        cfg.set_multivar("foo.bar", "^$", "b").unwrap();
+        cfg.set_multivar("foo.bar", "^$", "c").unwrap();
+        assert_eq!(values(&cfg, "foo.bar"), ["a", "b", "c"]);
+        assert_eq!(cfg.get_string("foo.bar").unwrap(), "c");
+        assert!(cfg.set_str("foo.bar", "d").is_err());
+        assert!(cfg.remove("foo.bar").is_err());
+
+        cfg.set_multivar("foo.bar", "[ab]", "d").unwrap();
+        assert_eq!(values(&cfg, "foo.bar"), ["d", "c"]);
+
+        cfg.remove_multivar("foo.bar", "^c$").unwrap();
+        assert_eq!(values(&cfg, "foo.bar"), ["d"]);
+        let err = cfg.remove_multivar("foo.bar", "x").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        cfg.remove_multivar("foo.bar", ".*").unwrap();
+        assert!(values(&cfg, "foo.bar").is_empty());
+    }
+
+    #[test]
+    fn custom() {
+        let mut cfg = Config::new_in_memory().unwrap();
+        cfg.add_backend(Tenant("foo"), ConfigLevel::Global, false)
+            .unwrap();
+        assert_eq!(cfg.get_string("user.name").unwrap(), "foo");
+        assert_eq!(cfg.get_string("core.autocrlf").unwrap(), "input");
+
+        cfg.set_str("user.name", "bar").unwrap();
+        assert_eq!(cfg.get_string("user.name").unwrap(), "bar");
+        let mut global = cfg.open_level(ConfigLevel::Global).unwrap();
+        assert_eq!(global.get_string("user.name").unwrap(), "foo");
+        assert!(global.set_str("user.name", "baz").is_err());
+
+        // Values with a NUL byte can't be handed to libgit2.
+        let mut cfg = Config::new().unwrap();
+        let entries = vec![("user.name".to_string(), "f\0o".to_string())];
+        cfg.add_backend(MemoryConfig::new(entries), ConfigLevel::App, false)
+            .unwrap();
+        assert!(cfg.get_string("user.name").is_err());
+        assert!(cfg.entries(None).unwrap().next().unwrap().is_err());
+        assert!(cfg.snapshot().is_err());
+    }
+}
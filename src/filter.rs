@@ -0,0 +1,518 @@
+//! Interfaces for adding custom filters to libgit2
+//!
+//! Filters transform the contents of files as they move between the object
+//! database and the working directory, for example during checkout,
+//! [`Index::add_path`](crate::Index::add_path) and
+//! [`Repository::blob_path`](crate::Repository::blob_path). Which files a
+//! filter applies to is decided by the attributes it asks for, usually a
+//! `filter=<name>` entry in `.gitattributes`.
+
+use libc::{c_char, c_int, c_void, size_t};
+use std::ffi::CString;
+use std::marker;
+use std::mem::{self, ManuallyDrop};
+use std::ptr;
+use std::slice;
+use std::str;
+
+use crate::util::Binding;
+use crate::{panic, raw, AttrValue, Error, FilterFlags, FilterMode, Oid, Repository};
+
+/// A custom filter which can be registered with [`register`].
+///
+/// Every method has a default implementation; a filter which only overrides
+/// [`Filter::apply`] receives the whole content of each file at once, while
+/// one overriding [`Filter::stream`] can process files of any size in chunks.
+///
+/// Panics in any of these methods are caught and re-raised once control
+/// returns to Rust.
+pub trait Filter: Send + Sync + 'static {
+    /// A whitespace separated list of attributes to check for this filter,
+    /// e.g. `"filter=lfs"` or `"eol crlf text"`.
+    ///
+    /// A bare attribute name is simply loaded and passed to
+    /// [`Filter::check`]. An attribute with a value must match it for the
+    /// filter to be applied; the value may be `*` to match any value. If no
+    /// attributes are given the filter is considered for every file.
+    ///
+    /// This is only called once, when the filter is registered.
+    fn attributes(&self) -> &str {
+        ""
+    }
+
+    /// Decide whether the filter should be applied to the given file.
+    ///
+    /// `attributes` contains the value of each attribute listed in
+    /// [`Filter::attributes`], in the same order. Returning `false` passes
+    /// the content through unmodified.
+    fn check(
+        &self,
+        _source: &FilterSource<'_>,
+        _attributes: &[AttrValue<'_>],
+    ) -> Result<bool, Error> {
+        Ok(true)
+    }
+
+    /// Filter the complete content of a file.
+    ///
+    /// This is only called when [`Filter::stream`] does not return a stream.
+    /// The default implementation returns the content unmodified.
+    fn apply(&self, _source: &FilterSource<'_>, input: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(input.to_vec())
+    }
+
+    /// Create a stream which filters the content of a file as it is written.
+    ///
+    /// Returning `None`, which is what the default implementation does,
+    /// buffers the whole content and hands it to [`Filter::apply`] instead.
+    fn stream(&self, _source: &FilterSource<'_>) -> Result<Option<Box<dyn FilterStream>>, Error> {
+        Ok(None)
+    }
+}
+
+/// A stream created by [`Filter::stream`] for filtering a single file.
+pub trait FilterStream {
+    /// Filter the next chunk of content, writing the result to `output`.
+    fn write(&mut self, data: &[u8], output: &mut FilterOutput<'_>) -> Result<(), Error>;
+
+    /// Called once all content has been written, to flush any remaining
+    /// output.
+    fn close(&mut self, _output: &mut FilterOutput<'_>) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Information about the file being filtered.
+pub struct FilterSource<'a> {
+    raw: *const raw::git_filter_source,
+    repo: Option<ManuallyDrop<Repository>>,
+    _marker: marker::PhantomData<&'a raw::git_filter_source>,
+}
+
+/// The destination a [`FilterStream`] writes its filtered content to.
+pub struct FilterOutput<'a> {
+    raw: *mut raw::git_writestream,
+    _marker: marker::PhantomData<&'a raw::git_writestream>,
+}
+
+/// Instance of a `git_filter`, must use `#[repr(C)]` to ensure that the C
+/// fields come first.
+#[repr(C)]
+struct RawFilter {
+    raw: raw::git_filter,
+    attributes: Option<CString>,
+    nattrs: usize,
+    obj: Box<dyn Filter>,
+}
+
+/// Instance of a `git_writestream` created for a filter, must use
+/// `#[repr(C)]` to ensure that the C fields come first.
+#[repr(C)]
+struct RawFilterStream {
+    raw: raw::git_writestream,
+    filter: *const RawFilter,
+    source: *const raw::git_filter_source,
+    next: *mut raw::git_writestream,
+    stream: Option<Box<dyn FilterStream>>,
+    // content buffered for `Filter::apply` when there is no stream
+    buf: Vec<u8>,
+}
+
+/// Register a custom filter under the given name.
+///
+/// Filters are applied in order of their priority, lowest first when writing
+/// to the object database and highest first when writing to the working
+/// directory. libgit2's built-in `crlf` and `ident` filters have a priority of
+/// 0 and 100 respectively, and filter drivers are expected to use 200.
+///
+/// Registering a filter under a name which is already in use fails. The
+/// filter is never dropped, as libgit2 may still refer to it until the
+/// process exits, even after it is unregistered.
+pub fn register<F>(name: &str, priority: i32, filter: F) -> Result<(), Error>
+where
+    F: Filter,
+{
+    crate::init();
+    let name = CString::new(name)?;
+    let attributes = filter.attributes();
+    let nattrs = attributes.split_ascii_whitespace().count();
+    let attributes = if nattrs > 0 {
+        Some(CString::new(attributes)?)
+    } else {
+        None
+    };
+    unsafe {
+        let mut raw = Box::new(RawFilter {
+            raw: mem::zeroed(),
+            attributes,
+            nattrs,
+            obj: Box::new(filter),
+        });
+        try_call!(raw::git_filter_init(&mut raw.raw, raw::GIT_FILTER_VERSION));
+        raw.raw.attributes = raw.attributes.as_ref().map_or(ptr::null(), |a| a.as_ptr());
+        raw.raw.check = Some(filter_check);
+        raw.raw.stream = Some(filter_stream);
+
+        let raw = Box::into_raw(raw);
+        let rc = raw::git_filter_register(name.as_ptr(), raw as *mut _, priority as c_int);
+        if rc < 0 {
+            drop(Box::from_raw(raw));
+            return Err(Error::last_error(rc));
+        }
+    }
+    Ok(())
+}
+
+/// Unregister a filter previously registered with [`register`].
+///
+/// The built-in `crlf` and `ident` filters cannot be unregistered.
+pub fn unregister(name: &str) -> Result<(), Error> {
+    crate::init();
+    let name = CString::new(name)?;
+    unsafe {
+        try_call!(raw::git_filter_unregister(name));
+    }
+    Ok(())
+}
+
+impl<'a> FilterSource<'a> {
+    unsafe fn from_raw(raw: *const raw::git_filter_source) -> FilterSource<'a> {
+        let repo = raw::git_filter_source_repo(raw);
+        FilterSource {
+            raw,
+            repo: if repo.is_null() {
+                None
+            } else {
+                Some(ManuallyDrop::new(Repository::from_raw(repo)))
+            },
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// The repository the file belongs to, if any.
+    pub fn repo(&self) -> Option<&Repository> {
+        self.repo.as_deref()
+    }
+
+    /// The path of the file being filtered, relative to the working
+    /// directory.
+    ///
+    /// Returns `None` if the path is not valid utf-8.
+    pub fn path(&self) -> Option<&str> {
+        str::from_utf8(self.path_bytes()).ok()
+    }
+
+    /// The path of the file being filtered, as a byte slice.
+    pub fn path_bytes(&self) -> &[u8] {
+        unsafe { crate::opt_bytes(self, raw::git_filter_source_path(self.raw)).unwrap_or(&[]) }
+    }
+
+    /// The file mode of the file being filtered, or 0 if it is not known.
+    pub fn filemode(&self) -> u32 {
+        unsafe { raw::git_filter_source_filemode(self.raw) as u32 }
+    }
+
+    /// The id of the blob being filtered, if known.
+    ///
+    /// This is usually only available when filtering to the working
+    /// directory.
+    pub fn id(&self) -> Option<Oid> {
+        unsafe {
+            let id = raw::git_filter_source_id(self.raw);
+            if id.is_null() {
+                None
+            } else {
+                Some(Binding::from_raw(id))
+            }
+        }
+    }
+
+    /// The direction in which the file is being filtered.
+    pub fn mode(&self) -> FilterMode {
+        // The filters of libgit2 treat every mode but `GIT_FILTER_SMUDGE` as
+        // a clean. This runs in callbacks, which must not panic.
+        let mode = unsafe { raw::git_filter_source_mode(self.raw) };
+        FilterMode::from_raw(mode).unwrap_or(FilterMode::ToOdb)
+    }
+
+    /// The flags the filters were loaded with.
+    pub fn flags(&self) -> FilterFlags {
+        FilterFlags::from_bits_truncate(unsafe { raw::git_filter_source_flags(self.raw) })
+    }
+}
+
+impl<'a> FilterOutput<'a> {
+    unsafe fn from_raw(raw: *mut raw::git_writestream) -> FilterOutput<'a> {
+        FilterOutput {
+            raw,
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// Write filtered content to the next stage of the filter chain.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        unsafe {
+            let rc =
+                (*self.raw).write.unwrap()(self.raw, data.as_ptr() as *const c_char, data.len());
+            if rc < 0 {
+                return Err(Error::last_error(rc));
+            }
+        }
+        Ok(())
+    }
+}
+
+extern "C" fn filter_check(
+    filter: *mut raw::git_filter,
+    _payload: *mut *mut c_void,
+    source: *const raw::git_filter_source,
+    attr_values: *mut *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let f = &*(filter as *const RawFilter);
+        let values = if f.nattrs == 0 || attr_values.is_null() {
+            &[][..]
+        } else {
+            slice::from_raw_parts(attr_values as *const *const c_char, f.nattrs)
+        };
+        let values = values
+            .iter()
+            .map(|&v| AttrValue::from_bytes(crate::opt_bytes(f, v)))
+            .collect::<Vec<_>>();
+        let source = FilterSource::from_raw(source);
+        match f.obj.check(&source, &values) {
+            Ok(true) => 0,
+            Ok(false) => raw::GIT_PASSTHROUGH,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn filter_stream(
+    out: *mut *mut raw::git_writestream,
+    filter: *mut raw::git_filter,
+    _payload: *mut *mut c_void,
+    source: *const raw::git_filter_source,
+    next: *mut raw::git_writestream,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let f = &*(filter as *const RawFilter);
+        match f.obj.stream(&FilterSource::from_raw(source)) {
+            Ok(stream) => {
+                let stream = Box::new(RawFilterStream {
+                    raw: raw::git_writestream {
+                        write: Some(stream_write),
+                        close: Some(stream_close),
+                        free: Some(stream_free),
+                    },
+                    filter: f,
+                    source,
+                    next,
+                    stream,
+                    buf: Vec::new(),
+                });
+                *out = Box::into_raw(stream) as *mut raw::git_writestream;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_write(
+    stream: *mut raw::git_writestream,
+    buffer: *const c_char,
+    len: size_t,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawFilterStream);
+        let data = if len == 0 {
+            &[][..]
+        } else {
+            slice::from_raw_parts(buffer as *const u8, len)
+        };
+        let mut output = FilterOutput::from_raw(s.next);
+        let res = match s.stream {
+            Some(ref mut stream) => stream.write(data, &mut output),
+            None => {
+                s.buf.extend_from_slice(data);
+                Ok(())
+            }
+        };
+        match res {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_close(stream: *mut raw::git_writestream) -> c_int {
+    panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawFilterStream);
+        let mut output = FilterOutput::from_raw(s.next);
+        let res = match s.stream {
+            Some(ref mut stream) => stream.close(&mut output),
+            None => {
+                let f = &*s.filter;
+                let source = FilterSource::from_raw(s.source);
+                f.obj
+                    .apply(&source, &s.buf)
+                    .and_then(|data| output.write(&data))
+            }
+        };
+        match res {
+            Ok(()) => (*s.next).close.unwrap()(s.next),
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_free(stream: *mut raw::git_writestream) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(stream as *mut RawFilterStream));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{Filter, FilterOutput, FilterSource, FilterStream};
+    use crate::build::CheckoutBuilder;
+    use crate::{raw, AttrValue, Error, ErrorCode, FilterMode};
+
+    struct Upper;
+
+    impl Filter for Upper {
+        fn attributes(&self) -> &str {
+            "filter=git2-rs-upper"
+        }
+
+        fn apply(&self, source: &FilterSource<'_>, input: &[u8]) -> Result<Vec<u8>, Error> {
+            assert!(source.repo().is_some());
+            Ok(match source.mode() {
+                FilterMode::ToOdb => input.to_ascii_uppercase(),
+                FilterMode::ToWorktree => input.to_ascii_lowercase(),
+            })
+        }
+    }
+
+    struct Quote;
+
+    impl Filter for Quote {
+        fn attributes(&self) -> &str {
+            "filter=git2-rs-quote quote-prefix"
+        }
+
+        fn check(
+            &self,
+            source: &FilterSource<'_>,
+            attributes: &[AttrValue<'_>],
+        ) -> Result<bool, Error> {
+            assert_eq!(attributes.len(), 2);
+            assert_eq!(attributes[0], AttrValue::String("git2-rs-quote"));
+            match source.path() {
+                Some("skip.txt") => Ok(false),
+                Some("bad.txt") => Err(Error::from_str("bad file")),
+                _ => Ok(true),
+            }
+        }
+
+        fn stream(
+            &self,
+            source: &FilterSource<'_>,
+        ) -> Result<Option<Box<dyn FilterStream>>, Error> {
+            assert_eq!(source.mode(), FilterMode::ToOdb);
+            Ok(Some(Box::new(QuoteStream { started: false })))
+        }
+    }
+
+    struct QuoteStream {
+        started: bool,
+    }
+
+    impl FilterStream for QuoteStream {
+        fn write(&mut self, data: &[u8], output: &mut FilterOutput<'_>) -> Result<(), Error> {
+            if !self.started {
+                output.write(b"> ")?;
+                self.started = true;
+            }
+            output.write(data)
+        }
+
+        fn close(&mut self, output: &mut FilterOutput<'_>) -> Result<(), Error> {
+            output.write(b"<\n")
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let (td, repo) = crate::test::repo_init();
+        super::register("git2-rs-upper", 200, Upper).unwrap();
+        let err = super::register("git2-rs-upper", 200, Upper).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Exists);
+
+        fs::write(
+            td.path().join(".gitattributes"),
+            "*.txt filter=git2-rs-upper\n",
+        )
+        .unwrap();
+        fs::write(td.path().join("a.txt"), "hello\n").unwrap();
+        fs::write(td.path().join("a.dat"), "hello\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(".gitattributes")).unwrap();
+        index.add_path(Path::new("a.txt")).unwrap();
+        index.add_path(Path::new("a.dat")).unwrap();
+        let blob = index.get_path(Path::new("a.txt"), 0).unwrap().id;
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"HELLO\n");
+        let blob = index.get_path(Path::new("a.dat"), 0).unwrap().id;
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"hello\n");
+        let blob = repo.blob_path(&td.path().join("a.txt")).unwrap();
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"HELLO\n");
+
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = repo.signature().unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "upper", &tree, &[&parent])
+            .unwrap();
+        fs::remove_file(td.path().join("a.txt")).unwrap();
+        repo.checkout_head(Some(CheckoutBuilder::new().force()))
+            .unwrap();
+        assert_eq!(fs::read(td.path().join("a.txt")).unwrap(), b"hello\n");
+
+        super::unregister("git2-rs-upper").unwrap();
+        let err = super::unregister("git2-rs-upper").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        let blob = repo.blob_path(&td.path().join("a.txt")).unwrap();
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"hello\n");
+        let mode = FilterMode::from_raw(raw::GIT_FILTER_TO_ODB);
+        assert_eq!(mode, Some(FilterMode::ToOdb));
+        assert_eq!(FilterMode::from_raw(7), None);
+    }
+
+    #[test]
+    fn stream() {
+        let (td, repo) = crate::test::repo_init();
+        super::register("git2-rs-quote", 200, Quote).unwrap();
+
+        fs::write(
+            td.path().join(".gitattributes"),
+            "*.txt filter=git2-rs-quote\n",
+        )
+        .unwrap();
+        for name in ["a.txt", "skip.txt", "bad.txt"] {
+            fs::write(td.path().join(name), "hello\n").unwrap();
+        }
+        let blob = repo.blob_path(&td.path().join("a.txt")).unwrap();
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"> hello\n<\n");
+        let blob = repo.blob_path(&td.path().join("skip.txt")).unwrap();
+        assert_eq!(repo.find_blob(blob).unwrap().content(), b"hello\n");
+        let err = repo.blob_path(&td.path().join("bad.txt")).unwrap_err();
+        assert_eq!(err.message(), "bad file");
+    }
+}
@@ -0,0 +1,179 @@
+use std::ffi::CString;
+use std::marker;
+use std::path::Path;
+use std::ptr;
+
+use crate::util::{path_to_repo_path, Binding, IntoCString};
+use crate::{raw, Blob, Buf, Error, FilterFlags, FilterMode, Repository};
+
+/// A list of filters that apply to a particular path.
+///
+/// The list is built from the gitattributes that match the path (and
+/// core.autocrlf and friends), the same way a checkout or `git add` would
+/// decide which filters to run. It can then be applied to arbitrary data,
+/// files or blobs.
+pub struct FilterList<'repo> {
+    raw: *mut raw::git_filter_list,
+    _marker: marker::PhantomData<&'repo Repository>,
+}
+
+impl<'repo> FilterList<'repo> {
+    /// Load the filters that apply to `path` in `repo` for the given
+    /// direction.
+    ///
+    /// If `blob` is given, it is used to decide whether the content is
+    /// binary (for example when a filter should only apply to text). The
+    /// resulting list may be empty, in which case applying it leaves the
+    /// data untouched.
+    pub fn load(
+        repo: &'repo Repository,
+        blob: Option<&Blob<'_>>,
+        path: &Path,
+        mode: FilterMode,
+        flags: FilterFlags,
+    ) -> Result<FilterList<'repo>, Error> {
+        let path = path_to_repo_path(path)?;
+        let mut raw = ptr::null_mut();
+        unsafe {
+            try_call!(raw::git_filter_list_load(
+                &mut raw,
+                repo.raw(),
+                blob.map(|b| b.raw()),
+                path,
+                mode,
+                flags.bits()
+            ));
+            Ok(Binding::from_raw(raw))
+        }
+    }
+
+    /// Check whether the filter with the given name is part of this list.
+    pub fn contains(&self, name: &str) -> Result<bool, Error> {
+        let name = CString::new(name)?;
+        unsafe { Ok(raw::git_filter_list_contains(self.raw, name.as_ptr()) == 1) }
+    }
+
+    /// Check whether this list has no filters in it.
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_null()
+    }
+
+    /// Apply the filters to a buffer of data.
+    pub fn apply_to_buffer(&self, data: &[u8]) -> Result<Buf, Error> {
+        let buf = Buf::new();
+        unsafe {
+            try_call!(raw::git_filter_list_apply_to_buffer(
+                buf.raw(),
+                self.raw,
+                data.as_ptr() as *const _,
+                data.len()
+            ));
+        }
+        Ok(buf)
+    }
+
+    /// Apply the filters to the contents of a file on disk.
+    ///
+    /// A relative `path` is interpreted relative to the working directory of
+    /// `repo`.
+    pub fn apply_to_file<P: IntoCString>(&self, repo: &Repository, path: P) -> Result<Buf, Error> {
+        let path = path.into_c_string()?;
+        let buf = Buf::new();
+        unsafe {
+            try_call!(raw::git_filter_list_apply_to_file(
+                buf.raw(),
+                self.raw,
+                repo.raw(),
+                path
+            ));
+        }
+        Ok(buf)
+    }
+
+    /// Apply the filters to the contents of a blob.
+    pub fn apply_to_blob(&self, blob: &Blob<'_>) -> Result<Buf, Error> {
+        let buf = Buf::new();
+        unsafe {
+            try_call!(raw::git_filter_list_apply_to_blob(
+                buf.raw(),
+                self.raw,
+                blob.raw()
+            ));
+        }
+        Ok(buf)
+    }
+}
+
+impl<'repo> Binding for FilterList<'repo> {
+    type Raw = *mut raw::git_filter_list;
+
+    unsafe fn from_raw(raw: *mut raw::git_filter_list) -> FilterList<'repo> {
+        FilterList {
+            raw,
+            _marker: marker::PhantomData,
+        }
+    }
+    fn raw(&self) -> *mut raw::git_filter_list {
+        self.raw
+    }
+}
+
+impl<'repo> Drop for FilterList<'repo> {
+    fn drop(&mut self) {
+        unsafe { raw::git_filter_list_free(self.raw) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::{FilterFlags, FilterList, FilterMode};
+
+    #[test]
+    fn smoke() {
+        let (td, repo) = crate::test::repo_init();
+        t!(fs::write(
+            td.path().join(".gitattributes"),
+            "*.txt text eol=crlf\n"
+        ));
+
+        let list = t!(FilterList::load(
+            &repo,
+            None,
+            Path::new("foo.txt"),
+            FilterMode::ToWorktree,
+            FilterFlags::DEFAULT
+        ));
+        assert!(!list.is_empty());
+        assert!(t!(list.contains("crlf")));
+        assert!(!t!(list.contains("ident")));
+        assert_eq!(&*t!(list.apply_to_buffer(b"a\nb\n")), b"a\r\nb\r\n");
+
+        let id = t!(repo.blob(b"c\nd\n"));
+        let blob = t!(repo.find_blob(id));
+        assert_eq!(&*t!(list.apply_to_blob(&blob)), b"c\r\nd\r\n");
+
+        t!(fs::write(td.path().join("foo.txt"), "e\r\nf\r\n"));
+        let list = t!(FilterList::load(
+            &repo,
+            None,
+            Path::new("foo.txt"),
+            FilterMode::ToOdb,
+            FilterFlags::DEFAULT
+        ));
+        assert_eq!(&*t!(list.apply_to_file(&repo, "foo.txt")), b"e\nf\n");
+
+        let list = t!(FilterList::load(
+            &repo,
+            None,
+            Path::new("foo.bin"),
+            FilterMode::ToWorktree,
+            FilterFlags::DEFAULT
+        ));
+        assert!(list.is_empty());
+        assert!(!t!(list.contains("crlf")));
+        assert_eq!(&*t!(list.apply_to_buffer(b"a\nb\n")), b"a\nb\n");
+    }
+}
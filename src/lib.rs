@@ -82,21 +82,26 @@ use std::sync::Once;
 pub use crate::apply::{ApplyLocation, ApplyOptions};
 pub use crate::attr::AttrValue;
 pub use crate::blame::{Blame, BlameHunk, BlameIter, BlameOptions};
-pub use crate::blob::{Blob, BlobWriter};
+pub use crate::blob::{Blob, BlobFilterOptions, BlobWriter};
 pub use crate::branch::{Branch, Branches};
 pub use crate::buf::Buf;
 pub use crate::cherrypick::CherrypickOptions;
 pub use crate::commit::{Commit, Parents};
 pub use crate::config::{Config, ConfigEntries, ConfigEntry};
-pub use crate::cred::Cred;
+pub use crate::config_backend::ConfigBackend;
+pub use crate::cred::{Cred, SshPrompt};
 #[cfg(feature = "cred")]
-pub use crate::cred::CredentialHelper;
+pub use crate::cred::{
+    CredentialChain, CredentialHelper, CredentialHelpers, CredentialProvider, CredentialStore,
+    HelperCredential, Netrc, SshAgent, SshKeyFile,
+};
 pub use crate::describe::{Describe, DescribeFormatOptions, DescribeOptions};
 pub use crate::diff::{Deltas, Diff, DiffDelta, DiffFile, DiffOptions};
 pub use crate::diff::{DiffBinary, DiffBinaryFile, DiffBinaryKind, DiffPatchidOptions};
 pub use crate::diff::{DiffFindOptions, DiffHunk, DiffLine, DiffLineType, DiffStats};
 pub use crate::email::{Email, EmailCreateOptions};
 pub use crate::error::Error;
+pub use crate::filter_list::FilterList;
 pub use crate::index::{
     Index, IndexConflict, IndexConflicts, IndexEntries, IndexEntry, IndexMatchedPath,
 };
@@ -112,20 +117,22 @@ pub use crate::message::{
 pub use crate::note::{Note, Notes};
 pub use crate::object::Object;
 pub use crate::odb::{Odb, OdbObject, OdbPackwriter, OdbReader, OdbWriter};
+pub use crate::odb_backend::{OdbBackend, OdbWritepack};
 pub use crate::oid::Oid;
 pub use crate::packbuilder::{PackBuilder, PackBuilderStage};
+pub use crate::partial_clone::ObjectFilter;
 pub use crate::patch::Patch;
 pub use crate::pathspec::{Pathspec, PathspecFailedEntries, PathspecMatchList};
 pub use crate::pathspec::{PathspecDiffEntries, PathspecEntries};
 pub use crate::proxy_options::ProxyOptions;
-pub use crate::push_update::PushUpdate;
+pub use crate::push_update::{PushPlan, PushPlanKind, PushUpdate};
 pub use crate::rebase::{Rebase, RebaseOperation, RebaseOperationType, RebaseOptions};
+pub use crate::refdb::{Refdb, RefdbBackend, RefdbIter, RefdbPrecondition, RefdbTarget};
 pub use crate::reference::{Reference, ReferenceNames, References};
 pub use crate::reflog::{Reflog, ReflogEntry, ReflogIter};
 pub use crate::refspec::Refspec;
-pub use crate::remote::{
-    FetchOptions, PushOptions, Refspecs, Remote, RemoteConnection, RemoteHead, RemoteRedirect,
-};
+pub use crate::remote::{FetchOptions, FetchReport, FetchUpdate, FetchUpdateKind, PushOptions};
+pub use crate::remote::{Refspecs, Remote, RemoteConnection, RemoteHead, RemoteRedirect};
 pub use crate::remote_callbacks::{CertificateCheckStatus, Credentials, RemoteCallbacks};
 pub use crate::remote_callbacks::{TransportMessage, UpdateTips};
 pub use crate::repo::{Repository, RepositoryInitOptions};
@@ -689,9 +696,14 @@ mod call;
 mod util;
 
 pub mod build;
+pub mod bundle;
 pub mod cert;
+pub mod filter;
+pub mod merge_driver;
 pub mod oid_array;
 pub mod opts;
+pub mod server;
+pub mod stream;
 pub mod string_array;
 pub mod transport;
 
@@ -703,11 +715,13 @@ mod buf;
 mod cherrypick;
 mod commit;
 mod config;
+mod config_backend;
 mod cred;
 mod describe;
 mod diff;
 mod email;
 mod error;
+mod filter_list;
 mod index;
 mod indexer;
 mod mailmap;
@@ -717,13 +731,16 @@ mod message;
 mod note;
 mod object;
 mod odb;
+mod odb_backend;
 mod oid;
 mod packbuilder;
+mod partial_clone;
 mod patch;
 mod pathspec;
 mod proxy_options;
 mod push_update;
 mod rebase;
+mod refdb;
 mod reference;
 mod reflog;
 mod refspec;
@@ -1543,6 +1560,52 @@ impl Default for AttrCheckFlags {
     }
 }
 
+/// The direction in which content is being filtered.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FilterMode {
+    /// Content is being written to the working directory, also known as
+    /// "smudge".
+    ToWorktree,
+    /// Content is being written to the object database, also known as
+    /// "clean".
+    ToOdb,
+}
+
+impl FilterMode {
+    /// Converts a [`raw::git_filter_mode_t`] to a [`FilterMode`], or `None`
+    /// if the mode is unknown.
+    pub fn from_raw(raw: raw::git_filter_mode_t) -> Option<FilterMode> {
+        match raw {
+            raw::GIT_FILTER_TO_WORKTREE => Some(FilterMode::ToWorktree),
+            raw::GIT_FILTER_TO_ODB => Some(FilterMode::ToOdb),
+            _ => None,
+        }
+    }
+}
+
+bitflags! {
+    /// Flags controlling how filters are loaded and applied.
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
+    pub struct FilterFlags: u32 {
+        /// Default behavior.
+        const DEFAULT = raw::GIT_FILTER_DEFAULT;
+        /// Don't error for `safecrlf` violations, allow them to continue.
+        const ALLOW_UNSAFE = raw::GIT_FILTER_ALLOW_UNSAFE;
+        /// Don't load `/etc/gitattributes` (or the system equivalent).
+        const NO_SYSTEM_ATTRIBUTES = raw::GIT_FILTER_NO_SYSTEM_ATTRIBUTES;
+        /// Load attributes from `.gitattributes` in the root of HEAD.
+        const ATTRIBUTES_FROM_HEAD = raw::GIT_FILTER_ATTRIBUTES_FROM_HEAD;
+        /// Load attributes from `.gitattributes` in a given commit.
+        const ATTRIBUTES_FROM_COMMIT = raw::GIT_FILTER_ATTRIBUTES_FROM_COMMIT;
+    }
+}
+
+impl Default for FilterFlags {
+    fn default() -> Self {
+        FilterFlags::DEFAULT
+    }
+}
+
 bitflags! {
     #[allow(missing_docs)]
     #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd, Ord, Hash)]
@@ -22,6 +22,7 @@ pub struct AnnotatedCommit<'repo> {
 
 /// Options to specify when merging.
 pub struct MergeOptions {
+    default_driver: Option<CString>,
     raw: raw::git_merge_options,
 }
 
@@ -67,6 +68,7 @@ impl MergeOptions {
     /// Creates a default set of merge options.
     pub fn new() -> MergeOptions {
         let mut opts = MergeOptions {
+            default_driver: None,
             raw: unsafe { mem::zeroed() },
         };
         assert_eq!(unsafe { raw::git_merge_init_options(&mut opts.raw, 1) }, 0);
@@ -184,6 +186,18 @@ impl MergeOptions {
         self.file_flag(raw::GIT_MERGE_FILE_DIFF_MINIMAL as u32, minimal)
     }
 
+    /// The merge driver to use for files which don't specify one with the
+    /// `merge` attribute, instead of the built-in `text` driver.
+    ///
+    /// See [`merge_driver`](crate::merge_driver) for registering custom
+    /// drivers.
+    pub fn default_driver(&mut self, name: &str) -> &mut MergeOptions {
+        let name = CString::new(name).unwrap();
+        self.raw.default_driver = name.as_ptr();
+        self.default_driver = Some(name);
+        self
+    }
+
     /// Acquire a pointer to the underlying raw options.
     pub unsafe fn raw(&self) -> *const raw::git_merge_options {
         &self.raw as *const _
@@ -0,0 +1,304 @@
+//! Interfaces for adding custom merge drivers to libgit2
+//!
+//! A merge driver resolves conflicting changes to a file when both sides of a
+//! merge modified it. The driver used for a file is chosen by its `merge=`
+//! attribute in `.gitattributes` (or
+//! [`MergeOptions::default_driver`](crate::MergeOptions::default_driver)),
+//! and is invoked by every operation which merges trees, such as
+//! [`Repository::merge`](crate::Repository::merge),
+//! [`Repository::merge_trees`](crate::Repository::merge_trees),
+//! [`Repository::cherrypick`](crate::Repository::cherrypick) and
+//! [`Repository::rebase`](crate::Repository::rebase).
+
+use libc::{c_char, c_int};
+use std::ffi::{CStr, CString};
+use std::marker;
+use std::mem::ManuallyDrop;
+
+use crate::util::Binding;
+use crate::{panic, raw, Error, IndexEntry, Repository};
+
+/// A custom merge driver which can be registered with [`register`].
+///
+/// Panics in [`MergeDriver::merge`] are caught and re-raised once control
+/// returns to Rust.
+pub trait MergeDriver: Send + Sync + 'static {
+    /// Merge the conflicting versions of a file.
+    ///
+    /// `name` is the name the driver was selected by, which is the value of
+    /// the `merge=` attribute.
+    fn merge(&self, name: &str, source: &MergeDriverSource<'_>) -> Result<MergeOutcome, Error>;
+}
+
+/// The result of running a [`MergeDriver`] on a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeOutcome {
+    /// The file was merged cleanly into the given content.
+    Merged(Vec<u8>),
+    /// The changes could not be merged; the file is left conflicted in the
+    /// resulting index.
+    Conflict,
+    /// Let libgit2's built-in `text` driver merge the file instead.
+    Passthrough,
+}
+
+/// The versions of a file being merged by a [`MergeDriver`].
+pub struct MergeDriverSource<'a> {
+    raw: *const raw::git_merge_driver_source,
+    repo: ManuallyDrop<Repository>,
+    _marker: marker::PhantomData<&'a raw::git_merge_driver_source>,
+}
+
+/// Instance of a `git_merge_driver`, must use `#[repr(C)]` to ensure that the
+/// C fields come first.
+#[repr(C)]
+struct RawMergeDriver {
+    raw: raw::git_merge_driver,
+    obj: Box<dyn MergeDriver>,
+}
+
+/// Register a custom merge driver under the given name.
+///
+/// The driver is used for every file whose `merge` attribute is `name`. A
+/// driver registered as `*` is used for files whose driver has not been
+/// registered. Registering a driver under a name which is already in use
+/// fails. The driver is never dropped, as libgit2 may still refer to it until
+/// the process exits, even after it is unregistered.
+pub fn register<D>(name: &str, driver: D) -> Result<(), Error>
+where
+    D: MergeDriver,
+{
+    crate::init();
+    let name = CString::new(name)?;
+    unsafe {
+        let raw = Box::into_raw(Box::new(RawMergeDriver {
+            raw: raw::git_merge_driver {
+                version: raw::GIT_MERGE_DRIVER_VERSION,
+                initialize: None,
+                shutdown: None,
+                apply: Some(driver_apply),
+            },
+            obj: Box::new(driver),
+        }));
+        let rc = raw::git_merge_driver_register(name.as_ptr(), raw as *mut _);
+        if rc < 0 {
+            drop(Box::from_raw(raw));
+            return Err(Error::last_error(rc));
+        }
+    }
+    Ok(())
+}
+
+/// Unregister a merge driver previously registered with [`register`].
+///
+/// The built-in `text`, `union` and `binary` drivers cannot be
+/// unregistered.
+pub fn unregister(name: &str) -> Result<(), Error> {
+    crate::init();
+    let name = CString::new(name)?;
+    unsafe {
+        try_call!(raw::git_merge_driver_unregister(name));
+    }
+    Ok(())
+}
+
+impl<'a> MergeDriverSource<'a> {
+    unsafe fn from_raw(raw: *const raw::git_merge_driver_source) -> MergeDriverSource<'a> {
+        MergeDriverSource {
+            raw,
+            repo: ManuallyDrop::new(Repository::from_raw(raw::git_merge_driver_source_repo(raw))),
+            _marker: marker::PhantomData,
+        }
+    }
+
+    /// The repository in which the merge is taking place.
+    pub fn repo(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// The version of the file in the merge base, if it existed there.
+    pub fn ancestor(&self) -> Option<IndexEntry> {
+        unsafe { entry(raw::git_merge_driver_source_ancestor(self.raw)) }
+    }
+
+    /// Our version of the file.
+    pub fn ours(&self) -> Option<IndexEntry> {
+        unsafe { entry(raw::git_merge_driver_source_ours(self.raw)) }
+    }
+
+    /// Their version of the file.
+    pub fn theirs(&self) -> Option<IndexEntry> {
+        unsafe { entry(raw::git_merge_driver_source_theirs(self.raw)) }
+    }
+}
+
+unsafe fn entry(raw: *const raw::git_index_entry) -> Option<IndexEntry> {
+    if raw.is_null() {
+        return None;
+    }
+    // Entries produced by the merge don't encode the path length in their
+    // flags, so read the nul-terminated path instead.
+    let mut entry = IndexEntry::from_raw(*raw);
+    entry.path = CStr::from_ptr((*raw).path).to_bytes().to_vec();
+    Some(entry)
+}
+
+/// Pick the path or mode of the merged file: whichever side changed it
+/// relative to the ancestor, preferring ours.
+unsafe fn best<T: PartialEq>(
+    ancestor: *const raw::git_index_entry,
+    ours: *const raw::git_index_entry,
+    theirs: *const raw::git_index_entry,
+    field: impl Fn(&raw::git_index_entry) -> T,
+) -> T {
+    if !ancestor.is_null() && field(&*ancestor) == field(&*ours) {
+        field(&*theirs)
+    } else {
+        field(&*ours)
+    }
+}
+
+extern "C" fn driver_apply(
+    driver: *mut raw::git_merge_driver,
+    path_out: *mut *const c_char,
+    mode_out: *mut u32,
+    merged_out: *mut raw::git_buf,
+    name: *const c_char,
+    source: *const raw::git_merge_driver_source,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let d = &*(driver as *const RawMergeDriver);
+        let name = CStr::from_ptr(name).to_string_lossy();
+        let src = MergeDriverSource::from_raw(source);
+        match d.obj.merge(&name, &src) {
+            Ok(MergeOutcome::Merged(data)) => {
+                let ancestor = raw::git_merge_driver_source_ancestor(source);
+                let ours = raw::git_merge_driver_source_ours(source);
+                let theirs = raw::git_merge_driver_source_theirs(source);
+                *path_out = best(ancestor, ours, theirs, |e| CStr::from_ptr(e.path)).as_ptr();
+                *mode_out = best(ancestor, ours, theirs, |e| e.mode);
+                raw::git_buf_set(merged_out, data.as_ptr() as *const _, data.len())
+            }
+            Ok(MergeOutcome::Conflict) => raw::GIT_EMERGECONFLICT,
+            Ok(MergeOutcome::Passthrough) => raw::GIT_PASSTHROUGH,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{MergeDriver, MergeDriverSource, MergeOutcome};
+    use crate::{Error, ErrorCode, MergeOptions, Oid, Repository};
+
+    /// Merges files of sorted, unique lines by taking their union.
+    struct SortedUnion;
+
+    impl MergeDriver for SortedUnion {
+        fn merge(&self, name: &str, source: &MergeDriverSource<'_>) -> Result<MergeOutcome, Error> {
+            assert!(name.starts_with("git2-rs-sorted"));
+            let repo = source.repo();
+            let ours = source.ours().unwrap();
+            let theirs = source.theirs().unwrap();
+            assert!(source.ancestor().is_some());
+            assert_eq!(ours.path, theirs.path);
+            match &ours.path[..] {
+                b"conflict.list" => return Ok(MergeOutcome::Conflict),
+                b"text.list" => return Ok(MergeOutcome::Passthrough),
+                _ => {}
+            }
+            let ours = repo.find_blob(ours.id)?;
+            let theirs = repo.find_blob(theirs.id)?;
+            let ours = std::str::from_utf8(ours.content()).unwrap();
+            let theirs = std::str::from_utf8(theirs.content()).unwrap();
+            let mut lines = ours.lines().chain(theirs.lines()).collect::<Vec<_>>();
+            lines.sort();
+            lines.dedup();
+            let mut merged = lines.join("\n");
+            merged.push('\n');
+            Ok(MergeOutcome::Merged(merged.into_bytes()))
+        }
+    }
+
+    fn commit_files(repo: &Repository, parent: Oid, files: &[(&str, &str)]) -> Oid {
+        let parent = repo.find_commit(parent).unwrap();
+        let mut index = repo.index().unwrap();
+        index.read_tree(&parent.tree().unwrap()).unwrap();
+        let workdir = repo.workdir().unwrap();
+        for (path, content) in files {
+            fs::write(workdir.join(path), content).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = repo.signature().unwrap();
+        repo.commit(None, &sig, &sig, "files", &tree, &[&parent])
+            .unwrap()
+    }
+
+    #[test]
+    fn smoke() {
+        let (_td, repo) = crate::test::repo_init();
+        super::register("git2-rs-sorted", SortedUnion).unwrap();
+        let err = super::register("git2-rs-sorted", SortedUnion).unwrap_err();
+        assert_eq!(err.code(), ErrorCode::Exists);
+
+        let head = repo.head().unwrap().target().unwrap();
+        let base = commit_files(
+            &repo,
+            head,
+            &[
+                (".gitattributes", "*.list merge=git2-rs-sorted\n"),
+                ("a.list", "b\n"),
+                ("conflict.list", "b\n"),
+                ("text.list", "b\n"),
+                ("z.txt", "b\n"),
+            ],
+        );
+        let files = ["a.list", "conflict.list", "text.list", "z.txt"];
+        let ours = files.map(|f| (f, "a\n"));
+        let theirs = files.map(|f| (f, "c\n"));
+        let ours = repo.find_commit(commit_files(&repo, base, &ours)).unwrap();
+        let theirs = repo
+            .find_commit(commit_files(&repo, base, &theirs))
+            .unwrap();
+        let base = repo.find_commit(base).unwrap();
+
+        let index = repo
+            .merge_trees(
+                &base.tree().unwrap(),
+                &ours.tree().unwrap(),
+                &theirs.tree().unwrap(),
+                None,
+            )
+            .unwrap();
+        let merged = index.get_path(Path::new("a.list"), 0).unwrap();
+        assert_eq!(repo.find_blob(merged.id).unwrap().content(), b"a\nc\n");
+        assert_eq!(merged.mode, 0o100644);
+        // `a.list` is merged, the rest conflict either by request of the
+        // driver or because the text driver can't merge them.
+        let conflicts = index
+            .conflicts()
+            .unwrap()
+            .map(|c| c.unwrap().our.unwrap().path)
+            .collect::<Vec<_>>();
+        assert_eq!(conflicts, [&b"conflict.list"[..], b"text.list", b"z.txt"]);
+
+        let mut opts = MergeOptions::new();
+        opts.default_driver("git2-rs-sorted");
+        let index = repo.merge_commits(&ours, &theirs, Some(&opts)).unwrap();
+        let merged = index.get_path(Path::new("z.txt"), 0).unwrap();
+        assert_eq!(repo.find_blob(merged.id).unwrap().content(), b"a\nc\n");
+
+        let index = repo.cherrypick_commit(&theirs, &ours, 0, None).unwrap();
+        let merged = index.get_path(Path::new("a.list"), 0).unwrap();
+        assert_eq!(repo.find_blob(merged.id).unwrap().content(), b"a\nc\n");
+
+        super::unregister("git2-rs-sorted").unwrap();
+        let err = super::unregister("git2-rs-sorted").unwrap_err();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+    }
+}
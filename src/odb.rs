@@ -7,10 +7,12 @@ use std::ffi::CString;
 
 use libc::{c_char, c_int, c_uint, c_void, size_t};
 
+use crate::odb_backend::RawOdbBackend;
 use crate::panic;
 use crate::util::Binding;
 use crate::{
-    raw, Error, IndexerProgress, Mempack, Object, ObjectType, OdbLookupFlags, Oid, Progress,
+    raw, Error, IndexerProgress, Mempack, Object, ObjectType, OdbBackend, OdbLookupFlags, Oid,
+    Progress,
 };
 
 /// A structure to represent a git object database
@@ -269,6 +271,26 @@ impl<'repo> Odb<'repo> {
             Ok(Mempack::from_raw(mempack))
         }
     }
+
+    /// Add a custom backend to this object database with the given priority.
+    ///
+    /// Higher values give the backend higher precedence, as with
+    /// [`Odb::add_new_mempack_backend`]. The object database takes ownership
+    /// of the backend and drops it when the database itself is freed.
+    pub fn add_backend<B>(&self, backend: B, priority: i32) -> Result<(), Error>
+    where
+        B: OdbBackend,
+    {
+        unsafe {
+            let backend = RawOdbBackend::boxed(backend)?;
+            let rc = raw::git_odb_add_backend(self.raw, backend, priority as c_int);
+            if rc < 0 {
+                (*backend).free.unwrap()(backend);
+                return Err(Error::last_error(rc));
+            }
+        }
+        Ok(())
+    }
 }
 
 /// An object from the Object Database.
@@ -0,0 +1,525 @@
+use std::mem;
+use std::ptr;
+use std::slice;
+
+use libc::{c_int, c_void, size_t};
+
+use crate::panic;
+use crate::util::Binding;
+use crate::{raw, Error, ErrorClass, ErrorCode, ObjectType, Oid};
+
+/// A custom object database backend.
+///
+/// Backends are attached to an object database with
+/// [`Odb::add_backend`](crate::Odb::add_backend), after which libgit2 consults
+/// them, in order of priority, whenever objects are looked up or written. The
+/// backend is owned by the object database and is dropped when the database is
+/// freed.
+///
+/// Lookups that fail with an error whose code is [`ErrorCode::NotFound`] let
+/// libgit2 continue on to the next backend, any other error is reported to
+/// the caller. Panics in any of these methods are caught and re-raised once
+/// control returns to Rust.
+pub trait OdbBackend: Send + Sync + 'static {
+    /// Read the object with the given id, returning its type and contents.
+    fn read(&self, oid: Oid) -> Result<(ObjectType, Vec<u8>), Error>;
+
+    /// Find and read a unique object whose id starts with the first `len`
+    /// hex characters of `short_oid`, returning its full id, type and
+    /// contents.
+    ///
+    /// The default implementation scans the backend with `foreach` and then
+    /// reads the single matching object.
+    fn read_prefix(&self, short_oid: Oid, len: usize) -> Result<(Oid, ObjectType, Vec<u8>), Error> {
+        let prefix = &short_oid.to_string()[..len];
+        let mut found = None;
+        let mut ambiguous = false;
+        self.foreach(&mut |oid| {
+            if !oid.to_string().starts_with(prefix) {
+                return true;
+            }
+            if found.is_some() && found != Some(*oid) {
+                ambiguous = true;
+                return false;
+            }
+            found = Some(*oid);
+            true
+        })?;
+        if ambiguous {
+            return Err(Error::new(
+                ErrorCode::Ambiguous,
+                ErrorClass::Odb,
+                "found multiple objects with the given prefix",
+            ));
+        }
+        match found {
+            Some(oid) => {
+                let (kind, data) = self.read(oid)?;
+                Ok((oid, kind, data))
+            }
+            None => Err(Error::new(
+                ErrorCode::NotFound,
+                ErrorClass::Odb,
+                "no object found with the given prefix",
+            )),
+        }
+    }
+
+    /// Read the size and type of the object with the given id.
+    ///
+    /// The default implementation reads the whole object.
+    fn read_header(&self, oid: Oid) -> Result<(usize, ObjectType), Error> {
+        let (kind, data) = self.read(oid)?;
+        Ok((data.len(), kind))
+    }
+
+    /// Write an object into the backend. The id of the object has already
+    /// been calculated by libgit2 and is passed in.
+    fn write(&self, oid: Oid, kind: ObjectType, data: &[u8]) -> Result<(), Error>;
+
+    /// Checks if the backend contains the object with the given id.
+    fn exists(&self, oid: Oid) -> bool;
+
+    /// Iterate over the ids of all objects in the backend.
+    ///
+    /// Iteration must stop as soon as `callback` returns `false`.
+    fn foreach(&self, callback: &mut dyn FnMut(&Oid) -> bool) -> Result<(), Error>;
+
+    /// Refresh the backend, picking up objects that were added to the
+    /// underlying storage by someone else.
+    ///
+    /// libgit2 calls this automatically when a lookup fails. The default
+    /// implementation does nothing.
+    fn refresh(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Create a stream to write a packfile into this backend.
+    ///
+    /// This is used when fetching into a repository. The default
+    /// implementation returns an error, in which case libgit2 tries the
+    /// remaining backends of the object database.
+    fn writepack(&self) -> Result<Box<dyn OdbWritepack>, Error> {
+        Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Odb,
+            "writing packfiles is not supported by this backend",
+        ))
+    }
+}
+
+/// A stream receiving a packfile for an [`OdbBackend`].
+pub trait OdbWritepack: Send + 'static {
+    /// Append a chunk of packfile data to the stream.
+    fn append(&mut self, data: &[u8]) -> Result<(), Error>;
+
+    /// Finish writing the packfile, making its objects available in the
+    /// backend.
+    fn commit(&mut self) -> Result<(), Error>;
+}
+
+/// Instance of a `git_odb_backend`, must use `#[repr(C)]` to ensure that the
+/// C fields come first.
+#[repr(C)]
+pub(crate) struct RawOdbBackend {
+    raw: raw::git_odb_backend,
+    obj: Box<dyn OdbBackend>,
+}
+
+/// Instance of a `git_odb_writepack`, must use `#[repr(C)]` to ensure that the
+/// C fields come first.
+#[repr(C)]
+struct RawOdbWritepack {
+    raw: raw::git_odb_writepack,
+    obj: Box<dyn OdbWritepack>,
+}
+
+impl RawOdbBackend {
+    // Boxes `backend` into a `git_odb_backend` whose `free` callback releases
+    // it again.
+    pub(crate) fn boxed<B: OdbBackend>(backend: B) -> Result<*mut raw::git_odb_backend, Error> {
+        unsafe {
+            let mut raw = Box::new(RawOdbBackend {
+                raw: mem::zeroed(),
+                obj: Box::new(backend),
+            });
+            try_call!(raw::git_odb_init_backend(
+                &mut raw.raw,
+                raw::GIT_ODB_BACKEND_VERSION
+            ));
+            raw.raw.read = Some(backend_read);
+            raw.raw.read_prefix = Some(backend_read_prefix);
+            raw.raw.read_header = Some(backend_read_header);
+            raw.raw.write = Some(backend_write);
+            raw.raw.exists = Some(backend_exists);
+            raw.raw.refresh = Some(backend_refresh);
+            raw.raw.foreach = Some(backend_foreach);
+            raw.raw.writepack = Some(backend_writepack);
+            raw.raw.free = Some(backend_free);
+            Ok(Box::into_raw(raw) as *mut raw::git_odb_backend)
+        }
+    }
+}
+
+// Hands `data` to libgit2 in a buffer allocated with
+// `git_odb_backend_data_alloc`, which libgit2 frees once it is done with it.
+unsafe fn alloc_data(
+    backend: *mut raw::git_odb_backend,
+    data: &[u8],
+    data_p: *mut *mut c_void,
+    len_p: *mut size_t,
+) -> c_int {
+    let buf = raw::git_odb_backend_data_alloc(backend, data.len());
+    if buf.is_null() {
+        return -1;
+    }
+    ptr::copy_nonoverlapping(data.as_ptr(), buf as *mut u8, data.len());
+    *data_p = buf;
+    *len_p = data.len();
+    0
+}
+
+extern "C" fn backend_read(
+    data_p: *mut *mut c_void,
+    len_p: *mut size_t,
+    type_p: *mut raw::git_object_t,
+    backend: *mut raw::git_odb_backend,
+    oid: *const raw::git_oid,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        match b.obj.read(Binding::from_raw(oid)) {
+            Ok((kind, data)) => {
+                *type_p = kind.raw();
+                alloc_data(backend, &data, data_p, len_p)
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_read_prefix(
+    oid_p: *mut raw::git_oid,
+    data_p: *mut *mut c_void,
+    len_p: *mut size_t,
+    type_p: *mut raw::git_object_t,
+    backend: *mut raw::git_odb_backend,
+    short_oid: *const raw::git_oid,
+    len: size_t,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        match b.obj.read_prefix(Binding::from_raw(short_oid), len) {
+            Ok((oid, kind, data)) => {
+                *oid_p = *oid.raw();
+                *type_p = kind.raw();
+                alloc_data(backend, &data, data_p, len_p)
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_read_header(
+    len_p: *mut size_t,
+    type_p: *mut raw::git_object_t,
+    backend: *mut raw::git_odb_backend,
+    oid: *const raw::git_oid,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        match b.obj.read_header(Binding::from_raw(oid)) {
+            Ok((len, kind)) => {
+                *len_p = len;
+                *type_p = kind.raw();
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_write(
+    backend: *mut raw::git_odb_backend,
+    oid: *const raw::git_oid,
+    data: *const c_void,
+    len: size_t,
+    kind: raw::git_object_t,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        let kind = match ObjectType::from_raw(kind) {
+            Some(kind) => kind,
+            None => {
+                let msg = format!("invalid object type {}", kind);
+                return Error::new(ErrorCode::GenericError, ErrorClass::Odb, msg)
+                    .raw_set_git_error();
+            }
+        };
+        let data = if len == 0 {
+            &[][..]
+        } else {
+            slice::from_raw_parts(data as *const u8, len)
+        };
+        match b.obj.write(Binding::from_raw(oid), kind, data) {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_exists(
+    backend: *mut raw::git_odb_backend,
+    oid: *const raw::git_oid,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        b.obj.exists(Binding::from_raw(oid)) as c_int
+    })
+    .unwrap_or(0)
+}
+
+extern "C" fn backend_refresh(backend: *mut raw::git_odb_backend) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        match b.obj.refresh() {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_foreach(
+    backend: *mut raw::git_odb_backend,
+    cb: raw::git_odb_foreach_cb,
+    payload: *mut c_void,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        let cb = match cb {
+            Some(cb) => cb,
+            None => return -1,
+        };
+        // A non-zero return from the callback stops the iteration and must be
+        // handed back to the caller unchanged.
+        let mut stopped = 0;
+        let res = b.obj.foreach(&mut |oid| {
+            stopped = cb(oid.raw(), payload);
+            stopped == 0
+        });
+        match res {
+            Ok(()) => stopped,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_writepack(
+    out: *mut *mut raw::git_odb_writepack,
+    backend: *mut raw::git_odb_backend,
+    _odb: *mut raw::git_odb,
+    _progress_cb: raw::git_indexer_progress_cb,
+    _progress_payload: *mut c_void,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawOdbBackend);
+        match b.obj.writepack() {
+            Ok(obj) => {
+                let writepack = Box::new(RawOdbWritepack {
+                    raw: raw::git_odb_writepack {
+                        backend,
+                        append: Some(writepack_append),
+                        commit: Some(writepack_commit),
+                        free: Some(writepack_free),
+                    },
+                    obj,
+                });
+                *out = Box::into_raw(writepack) as *mut raw::git_odb_writepack;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+// callback used by libgit2 when the object database owning the backend is
+// freed.
+extern "C" fn backend_free(backend: *mut raw::git_odb_backend) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(backend as *mut RawOdbBackend));
+    });
+}
+
+extern "C" fn writepack_append(
+    writepack: *mut raw::git_odb_writepack,
+    data: *const c_void,
+    len: size_t,
+    _stats: *mut raw::git_indexer_progress,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let w = &mut *(writepack as *mut RawOdbWritepack);
+        let data = slice::from_raw_parts(data as *const u8, len);
+        match w.obj.append(data) {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+unsafe extern "C" fn writepack_commit(
+    writepack: *mut raw::git_odb_writepack,
+    _stats: *mut raw::git_indexer_progress,
+) -> c_int {
+    panic::wrap(|| {
+        let w = &mut *(writepack as *mut RawOdbWritepack);
+        match w.obj.commit() {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+unsafe extern "C" fn writepack_free(writepack: *mut raw::git_odb_writepack) {
+    let _ = panic::wrap(|| {
+        drop(Box::from_raw(writepack as *mut RawOdbWritepack));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::ptr;
+    use std::sync::{Arc, Mutex};
+
+    use super::OdbBackend;
+    use crate::util::Binding;
+    use crate::{raw, Error, ErrorClass, ErrorCode, ObjectType, Odb, Oid, Repository};
+
+    type Objects = HashMap<Oid, (ObjectType, Vec<u8>)>;
+
+    #[derive(Clone, Default)]
+    struct MemoryBackend {
+        objects: Arc<Mutex<Objects>>,
+    }
+
+    impl OdbBackend for MemoryBackend {
+        fn read(&self, oid: Oid) -> Result<(ObjectType, Vec<u8>), Error> {
+            match self.objects.lock().unwrap().get(&oid) {
+                Some(obj) => Ok(obj.clone()),
+                None => Err(Error::new(ErrorCode::NotFound, ErrorClass::Odb, "missing")),
+            }
+        }
+
+        fn write(&self, oid: Oid, kind: ObjectType, data: &[u8]) -> Result<(), Error> {
+            let mut objects = self.objects.lock().unwrap();
+            objects.insert(oid, (kind, data.to_vec()));
+            Ok(())
+        }
+
+        fn exists(&self, oid: Oid) -> bool {
+            self.objects.lock().unwrap().contains_key(&oid)
+        }
+
+        fn foreach(&self, callback: &mut dyn FnMut(&Oid) -> bool) -> Result<(), Error> {
+            let ids: Vec<Oid> = self.objects.lock().unwrap().keys().cloned().collect();
+            for id in ids.iter() {
+                if !callback(id) {
+                    break;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn smoke() {
+        let backend = MemoryBackend::default();
+        let odb = Odb::new().unwrap();
+        odb.add_backend(backend.clone(), 1).unwrap();
+
+        let id = odb.write(ObjectType::Blob, b"hello").unwrap();
+        assert!(backend.objects.lock().unwrap().contains_key(&id));
+        assert!(odb.exists(id));
+
+        let obj = odb.read(id).unwrap();
+        assert_eq!(obj.kind(), ObjectType::Blob);
+        assert_eq!(obj.data(), b"hello");
+        assert_eq!(odb.read_header(id).unwrap(), (5, ObjectType::Blob));
+
+        let mut ids = Vec::new();
+        odb.foreach(|id| {
+            ids.push(*id);
+            true
+        })
+        .unwrap();
+        assert_eq!(ids, vec![id]);
+
+        let missing = Oid::from_str("0123456789012345678901234567890123456789").unwrap();
+        assert!(!odb.exists(missing));
+        assert_eq!(odb.read(missing).err().unwrap().code(), ErrorCode::NotFound);
+
+        // Objects of an unknown type are refused with an error.
+        unsafe {
+            let raw = super::RawOdbBackend::boxed(backend).unwrap();
+            let write = (*raw).write.unwrap();
+            let ret = write(raw, id.raw(), ptr::null(), 0, raw::GIT_OBJECT_INVALID);
+            assert!(ret < 0);
+            assert_eq!(Error::last_error(ret).message(), "invalid object type -1");
+            ((*raw).free.unwrap())(raw);
+        }
+    }
+
+    #[test]
+    fn repository() {
+        let backend = MemoryBackend::default();
+        let odb = Odb::new().unwrap();
+        odb.add_backend(backend.clone(), 1).unwrap();
+        let repo = Repository::from_odb(odb).unwrap();
+
+        let blob = repo.blob(b"foo").unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("foo", blob, 0o100644).unwrap();
+        let tree = builder.write().unwrap();
+        assert_eq!(backend.objects.lock().unwrap().len(), 2);
+
+        let short = &tree.to_string()[..8];
+        let obj = repo.revparse_single(short).unwrap();
+        assert_eq!(obj.id(), tree);
+        let tree = obj.peel_to_tree().unwrap();
+        assert_eq!(tree.get_name("foo").unwrap().id(), blob);
+    }
+
+    #[test]
+    #[should_panic]
+    fn panic_propagates() {
+        struct PanickingBackend;
+
+        impl OdbBackend for PanickingBackend {
+            fn read(&self, _oid: Oid) -> Result<(ObjectType, Vec<u8>), Error> {
+                panic!("read")
+            }
+            fn write(&self, _: Oid, _: ObjectType, _: &[u8]) -> Result<(), Error> {
+                panic!("write")
+            }
+            fn exists(&self, _oid: Oid) -> bool {
+                false
+            }
+            fn foreach(&self, _: &mut dyn FnMut(&Oid) -> bool) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let odb = Odb::new().unwrap();
+        odb.add_backend(PanickingBackend, 1).unwrap();
+        let _ = odb.write(ObjectType::Blob, b"boom");
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::{AutotagOption, Error, ErrorClass, ErrorCode, FetchOptions, ObjectType, Oid};
+use crate::{OdbBackend, Repository};
+
+/// A filter limiting the objects sent by the remote for a partial clone, as
+/// given to [`FetchOptions::filter`].
+///
+/// The objects left out are fetched lazily from the remote when they are
+/// first read, see [`Repository::enable_lazy_fetch`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ObjectFilter {
+    /// Leave out every blob, `blob:none`.
+    BlobNone,
+    /// Leave out the blobs of at least this many bytes, `blob:limit=<n>`.
+    BlobLimit(u64),
+    /// Leave out the trees and blobs at this depth or deeper, where the root
+    /// tree of a commit is at depth 0, `tree:<depth>`. `TreeDepth(0)` only
+    /// sends commits and tags.
+    TreeDepth(u64),
+}
+
+impl ObjectFilter {
+    /// Whether a blob of `size` bytes found at `depth` is sent.
+    pub(crate) fn includes_blob(&self, size: usize, depth: u64) -> bool {
+        match *self {
+            ObjectFilter::BlobNone => false,
+            ObjectFilter::BlobLimit(limit) => (size as u64) < limit,
+            ObjectFilter::TreeDepth(max) => depth < max,
+        }
+    }
+
+    /// Whether a tree found at `depth` is sent.
+    pub(crate) fn includes_tree(&self, depth: u64) -> bool {
+        match *self {
+            ObjectFilter::TreeDepth(max) => depth < max,
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for ObjectFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            ObjectFilter::BlobNone => f.write_str("blob:none"),
+            ObjectFilter::BlobLimit(limit) => write!(f, "blob:limit={}", limit),
+            ObjectFilter::TreeDepth(depth) => write!(f, "tree:{}", depth),
+        }
+    }
+}
+
+impl FromStr for ObjectFilter {
+    type Err = Error;
+
+    /// Parse a filter specification as given to `git clone --filter`. Blob
+    /// limits may have a `k`, `m` or `g` suffix.
+    fn from_str(s: &str) -> Result<ObjectFilter, Error> {
+        let invalid = || {
+            Error::new(
+                ErrorCode::Invalid,
+                ErrorClass::Invalid,
+                format!("invalid object filter '{}'", s),
+            )
+        };
+        if s == "blob:none" {
+            Ok(ObjectFilter::BlobNone)
+        } else if let Some(limit) = s.strip_prefix("blob:limit=") {
+            let (digits, unit) = match limit.char_indices().last() {
+                Some((i, 'k')) | Some((i, 'K')) => (&limit[..i], 1 << 10),
+                Some((i, 'm')) | Some((i, 'M')) => (&limit[..i], 1 << 20),
+                Some((i, 'g')) | Some((i, 'G')) => (&limit[..i], 1 << 30),
+                _ => (limit, 1),
+            };
+            let limit = digits.parse::<u64>().map_err(|_| invalid())?;
+            Ok(ObjectFilter::BlobLimit(limit.saturating_mul(unit)))
+        } else if let Some(depth) = s.strip_prefix("tree:") {
+            Ok(ObjectFilter::TreeDepth(
+                depth.parse().map_err(|_| invalid())?,
+            ))
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+/// Mark `remote` of `repo` as the promisor remote of a partial clone
+/// filtered with `filter`, the way git does.
+pub(crate) fn configure(
+    repo: &Repository,
+    remote: &str,
+    filter: &ObjectFilter,
+) -> Result<(), Error> {
+    let mut config = repo.config()?;
+    config.set_bool(&format!("remote.{}.promisor", remote), true)?;
+    config.set_str(
+        &format!("remote.{}.partialclonefilter", remote),
+        &filter.to_string(),
+    )?;
+    // git honors this extension in repositories of format version 0, which
+    // libgit2 doesn't check extensions of, so the repository stays readable
+    // by both.
+    config.set_str("extensions.partialclone", remote)
+}
+
+/// The filter of later fetches from `remote`, if it's a promisor remote.
+pub(crate) fn remote_filter(repo: &Repository, remote: &str) -> Result<Option<String>, Error> {
+    let config = repo.config()?;
+    if !config
+        .get_bool(&format!("remote.{}.promisor", remote))
+        .unwrap_or(false)
+    {
+        return Ok(None);
+    }
+    Ok(config
+        .get_string(&format!("remote.{}.partialclonefilter", remote))
+        .ok())
+}
+
+/// Find the promisor remote of a partial clone.
+pub(crate) fn promisor_remote(repo: &Repository) -> Result<Option<String>, Error> {
+    let config = repo.config()?;
+    if let Ok(remote) = config.get_string("extensions.partialclone") {
+        return Ok(Some(remote));
+    }
+    for remote in repo.remotes()?.iter().flatten() {
+        if config
+            .get_bool(&format!("remote.{}.promisor", remote))
+            .unwrap_or(false)
+        {
+            return Ok(Some(remote.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+/// Object database backend fetching the objects missing from a partial
+/// clone from its promisor remote.
+///
+/// It comes after the loose and packed objects of the repository, so it's
+/// only asked for the objects they lack.
+pub(crate) struct LazyFetch<F> {
+    path: PathBuf,
+    remote: String,
+    options: F,
+    /// The objects the remote doesn't have, which aren't asked for again.
+    missing: Mutex<HashSet<Oid>>,
+}
+
+impl<F> LazyFetch<F>
+where
+    F: Fn() -> FetchOptions<'static> + Send + Sync + 'static,
+{
+    pub(crate) fn new(repo: &Repository, remote: &str, options: F) -> LazyFetch<F> {
+        LazyFetch {
+            path: repo.path().to_path_buf(),
+            remote: remote.to_string(),
+            options,
+            missing: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Fetch `oid` from the promisor remote, returning a handle to the
+    /// repository which sees it.
+    fn fetch(&self, oid: Oid) -> Result<Repository, Error> {
+        let missing = || {
+            Error::new(
+                ErrorCode::NotFound,
+                ErrorClass::Odb,
+                format!(
+                    "object {} is missing from promisor remote '{}'",
+                    oid, self.remote
+                ),
+            )
+        };
+        if self.missing.lock().unwrap().contains(&oid) {
+            return Err(missing());
+        }
+        // A separate handle has an object database without this backend,
+        // and sees the packs added since the one of the repository was last
+        // refreshed, such as the one being fetched.
+        let repo = Repository::open(&self.path)?;
+        if repo.odb()?.exists(oid) {
+            return Ok(repo);
+        }
+        let fetched = repo.find_remote(&self.remote).and_then(|mut remote| {
+            let mut options = (self.options)();
+            options
+                .download_tags(AutotagOption::None)
+                .update_fetchhead(false);
+            remote.fetch_unfiltered(&[oid.to_string()], Some(&mut options), None)
+        });
+        match fetched {
+            Ok(_) if repo.odb()?.exists(oid) => Ok(repo),
+            Ok(_) => {
+                self.missing.lock().unwrap().insert(oid);
+                Err(missing())
+            }
+            Err(e) => {
+                // Other failures, such as network errors, may not last.
+                if e.code() == ErrorCode::NotFound {
+                    self.missing.lock().unwrap().insert(oid);
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<F> OdbBackend for LazyFetch<F>
+where
+    F: Fn() -> FetchOptions<'static> + Send + Sync + 'static,
+{
+    fn read(&self, oid: Oid) -> Result<(ObjectType, Vec<u8>), Error> {
+        let repo = self.fetch(oid)?;
+        let odb = repo.odb()?;
+        let object = odb.read(oid)?;
+        Ok((object.kind(), object.data().to_vec()))
+    }
+
+    fn write(&self, _oid: Oid, _kind: ObjectType, _data: &[u8]) -> Result<(), Error> {
+        Err(Error::new(
+            ErrorCode::GenericError,
+            ErrorClass::Odb,
+            "objects can't be written to a promisor remote",
+        ))
+    }
+
+    fn exists(&self, _oid: Oid) -> bool {
+        // Objects are only fetched when read: libgit2 checks which objects
+        // exist in bulk, such as the tips of the remote while fetching,
+        // which must not fetch them one by one.
+        false
+    }
+
+    fn foreach(&self, _callback: &mut dyn FnMut(&Oid) -> bool) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ObjectFilter;
+    use crate::build::RepoBuilder;
+    use crate::{FetchOptions, ObjectType, Oid, Repository};
+    use std::fs;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    fn commit_files(repo: &Repository, files: &[(&str, &[u8])]) -> Vec<Oid> {
+        let root = repo.workdir().unwrap();
+        let mut index = repo.index().unwrap();
+        for (path, contents) in files {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), contents).unwrap();
+            index.add_path(Path::new(path)).unwrap();
+        }
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = repo.signature().unwrap();
+        let parent = repo.head().unwrap().peel_to_commit().unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "files", &tree, &[&parent])
+            .unwrap();
+        files
+            .iter()
+            .map(|(_, contents)| repo.blob(contents).unwrap())
+            .collect()
+    }
+
+    fn clone(into: &Path, filter: ObjectFilter, bare: bool) -> Repository {
+        let mut options = FetchOptions::new();
+        options.filter(filter);
+        RepoBuilder::new()
+            .bare(bare)
+            .fetch_options(options)
+            .clone("git2-partial://upstream", into)
+            .unwrap()
+    }
+
+    #[test]
+    fn partial_clone() {
+        let (td, upstream) = crate::test::repo_init();
+        let blobs = commit_files(
+            &upstream,
+            &[
+                ("small", b"small"),
+                ("big", &[b'x'; 2000]),
+                ("dir/nested", b"nested"),
+            ],
+        );
+        unsafe {
+            let upstream = Repository::open(td.path()).unwrap();
+            crate::transport::register("git2-partial", crate::transport::in_process(upstream))
+                .unwrap();
+        }
+
+        let td2 = TempDir::new().unwrap();
+        let repo = clone(td2.path(), ObjectFilter::BlobNone, true);
+        let config = repo.config().unwrap();
+        assert!(config.get_bool("remote.origin.promisor").unwrap());
+        assert_eq!(
+            config
+                .get_string("remote.origin.partialclonefilter")
+                .unwrap(),
+            "blob:none"
+        );
+        // The clone itself fetches missing objects, other handles only once
+        // asked to.
+        let other = Repository::open(td2.path()).unwrap();
+        assert!(blobs.iter().all(|id| !other.odb().unwrap().exists(*id)));
+        other.enable_lazy_fetch(FetchOptions::new).unwrap();
+        assert_eq!(other.find_blob(blobs[0]).unwrap().content(), b"small");
+        assert!(repo.find_blob(blobs[2]).is_ok());
+        let fresh = Repository::open(td2.path()).unwrap();
+        assert!(fresh.odb().unwrap().exists(blobs[0]));
+        assert!(!fresh.odb().unwrap().exists(blobs[1]));
+
+        // Checking objects exist doesn't fetch them. Failed fetches are
+        // tried again, unlike those which didn't bring the object.
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let counter = fetches.clone();
+        let other = Repository::open(td2.path()).unwrap();
+        other
+            .enable_lazy_fetch(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+                FetchOptions::new()
+            })
+            .unwrap();
+        assert!(!other.odb().unwrap().exists(blobs[1]));
+        assert_eq!(fetches.load(Ordering::SeqCst), 0);
+        assert!(other.find_blob(blobs[1]).is_ok());
+        let unknown = Oid::hash_object(ObjectType::Blob, b"unknown").unwrap();
+        assert!(other.find_blob(unknown).is_err());
+        assert!(other.find_blob(unknown).is_err());
+        assert_eq!(fetches.load(Ordering::SeqCst), 3);
+        assert!(Repository::open(td.path())
+            .unwrap()
+            .enable_lazy_fetch(FetchOptions::new)
+            .is_err());
+
+        // Later fetches use the filter of the clone.
+        let (new, _) = crate::test::commit(&upstream);
+        let new_blob = commit_files(&upstream, &[("later", b"later")])[0];
+        let mut origin = fresh.find_remote("origin").unwrap();
+        origin.fetch(&[] as &[&str], None, None).unwrap();
+        assert!(fresh.odb().unwrap().exists(new));
+        assert!(!fresh.odb().unwrap().exists(new_blob));
+
+        // Checkouts fetch the blobs they need.
+        let td3 = TempDir::new().unwrap();
+        let repo = clone(td3.path(), ObjectFilter::BlobLimit(1000), false);
+        assert_eq!(fs::read(td3.path().join("big")).unwrap(), [b'x'; 2000]);
+        assert_eq!(fs::read(td3.path().join("dir/nested")).unwrap(), b"nested");
+        let fresh = Repository::open(td3.path()).unwrap();
+        assert!(fresh.odb().unwrap().exists(new_blob));
+        drop(repo);
+
+        // The built-in transports can't send filters.
+        let td5 = TempDir::new().unwrap();
+        let mut options = FetchOptions::new();
+        options.filter(ObjectFilter::BlobNone);
+        let err = RepoBuilder::new()
+            .fetch_options(options)
+            .clone(td.path().to_str().unwrap(), td5.path())
+            .err()
+            .unwrap();
+        assert!(err.message().contains("object filters"));
+
+        let td4 = TempDir::new().unwrap();
+        let repo = clone(td4.path(), ObjectFilter::TreeDepth(1), true);
+        let commit = repo.head().unwrap().peel_to_commit().unwrap();
+        let fresh = Repository::open(td4.path()).unwrap();
+        let odb = fresh.odb().unwrap();
+        assert!(odb.exists(commit.tree_id()));
+        let tree = commit.tree().unwrap();
+        let dir = tree.get_name("dir").unwrap().id();
+        assert!(!odb.exists(dir));
+        assert!(!odb.exists(blobs[0]));
+    }
+
+    #[test]
+    fn parse_filters() {
+        for (spec, filter) in [
+            ("blob:none", ObjectFilter::BlobNone),
+            ("blob:limit=1024", ObjectFilter::BlobLimit(1024)),
+            ("tree:0", ObjectFilter::TreeDepth(0)),
+            ("tree:3", ObjectFilter::TreeDepth(3)),
+        ] {
+            assert_eq!(spec.parse::<ObjectFilter>().unwrap(), filter);
+            assert_eq!(filter.to_string(), spec);
+        }
+        assert_eq!(
+            "blob:limit=2k".parse::<ObjectFilter>().unwrap(),
+            ObjectFilter::BlobLimit(2048)
+        );
+        for spec in [
+            "",
+            "blob:limit=",
+            "blob:limit=1x",
+            "tree:-1",
+            "sparse:oid=x",
+        ] {
+            assert!(spec.parse::<ObjectFilter>().is_err());
+        }
+    }
+}
@@ -53,3 +53,63 @@ impl PushUpdate<'_> {
         unsafe { Binding::from_raw(&(*self.raw).dst as *const _) }
     }
 }
+
+/// An update a push would perform on the remote, as planned by
+/// [`Remote::push_dry_run`](crate::Remote::push_dry_run).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PushPlan {
+    pub(crate) src: Option<String>,
+    pub(crate) dst: String,
+    pub(crate) old_id: Oid,
+    pub(crate) new_id: Oid,
+    pub(crate) kind: PushPlanKind,
+}
+
+/// The kinds of updates a push can perform on a remote reference.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PushPlanKind {
+    /// The reference doesn't exist on the remote and would be created.
+    Create,
+    /// The reference would be deleted from the remote.
+    Delete,
+    /// The remote already has the reference pointing to the new object.
+    UpToDate,
+    /// The new object is a descendant of the old one.
+    FastForward,
+    /// The update isn't a fast-forward, but the refspec is forced or the
+    /// reference is leased.
+    Forced,
+    /// The update would be refused, as it isn't a fast-forward, the old
+    /// object isn't known locally, or the lease of the reference is stale.
+    Rejected,
+}
+
+impl PushPlan {
+    /// Returns the local reference or revision pushed, or `None` if the
+    /// remote reference would be deleted.
+    pub fn src(&self) -> Option<&str> {
+        self.src.as_deref()
+    }
+
+    /// Returns the name of the reference to update on the remote.
+    pub fn dst(&self) -> &str {
+        &self.dst
+    }
+
+    /// Returns the current target of the reference on the remote, or zero if
+    /// it doesn't exist.
+    pub fn old_id(&self) -> Oid {
+        self.old_id
+    }
+
+    /// Returns the new target of the reference, or zero if it would be
+    /// deleted.
+    pub fn new_id(&self) -> Oid {
+        self.new_id
+    }
+
+    /// Returns the kind of update.
+    pub fn kind(&self) -> PushPlanKind {
+        self.kind
+    }
+}
@@ -0,0 +1,1127 @@
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{CStr, CString};
+use std::marker;
+use std::mem;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use libc::{c_char, c_int, c_void};
+
+use crate::util::Binding;
+use crate::ReferenceType;
+use crate::{panic, raw, signature, Error, ErrorClass, ErrorCode, Oid, Repository, Signature};
+
+/// A structure to represent a git reference database.
+///
+/// A reference database is where a repository stores its references. By
+/// default it is backed by the `refs` directory and `packed-refs` file inside
+/// of the repository, but a custom [`RefdbBackend`] can be used instead.
+pub struct Refdb<'repo> {
+    raw: *mut raw::git_refdb,
+    _marker: marker::PhantomData<&'repo Repository>,
+}
+
+impl<'repo> Binding for Refdb<'repo> {
+    type Raw = *mut raw::git_refdb;
+
+    unsafe fn from_raw(raw: *mut raw::git_refdb) -> Refdb<'repo> {
+        Refdb {
+            raw,
+            _marker: marker::PhantomData,
+        }
+    }
+    fn raw(&self) -> *mut raw::git_refdb {
+        self.raw
+    }
+}
+
+impl<'repo> Drop for Refdb<'repo> {
+    fn drop(&mut self) {
+        unsafe { raw::git_refdb_free(self.raw) }
+    }
+}
+
+impl<'repo> Refdb<'repo> {
+    /// Create a new reference database for the given repository without any
+    /// backend.
+    ///
+    /// A backend must be set with [`Refdb::set_backend`] before the database
+    /// can be used.
+    pub fn new(repo: &'repo Repository) -> Result<Refdb<'repo>, Error> {
+        let mut out = ptr::null_mut();
+        unsafe {
+            try_call!(raw::git_refdb_new(&mut out, repo.raw()));
+            Ok(Binding::from_raw(out))
+        }
+    }
+
+    /// Create a new reference database for the given repository, backed by
+    /// the default filesystem backend.
+    pub fn open(repo: &'repo Repository) -> Result<Refdb<'repo>, Error> {
+        let mut out = ptr::null_mut();
+        unsafe {
+            try_call!(raw::git_refdb_open(&mut out, repo.raw()));
+            Ok(Binding::from_raw(out))
+        }
+    }
+
+    /// Set a custom backend for this reference database, replacing the
+    /// previous one.
+    ///
+    /// The reference database takes ownership of the backend and drops it when
+    /// the database itself is freed.
+    pub fn set_backend<B>(&self, backend: B) -> Result<(), Error>
+    where
+        B: RefdbBackend,
+    {
+        unsafe {
+            let mut raw = Box::new(RawRefdbBackend {
+                raw: mem::zeroed(),
+                obj: Box::new(backend),
+                locks: Mutex::new(HashSet::new()),
+            });
+            try_call!(raw::git_refdb_init_backend(
+                &mut raw.raw,
+                raw::GIT_REFDB_BACKEND_VERSION
+            ));
+            raw.raw.exists = Some(backend_exists);
+            raw.raw.lookup = Some(backend_lookup);
+            raw.raw.iterator = Some(backend_iterator);
+            raw.raw.write = Some(backend_write);
+            raw.raw.rename = Some(backend_rename);
+            raw.raw.del = Some(backend_del);
+            raw.raw.compress = Some(backend_compress);
+            raw.raw.has_log = Some(backend_has_log);
+            raw.raw.ensure_log = Some(backend_ensure_log);
+            raw.raw.free = Some(backend_free);
+            raw.raw.reflog_read = Some(backend_reflog_read);
+            raw.raw.reflog_write = Some(backend_reflog_write);
+            raw.raw.reflog_rename = Some(backend_reflog_rename);
+            raw.raw.reflog_delete = Some(backend_reflog_delete);
+            raw.raw.lock = Some(backend_lock);
+            raw.raw.unlock = Some(backend_unlock);
+
+            let raw = Box::into_raw(raw);
+            let rc = raw::git_refdb_set_backend(self.raw, raw as *mut _);
+            if rc < 0 {
+                drop(Box::from_raw(raw));
+                return Err(Error::last_error(rc));
+            }
+            Ok(())
+        }
+    }
+
+    /// Suggests that the reference database compress or optimize its
+    /// references.
+    ///
+    /// This mechanism is implementation specific. For on-disk reference
+    /// databases, for example, this may pack all loose references.
+    pub fn compress(&self) -> Result<(), Error> {
+        unsafe {
+            try_call!(raw::git_refdb_compress(self.raw));
+        }
+        Ok(())
+    }
+}
+
+/// The value of a reference stored in a [`RefdbBackend`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefdbTarget {
+    /// A direct reference pointing at an object.
+    Direct(Oid),
+    /// A symbolic reference pointing at another reference.
+    Symbolic(String),
+}
+
+impl RefdbTarget {
+    /// Returns the kind of reference this target represents.
+    pub fn kind(&self) -> ReferenceType {
+        match self {
+            RefdbTarget::Direct(_) => ReferenceType::Direct,
+            RefdbTarget::Symbolic(_) => ReferenceType::Symbolic,
+        }
+    }
+}
+
+/// What a reference has to look like for an update passed to a
+/// [`RefdbBackend`] to go ahead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefdbPrecondition {
+    force: bool,
+    old: Option<RefdbTarget>,
+}
+
+impl RefdbPrecondition {
+    /// Creates a precondition.
+    ///
+    /// Unless `force` is set, the reference must not exist yet. If `old` is
+    /// given, the reference must currently have that value, where a direct
+    /// target of [`Oid::zero`] means that it must not exist.
+    pub fn new(force: bool, old: Option<RefdbTarget>) -> RefdbPrecondition {
+        RefdbPrecondition { force, old }
+    }
+
+    /// Whether an existing reference may be overwritten.
+    pub fn force(&self) -> bool {
+        self.force
+    }
+
+    /// The value the reference is expected to have, if any.
+    pub fn old(&self) -> Option<&RefdbTarget> {
+        self.old.as_ref()
+    }
+
+    /// Check this precondition against `current`, the value the reference
+    /// `name` has right now, in the same way as libgit2's filesystem backend
+    /// does.
+    pub fn check(&self, name: &str, current: Option<&RefdbTarget>) -> Result<(), Error> {
+        if !self.force && current.is_some() {
+            return Err(Error::new(
+                ErrorCode::Exists,
+                ErrorClass::Reference,
+                format!("a reference with the name '{}' already exists", name),
+            ));
+        }
+        let matches = match (current, &self.old) {
+            (_, None) => true,
+            (None, Some(RefdbTarget::Direct(id))) => id.is_zero(),
+            (Some(cur), Some(old)) => cur == old,
+            (None, Some(RefdbTarget::Symbolic(_))) => false,
+        };
+        if matches {
+            Ok(())
+        } else {
+            Err(Error::new(
+                ErrorCode::Modified,
+                ErrorClass::Reference,
+                format!("old reference value does not match for '{}'", name),
+            ))
+        }
+    }
+}
+
+/// An iterator over references returned by [`RefdbBackend::iter`].
+pub type RefdbIter = Box<dyn Iterator<Item = Result<(String, RefdbTarget), Error>>>;
+
+/// A custom reference database backend.
+///
+/// Backends are attached to a [`Refdb`] with [`Refdb::set_backend`], which in
+/// turn is attached to a repository with [`Repository::set_refdb`].
+///
+/// Lookups of references which don't exist must fail with an error whose code
+/// is [`ErrorCode::NotFound`]. Updates come with a [`RefdbPrecondition`],
+/// which backends must check and apply atomically, so that concurrent
+/// updates can't overwrite each other. Panics in any of these methods are
+/// caught and re-raised once control returns to Rust.
+///
+/// Transactions lock references within the [`Refdb`] the backend is
+/// attached to. While a reference is locked, other updates to it through that
+/// `Refdb` fail with [`ErrorCode::Locked`].
+///
+/// Custom reference databases have no reflogs. libgit2 reads them from a
+/// backend as a `git_reflog`, which it has no public way to create, so
+/// everything reading a reflog fails with [`ErrorCode::NotFound`]:
+/// [`Repository::reflog`], revisions such as `main@{1}` or `@{-1}`, and
+/// listing, applying, popping or dropping stashes. Saving a stash and other
+/// updates with a log message still work, the message being passed to
+/// `write`, where backends may record it for their own use. The reflog
+/// methods below only manage such records, and default to keeping none.
+pub trait RefdbBackend: Send + 'static {
+    /// Checks if a reference with the given name exists.
+    fn exists(&self, name: &str) -> Result<bool, Error>;
+
+    /// Look up the reference with the given name.
+    fn lookup(&self, name: &str) -> Result<RefdbTarget, Error>;
+
+    /// Iterate over all references, returning their names and values.
+    ///
+    /// If `glob` is given only references matching it are of interest. The
+    /// results are filtered against the glob again afterwards, so backends
+    /// are free to ignore it.
+    fn iter(&self, glob: Option<&str>) -> Result<RefdbIter, Error>;
+
+    /// Store a reference, creating or replacing it if it satisfies
+    /// `precondition`.
+    ///
+    /// If `who` and `message` are given the update should also be recorded in
+    /// the reflog of the reference, if the backend keeps one.
+    fn write(
+        &self,
+        name: &str,
+        target: &RefdbTarget,
+        precondition: &RefdbPrecondition,
+        who: Option<&Signature<'_>>,
+        message: Option<&str>,
+    ) -> Result<(), Error>;
+
+    /// Delete the reference with the given name if it has the value `old`.
+    fn delete(&self, name: &str, old: Option<&RefdbTarget>) -> Result<(), Error>;
+
+    /// Rename a reference, returning its value.
+    ///
+    /// The default implementation moves the reference and its reflog with
+    /// `lookup`, `write`, `delete` and `reflog_rename`. Backends which can do
+    /// this atomically should override it.
+    fn rename(
+        &self,
+        old_name: &str,
+        new_name: &str,
+        force: bool,
+        who: Option<&Signature<'_>>,
+        message: Option<&str>,
+    ) -> Result<RefdbTarget, Error> {
+        let target = self.lookup(old_name)?;
+        let precondition = RefdbPrecondition::new(force, None);
+        self.write(new_name, &target, &precondition, who, message)?;
+        self.delete(old_name, Some(&target))?;
+        self.reflog_rename(old_name, new_name)?;
+        Ok(target)
+    }
+
+    /// Suggests that the backend compress or optimize its references.
+    fn compress(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Checks if a reflog exists for the given reference.
+    fn has_log(&self, _name: &str) -> Result<bool, Error> {
+        Ok(false)
+    }
+
+    /// Make sure a reflog exists for the given reference, so that future
+    /// updates to it are logged.
+    fn ensure_log(&self, _name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Rename the reflog of a reference.
+    fn reflog_rename(&self, _old_name: &str, _new_name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Delete the reflog of the given reference.
+    fn reflog_delete(&self, _name: &str) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A reference database backend which keeps references in memory, used by
+/// [`Repository::new_in_memory`].
+#[derive(Clone, Default)]
+pub(crate) struct MemoryRefdb {
+    pub(crate) refs: Arc<Mutex<BTreeMap<String, RefdbTarget>>>,
+}
+
+impl RefdbBackend for MemoryRefdb {
+    fn exists(&self, name: &str) -> Result<bool, Error> {
+        Ok(self.refs.lock().unwrap().contains_key(name))
+    }
+
+    fn lookup(&self, name: &str) -> Result<RefdbTarget, Error> {
+        match self.refs.lock().unwrap().get(name) {
+            Some(target) => Ok(target.clone()),
+            None => Err(not_found(name)),
+        }
+    }
+
+    fn iter(&self, _glob: Option<&str>) -> Result<RefdbIter, Error> {
+        // like the filesystem backend, only references under `refs/` are
+        // iterated over, which leaves out `HEAD`
+        let refs = self.refs.lock().unwrap().clone();
+        Ok(Box::new(
+            refs.into_iter()
+                .filter(|(name, _)| name.starts_with("refs/"))
+                .map(Ok),
+        ))
+    }
+
+    fn write(
+        &self,
+        name: &str,
+        target: &RefdbTarget,
+        precondition: &RefdbPrecondition,
+        _who: Option<&Signature<'_>>,
+        _message: Option<&str>,
+    ) -> Result<(), Error> {
+        let mut refs = self.refs.lock().unwrap();
+        precondition.check(name, refs.get(name))?;
+        refs.insert(name.to_string(), target.clone());
+        Ok(())
+    }
+
+    fn delete(&self, name: &str, old: Option<&RefdbTarget>) -> Result<(), Error> {
+        let mut refs = self.refs.lock().unwrap();
+        match refs.get(name) {
+            Some(_) => {
+                RefdbPrecondition::new(true, old.cloned()).check(name, refs.get(name))?;
+                refs.remove(name);
+                Ok(())
+            }
+            None => Err(not_found(name)),
+        }
+    }
+}
+
+fn not_found(name: &str) -> Error {
+    Error::new(
+        ErrorCode::NotFound,
+        ErrorClass::Reference,
+        format!("reference '{}' not found", name),
+    )
+}
+
+/// Instance of a `git_refdb_backend`, must use `#[repr(C)]` to ensure that the
+/// C fields come first.
+#[repr(C)]
+struct RawRefdbBackend {
+    raw: raw::git_refdb_backend,
+    obj: Box<dyn RefdbBackend>,
+    // the references locked by transactions, held while updating any
+    // reference so that they can't be changed in between
+    locks: Mutex<HashSet<String>>,
+}
+
+/// Instance of a `git_reference_iterator`, must use `#[repr(C)]` to ensure
+/// that the C fields come first.
+#[repr(C)]
+struct RawRefdbIterator {
+    raw: raw::git_reference_iterator,
+    iter: RefdbIter,
+    glob: Option<String>,
+    // keeps the name handed out by `next_name` alive until the next call
+    current: Option<CString>,
+}
+
+fn str_arg<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        None
+    } else {
+        unsafe { CStr::from_ptr(s).to_str().ok() }
+    }
+}
+
+fn invalid_name() -> c_int {
+    let e = Error::new(
+        ErrorCode::Invalid,
+        ErrorClass::Reference,
+        "reference name is not valid utf-8",
+    );
+    unsafe { e.raw_set_git_error() }
+}
+
+// The value a reference is expected to have, as passed to the `write` and
+// `del` callbacks.
+fn old_value(old_id: *const raw::git_oid, old_target: *const c_char) -> Option<RefdbTarget> {
+    if !old_id.is_null() {
+        Some(RefdbTarget::Direct(unsafe { Binding::from_raw(old_id) }))
+    } else {
+        str_arg(old_target).map(|t| RefdbTarget::Symbolic(t.to_string()))
+    }
+}
+
+// The name and value of a reference passed in by libgit2.
+unsafe fn reference_arg<'a>(
+    reference: *const raw::git_reference,
+) -> Option<(&'a str, RefdbTarget)> {
+    let name = str_arg(raw::git_reference_name(reference))?;
+    let target = if raw::git_reference_type(reference) == raw::GIT_REFERENCE_SYMBOLIC {
+        RefdbTarget::Symbolic(str_arg(raw::git_reference_symbolic_target(reference))?.to_string())
+    } else {
+        RefdbTarget::Direct(Binding::from_raw(raw::git_reference_target(reference)))
+    };
+    Some((name, target))
+}
+
+fn locked(name: &str) -> Error {
+    Error::new(
+        ErrorCode::Locked,
+        ErrorClass::Reference,
+        format!("reference '{}' is locked", name),
+    )
+}
+
+fn reflog_unsupported() -> Error {
+    Error::new(
+        ErrorCode::NotFound,
+        ErrorClass::Reference,
+        "reflogs are not supported by custom reference databases",
+    )
+}
+
+impl RawRefdbBackend {
+    // Runs `f` unless one of the references is locked by a transaction.
+    fn unlocked<T>(
+        &self,
+        names: &[&str],
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let locks = self.locks.lock().unwrap();
+        if let Some(name) = names.iter().find(|n| locks.contains(**n)) {
+            return Err(locked(name));
+        }
+        f()
+    }
+}
+
+unsafe fn alloc_reference(
+    name: &str,
+    target: &RefdbTarget,
+) -> Result<*mut raw::git_reference, Error> {
+    let name = CString::new(name)?;
+    let raw = match *target {
+        RefdbTarget::Direct(ref id) => {
+            raw::git_reference__alloc(name.as_ptr(), id.raw(), ptr::null())
+        }
+        RefdbTarget::Symbolic(ref target) => {
+            let target = CString::new(target.as_str())?;
+            raw::git_reference__alloc_symbolic(name.as_ptr(), target.as_ptr())
+        }
+    };
+    if raw.is_null() {
+        Err(Error::from_str("failed to allocate reference"))
+    } else {
+        Ok(raw)
+    }
+}
+
+// Matches `name` against a glob in the same way as libgit2's `wildmatch`
+// without any flags, which is what the filesystem backend uses: `*` matches
+// any sequence of characters including `/`.
+//
+// On a mismatch only the last `*` seen has to absorb one more character, as
+// what comes before it matched already. Globs with many `*` then take time
+// proportional to the lengths of the glob and name multiplied, rather than
+// exponential in the number of `*`.
+fn glob_match(glob: &[u8], name: &[u8]) -> bool {
+    let (mut g, mut n) = (0, 0);
+    // The position in the glob after the last `*`, and the position in the
+    // name it was last tried from.
+    let mut star = None;
+    while n < name.len() {
+        if glob.get(g) == Some(&b'*') {
+            g += 1;
+            star = Some((g, n));
+        } else if let Some(len) = glob_token(&glob[g..], name[n]) {
+            g += len;
+            n += 1;
+        } else if let Some((star_g, star_n)) = star {
+            g = star_g;
+            n = star_n + 1;
+            star = Some((star_g, n));
+        } else {
+            return false;
+        }
+    }
+    glob[g..].iter().all(|&b| b == b'*')
+}
+
+// Matches the first token of `glob`, anything but a `*`, against `c`,
+// returning the length of the token if it matches.
+fn glob_token(glob: &[u8], c: u8) -> Option<usize> {
+    match glob.split_first()? {
+        (b'*', _) => None,
+        (b'?', _) => Some(1),
+        (b'[', rest) => {
+            let negate = matches!(rest.first(), Some(b'!') | Some(b'^'));
+            let rest = if negate { &rest[1..] } else { rest };
+            let end = rest.iter().skip(1).position(|&b| b == b']')? + 1;
+            let class = &rest[..end];
+            let mut matched = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    matched |= class[i] <= c && c <= class[i + 2];
+                    i += 3;
+                } else {
+                    matched |= class[i] == c;
+                    i += 1;
+                }
+            }
+            (matched != negate).then_some(1 + negate as usize + end + 1)
+        }
+        (b'\\', [escaped, ..]) => (*escaped == c).then_some(2),
+        (&other, _) => (other == c).then_some(1),
+    }
+}
+
+extern "C" fn backend_exists(
+    exists: *mut c_int,
+    backend: *mut raw::git_refdb_backend,
+    name: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        match b.obj.exists(name) {
+            Ok(found) => {
+                *exists = found as c_int;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_lookup(
+    out: *mut *mut raw::git_reference,
+    backend: *mut raw::git_refdb_backend,
+    name: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        match b.obj.lookup(name).and_then(|t| alloc_reference(name, &t)) {
+            Ok(reference) => {
+                *out = reference;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_iterator(
+    out: *mut *mut raw::git_reference_iterator,
+    backend: *mut raw::git_refdb_backend,
+    glob: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let glob = str_arg(glob);
+        match b.obj.iter(glob) {
+            Ok(iter) => {
+                let iter = Box::new(RawRefdbIterator {
+                    raw: raw::git_reference_iterator {
+                        db: ptr::null_mut(),
+                        next: Some(iterator_next),
+                        next_name: Some(iterator_next_name),
+                        free: Some(iterator_free),
+                    },
+                    iter,
+                    glob: glob.map(|s| s.to_string()),
+                    current: None,
+                });
+                *out = Box::into_raw(iter) as *mut raw::git_reference_iterator;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_write(
+    backend: *mut raw::git_refdb_backend,
+    reference: *const raw::git_reference,
+    force: c_int,
+    who: *const raw::git_signature,
+    message: *const c_char,
+    old_id: *const raw::git_oid,
+    old_target: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let (name, target) = match reference_arg(reference) {
+            Some(reference) => reference,
+            None => return invalid_name(),
+        };
+        let who = if who.is_null() {
+            None
+        } else {
+            Some(signature::from_raw_const(&who, who))
+        };
+        let precondition = RefdbPrecondition::new(force != 0, old_value(old_id, old_target));
+        let res = b.unlocked(&[name], || {
+            b.obj
+                .write(name, &target, &precondition, who.as_ref(), str_arg(message))
+        });
+        match res {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_rename(
+    out: *mut *mut raw::git_reference,
+    backend: *mut raw::git_refdb_backend,
+    old_name: *const c_char,
+    new_name: *const c_char,
+    force: c_int,
+    who: *const raw::git_signature,
+    message: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let (old_name, new_name) = match (str_arg(old_name), str_arg(new_name)) {
+            (Some(old_name), Some(new_name)) => (old_name, new_name),
+            _ => return invalid_name(),
+        };
+        let who = if who.is_null() {
+            None
+        } else {
+            Some(signature::from_raw_const(&who, who))
+        };
+        let res = b
+            .unlocked(&[old_name, new_name], || {
+                b.obj.rename(
+                    old_name,
+                    new_name,
+                    force != 0,
+                    who.as_ref(),
+                    str_arg(message),
+                )
+            })
+            .and_then(|target| alloc_reference(new_name, &target));
+        match res {
+            Ok(reference) => {
+                *out = reference;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_del(
+    backend: *mut raw::git_refdb_backend,
+    name: *const c_char,
+    old_id: *const raw::git_oid,
+    old_target: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        let old = old_value(old_id, old_target);
+        let res = b.unlocked(&[name], || {
+            b.obj.delete(name, old.as_ref())?;
+            b.obj.reflog_delete(name)
+        });
+        match res {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_compress(backend: *mut raw::git_refdb_backend) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        match b.obj.compress() {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_has_log(backend: *mut raw::git_refdb_backend, name: *const c_char) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        match b.obj.has_log(name) {
+            Ok(found) => found as c_int,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_ensure_log(
+    backend: *mut raw::git_refdb_backend,
+    name: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        match b.obj.ensure_log(name) {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+// callback used by libgit2 when the reference database owning the backend is
+// freed.
+extern "C" fn backend_free(backend: *mut raw::git_refdb_backend) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(backend as *mut RawRefdbBackend));
+    });
+}
+
+// libgit2 has no public way to create a `git_reflog`, so backends can't hand
+// one out, and have no way to find out the name of one handed to them.
+extern "C" fn backend_reflog_read(
+    _out: *mut *mut raw::git_reflog,
+    _backend: *mut raw::git_refdb_backend,
+    _name: *const c_char,
+) -> c_int {
+    unsafe { reflog_unsupported().raw_set_git_error() }
+}
+
+extern "C" fn backend_reflog_write(
+    _backend: *mut raw::git_refdb_backend,
+    _reflog: *mut raw::git_reflog,
+) -> c_int {
+    unsafe { reflog_unsupported().raw_set_git_error() }
+}
+
+extern "C" fn backend_reflog_rename(
+    backend: *mut raw::git_refdb_backend,
+    old_name: *const c_char,
+    new_name: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let (old_name, new_name) = match (str_arg(old_name), str_arg(new_name)) {
+            (Some(old_name), Some(new_name)) => (old_name, new_name),
+            _ => return invalid_name(),
+        };
+        match b.obj.reflog_rename(old_name, new_name) {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_reflog_delete(
+    backend: *mut raw::git_refdb_backend,
+    name: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        match b.obj.reflog_delete(name) {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_lock(
+    payload_out: *mut *mut c_void,
+    backend: *mut raw::git_refdb_backend,
+    name: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = match str_arg(name) {
+            Some(name) => name,
+            None => return invalid_name(),
+        };
+        if !b.locks.lock().unwrap().insert(name.to_string()) {
+            return locked(name).raw_set_git_error();
+        }
+        *payload_out = Box::into_raw(Box::new(name.to_string())) as *mut c_void;
+        0
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn backend_unlock(
+    backend: *mut raw::git_refdb_backend,
+    payload: *mut c_void,
+    success: c_int,
+    update_reflog: c_int,
+    reference: *const raw::git_reference,
+    who: *const raw::git_signature,
+    message: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let b = &*(backend as *mut RawRefdbBackend);
+        let name = Box::from_raw(payload as *mut String);
+        // Updates happen while the lock table is held, so that nothing can
+        // get in between the update and the release of the lock.
+        let mut locks = b.locks.lock().unwrap();
+        let res = match success {
+            0 => Ok(()),
+            2 => b
+                .obj
+                .delete(&name, None)
+                .and_then(|()| b.obj.reflog_delete(&name)),
+            _ => match reference_arg(reference) {
+                Some((_, target)) => {
+                    let (who, message) = if update_reflog != 0 && !who.is_null() {
+                        (Some(signature::from_raw_const(&who, who)), str_arg(message))
+                    } else {
+                        (None, None)
+                    };
+                    let precondition = RefdbPrecondition::new(true, None);
+                    b.obj
+                        .write(&name, &target, &precondition, who.as_ref(), message)
+                }
+                None => Err(Error::new(
+                    ErrorCode::Invalid,
+                    ErrorClass::Reference,
+                    "reference name is not valid utf-8",
+                )),
+            },
+        };
+        locks.remove(&*name);
+        match res {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+impl RawRefdbIterator {
+    // Advances to the next reference matching the glob, if any.
+    fn advance(&mut self) -> Option<Result<(String, RefdbTarget), Error>> {
+        loop {
+            let next = self.iter.next()?;
+            if let (Ok((ref name, _)), Some(ref glob)) = (&next, &self.glob) {
+                if !glob_match(glob.as_bytes(), name.as_bytes()) {
+                    continue;
+                }
+            }
+            return Some(next);
+        }
+    }
+}
+
+extern "C" fn iterator_next(
+    out: *mut *mut raw::git_reference,
+    iter: *mut raw::git_reference_iterator,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let iter = &mut *(iter as *mut RawRefdbIterator);
+        match iter.advance() {
+            Some(Ok((name, target))) => match alloc_reference(&name, &target) {
+                Ok(reference) => {
+                    *out = reference;
+                    0
+                }
+                Err(e) => e.raw_set_git_error(),
+            },
+            Some(Err(e)) => e.raw_set_git_error(),
+            None => raw::GIT_ITEROVER,
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn iterator_next_name(
+    out: *mut *const c_char,
+    iter: *mut raw::git_reference_iterator,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let iter = &mut *(iter as *mut RawRefdbIterator);
+        match iter.advance() {
+            Some(Ok((name, _))) => match CString::new(name) {
+                Ok(name) => {
+                    *out = name.as_ptr();
+                    iter.current = Some(name);
+                    0
+                }
+                Err(e) => Error::from(e).raw_set_git_error(),
+            },
+            Some(Err(e)) => e.raw_set_git_error(),
+            None => raw::GIT_ITEROVER,
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn iterator_free(iter: *mut raw::git_reference_iterator) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(iter as *mut RawRefdbIterator));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{glob_match, MemoryRefdb, RefdbPrecondition, RefdbTarget};
+    use crate::{ErrorCode, Odb, Oid, Refdb, Repository};
+
+    fn repo_with_backend(backend: &MemoryRefdb) -> Repository {
+        let odb = Odb::new().unwrap();
+        odb.add_new_mempack_backend(1).unwrap();
+        let repo = Repository::from_odb(odb).unwrap();
+        {
+            let refdb = Refdb::new(&repo).unwrap();
+            refdb.set_backend(backend.clone()).unwrap();
+            repo.set_refdb(&refdb).unwrap();
+        }
+        repo
+    }
+
+    #[test]
+    fn smoke() {
+        let backend = MemoryRefdb::default();
+        let repo = repo_with_backend(&backend);
+        let a = repo.blob(b"a").unwrap();
+        let b = repo.blob(b"b").unwrap();
+
+        repo.reference("refs/heads/main", a, false, "create")
+            .unwrap();
+        repo.reference_symbolic("HEAD", "refs/heads/main", false, "head")
+            .unwrap();
+        assert_eq!(
+            backend.refs.lock().unwrap()["refs/heads/main"],
+            RefdbTarget::Direct(a)
+        );
+        assert_eq!(repo.refname_to_id("HEAD").unwrap(), a);
+        assert!(repo.reference("refs/heads/main", b, false, "").is_err());
+
+        let err = repo
+            .reference_matching("refs/heads/main", b, true, b, "")
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), ErrorCode::Modified);
+        repo.reference_matching("refs/heads/main", b, true, a, "update")
+            .unwrap();
+
+        repo.reference("refs/tags/v1", a, false, "").unwrap();
+        let names: Vec<String> = repo
+            .references()
+            .unwrap()
+            .names()
+            .map(|n| n.unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["refs/heads/main", "refs/tags/v1"]);
+        let names: Vec<String> = repo
+            .references_glob("refs/heads/*")
+            .unwrap()
+            .map(|r| r.unwrap().name().unwrap().to_string())
+            .collect();
+        assert_eq!(names, ["refs/heads/main"]);
+
+        let mut tag = repo.find_reference("refs/tags/v1").unwrap();
+        let renamed = tag.rename("refs/tags/v2", false, "rename").unwrap();
+        assert_eq!(renamed.target(), Some(a));
+        assert!(repo.find_reference("refs/tags/v1").is_err());
+
+        repo.find_reference("refs/tags/v2")
+            .unwrap()
+            .delete()
+            .unwrap();
+        assert!(!backend.refs.lock().unwrap().contains_key("refs/tags/v2"));
+    }
+
+    #[test]
+    fn reflog() {
+        let backend = MemoryRefdb::default();
+        let repo = repo_with_backend(&backend);
+        let a = repo.blob(b"a").unwrap();
+        repo.reference("refs/heads/main", a, false, "create")
+            .unwrap();
+        let err = repo.reflog("refs/heads/main").err().unwrap();
+        assert_eq!(err.code(), ErrorCode::NotFound);
+        assert!(repo.revparse_single("main@{1}").is_err());
+    }
+
+    #[test]
+    fn transaction() {
+        let backend = MemoryRefdb::default();
+        let repo = repo_with_backend(&backend);
+        let a = repo.blob(b"a").unwrap();
+        let b = repo.blob(b"b").unwrap();
+        repo.reference("refs/heads/main", a, false, "create")
+            .unwrap();
+        repo.reference("refs/heads/gone", a, false, "create")
+            .unwrap();
+
+        let mut tx = repo.transaction().unwrap();
+        tx.lock_ref("refs/heads/main").unwrap();
+        tx.lock_ref("refs/heads/new").unwrap();
+        tx.lock_ref("refs/heads/gone").unwrap();
+        assert_eq!(
+            tx.lock_ref("refs/heads/main").err().unwrap().code(),
+            ErrorCode::Locked
+        );
+        // Locked references can't be changed outside of the transaction.
+        let err = repo
+            .reference("refs/heads/main", b, true, "")
+            .err()
+            .unwrap();
+        assert_eq!(err.code(), ErrorCode::Locked);
+
+        tx.set_target("refs/heads/main", b, None, "update").unwrap();
+        tx.set_symbolic_target("refs/heads/new", "refs/heads/main", None, "new")
+            .unwrap();
+        tx.remove("refs/heads/gone").unwrap();
+        tx.commit().unwrap();
+
+        let refs = backend.refs.lock().unwrap().clone();
+        assert_eq!(refs["refs/heads/main"], RefdbTarget::Direct(b));
+        assert_eq!(
+            refs["refs/heads/new"],
+            RefdbTarget::Symbolic("refs/heads/main".to_string())
+        );
+        assert!(!refs.contains_key("refs/heads/gone"));
+
+        // Dropping a transaction releases its locks.
+        let mut tx = repo.transaction().unwrap();
+        tx.lock_ref("refs/heads/main").unwrap();
+        drop(tx);
+        repo.reference("refs/heads/main", a, true, "").unwrap();
+    }
+
+    #[test]
+    fn precondition() {
+        let a = RefdbTarget::Direct(Oid::from_bytes(&[1; 20]).unwrap());
+        let zero = RefdbTarget::Direct(Oid::zero());
+        let check = |force, old: Option<&RefdbTarget>, current| {
+            RefdbPrecondition::new(force, old.cloned())
+                .check("refs/heads/main", current)
+                .map_err(|e| e.code())
+        };
+        assert_eq!(check(false, None, None), Ok(()));
+        assert_eq!(check(false, None, Some(&a)), Err(ErrorCode::Exists));
+        assert_eq!(check(true, Some(&a), Some(&a)), Ok(()));
+        assert_eq!(check(true, Some(&zero), Some(&a)), Err(ErrorCode::Modified));
+        assert_eq!(check(true, Some(&zero), None), Ok(()));
+        assert_eq!(check(true, Some(&a), None), Err(ErrorCode::Modified));
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match(b"refs/heads/*", b"refs/heads/main"));
+        assert!(glob_match(b"refs/heads/*", b"refs/heads/feature/x"));
+        assert!(!glob_match(b"refs/heads/*", b"refs/tags/v1"));
+        assert!(glob_match(b"refs/tags/v?", b"refs/tags/v1"));
+        assert!(glob_match(b"refs/tags/v[0-9]", b"refs/tags/v1"));
+        assert!(!glob_match(b"refs/tags/v[!0-9]", b"refs/tags/v1"));
+        assert!(glob_match(b"refs/*/v*", b"refs/tags/v1"));
+        assert!(glob_match(b"refs/*/*1", b"refs/tags/v1/v1"));
+        assert!(!glob_match(b"refs/*/*2", b"refs/tags/v1"));
+        assert!(glob_match(b"refs/heads/\\*", b"refs/heads/*"));
+        assert!(!glob_match(b"refs/heads/\\*", b"refs/heads/main"));
+        assert!(glob_match(b"refs/[]]", b"refs/]"));
+        assert!(glob_match(b"*", b""));
+        assert!(!glob_match(b"refs/[a", b"refs/a"));
+
+        // Backtracking to every `*` would take forever here.
+        let glob = b"*a".repeat(30);
+        assert!(!glob_match(&glob, &b"a".repeat(29)));
+        assert!(glob_match(&glob, &b"a".repeat(60)));
+    }
+}
@@ -1,5 +1,6 @@
 use std::ffi::CString;
 use std::marker;
+use std::ptr;
 use std::str;
 
 use crate::util::Binding;
@@ -12,9 +13,28 @@ use crate::{raw, Buf, Direction, Error};
 /// [1]: http://git-scm.com/book/en/Git-Internals-The-Refspec
 pub struct Refspec<'remote> {
     raw: *const raw::git_refspec,
+    /// Whether the refspec was parsed on its own, and is freed on drop.
+    owned: bool,
     _marker: marker::PhantomData<&'remote raw::git_remote>,
 }
 
+impl Refspec<'static> {
+    /// Parse `refspec` as a refspec to fetch or push with.
+    pub(crate) fn parse(refspec: &str, direction: Direction) -> Result<Refspec<'static>, Error> {
+        let refspec = CString::new(refspec)?;
+        let is_fetch = direction == Direction::Fetch;
+        let mut raw = ptr::null_mut();
+        unsafe {
+            try_call!(raw::git_refspec_parse(&mut raw, refspec, is_fetch));
+        }
+        Ok(Refspec {
+            raw,
+            owned: true,
+            _marker: marker::PhantomData,
+        })
+    }
+}
+
 impl<'remote> Refspec<'remote> {
     /// Get the refspec's direction.
     pub fn direction(&self) -> Direction {
@@ -113,6 +133,7 @@ impl<'remote> Binding for Refspec<'remote> {
     unsafe fn from_raw(raw: *const raw::git_refspec) -> Refspec<'remote> {
         Refspec {
             raw,
+            owned: false,
             _marker: marker::PhantomData,
         }
     }
@@ -120,3 +141,11 @@ impl<'remote> Binding for Refspec<'remote> {
         self.raw
     }
 }
+
+impl<'remote> Drop for Refspec<'remote> {
+    fn drop(&mut self) {
+        if self.owned {
+            unsafe { raw::git_refspec_free(self.raw as *mut raw::git_refspec) }
+        }
+    }
+}
@@ -1,18 +1,22 @@
 use raw::git_strarray;
+use std::collections::{HashSet, VecDeque};
 use std::iter::FusedIterator;
 use std::marker;
-use std::mem;
+use std::mem::{self, ManuallyDrop};
 use std::ops::Range;
-use std::os::raw::c_uint;
+use std::os::raw::{c_uint, c_void};
 use std::ptr;
 use std::slice;
 use std::str;
 use std::{ffi::CString, os::raw::c_char};
 
+use crate::remote_callbacks::TipsLog;
 use crate::string_array::StringArray;
+use crate::transport::Operation;
 use crate::util::Binding;
-use crate::{call, raw, Buf, Direction, Error, FetchPrune, Oid, ProxyOptions, Refspec};
+use crate::{call, raw, Buf, Direction, Error, ErrorClass, ErrorCode, FetchPrune, Oid};
 use crate::{AutotagOption, Progress, RemoteCallbacks, RemoteUpdateFlags, Repository};
+use crate::{ObjectFilter, ProxyOptions, PushPlan, PushPlanKind, Refspec, Time};
 
 /// A structure representing a [remote][1] of a git repository.
 ///
@@ -38,10 +42,44 @@ pub struct RemoteHead<'remote> {
     _marker: marker::PhantomData<&'remote str>,
 }
 
+/// What changed in a fetch, as returned by [`Remote::fetch_report`].
+pub struct FetchReport {
+    updates: Vec<FetchUpdate>,
+    stats: Progress<'static>,
+}
+
+/// A reference updated by a fetch.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchUpdate {
+    name: String,
+    old_id: Oid,
+    new_id: Oid,
+    kind: FetchUpdateKind,
+}
+
+/// The kinds of changes a fetch can make to a reference.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FetchUpdateKind {
+    /// The reference didn't exist before.
+    Created,
+    /// The new object is a descendant of the old one.
+    FastForward,
+    /// The reference was updated although it isn't a fast-forward.
+    Forced,
+    /// The reference was deleted as it no longer exists on the remote.
+    Pruned,
+    /// The reference already pointed to the fetched object.
+    Unchanged,
+}
+
 /// Options which can be specified to various fetch operations.
 pub struct FetchOptions<'cb> {
     callbacks: Option<RemoteCallbacks<'cb>>,
     depth: i32,
+    deepen: Option<i32>,
+    shallow_since: Option<Time>,
+    shallow_exclude: Vec<String>,
+    filter: Option<ObjectFilter>,
     proxy: Option<ProxyOptions<'cb>>,
     prune: FetchPrune,
     update_flags: RemoteUpdateFlags,
@@ -61,6 +99,8 @@ pub struct PushOptions<'cb> {
     custom_headers_ptrs: Vec<*const c_char>,
     remote_push_options: Vec<CString>,
     remote_push_options_ptrs: Vec<*const c_char>,
+    atomic: bool,
+    leases: Vec<(String, Oid)>,
 }
 
 /// Holds callbacks for a connection to a `Remote`. Disconnects when dropped
@@ -75,6 +115,7 @@ pub struct RemoteConnection<'repo, 'connection, 'cb> {
 ///
 /// By default, git will follow a redirect on the initial request
 /// (`/info/refs`), but not subsequent requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum RemoteRedirect {
     /// Do not follow any off-site redirects at any stage of the fetch or push.
     None,
@@ -249,12 +290,24 @@ impl<'repo> Remote<'repo> {
         specs: &[Str],
         opts: Option<&mut FetchOptions<'_>>,
     ) -> Result<(), Error> {
-        let (_a, _b, arr) = crate::util::iter2cstrs(specs.iter())?;
-        let raw = opts.map(|o| o.raw());
-        unsafe {
-            try_call!(raw::git_remote_download(self.raw, &arr, raw.as_ref()));
+        let filter = self.filter(opts.as_deref())?;
+        self.transfer(specs, opts, filter, |remote, specs, opts| unsafe {
+            try_call!(raw::git_remote_download(remote, specs, opts));
+            Ok(())
+        })
+    }
+
+    /// Get the object filter to fetch with: the one of `opts`, or the one
+    /// configured for the remote if it's the promisor remote of a partial
+    /// clone.
+    fn filter(&self, opts: Option<&FetchOptions<'_>>) -> Result<Option<String>, Error> {
+        if let Some(filter) = opts.and_then(|o| o.filter) {
+            return Ok(Some(filter.to_string()));
+        }
+        match (self.name(), self.owner()) {
+            (Some(name), Ok(repo)) => crate::partial_clone::remote_filter(&repo, name),
+            _ => Ok(None),
         }
-        Ok(())
     }
 
     /// Cancel the operation
@@ -310,13 +363,134 @@ impl<'repo> Remote<'repo> {
         opts: Option<&mut FetchOptions<'_>>,
         reflog_msg: Option<&str>,
     ) -> Result<(), Error> {
-        let (_a, _b, arr) = crate::util::iter2cstrs(refspecs.iter())?;
+        let filter = self.filter(opts.as_deref())?;
+        self.fetch_filtered(refspecs, opts, reflog_msg, filter)
+    }
+
+    /// Fetch without setting up the object filter of partial clones.
+    pub(crate) fn fetch_unfiltered<Str: AsRef<str> + crate::IntoCString + Clone>(
+        &mut self,
+        refspecs: &[Str],
+        opts: Option<&mut FetchOptions<'_>>,
+        reflog_msg: Option<&str>,
+    ) -> Result<(), Error> {
+        self.fetch_filtered(refspecs, opts, reflog_msg, None)
+    }
+
+    fn fetch_filtered<Str: AsRef<str> + crate::IntoCString + Clone>(
+        &mut self,
+        refspecs: &[Str],
+        opts: Option<&mut FetchOptions<'_>>,
+        reflog_msg: Option<&str>,
+        filter: Option<String>,
+    ) -> Result<(), Error> {
         let msg = crate::opt_cstr(reflog_msg)?;
-        let raw = opts.map(|o| o.raw());
-        unsafe {
-            try_call!(raw::git_remote_fetch(self.raw, &arr, raw.as_ref(), msg));
+        self.transfer(refspecs, opts, filter, |remote, refspecs, opts| unsafe {
+            try_call!(raw::git_remote_fetch(remote, refspecs, opts, msg));
+            Ok(())
+        })
+    }
+
+    /// Run `f`, a download or a fetch of `refspecs` with `opts`, with what
+    /// libgit2 doesn't know about handed to the transports: the object
+    /// `filter`, the limits of shallow fetches and the refspecs themselves.
+    fn transfer<Str: AsRef<str> + crate::IntoCString + Clone>(
+        &mut self,
+        refspecs: &[Str],
+        opts: Option<&mut FetchOptions<'_>>,
+        filter: Option<String>,
+        f: impl FnOnce(
+            *mut raw::git_remote,
+            &raw::git_strarray,
+            Option<&raw::git_fetch_options>,
+        ) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let (_a, _b, arr) = crate::util::iter2cstrs(refspecs.iter())?;
+        let mut default_opts = FetchOptions::new();
+        let opts = opts.unwrap_or(&mut default_opts);
+        let payload = opts.payload();
+        let mut raw = opts.raw();
+        let mut deepen = Vec::new();
+        if let Some(since) = opts.shallow_since {
+            deepen.push(format!("deepen-since {}", since.seconds()));
         }
-        Ok(())
+        for name in &opts.shallow_exclude {
+            deepen.push(format!("deepen-not {}", name));
+        }
+        if !deepen.is_empty() {
+            if raw.depth != 0 {
+                return Err(Error::from_str(
+                    "a depth can't be combined with shallow_since or shallow_exclude",
+                ));
+            }
+            // libgit2 only asks for the shallow commits along with a depth,
+            // which the transports replace with `deepen`.
+            raw.depth = 1;
+        } else if let Some(depth) = opts.deepen {
+            raw.depth = shallow_depth(self)?.saturating_add(depth);
+        }
+        let url = self.url().unwrap_or("").to_string();
+        let refspecs = refspecs.iter().map(|s| s.as_ref().to_string()).collect();
+        let operation = Operation::fetch(refspecs, filter, deepen);
+        operation.run(&url, payload, || f(self.raw, &arr, Some(&raw)))
+    }
+
+    /// Download new data and update tips, reporting what changed
+    ///
+    /// This is the same as [`fetch`](Remote::fetch), but also returns every
+    /// reference the fetch updated, pruned or left unchanged, along with the
+    /// statistics of the transfer. Callbacks set in `opts` are still called,
+    /// including `update_tips`.
+    pub fn fetch_report<Str: AsRef<str> + crate::IntoCString + Clone>(
+        &mut self,
+        refspecs: &[Str],
+        opts: Option<&mut FetchOptions<'_>>,
+        reflog_msg: Option<&str>,
+    ) -> Result<FetchReport, Error> {
+        let mut default_opts = FetchOptions::new();
+        let opts = opts.unwrap_or(&mut default_opts);
+        let flags = opts.update_flags;
+        let log = TipsLog {
+            updates: Vec::new(),
+            report_unchanged: flags.contains(RemoteUpdateFlags::REPORT_UNCHANGED),
+        };
+        opts.update_flags |= RemoteUpdateFlags::REPORT_UNCHANGED;
+        let callbacks = opts.callbacks.get_or_insert_with(RemoteCallbacks::new);
+        callbacks.record_tips(Some(log));
+        let result = self.fetch(refspecs, Some(&mut *opts), reflog_msg);
+        opts.update_flags = flags;
+        let log = opts
+            .callbacks
+            .as_mut()
+            .and_then(|callbacks| callbacks.record_tips(None))
+            .unwrap();
+        result?;
+
+        let repo = self.owner()?;
+        let mut updates = Vec::new();
+        for (name, old_id, new_id) in log.updates {
+            let kind = if old_id == new_id {
+                FetchUpdateKind::Unchanged
+            } else if old_id.is_zero() {
+                FetchUpdateKind::Created
+            } else if new_id.is_zero() {
+                FetchUpdateKind::Pruned
+            } else if is_fast_forward(&repo, old_id, new_id) {
+                FetchUpdateKind::FastForward
+            } else {
+                FetchUpdateKind::Forced
+            };
+            updates.push(FetchUpdate {
+                name,
+                old_id,
+                new_id,
+                kind,
+            });
+        }
+        Ok(FetchReport {
+            updates,
+            stats: self.stats().to_owned(),
+        })
     }
 
     /// Update the tips to the new state
@@ -354,14 +528,257 @@ impl<'repo> Remote<'repo> {
         refspecs: &[Str],
         opts: Option<&mut PushOptions<'_>>,
     ) -> Result<(), Error> {
-        let (_a, _b, arr) = crate::util::iter2cstrs(refspecs.iter())?;
-        let raw = opts.map(|o| o.raw());
+        let mut refspecs = refspecs
+            .iter()
+            .map(|s| s.as_ref().to_string())
+            .collect::<Vec<_>>();
+        let mut default_opts = PushOptions::new();
+        let opts = opts.unwrap_or(&mut default_opts);
+        let payload = opts.payload();
+        let url = self.pushurl().or_else(|| self.url()).unwrap_or("");
+        let url = url.to_string();
+        Operation::push(opts.atomic).run(&url, payload, || {
+            let stale = if opts.leases.is_empty() {
+                None
+            } else {
+                self.check_leases(&mut refspecs, opts)?
+            };
+            if refspecs.is_empty() {
+                if let Some(err) = stale {
+                    // Pushing no refspecs would push the configured ones instead.
+                    self.disconnect()?;
+                    return Err(err);
+                }
+            }
+            let (_a, _b, arr) = crate::util::iter2cstrs(refspecs.iter())?;
+            let raw = opts.raw();
+            unsafe {
+                try_call!(raw::git_remote_push(self.raw, &arr, &raw));
+            }
+            match stale {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        })
+    }
+
+    /// Plan a push without performing it.
+    ///
+    /// This connects to the remote and matches `refspecs` against its
+    /// references like [`push`](Remote::push) does, including the
+    /// [leases](PushOptions::force_with_lease) of `opts`, but sends nothing.
+    /// The updates are returned in the order of the refspecs, with wildcard
+    /// refspecs such as `refs/heads/*:refs/heads/mirror/*` expanded to the
+    /// local references they match, and short names such as `main`
+    /// completed from the local reference they name, as git does. `push`
+    /// itself hands refspecs to libgit2 as they are, which only takes full
+    /// reference names.
+    ///
+    /// If `refspecs` is empty, the configured push refspecs are used.
+    pub fn push_dry_run<Str: AsRef<str>>(
+        &mut self,
+        refspecs: &[Str],
+        opts: Option<&PushOptions<'_>>,
+    ) -> Result<Vec<PushPlan>, Error> {
+        let mut refspecs = refspecs
+            .iter()
+            .map(|s| s.as_ref().to_string())
+            .collect::<Vec<_>>();
+        if refspecs.is_empty() {
+            refspecs = self
+                .push_refspecs()?
+                .iter()
+                .flatten()
+                .map(String::from)
+                .collect();
+        }
+        let default_opts = PushOptions::new();
+        let opts = opts.unwrap_or(&default_opts);
+        let connected = self.connected();
+        if !connected {
+            self.connect_push(opts)?;
+        }
+        let result = self.plan_push(&refspecs, opts);
+        if !connected {
+            self.disconnect()?;
+        }
+        result
+    }
+
+    fn plan_push(
+        &self,
+        refspecs: &[String],
+        opts: &PushOptions<'_>,
+    ) -> Result<Vec<PushPlan>, Error> {
+        let repo = self.owner()?;
+
+        let mut updates = Vec::new();
+        for refspec in expand_push_refspecs(&repo, refspecs)? {
+            let spec = Refspec::parse(&refspec, Direction::Push)?;
+            let force = spec.is_force();
+            let (src, dst) = (
+                refspec_str(spec.src_bytes())?,
+                refspec_str(spec.dst_bytes())?,
+            );
+            if !dst.starts_with("refs/") {
+                return Err(Error::from_str(&format!("not a valid reference '{}'", dst)));
+            }
+            if src.is_empty() {
+                updates.push((None, dst.to_string(), Oid::zero(), force));
+            } else {
+                let id = repo.revparse_single(src)?.id();
+                updates.push((Some(src.to_string()), dst.to_string(), id, force));
+            }
+        }
+
+        let mut plans = Vec::new();
+        for (src, dst, new_id, force) in updates {
+            let old_id = self.advertised(&dst)?;
+            let lease = opts.leases.iter().find(|(name, _)| *name == dst);
+            let kind = if lease.is_some_and(|(_, expected)| *expected != old_id) {
+                PushPlanKind::Rejected
+            } else if old_id == new_id {
+                PushPlanKind::UpToDate
+            } else if old_id.is_zero() {
+                PushPlanKind::Create
+            } else if new_id.is_zero() {
+                PushPlanKind::Delete
+            } else if is_fast_forward(&repo, old_id, new_id) {
+                PushPlanKind::FastForward
+            } else if force || lease.is_some() {
+                PushPlanKind::Forced
+            } else {
+                PushPlanKind::Rejected
+            };
+            plans.push(PushPlan {
+                src,
+                dst,
+                old_id,
+                new_id,
+                kind,
+            });
+        }
+        Ok(plans)
+    }
+
+    /// Get the repository the remote belongs to.
+    fn owner(&self) -> Result<ManuallyDrop<Repository>, Error> {
+        let repo = unsafe { raw::git_remote_owner(self.raw) };
+        if repo.is_null() {
+            return Err(Error::from_str("the remote is detached from a repository"));
+        }
+        Ok(unsafe { ManuallyDrop::new(Repository::from_raw(repo)) })
+    }
+
+    /// Connect to push with the options of `opts`.
+    fn connect_push(&mut self, opts: &PushOptions<'_>) -> Result<(), Error> {
+        let push = opts.raw();
+        let connect = raw::git_remote_connect_options {
+            version: 1,
+            callbacks: push.callbacks,
+            proxy_opts: push.proxy_opts,
+            follow_redirects: push.follow_redirects,
+            custom_headers: push.custom_headers,
+        };
         unsafe {
-            try_call!(raw::git_remote_push(self.raw, &arr, raw.as_ref()));
+            try_call!(raw::git_remote_connect_ext(
+                self.raw,
+                raw::GIT_DIRECTION_PUSH,
+                &connect
+            ));
         }
         Ok(())
     }
 
+    /// Get the object the remote advertised for `refname`, or zero if it
+    /// doesn't have the reference.
+    fn advertised(&self, refname: &str) -> Result<Oid, Error> {
+        Ok(self
+            .list()?
+            .iter()
+            .find(|head| head.name() == refname)
+            .map(|head| head.oid())
+            .unwrap_or_else(Oid::zero))
+    }
+
+    /// Check the leases of `opts` against the references advertised by the
+    /// remote, connecting to it if needed.
+    ///
+    /// The refspecs of leased references are forced, and those of stale ones
+    /// removed, the configured ones if `refspecs` is empty. The push then
+    /// reuses the connection, so the remote refuses the update if the
+    /// reference changes in between. Returns the error to report once the
+    /// rest is pushed.
+    fn check_leases(
+        &mut self,
+        refspecs: &mut Vec<String>,
+        opts: &mut PushOptions<'_>,
+    ) -> Result<Option<Error>, Error> {
+        if !self.connected() {
+            self.connect_push(opts)?;
+        }
+        if refspecs.is_empty() {
+            *refspecs = self
+                .push_refspecs()?
+                .iter()
+                .flatten()
+                .map(String::from)
+                .collect();
+        }
+        let mut stale = Vec::new();
+        for (name, expected) in &opts.leases {
+            let actual = self.advertised(name)?;
+            if actual != *expected {
+                stale.push((name.as_str(), *expected, actual));
+            }
+        }
+
+        for spec in refspecs.iter_mut() {
+            let leased = opts.leases.iter().any(|(name, _)| *name == push_dst(spec));
+            if leased && !spec.starts_with('+') {
+                spec.insert(0, '+');
+            }
+        }
+        if stale.is_empty() {
+            return Ok(None);
+        }
+
+        let mut rejected = stale
+            .iter()
+            .map(|(name, _, _)| (name.to_string(), "stale info"))
+            .collect::<Vec<_>>();
+        if opts.atomic {
+            for spec in refspecs.iter() {
+                let dst = push_dst(spec);
+                if !rejected.iter().any(|(name, _)| name == dst) {
+                    rejected.push((dst.to_string(), "atomic push failed"));
+                }
+            }
+        }
+        let message = stale
+            .iter()
+            .map(|(name, expected, actual)| {
+                format!(
+                    "stale info for {}: expected {} but the remote has {}",
+                    name, expected, actual
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        let err = Error::new(ErrorCode::Modified, ErrorClass::Reference, message);
+        if let Some(callbacks) = opts.callbacks.as_mut() {
+            for (name, status) in &rejected {
+                callbacks.report_push_update(name, Some(status))?;
+            }
+        }
+        if opts.atomic {
+            self.disconnect()?;
+            return Err(err);
+        }
+        refspecs.retain(|spec| !rejected.iter().any(|(name, _)| name == push_dst(spec)));
+        Ok(Some(err))
+    }
+
     /// Get the statistics structure that is filled in by the fetch operation.
     pub fn stats(&self) -> Progress<'_> {
         unsafe { Binding::from_raw(raw::git_remote_stats(self.raw)) }
@@ -420,6 +837,194 @@ impl<'repo> Remote<'repo> {
     }
 }
 
+/// Get the depth of the history of a shallow repository fetched from
+/// `remote`, the largest number of commits between a reference it fetches
+/// into and a shallow root, or 0 if the repository isn't shallow.
+///
+/// Only the history between those references and the shallow roots is
+/// walked.
+fn shallow_depth(remote: &Remote<'_>) -> Result<i32, Error> {
+    let repo = remote.owner()?;
+    let roots = repo.shallow_roots()?.into_iter().collect::<HashSet<_>>();
+    if roots.is_empty() {
+        return Ok(0);
+    }
+    let refspecs = remote
+        .refspecs()
+        .filter(|spec| spec.direction() == Direction::Fetch)
+        .collect::<Vec<_>>();
+    let mut queue = VecDeque::new();
+    let mut seen = HashSet::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        let name = reference.name().unwrap_or("");
+        if !refspecs.is_empty() && !refspecs.iter().any(|spec| spec.dst_matches(name)) {
+            continue;
+        }
+        // References to other objects, or to history not fetched, are left
+        // out.
+        if let Ok(commit) = reference.peel_to_commit() {
+            if seen.insert(commit.id()) {
+                queue.push_back((commit.id(), 1));
+            }
+        }
+    }
+    // The repository caches the commits it looks up with the parents they
+    // had when they were read, so that a former shallow root stays without
+    // parents after its history is fetched: read them from the object
+    // database instead.
+    let odb = repo.odb()?;
+    let mut depth = 0;
+    while let Some((id, distance)) = queue.pop_front() {
+        if roots.contains(&id) {
+            depth = depth.max(distance);
+            continue;
+        }
+        let object = match odb.read(id) {
+            Ok(object) => object,
+            Err(_) => continue,
+        };
+        let parents = object
+            .data()
+            .split(|&b| b == b'\n')
+            .skip(1)
+            .map_while(|line| line.strip_prefix(b"parent "))
+            .filter_map(|hex| str::from_utf8(hex).ok()?.parse::<Oid>().ok());
+        for parent in parents {
+            if seen.insert(parent) {
+                queue.push_back((parent, distance + 1));
+            }
+        }
+    }
+    Ok(depth)
+}
+
+/// Check whether updating from `old_id` to `new_id` is a fast-forward.
+fn is_fast_forward(repo: &Repository, old_id: Oid, new_id: Oid) -> bool {
+    let peel = |id| repo.find_object(id, None).and_then(|o| o.peel_to_commit());
+    match (peel(old_id), peel(new_id)) {
+        (Ok(old), Ok(new)) => repo
+            .graph_descendant_of(new.id(), old.id())
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Expand the refspecs of a push into a refspec for every reference they
+/// update, to plan the push like git would make it.
+///
+/// Wildcards are matched against the local references. Short names, such as
+/// `main` or `HEAD`, are completed from the local reference they name, and
+/// short destinations are put next to it, in `refs/heads/` or `refs/tags/`.
+fn expand_push_refspecs(repo: &Repository, refspecs: &[String]) -> Result<Vec<String>, Error> {
+    let mut expanded = Vec::new();
+    for refspec in refspecs {
+        let spec = Refspec::parse(refspec, Direction::Push)?;
+        let force = if spec.is_force() { "+" } else { "" };
+        let (src, dst) = (
+            refspec_str(spec.src_bytes())?,
+            refspec_str(spec.dst_bytes())?,
+        );
+        if src.contains('*') {
+            for reference in repo.references()? {
+                let reference = reference?;
+                let name = match reference.name() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                // Symbolic references to nothing, or to a missing reference,
+                // have nothing to push.
+                let resolved = reference.resolve().ok().and_then(|r| r.target());
+                if spec.src_matches(name) && resolved.is_some() {
+                    let dst = spec.transform(name)?;
+                    expanded.push(format!("{}{}:{}", force, name, refspec_str(&dst)?));
+                }
+            }
+            continue;
+        }
+        let short = !src.starts_with("refs/") || !dst.starts_with("refs/");
+        let name = match repo.resolve_reference_from_short_name(src) {
+            Ok(reference) if !src.is_empty() && short => reference
+                .resolve()
+                .ok()
+                .and_then(|r| r.name().map(String::from)),
+            _ => None,
+        };
+        let name = match name {
+            Some(name) => name,
+            // Deletions, object ids and full names are pushed as they are.
+            None => {
+                expanded.push(refspec.clone());
+                continue;
+            }
+        };
+        let dst = if dst.starts_with("refs/") {
+            dst.to_string()
+        } else if dst == src {
+            name.clone()
+        } else if let Some(namespace) = ["refs/heads/", "refs/tags/"]
+            .into_iter()
+            .find(|namespace| name.starts_with(namespace))
+        {
+            format!("{}{}", namespace, dst)
+        } else {
+            dst.to_string()
+        };
+        expanded.push(format!("{}{}:{}", force, name, dst));
+    }
+    Ok(expanded)
+}
+
+/// Get a part of a refspec as a string.
+fn refspec_str(bytes: &[u8]) -> Result<&str, Error> {
+    str::from_utf8(bytes).map_err(|_| Error::from_str("refspec is not valid UTF-8"))
+}
+
+/// Get the reference a push refspec updates on the remote.
+fn push_dst(refspec: &str) -> &str {
+    let refspec = refspec.strip_prefix('+').unwrap_or(refspec);
+    match refspec.split_once(':') {
+        Some((_, dst)) => dst,
+        None => refspec,
+    }
+}
+
+impl FetchReport {
+    /// Get the references the fetch updated, pruned or left unchanged, in the
+    /// order libgit2 processed them.
+    pub fn updates(&self) -> &[FetchUpdate] {
+        &self.updates
+    }
+
+    /// Get the statistics of the transfer.
+    pub fn stats(&self) -> &Progress<'static> {
+        &self.stats
+    }
+}
+
+impl FetchUpdate {
+    /// Get the name of the local reference.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the object the reference pointed to before, or zero if it didn't
+    /// exist.
+    pub fn old_id(&self) -> Oid {
+        self.old_id
+    }
+
+    /// Get the object the reference points to now, or zero if it was pruned.
+    pub fn new_id(&self) -> Oid {
+        self.new_id
+    }
+
+    /// Get the kind of change.
+    pub fn kind(&self) -> FetchUpdateKind {
+        self.kind
+    }
+}
+
 impl<'repo> Clone for Remote<'repo> {
     fn clone(&self) -> Remote<'repo> {
         let mut ret = ptr::null_mut();
@@ -515,6 +1120,10 @@ impl<'cb> FetchOptions<'cb> {
             custom_headers: Vec::new(),
             custom_headers_ptrs: Vec::new(),
             depth: 0, // Not limited depth
+            deepen: None,
+            shallow_since: None,
+            shallow_exclude: Vec::new(),
+            filter: None,
         }
     }
 
@@ -556,15 +1165,118 @@ impl<'cb> FetchOptions<'cb> {
 
     /// Set fetch depth, a value less or equal to 0 is interpreted as pull
     /// everything (effectively the same as not declaring a limit depth).
-
-    // FIXME(blyxyas): We currently don't have a test for shallow functions
-    // because libgit2 doesn't support local shallow clones.
-    // https://github.com/rust-lang/git2-rs/pull/979#issuecomment-1716299900
     pub fn depth(&mut self, depth: i32) -> &mut Self {
         self.depth = depth.max(0);
+        self.deepen = None;
+        self
+    }
+
+    /// Deepen the history of a shallow repository by `depth` commits, like
+    /// `git fetch --deepen`.
+    ///
+    /// libgit2 only knows absolute depths, so [`Remote::fetch`] turns this
+    /// into one by adding `depth` to the current depth of the repository,
+    /// counted from its references to its [shallow
+    /// roots](Repository::shallow_roots). Elsewhere, such as when cloning,
+    /// this is the same as [`depth`](FetchOptions::depth).
+    pub fn deepen(&mut self, depth: i32) -> &mut Self {
+        self.depth = depth.max(0);
+        self.deepen = Some(depth.max(0));
         self
     }
 
+    /// Fetch the complete history of a shallow repository, like
+    /// `git fetch --unshallow`.
+    pub fn unshallow(&mut self) -> &mut Self {
+        self.depth = raw::GIT_FETCH_DEPTH_UNSHALLOW;
+        self.deepen = None;
+        self
+    }
+
+    /// Fetch the history after `time`, like `git fetch --shallow-since`.
+    ///
+    /// libgit2 can only ask for a depth, so this is only sent by the smart
+    /// transports created with [`Transport::smart`] and added with
+    /// [`transport::register`], to servers supporting it, such as
+    /// [`UploadPack`]. The built-in transports of libgit2, used for local
+    /// paths and for `http`, `https`, `ssh` and `git` URLs unless another
+    /// transport was registered for them, can't send it: fetching with it
+    /// through them fails before connecting. Combining it with a
+    /// [`depth`](FetchOptions::depth) fails too. It is used by
+    /// [`Remote::fetch`] and [`Remote::download`]; cloning with it fails,
+    /// clone with a depth and fetch with this instead.
+    ///
+    /// [`Transport::smart`]: crate::transport::Transport::smart
+    /// [`transport::register`]: crate::transport::register
+    /// [`UploadPack`]: crate::server::UploadPack
+    pub fn shallow_since(&mut self, time: Time) -> &mut Self {
+        self.shallow_since = Some(time);
+        self
+    }
+
+    /// Fetch the history not reachable from `refs`, like
+    /// `git fetch --shallow-exclude`.
+    ///
+    /// The references are names on the remote, such as `refs/tags/v1.0` or
+    /// `v1.0`. Like [`shallow_since`](FetchOptions::shallow_since), they are
+    /// only sent by the smart transports created with [`Transport::smart`]
+    /// and added with [`transport::register`]. The built-in transports of
+    /// libgit2, used for local paths and for `http`, `https`, `ssh` and `git`
+    /// URLs unless another transport was registered for them, can't send
+    /// them: fetching with them through those fails before connecting, as
+    /// does combining them with a [`depth`](FetchOptions::depth) or cloning
+    /// with them.
+    ///
+    /// [`Transport::smart`]: crate::transport::Transport::smart
+    /// [`transport::register`]: crate::transport::register
+    pub fn shallow_exclude(&mut self, refs: &[&str]) -> &mut Self {
+        self.shallow_exclude = refs.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    /// Only fetch the objects passing `filter`, for a partial clone.
+    ///
+    /// libgit2 doesn't know about filters, so they are only sent by the smart
+    /// transports created with [`Transport::smart`] and added with
+    /// [`transport::register`], and only to servers supporting them, such as
+    /// [`UploadPack`]; fetching from a server which doesn't fails. The
+    /// built-in transports of libgit2, used for local paths and for `http`,
+    /// `https`, `ssh` and `git` URLs unless another transport was registered
+    /// for them, can't send filters: fetching or cloning with one through
+    /// them fails before connecting.
+    ///
+    /// Cloning with a filter with [`RepoBuilder`] sets up the cloned
+    /// repository as a partial clone, so that later fetches from its remote
+    /// use the same filter and that the objects left out are fetched when
+    /// they are needed, see [`Repository::enable_lazy_fetch`].
+    ///
+    /// [`Transport::smart`]: crate::transport::Transport::smart
+    /// [`transport::register`]: crate::transport::register
+    /// [`UploadPack`]: crate::server::UploadPack
+    /// [`RepoBuilder`]: crate::build::RepoBuilder
+    pub fn filter(&mut self, filter: ObjectFilter) -> &mut Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Whether [`shallow_since`](FetchOptions::shallow_since) or
+    /// [`shallow_exclude`](FetchOptions::shallow_exclude) was set.
+    pub(crate) fn has_shallow_limits(&self) -> bool {
+        self.shallow_since.is_some() || !self.shallow_exclude.is_empty()
+    }
+
+    /// Get the payload of the callbacks given to libgit2, which identifies
+    /// the operation to its transports.
+    pub(crate) fn payload(&mut self) -> *mut c_void {
+        let callbacks = self.callbacks.get_or_insert_with(RemoteCallbacks::new);
+        callbacks as *mut RemoteCallbacks<'_> as *mut c_void
+    }
+
+    /// Get the object filter set with [`filter`](FetchOptions::filter).
+    pub(crate) fn object_filter(&self) -> Option<ObjectFilter> {
+        self.filter
+    }
+
     /// Set how to behave regarding tags on the remote, such as auto-downloading
     /// tags for objects we're downloading or downloading all of them.
     ///
@@ -648,6 +1360,8 @@ impl<'cb> PushOptions<'cb> {
             custom_headers_ptrs: Vec::new(),
             remote_push_options: Vec::new(),
             remote_push_options_ptrs: Vec::new(),
+            atomic: false,
+            leases: Vec::new(),
         }
     }
 
@@ -707,6 +1421,55 @@ impl<'cb> PushOptions<'cb> {
             .collect();
         self
     }
+
+    /// Set whether the references are updated all or none at all.
+    ///
+    /// When a [lease](PushOptions::force_with_lease) doesn't hold, nothing is
+    /// pushed and every other reference is reported as rejected with the
+    /// status `atomic push failed`. Otherwise the `atomic` capability asks
+    /// the remote to reject every update if it rejects any of them.
+    ///
+    /// libgit2 doesn't know about the capability, so it's only sent by the
+    /// smart transports created with [`Transport::smart`] and added with
+    /// [`transport::register`]. The built-in transports of libgit2, used for
+    /// local paths and for `http`, `https`, `ssh` and `git` URLs unless
+    /// another transport was registered for them, can't send it: pushing
+    /// atomically through them fails before connecting. Pushing to a remote
+    /// which doesn't advertise the capability fails as well.
+    ///
+    /// Defaults to `false`.
+    ///
+    /// [`Transport::smart`]: crate::transport::Transport::smart
+    /// [`transport::register`]: crate::transport::register
+    pub fn atomic(&mut self, atomic: bool) -> &mut Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Get the payload of the callbacks given to libgit2, which identifies
+    /// the operation to its transports.
+    pub(crate) fn payload(&mut self) -> *mut c_void {
+        let callbacks = self.callbacks.get_or_insert_with(RemoteCallbacks::new);
+        callbacks as *mut RemoteCallbacks<'_> as *mut c_void
+    }
+
+    /// Only update `refname` on the remote if it currently points to
+    /// `expected`, or doesn't exist if `expected` is zero.
+    ///
+    /// The reference is then updated even if it isn't a fast-forward, like
+    /// `git push --force-with-lease=<refname>:<expected>`. If the remote has
+    /// another value, the reference is reported to the
+    /// [`push_update_reference`](RemoteCallbacks::push_update_reference)
+    /// callback with the status `stale info`, and the push returns an error
+    /// with the code [`ErrorCode::Modified`](crate::ErrorCode::Modified)
+    /// once the other references are pushed.
+    ///
+    /// `refname` is the full name of the reference on the remote, as in the
+    /// destination of the refspecs pushed.
+    pub fn force_with_lease(&mut self, refname: &str, expected: Oid) -> &mut Self {
+        self.leases.push((refname.to_string(), expected));
+        self
+    }
 }
 
 impl<'cb> Binding for PushOptions<'cb> {
@@ -790,12 +1553,22 @@ impl RemoteRedirect {
             RemoteRedirect::All => raw::GIT_REMOTE_REDIRECT_ALL,
         }
     }
+
+    pub(crate) fn from_raw(raw: raw::git_remote_redirect_t) -> RemoteRedirect {
+        // Like libgit2, treat unknown values as not following redirects.
+        match raw {
+            raw::GIT_REMOTE_REDIRECT_INITIAL => RemoteRedirect::Initial,
+            raw::GIT_REMOTE_REDIRECT_ALL => RemoteRedirect::All,
+            _ => RemoteRedirect::None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{AutotagOption, PushOptions, RemoteUpdateFlags};
-    use crate::{Direction, FetchOptions, Remote, RemoteCallbacks, Repository};
+    use crate::{AutotagOption, Oid, PushOptions, PushPlanKind, RemoteUpdateFlags};
+    use crate::{Direction, FetchOptions, FetchUpdateKind, Remote, RemoteCallbacks, Repository};
+    use crate::{Signature, Time};
     use std::cell::Cell;
     use tempfile::TempDir;
 
@@ -1036,6 +1809,463 @@ mod tests {
         assert_eq!(commit.message(), Some("initial\n\nbody"));
     }
 
+    #[test]
+    fn push_force_with_lease() {
+        let (_td, repo) = crate::test::repo_init();
+        let base = repo.head().unwrap().target().unwrap();
+        let td2 = TempDir::new().unwrap();
+        let mut opts = crate::RepositoryInitOptions::new();
+        opts.bare(true);
+        opts.initial_head("main");
+        let upstream = Repository::init_opts(td2.path(), &opts).unwrap();
+        unsafe {
+            let upstream = Repository::open(td2.path()).unwrap();
+            crate::transport::register("git2-lease", crate::transport::in_process(upstream))
+                .unwrap();
+        }
+        let mut remote = repo.remote("origin", "git2-lease://upstream").unwrap();
+        remote.push(&["refs/heads/main"], None).unwrap();
+        let (tip, _) = crate::test::commit(&repo);
+        let tree = repo.find_commit(tip).unwrap().tree().unwrap();
+        let sig = repo.signature().unwrap();
+        let unrelated = repo.commit(None, &sig, &sig, "other", &tree, &[]).unwrap();
+        repo.reference("refs/heads/unrelated", unrelated, false, "")
+            .unwrap();
+
+        let push = |remote: &mut Remote<'_>, refspecs: &[&str], leases: &[(&str, Oid)], atomic| {
+            let mut updates = Vec::new();
+            let result = {
+                let mut callbacks = RemoteCallbacks::new();
+                callbacks.push_update_reference(|refname, status| {
+                    updates.push((refname.to_string(), status.map(|s| s.to_string())));
+                    Ok(())
+                });
+                let mut options = PushOptions::new();
+                options.remote_callbacks(callbacks).atomic(atomic);
+                for (refname, expected) in leases {
+                    options.force_with_lease(refname, *expected);
+                }
+                remote.push(refspecs, Some(&mut options))
+            };
+            updates.sort();
+            (result, updates)
+        };
+        let status = |refname: &str, status: Option<&str>| {
+            (refname.to_string(), status.map(|s| s.to_string()))
+        };
+
+        // A stale lease only rejects its own reference.
+        let (result, updates) = push(
+            &mut remote,
+            &["refs/heads/main", "refs/heads/main:refs/heads/other"],
+            &[("refs/heads/main", tip)],
+            false,
+        );
+        let err = result.unwrap_err();
+        assert_eq!(err.code(), crate::ErrorCode::Modified);
+        assert!(err.message().contains("refs/heads/main"));
+        assert_eq!(
+            updates,
+            [
+                status("refs/heads/main", Some("stale info")),
+                status("refs/heads/other", None)
+            ]
+        );
+        assert_eq!(upstream.refname_to_id("refs/heads/main").unwrap(), base);
+        assert_eq!(upstream.refname_to_id("refs/heads/other").unwrap(), tip);
+
+        // With an atomic push nothing is updated.
+        let (result, updates) = push(
+            &mut remote,
+            &["refs/heads/main", "refs/heads/main:refs/heads/third"],
+            &[("refs/heads/main", Oid::zero())],
+            true,
+        );
+        assert_eq!(result.unwrap_err().code(), crate::ErrorCode::Modified);
+        assert_eq!(
+            updates,
+            [
+                status("refs/heads/main", Some("stale info")),
+                status("refs/heads/third", Some("atomic push failed"))
+            ]
+        );
+        assert_eq!(upstream.refname_to_id("refs/heads/main").unwrap(), base);
+        assert!(upstream.find_reference("refs/heads/third").is_err());
+
+        // Leases that hold allow updates which aren't fast-forwards.
+        assert!(remote
+            .push(&["refs/heads/unrelated:refs/heads/main"], None)
+            .is_err());
+        let (result, updates) = push(
+            &mut remote,
+            &[
+                "refs/heads/unrelated:refs/heads/main",
+                "refs/heads/main:refs/heads/new",
+            ],
+            &[("refs/heads/main", base), ("refs/heads/new", Oid::zero())],
+            true,
+        );
+        result.unwrap();
+        assert_eq!(
+            updates,
+            [
+                status("refs/heads/main", None),
+                status("refs/heads/new", None)
+            ]
+        );
+        assert_eq!(
+            upstream.refname_to_id("refs/heads/main").unwrap(),
+            unrelated
+        );
+        assert_eq!(upstream.refname_to_id("refs/heads/new").unwrap(), tip);
+
+        // Leases apply to the configured refspecs when none are given.
+        for refspec in [
+            "refs/heads/main:refs/heads/mirror/main",
+            "refs/heads/unrelated:refs/heads/mirror/unrelated",
+        ] {
+            repo.remote_add_push("origin", refspec).unwrap();
+        }
+        let mut configured = repo.find_remote("origin").unwrap();
+        let (result, updates) = push(
+            &mut configured,
+            &[],
+            &[("refs/heads/mirror/main", base)],
+            false,
+        );
+        assert_eq!(result.unwrap_err().code(), crate::ErrorCode::Modified);
+        assert!(updates.contains(&status("refs/heads/mirror/main", Some("stale info"))));
+        assert!(updates.contains(&status("refs/heads/mirror/unrelated", None)));
+        assert!(upstream.find_reference("refs/heads/mirror/main").is_err());
+        assert_eq!(
+            upstream
+                .refname_to_id("refs/heads/mirror/unrelated")
+                .unwrap(),
+            unrelated
+        );
+
+        // The remote rejects every update of an atomic push if it rejects
+        // one, here as the reference changed since it was advertised.
+        remote.connect(Direction::Push).unwrap();
+        upstream
+            .find_reference("refs/heads/other")
+            .unwrap()
+            .delete()
+            .unwrap();
+        let (result, updates) = push(
+            &mut remote,
+            &[
+                "refs/heads/unrelated:refs/heads/other",
+                "refs/heads/main:refs/heads/fourth",
+            ],
+            &[("refs/heads/other", tip)],
+            true,
+        );
+        result.unwrap();
+        assert_eq!(
+            updates,
+            [
+                status("refs/heads/fourth", Some("atomic push failure")),
+                status("refs/heads/other", Some("stale info"))
+            ]
+        );
+        assert!(upstream.find_reference("refs/heads/fourth").is_err());
+
+        // The built-in transports can't push atomically.
+        let url = crate::test::path2url(&td2.path());
+        let mut local = repo.remote("local", &url).unwrap();
+        let (result, updates) = push(&mut local, &["refs/heads/main"], &[], true);
+        assert!(result.unwrap_err().message().contains("atomic pushes"));
+        assert!(updates.is_empty());
+    }
+
+    #[test]
+    fn push_dry_run() {
+        let (_td, repo) = crate::test::repo_init();
+        let base = repo.head().unwrap().target().unwrap();
+        let td2 = TempDir::new().unwrap();
+        let url = crate::test::path2url(&td2.path());
+        let mut opts = crate::RepositoryInitOptions::new();
+        opts.bare(true);
+        opts.initial_head("main");
+        let upstream = Repository::init_opts(td2.path(), &opts).unwrap();
+        let mut remote = repo.remote("origin", &url).unwrap();
+        remote
+            .push(&["refs/heads/main", "refs/heads/main:refs/heads/old"], None)
+            .unwrap();
+        assert_eq!(upstream.refname_to_id("refs/heads/old").unwrap(), base);
+        let (tip, _) = crate::test::commit(&repo);
+        let tree = repo.find_commit(tip).unwrap().tree().unwrap();
+        let sig = repo.signature().unwrap();
+        let unrelated = repo.commit(None, &sig, &sig, "other", &tree, &[]).unwrap();
+        repo.reference("refs/heads/unrelated", unrelated, false, "")
+            .unwrap();
+        // Wildcards skip references which don't resolve.
+        repo.reference_symbolic("refs/heads/dangling", "refs/heads/nowhere", false, "")
+            .unwrap();
+
+        let mut options = PushOptions::new();
+        options.force_with_lease("refs/heads/old", tip);
+        let plans = remote
+            .push_dry_run(
+                &[
+                    "HEAD",
+                    "unrelated:main",
+                    "+refs/heads/unrelated:refs/heads/old",
+                    "refs/heads/*:refs/heads/mirror/*",
+                    ":refs/heads/old",
+                ],
+                Some(&options),
+            )
+            .unwrap();
+        let plans = plans
+            .iter()
+            .map(|p| (p.src(), p.dst(), p.old_id(), p.new_id(), p.kind()))
+            .collect::<Vec<_>>();
+        let main = Some("refs/heads/main");
+        let other = Some("refs/heads/unrelated");
+        assert_eq!(
+            plans,
+            [
+                (
+                    main,
+                    "refs/heads/main",
+                    base,
+                    tip,
+                    PushPlanKind::FastForward
+                ),
+                (
+                    other,
+                    "refs/heads/main",
+                    base,
+                    unrelated,
+                    PushPlanKind::Rejected
+                ),
+                (
+                    other,
+                    "refs/heads/old",
+                    base,
+                    unrelated,
+                    PushPlanKind::Rejected
+                ),
+                (
+                    main,
+                    "refs/heads/mirror/main",
+                    Oid::zero(),
+                    tip,
+                    PushPlanKind::Create
+                ),
+                (
+                    other,
+                    "refs/heads/mirror/unrelated",
+                    Oid::zero(),
+                    unrelated,
+                    PushPlanKind::Create
+                ),
+                (
+                    None,
+                    "refs/heads/old",
+                    base,
+                    Oid::zero(),
+                    PushPlanKind::Rejected
+                ),
+            ]
+        );
+        assert!(!remote.connected());
+
+        let plans = remote
+            .push_dry_run(
+                &["+refs/heads/unrelated:refs/heads/old", ":refs/heads/old"],
+                None,
+            )
+            .unwrap();
+        let kinds = plans.iter().map(|p| p.kind()).collect::<Vec<_>>();
+        assert_eq!(kinds, [PushPlanKind::Forced, PushPlanKind::Delete]);
+
+        // Nothing was pushed.
+        assert_eq!(upstream.refname_to_id("refs/heads/main").unwrap(), base);
+        assert!(upstream.find_reference("refs/heads/mirror/main").is_err());
+    }
+
+    #[test]
+    fn shallow() {
+        let (td, upstream) = crate::test::repo_init();
+        let sig = upstream.signature().unwrap();
+        let mut commits = Vec::new();
+        for i in 1..=5 {
+            let time = Time::new(i * 1000, 0);
+            let sig = Signature::new(sig.name().unwrap(), sig.email().unwrap(), &time).unwrap();
+            let parent = upstream.head().unwrap().peel_to_commit().unwrap();
+            let tree = parent.tree().unwrap();
+            let id = upstream
+                .commit(Some("HEAD"), &sig, &sig, "commit", &tree, &[&parent])
+                .unwrap();
+            commits.push(id);
+        }
+        upstream
+            .reference("refs/tags/v2", commits[1], false, "")
+            .unwrap();
+        unsafe {
+            let upstream = Repository::open(td.path()).unwrap();
+            crate::transport::register("git2-shallow", crate::transport::in_process(upstream))
+                .unwrap();
+        }
+
+        let clone = |td: &TempDir| {
+            let mut options = FetchOptions::new();
+            options.depth(1);
+            crate::build::RepoBuilder::new()
+                .fetch_options(options)
+                .clone("git2-shallow://upstream", td.path())
+                .unwrap()
+        };
+        let fetch = |repo: &Repository, options: &mut FetchOptions<'_>| {
+            let mut origin = repo.find_remote("origin").unwrap();
+            origin.fetch(&[] as &[&str], Some(options), None).unwrap();
+        };
+
+        let td2 = TempDir::new().unwrap();
+        let repo = clone(&td2);
+        assert_eq!(repo.shallow_roots().unwrap(), [commits[4]]);
+
+        fetch(&repo, FetchOptions::new().deepen(1));
+        assert_eq!(repo.shallow_roots().unwrap(), [commits[3]]);
+
+        fetch(&repo, FetchOptions::new().shallow_since(Time::new(2500, 0)));
+        assert_eq!(repo.shallow_roots().unwrap(), [commits[2]]);
+        assert!(repo.find_commit(commits[1]).is_err());
+
+        fetch(&repo, FetchOptions::new().unshallow());
+        assert!(!repo.is_shallow());
+        assert!(repo.shallow_roots().unwrap().is_empty());
+        assert!(repo.find_commit(commits[0]).is_ok());
+
+        let td3 = TempDir::new().unwrap();
+        let repo = clone(&td3);
+        fetch(&repo, FetchOptions::new().shallow_exclude(&["v2"]));
+        assert_eq!(repo.shallow_roots().unwrap(), [commits[2]]);
+
+        // Only the transports implemented here can send these, and servers
+        // don't take them along with a depth.
+        let mut origin = repo.find_remote("origin").unwrap();
+        let mut options = FetchOptions::new();
+        options.depth(2).shallow_since(Time::new(2500, 0));
+        let err = origin.fetch(&[] as &[&str], Some(&mut options), None);
+        assert!(err.unwrap_err().message().contains("depth"));
+        let url = crate::test::path2url(td.path());
+        let mut remote = repo.remote_anonymous(&url).unwrap();
+        let mut options = FetchOptions::new();
+        options.shallow_since(Time::new(2500, 0));
+        let err = remote.fetch(&[] as &[&str], Some(&mut options), None);
+        assert!(err.unwrap_err().message().contains("transport::register"));
+
+        // Nor can clones, which libgit2 makes.
+        let td4 = TempDir::new().unwrap();
+        let mut options = FetchOptions::new();
+        options.shallow_exclude(&["v2"]);
+        let err = crate::build::RepoBuilder::new()
+            .fetch_options(options)
+            .clone("git2-shallow://upstream", td4.path())
+            .err()
+            .unwrap();
+        assert!(err.message().contains("clone"));
+
+        let repo = Repository::new_in_memory().unwrap();
+        assert!(repo.shallow_roots().unwrap().is_empty());
+    }
+
+    #[test]
+    fn fetch_report() {
+        let (td, upstream) = crate::test::repo_init();
+        let base = upstream.head().unwrap().target().unwrap();
+        let commit = upstream.find_commit(base).unwrap();
+        upstream.branch("stale", &commit, false).unwrap();
+        upstream.branch("rewritten", &commit, false).unwrap();
+        let td2 = TempDir::new().unwrap();
+        let url = crate::test::path2url(&td.path());
+        let repo = Repository::clone(&url, &td2).unwrap();
+
+        let (tip, tree) = crate::test::commit(&upstream);
+        upstream.branch("new", &commit, false).unwrap();
+        upstream
+            .find_branch("stale", crate::BranchType::Local)
+            .unwrap()
+            .delete()
+            .unwrap();
+        let tree = upstream.find_tree(tree).unwrap();
+        let sig = upstream.signature().unwrap();
+        let unrelated = upstream
+            .commit(None, &sig, &sig, "other", &tree, &[])
+            .unwrap();
+        upstream
+            .reference("refs/heads/rewritten", unrelated, true, "")
+            .unwrap();
+
+        let mut tips = Vec::new();
+        let report = {
+            let mut callbacks = RemoteCallbacks::new();
+            callbacks.update_tips(|refname, _, _| {
+                tips.push(refname.to_string());
+                true
+            });
+            let mut options = FetchOptions::new();
+            options
+                .remote_callbacks(callbacks)
+                .prune(crate::FetchPrune::On);
+            let mut origin = repo.find_remote("origin").unwrap();
+            origin
+                .fetch_report(&[] as &[&str], Some(&mut options), None)
+                .unwrap()
+        };
+        let mut updates = report
+            .updates()
+            .iter()
+            .map(|u| (u.name(), u.old_id(), u.new_id(), u.kind()))
+            .collect::<Vec<_>>();
+        updates.sort_by_key(|u| u.0);
+        assert_eq!(
+            updates,
+            [
+                (
+                    "refs/remotes/origin/main",
+                    base,
+                    tip,
+                    FetchUpdateKind::FastForward
+                ),
+                (
+                    "refs/remotes/origin/new",
+                    Oid::zero(),
+                    base,
+                    FetchUpdateKind::Created
+                ),
+                (
+                    "refs/remotes/origin/rewritten",
+                    base,
+                    unrelated,
+                    FetchUpdateKind::Forced
+                ),
+                (
+                    "refs/remotes/origin/stale",
+                    base,
+                    Oid::zero(),
+                    FetchUpdateKind::Pruned
+                ),
+            ]
+        );
+        assert!(report.stats().received_objects() > 0);
+        // Unchanged tips are only reported to the callback if asked for.
+        assert_eq!(tips.len(), 4);
+
+        let mut origin = repo.find_remote("origin").unwrap();
+        let report = origin.fetch_report(&[] as &[&str], None, None).unwrap();
+        assert!(report
+            .updates()
+            .iter()
+            .all(|u| u.kind() == FetchUpdateKind::Unchanged));
+        assert_eq!(report.updates().len(), 3);
+        assert_eq!(report.stats().received_objects(), 0);
+    }
+
     #[test]
     fn prune() {
         let (td, remote_repo) = crate::test::repo_init();
@@ -6,6 +6,13 @@ use std::slice;
 use std::str;
 
 use crate::cert::Cert;
+#[cfg(all(
+    unix,
+    not(target_os = "macos"),
+    not(target_os = "ios"),
+    feature = "https"
+))]
+use crate::cert::X509Roots;
 use crate::util::Binding;
 use crate::{
     panic, raw, Cred, CredentialType, Error, IndexerProgress, Oid, PackBuilderStage, Progress,
@@ -27,6 +34,16 @@ pub struct RemoteCallbacks<'a> {
     certificate_check: Option<Box<CertificateCheck<'a>>>,
     push_update_reference: Option<Box<PushUpdateReference<'a>>>,
     push_negotiation: Option<Box<PushNegotiation<'a>>>,
+    tips_log: Option<TipsLog>,
+}
+
+/// The tips updated during a fetch, recorded for
+/// [`Remote::fetch_report`](crate::Remote::fetch_report).
+pub(crate) struct TipsLog {
+    /// The reference name, the old and the new object of each update.
+    pub(crate) updates: Vec<(String, Oid, Oid)>,
+    /// Whether the `update_tips` callback wants unchanged tips as well.
+    pub(crate) report_unchanged: bool,
 }
 
 /// Callback used to acquire credentials for when a remote is fetched.
@@ -119,6 +136,7 @@ impl<'a> RemoteCallbacks<'a> {
             push_update_reference: None,
             push_progress: None,
             push_negotiation: None,
+            tips_log: None,
         }
     }
 
@@ -193,6 +211,27 @@ impl<'a> RemoteCallbacks<'a> {
         self
     }
 
+    /// Verify the X.509 certificates of HTTPS remotes against `roots` only,
+    /// instead of the system's trust store.
+    ///
+    /// This replaces the certificate check callback. SSH host keys are left
+    /// to libgit2.
+    #[cfg(all(
+        unix,
+        not(target_os = "macos"),
+        not(target_os = "ios"),
+        feature = "https"
+    ))]
+    pub fn verify_x509_with(&mut self, roots: X509Roots) -> &mut RemoteCallbacks<'a> {
+        self.certificate_check(move |cert, host| match cert.as_x509() {
+            Some(x509) => {
+                roots.verify(x509, host)?;
+                Ok(CertificateCheckStatus::CertificateOk)
+            }
+            None => Ok(CertificateCheckStatus::CertificatePassthrough),
+        })
+    }
+
     /// Set a callback to get invoked for each updated reference on a push.
     ///
     /// The first argument to the callback is the name of the reference and the
@@ -206,6 +245,25 @@ impl<'a> RemoteCallbacks<'a> {
         self
     }
 
+    /// Start or stop recording the tips updated, returning what was recorded
+    /// so far.
+    pub(crate) fn record_tips(&mut self, log: Option<TipsLog>) -> Option<TipsLog> {
+        mem::replace(&mut self.tips_log, log)
+    }
+
+    /// Report the status of a reference to the `push_update_reference`
+    /// callback, for updates rejected before they reach the remote.
+    pub(crate) fn report_push_update(
+        &mut self,
+        refname: &str,
+        status: Option<&str>,
+    ) -> Result<(), Error> {
+        match self.push_update_reference {
+            Some(ref mut callback) => callback(refname, status),
+            None => Ok(()),
+        }
+    }
+
     /// The callback through which progress of push transfer is monitored
     ///
     /// Parameters:
@@ -286,7 +344,7 @@ impl<'a> Binding for RemoteCallbacks<'a> {
             if self.pack_progress.is_some() {
                 callbacks.pack_progress = Some(pack_progress_cb);
             }
-            if self.update_tips.is_some() {
+            if self.update_tips.is_some() || self.tips_log.is_some() {
                 let f: extern "C" fn(
                     *const c_char,
                     *const raw::git_oid,
@@ -394,13 +452,19 @@ extern "C" fn update_tips_cb(
 ) -> c_int {
     let ok = panic::wrap(|| unsafe {
         let payload = &mut *(data as *mut RemoteCallbacks<'_>);
+        let refname = str::from_utf8(CStr::from_ptr(refname).to_bytes()).unwrap();
+        let a = Binding::from_raw(a);
+        let b = Binding::from_raw(b);
+        if let Some(ref mut log) = payload.tips_log {
+            log.updates.push((refname.to_string(), a, b));
+            if a == b && !log.report_unchanged {
+                return true;
+            }
+        }
         let callback = match payload.update_tips {
             Some(ref mut c) => c,
             None => return true,
         };
-        let refname = str::from_utf8(CStr::from_ptr(refname).to_bytes()).unwrap();
-        let a = Binding::from_raw(a);
-        let b = Binding::from_raw(b);
         callback(refname, a, b)
     });
     if ok == Some(true) {
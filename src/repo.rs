@@ -1,16 +1,20 @@
 use libc::{c_char, c_int, c_uint, c_void, size_t};
 use std::env;
 use std::ffi::{CStr, CString, OsStr};
+use std::fs;
+use std::io;
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::ptr;
 use std::str;
 
 use crate::build::{CheckoutBuilder, RepoBuilder};
+use crate::config_backend::MemoryConfig;
 use crate::diff::{
     binary_cb_c, file_cb_c, hunk_cb_c, line_cb_c, BinaryCb, DiffCallbacks, FileCb, HunkCb, LineCb,
 };
 use crate::oid_array::OidArray;
+use crate::refdb::MemoryRefdb;
 use crate::stash::{stash_cb, StashApplyOptions, StashCbData, StashSaveOptions};
 use crate::string_array::StringArray;
 use crate::tagforeach::{tag_foreach_cb, TagForeachCB, TagForeachData};
@@ -32,8 +36,9 @@ use crate::{Blame, BlameOptions, Reference, References, ResetType, Signature, Su
 use crate::{
     Blob, BlobWriter, Branch, BranchType, Branches, Commit, Config, Index, IndexEntry, Oid, Tree,
 };
-use crate::{Describe, IntoCString, Reflog, RepositoryInitMode, RevparseMode};
-use crate::{DescribeOptions, Diff, DiffOptions, Odb, PackBuilder, TreeBuilder};
+use crate::{ConfigLevel, Describe, IntoCString, Reflog, RepositoryInitMode, RevparseMode};
+use crate::{DescribeOptions, Diff, DiffOptions, Mempack, Odb, PackBuilder, Refdb, TreeBuilder};
+use crate::{ErrorClass, ErrorCode, FetchOptions};
 use crate::{Note, Notes, ObjectType, Revwalk, Status, StatusOptions, Statuses, Tag, Transaction};
 
 type MergeheadForeachCb<'a> = dyn FnMut(&Oid) -> bool + 'a;
@@ -108,6 +113,9 @@ extern "C" fn fetchhead_foreach_cb(
 /// from the filesystem.
 pub struct Repository {
     raw: *mut raw::git_repository,
+    /// The object database of a repository created by
+    /// [`Repository::new_in_memory`], kept alive along with its mempack.
+    mempack: Option<(Odb<'static>, *mut raw::git_odb_backend)>,
 }
 
 // It is the current belief that a `Repository` can be sent among threads, or
@@ -352,6 +360,61 @@ impl Repository {
         }
     }
 
+    /// Create a new bare repository which lives entirely in memory.
+    ///
+    /// Objects are stored in a mempack backed object database, references in
+    /// an in-memory reference database and configuration in an in-memory
+    /// config, so nothing is ever read from or written to the filesystem.
+    /// `HEAD` initially points to the unborn branch `refs/heads/master`.
+    /// Like other custom reference databases, it keeps no reflogs, so
+    /// [`Repository::reflog`] and revisions such as `HEAD@{1}` fail.
+    ///
+    /// Everything stored in the repository is lost when it is dropped. The
+    /// mempack holding its objects is available through
+    /// [`Repository::mempack`], and [`Mempack::dump`](crate::Mempack::dump)
+    /// writes them out as a pack.
+    pub fn new_in_memory() -> Result<Repository, Error> {
+        crate::init();
+        let mut ret = ptr::null_mut();
+        let mut repo: Repository = unsafe {
+            try_call!(raw::git_repository_new(&mut ret));
+            Binding::from_raw(ret)
+        };
+
+        let odb = Odb::new()?;
+        let mempack = odb.add_new_mempack_backend(1)?.raw();
+        repo.set_odb(&odb)?;
+        repo.mempack = Some((odb, mempack));
+
+        {
+            let refdb = Refdb::new(&repo)?;
+            refdb.set_backend(MemoryRefdb::default())?;
+            repo.set_refdb(&refdb)?;
+        }
+
+        let mut config = Config::new()?;
+        config.add_backend(MemoryConfig::default(), ConfigLevel::Local, false)?;
+        unsafe {
+            try_call!(raw::git_repository_set_config(repo.raw(), config.raw()));
+        }
+
+        repo.reference_symbolic("HEAD", "refs/heads/master", false, "")?;
+        Ok(repo)
+    }
+
+    /// Get the mempack holding the objects of a repository created with
+    /// [`Repository::new_in_memory`].
+    ///
+    /// Returns `None` for other repositories, and once the object database
+    /// of the repository was replaced with [`Repository::set_odb`].
+    pub fn mempack(&self) -> Option<Mempack<'_>> {
+        let (odb, mempack) = self.mempack.as_ref()?;
+        if self.odb().ok()?.raw() != odb.raw() {
+            return None;
+        }
+        Some(unsafe { Mempack::from_raw(*mempack) })
+    }
+
     /// Update submodules recursively.
     ///
     /// Uninitialized submodules will be initialized.
@@ -438,6 +501,71 @@ impl Repository {
         unsafe { raw::git_repository_is_shallow(self.raw) == 1 }
     }
 
+    /// Get the shallow roots of the repository, the commits whose parents
+    /// are missing, as listed in its `shallow` file.
+    ///
+    /// The list is empty if the repository isn't shallow, or has no
+    /// directory, like those made by [`Repository::new_in_memory`].
+    pub fn shallow_roots(&self) -> Result<Vec<Oid>, Error> {
+        // The file is shared by all the worktrees of the repository.
+        let commondir = unsafe {
+            let ptr = raw::git_repository_commondir(self.raw);
+            crate::opt_bytes(self, ptr).map(util::bytes2path)
+        };
+        let path = match commondir {
+            Some(commondir) => commondir.join("shallow"),
+            None => return Ok(Vec::new()),
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(Error::from_str(&format!(
+                    "failed to read '{}': {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(Oid::from_str)
+            .collect()
+    }
+
+    /// Fetch the objects missing from this partial clone from its promisor
+    /// remote when they are read.
+    ///
+    /// Partial clones, made by cloning with [`FetchOptions::filter`], lack
+    /// some of the objects of their history. Once this is called, reading
+    /// one of them through this handle fetches it from the remote with the
+    /// options returned by `options`, instead of failing. Every object is
+    /// fetched on its own, as it's read; checking whether an object exists
+    /// doesn't fetch it. The repository returned by
+    /// [`RepoBuilder::clone`](crate::build::RepoBuilder::clone) already
+    /// fetches its missing objects with default options, which is how the
+    /// working directory of a partial clone is checked out.
+    ///
+    /// Returns an error if the repository doesn't have a promisor remote.
+    pub fn enable_lazy_fetch<F>(&self, options: F) -> Result<(), Error>
+    where
+        F: Fn() -> FetchOptions<'static> + Send + Sync + 'static,
+    {
+        let remote = match crate::partial_clone::promisor_remote(self)? {
+            Some(remote) => remote,
+            None => {
+                return Err(Error::new(
+                    ErrorCode::NotFound,
+                    ErrorClass::Config,
+                    "the repository is not a partial clone",
+                ))
+            }
+        };
+        let backend = crate::partial_clone::LazyFetch::new(self, &remote, options);
+        self.odb()?.add_backend(backend, 0)
+    }
+
     /// Tests whether this repository is a worktree.
     pub fn is_worktree(&self) -> bool {
         unsafe { raw::git_repository_is_worktree(self.raw) == 1 }
@@ -1218,6 +1346,23 @@ impl Repository {
         Ok(())
     }
 
+    /// Get the reference database for this repository
+    pub fn refdb(&self) -> Result<Refdb<'_>, Error> {
+        let mut refdb = ptr::null_mut();
+        unsafe {
+            try_call!(raw::git_repository_refdb(&mut refdb, self.raw()));
+            Ok(Refdb::from_raw(refdb))
+        }
+    }
+
+    /// Override the reference database for this repository
+    pub fn set_refdb(&self, refdb: &Refdb<'_>) -> Result<(), Error> {
+        unsafe {
+            try_call!(raw::git_repository_set_refdb(self.raw(), refdb.raw()));
+        }
+        Ok(())
+    }
+
     /// Create a new branch pointing at a target commit
     ///
     /// A new direct reference will be created pointing to this target commit.
@@ -2643,7 +2788,10 @@ impl Repository {
     /// Read the reflog for the given reference
     ///
     /// If there is no reflog file for the given reference yet, an empty reflog
-    /// object will be returned.
+    /// object will be returned. Repositories using a custom reference
+    /// database, such as [`Repository::new_in_memory`], have no reflogs to
+    /// read, and this fails with [`ErrorCode::NotFound`](crate::ErrorCode)
+    /// for them, see [`RefdbBackend`](crate::RefdbBackend).
     pub fn reflog(&self, name: &str) -> Result<Reflog, Error> {
         let name = CString::new(name)?;
         let mut ret = ptr::null_mut();
@@ -3381,7 +3529,10 @@ impl Repository {
 impl Binding for Repository {
     type Raw = *mut raw::git_repository;
     unsafe fn from_raw(ptr: *mut raw::git_repository) -> Repository {
-        Repository { raw: ptr }
+        Repository {
+            raw: ptr,
+            mempack: None,
+        }
     }
     fn raw(&self) -> *mut raw::git_repository {
         self.raw
@@ -3585,6 +3736,89 @@ mod tests {
         assert!(repo.namespace().is_none());
     }
 
+    #[test]
+    fn smoke_new_in_memory() {
+        let repo = Repository::new_in_memory().unwrap();
+        assert!(repo.is_bare());
+        assert!(repo.is_empty().unwrap());
+        assert!(repo.workdir().is_none());
+
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "name").unwrap();
+        config.set_str("user.email", "email").unwrap();
+        assert_eq!(
+            repo.config().unwrap().get_string("user.name").unwrap(),
+            "name"
+        );
+        let sig = repo.signature().unwrap();
+        assert_eq!(sig.name(), Some("name"));
+
+        let blob = repo.blob(b"hello\n").unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("hello.txt", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let first = repo
+            .commit(Some("HEAD"), &sig, &sig, "first", &tree, &[])
+            .unwrap();
+        assert_eq!(repo.head().unwrap().name(), Some("refs/heads/master"));
+        assert_eq!(repo.head().unwrap().target(), Some(first));
+        assert!(!repo.is_empty().unwrap());
+
+        let blob = repo.blob(b"world\n").unwrap();
+        let mut builder = repo.treebuilder(Some(&tree)).unwrap();
+        builder.insert("world.txt", blob, 0o100644).unwrap();
+        let tree2 = repo.find_tree(builder.write().unwrap()).unwrap();
+        let parent = repo.find_commit(first).unwrap();
+        let second = repo
+            .commit(Some("HEAD"), &sig, &sig, "second", &tree2, &[&parent])
+            .unwrap();
+        assert_eq!(repo.revparse_single("HEAD~1").unwrap().id(), first);
+
+        repo.branch("topic", &parent, false).unwrap();
+        repo.tag_lightweight("v1", parent.as_object(), false)
+            .unwrap();
+        let names: Vec<String> = repo
+            .references()
+            .unwrap()
+            .names()
+            .map(|n| n.unwrap().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            ["refs/heads/master", "refs/heads/topic", "refs/tags/v1"]
+        );
+        assert_eq!(repo.refname_to_id("HEAD").unwrap(), second);
+        // custom reference databases can't provide reflogs
+        let err = repo.reflog("refs/heads/master").err().unwrap();
+        assert_eq!(err.code(), crate::ErrorCode::NotFound);
+
+        let diff = repo
+            .diff_tree_to_tree(Some(&tree), Some(&tree2), None)
+            .unwrap();
+        assert_eq!(diff.deltas().len(), 1);
+        let delta = diff.deltas().next().unwrap();
+        assert_eq!(delta.new_file().path(), Some(Path::new("world.txt")));
+
+        // every repository gets its own storage
+        let other = Repository::new_in_memory().unwrap();
+        assert!(other.is_empty().unwrap());
+        assert!(other.find_commit(first).is_err());
+        assert!(other.config().unwrap().get_str("user.name").is_err());
+
+        let odb = repo.odb().unwrap();
+        let mempack = repo.mempack().unwrap();
+        let mut buf = crate::Buf::new();
+        mempack.dump(&repo, &mut buf).unwrap();
+        assert!(buf.starts_with(b"PACK"));
+        mempack.reset().unwrap();
+        assert!(!odb.exists(first));
+
+        let (_td, disk) = crate::test::repo_init();
+        assert!(disk.mempack().is_none());
+        other.set_odb(&crate::Odb::new().unwrap()).unwrap();
+        assert!(other.mempack().is_none());
+    }
+
     #[test]
     fn smoke_open() {
         let td = TempDir::new().unwrap();
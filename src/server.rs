@@ -0,0 +1,1805 @@
+//! Serving repositories over the git protocol
+//!
+//! libgit2 only implements the client side of the git protocol. The types in
+//! this module implement the server side on top of any duplex stream, which
+//! allows serving a repository without running `git upload-pack` or
+//! `git receive-pack`.
+//!
+//! The transport carrying the stream is up to the caller: a plain socket for
+//! `git://`, the standard input and output of an SSH session, or the bodies
+//! of HTTP requests for the smart HTTP protocol, in which case the server is
+//! run in [stateless RPC](UploadPack::stateless_rpc) mode.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, prelude::*};
+use std::str;
+
+use crate::util::Sha1;
+use crate::{Buf, Error, ErrorClass, ErrorCode, ObjectFilter, ObjectType, Oid, PackBuilder};
+use crate::{Reference, Repository, Revwalk};
+
+/// Versions of the git wire protocol.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum ProtocolVersion {
+    /// The original protocol, used when the client doesn't ask for a version.
+    V0,
+    /// Protocol version 2.
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Determine the protocol version a client asked for.
+    ///
+    /// `value` is the colon-separated list of parameters sent by the client:
+    /// the `GIT_PROTOCOL` environment variable for SSH, the `Git-Protocol`
+    /// header for HTTP, or the extra parameters of a `git://` request.
+    pub fn from_parameters(value: &str) -> ProtocolVersion {
+        let v2 = value
+            .split(':')
+            .filter_map(|param| param.strip_prefix("version="))
+            .any(|version| version == "2");
+        if v2 {
+            ProtocolVersion::V2
+        } else {
+            ProtocolVersion::V0
+        }
+    }
+}
+
+/// The server side of fetches and clones, the equivalent of
+/// `git upload-pack`.
+///
+/// It advertises the references of the repository, negotiates with the
+/// client which objects it is missing and sends them as a pack built with
+/// [`PackBuilder`].
+///
+/// Shallow fetches can be limited by depth, date or excluded references.
+/// Partial clones can be filtered with the filters of [`ObjectFilter`].
+/// Clients may ask for the objects references point to and for any commit
+/// reachable from them. Partial clones also ask for the trees and blobs they
+/// miss, which needs [`UploadPack::allow_any_sha1_in_want`].
+pub struct UploadPack<'repo> {
+    repo: &'repo Repository,
+    version: ProtocolVersion,
+    stateless_rpc: bool,
+    allow_any_sha1_in_want: bool,
+}
+
+/// A reference as advertised to clients.
+struct AdvertisedRef {
+    name: String,
+    id: Oid,
+    peeled: Option<Oid>,
+    symref_target: Option<String>,
+}
+
+/// The options a client asked for when fetching.
+#[derive(Default)]
+struct FetchCaps {
+    multi_ack: bool,
+    multi_ack_detailed: bool,
+    include_tag: bool,
+    /// The maximum amount of data in a side-band packet, or zero if the pack
+    /// is sent without one.
+    sideband: usize,
+    /// The number of commits of history to send, or zero for all of it.
+    depth: usize,
+    /// Commits older than this, in seconds since the epoch, aren't sent.
+    deepen_since: Option<i64>,
+    /// Commits reachable from these aren't sent.
+    deepen_not: Vec<Oid>,
+    /// The commits whose parents are missing from the client.
+    shallow: HashSet<Oid>,
+    /// The filter of a partial clone.
+    filter: Option<ObjectFilter>,
+}
+
+/// The commits to send to a client which has or wants a shallow history.
+struct ShallowPlan {
+    commits: Vec<Oid>,
+    /// Commits whose parents are not sent.
+    shallow: Vec<Oid>,
+    /// Shallow commits of the client whose parents are sent.
+    unshallow: Vec<Oid>,
+}
+
+const V0_CAPABILITIES: &str = "multi_ack multi_ack_detailed side-band side-band-64k ofs-delta \
+    shallow deepen-since deepen-not no-progress include-tag filter allow-reachable-sha1-in-want";
+
+impl<'repo> UploadPack<'repo> {
+    /// Create a new server for fetches from `repo`, using protocol version 0.
+    pub fn new(repo: &'repo Repository) -> UploadPack<'repo> {
+        UploadPack {
+            repo,
+            version: ProtocolVersion::V0,
+            stateless_rpc: false,
+            allow_any_sha1_in_want: false,
+        }
+    }
+
+    /// Set the version of the protocol to speak.
+    ///
+    /// Clients ask for a version out of band, see
+    /// [`ProtocolVersion::from_parameters`].
+    pub fn protocol_version(&mut self, version: ProtocolVersion) -> &mut UploadPack<'repo> {
+        self.version = version;
+        self
+    }
+
+    /// Serve a single request of a stateless protocol such as smart HTTP.
+    ///
+    /// In this mode [`UploadPack::serve`] doesn't advertise the references
+    /// first, and returns after answering one request. The advertisement is
+    /// sent separately with [`UploadPack::advertise`], in answer to the
+    /// initial `GET` of `info/refs`. Defaults to `false`.
+    pub fn stateless_rpc(&mut self, stateless: bool) -> &mut UploadPack<'repo> {
+        self.stateless_rpc = stateless;
+        self
+    }
+
+    /// Let clients ask for any object of the repository, like git's
+    /// `uploadpack.allowAnySHA1InWant`.
+    ///
+    /// Otherwise, clients may only ask for the objects references point to
+    /// and for the commits reachable from them, which keeps objects removed
+    /// from the history out of their reach. Partial clones need this to fetch
+    /// the trees and blobs they miss. Defaults to `false`.
+    pub fn allow_any_sha1_in_want(&mut self, allow: bool) -> &mut UploadPack<'repo> {
+        self.allow_any_sha1_in_want = allow;
+        self
+    }
+
+    /// Write the advertisement sent to clients when they connect.
+    ///
+    /// For protocol version 0 these are the references of the repository,
+    /// for version 2 the capabilities of the server. Smart HTTP servers must
+    /// precede it with the `# service=git-upload-pack` packet themselves.
+    pub fn advertise<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        match self.version {
+            ProtocolVersion::V0 => {
+                let refs = refs(self.repo)?;
+                let mut caps = format!("{} agent={}", V0_CAPABILITIES, agent());
+                if self.allow_any_sha1_in_want {
+                    caps.push_str(" allow-any-sha1-in-want");
+                }
+                let head = refs.iter().find(|r| r.name == "HEAD");
+                if let Some(target) = head.and_then(|r| r.symref_target.as_ref()) {
+                    caps.push_str(&format!(" symref=HEAD:{}", target));
+                }
+                if refs.is_empty() {
+                    pkt_line(
+                        &mut buf,
+                        &format!("{} capabilities^{{}}\0{}", Oid::zero(), caps),
+                    );
+                }
+                for (i, r) in refs.iter().enumerate() {
+                    if i == 0 {
+                        pkt_line(&mut buf, &format!("{} {}\0{}", r.id, r.name, caps));
+                    } else {
+                        pkt_line(&mut buf, &format!("{} {}", r.id, r.name));
+                    }
+                    if let Some(peeled) = r.peeled {
+                        pkt_line(&mut buf, &format!("{} {}^{{}}", peeled, r.name));
+                    }
+                }
+            }
+            ProtocolVersion::V2 => {
+                pkt_line(&mut buf, "version 2");
+                pkt_line(&mut buf, &format!("agent={}", agent()));
+                pkt_line(&mut buf, "ls-refs=unborn");
+                pkt_line(&mut buf, "fetch=shallow filter");
+                pkt_line(&mut buf, "object-format=sha1");
+            }
+        }
+        buf.extend_from_slice(FLUSH);
+        send(&mut out, &buf)
+    }
+
+    /// Serve a client connected over `stream`.
+    ///
+    /// This returns once the client is done, or after a single request in
+    /// [stateless RPC](UploadPack::stateless_rpc) mode. Clients which hang
+    /// up right after the advertisement, for example to only list the
+    /// references, are not considered an error.
+    pub fn serve<S: Read + Write>(&self, mut stream: S) -> Result<(), Error> {
+        if !self.stateless_rpc {
+            self.advertise(&mut stream)?;
+        }
+        match self.version {
+            ProtocolVersion::V0 => self.serve_v0(&mut stream),
+            ProtocolVersion::V2 => {
+                while self.serve_v2_command(&mut stream)? && !self.stateless_rpc {}
+                Ok(())
+            }
+        }
+    }
+
+    fn serve_v0<S: Read + Write>(&self, stream: &mut S) -> Result<(), Error> {
+        let mut wants = Vec::new();
+        let mut caps = FetchCaps::default();
+        let mut shallow = HashSet::new();
+        let mut depth = 0;
+        let mut deepen_since = None;
+        let mut deepen_not = Vec::new();
+        let mut filter = None;
+        loop {
+            let data = match read_pkt(stream)? {
+                None | Some(Pkt::Flush) => break,
+                Some(Pkt::Data(data)) => data,
+                Some(_) => return Err(protocol_error("unexpected special packet")),
+            };
+            let line = text(&data)?;
+            if let Some(rest) = line.strip_prefix("want ") {
+                let mut parts = rest.split(' ');
+                wants.push(parse_oid(parts.next().unwrap_or(""))?);
+                if wants.len() == 1 {
+                    caps = FetchCaps::parse(parts);
+                }
+            } else if let Some(id) = line.strip_prefix("shallow ") {
+                shallow.insert(parse_oid(id)?);
+            } else if let Some(n) = line.strip_prefix("deepen ") {
+                depth = parse_depth(stream, n)?;
+            } else if let Some(time) = line.strip_prefix("deepen-since ") {
+                deepen_since = Some(parse_time(stream, time)?);
+            } else if let Some(name) = line.strip_prefix("deepen-not ") {
+                deepen_not.push(self.parse_deepen_not(stream, name)?);
+            } else if let Some(spec) = line.strip_prefix("filter ") {
+                filter = Some(parse_filter(stream, spec)?);
+            } else {
+                return Err(reject(stream, &format!("unexpected '{}'", line)));
+            }
+        }
+        if wants.is_empty() {
+            return Ok(());
+        }
+        self.check_wants(stream, &wants)?;
+        caps.shallow = shallow;
+        caps.depth = depth;
+        caps.deepen_since = deepen_since;
+        caps.deepen_not = deepen_not;
+        caps.filter = filter;
+        if caps.deepens() {
+            let mut buf = Vec::new();
+            self.shallow_info(&mut buf, &wants, &caps)?;
+            buf.extend_from_slice(FLUSH);
+            send(stream, &buf)?;
+        }
+
+        let odb = self.repo.odb()?;
+        let mut common = Vec::new();
+        let mut buf = Vec::new();
+        loop {
+            let line = match read_pkt(stream)? {
+                Some(Pkt::Data(data)) => data,
+                Some(Pkt::Flush) => {
+                    if common.is_empty() || caps.multi_ack || caps.multi_ack_detailed {
+                        pkt_line(&mut buf, "NAK");
+                    }
+                    send(stream, &buf)?;
+                    buf.clear();
+                    if self.stateless_rpc {
+                        return Ok(());
+                    }
+                    continue;
+                }
+                Some(_) => return Err(protocol_error("unexpected special packet")),
+                // Stateless clients only send their wants to learn about the
+                // shallow commits first.
+                None if self.stateless_rpc && caps.deepens() => return Ok(()),
+                None => return Err(protocol_error("unexpected end of stream")),
+            };
+            let line = text(&line)?;
+            if line == "done" {
+                match common.last() {
+                    Some(last) if caps.multi_ack || caps.multi_ack_detailed => {
+                        pkt_line(&mut buf, &format!("ACK {}", last))
+                    }
+                    Some(_) => {}
+                    None => pkt_line(&mut buf, "NAK"),
+                }
+                send(stream, &buf)?;
+                break;
+            }
+            let id = match line.strip_prefix("have ") {
+                Some(id) => parse_oid(id)?,
+                None => return Err(reject(stream, &format!("unexpected '{}'", line))),
+            };
+            if !odb.exists(id) {
+                continue;
+            }
+            common.push(id);
+            if caps.multi_ack_detailed {
+                pkt_line(&mut buf, &format!("ACK {} common", id));
+            } else if caps.multi_ack {
+                pkt_line(&mut buf, &format!("ACK {} continue", id));
+            } else if common.len() == 1 {
+                pkt_line(&mut buf, &format!("ACK {}", id));
+            }
+        }
+        self.send_pack(stream, &wants, &common, &caps)
+    }
+
+    /// Serve one command of protocol version 2, returning `false` once the
+    /// client has no more commands.
+    fn serve_v2_command<S: Read + Write>(&self, stream: &mut S) -> Result<bool, Error> {
+        let command = match read_pkt(stream)? {
+            None | Some(Pkt::Flush) => return Ok(false),
+            Some(Pkt::Data(data)) => match text(&data)?.strip_prefix("command=") {
+                Some(command) => command.to_string(),
+                None => return Err(protocol_error("expected a command")),
+            },
+            Some(_) => return Err(protocol_error("unexpected special packet")),
+        };
+        let mut args = Vec::new();
+        let mut has_args = false;
+        loop {
+            match read_pkt(stream)? {
+                Some(Pkt::Delim) => has_args = true,
+                Some(Pkt::Flush) => break,
+                Some(Pkt::Data(data)) => {
+                    let line = text(&data)?;
+                    if has_args {
+                        args.push(line.to_string());
+                    } else if let Some(format) = line.strip_prefix("object-format=") {
+                        if format != "sha1" {
+                            let msg = format!("unsupported object format '{}'", format);
+                            return Err(reject(stream, &msg));
+                        }
+                    }
+                }
+                Some(Pkt::End) => return Err(protocol_error("unexpected special packet")),
+                None => return Err(protocol_error("unexpected end of stream")),
+            }
+        }
+        match &command[..] {
+            "ls-refs" => self.ls_refs(stream, &args)?,
+            "fetch" => self.fetch_v2(stream, &args)?,
+            _ => return Err(reject(stream, &format!("unknown command '{}'", command))),
+        }
+        Ok(true)
+    }
+
+    fn ls_refs<W: Write>(&self, out: &mut W, args: &[String]) -> Result<(), Error> {
+        let has = |arg: &str| args.iter().any(|a| a == arg);
+        let prefixes = args
+            .iter()
+            .filter_map(|a| a.strip_prefix("ref-prefix "))
+            .collect::<Vec<_>>();
+        let matches =
+            |name: &str| prefixes.is_empty() || prefixes.iter().any(|p| name.starts_with(p));
+
+        let mut buf = Vec::new();
+        for r in refs(self.repo)?.iter().filter(|r| matches(&r.name)) {
+            let mut line = format!("{} {}", r.id, r.name);
+            if let Some(target) = r.symref_target.as_ref().filter(|_| has("symrefs")) {
+                line.push_str(&format!(" symref-target:{}", target));
+            }
+            if let Some(peeled) = r.peeled.filter(|_| has("peel")) {
+                line.push_str(&format!(" peeled:{}", peeled));
+            }
+            pkt_line(&mut buf, &line);
+        }
+        if has("unborn") && matches("HEAD") {
+            let head = self.repo.find_reference("HEAD")?;
+            if let (Some(target), Err(_)) = (head.symbolic_target(), head.resolve()) {
+                let mut line = String::from("unborn HEAD");
+                if has("symrefs") {
+                    line.push_str(&format!(" symref-target:{}", target));
+                }
+                pkt_line(&mut buf, &line);
+            }
+        }
+        buf.extend_from_slice(FLUSH);
+        send(out, &buf)
+    }
+
+    fn fetch_v2<W: Write>(&self, out: &mut W, args: &[String]) -> Result<(), Error> {
+        let mut wants = Vec::new();
+        let mut haves = Vec::new();
+        let mut done = false;
+        let mut caps = FetchCaps {
+            sideband: 65515,
+            ..FetchCaps::default()
+        };
+        for arg in args {
+            if let Some(id) = arg.strip_prefix("want ") {
+                wants.push(parse_oid(id)?);
+            } else if let Some(id) = arg.strip_prefix("have ") {
+                haves.push(parse_oid(id)?);
+            } else if let Some(id) = arg.strip_prefix("shallow ") {
+                caps.shallow.insert(parse_oid(id)?);
+            } else if let Some(n) = arg.strip_prefix("deepen ") {
+                caps.depth = parse_depth(out, n)?;
+            } else if let Some(time) = arg.strip_prefix("deepen-since ") {
+                caps.deepen_since = Some(parse_time(out, time)?);
+            } else if let Some(name) = arg.strip_prefix("deepen-not ") {
+                caps.deepen_not.push(self.parse_deepen_not(out, name)?);
+            } else if let Some(spec) = arg.strip_prefix("filter ") {
+                caps.filter = Some(parse_filter(out, spec)?);
+            } else {
+                match &arg[..] {
+                    "done" => done = true,
+                    "include-tag" => caps.include_tag = true,
+                    "thin-pack" | "ofs-delta" | "no-progress" => {}
+                    _ => return Err(reject(out, &format!("unsupported argument '{}'", arg))),
+                }
+            }
+        }
+        if wants.is_empty() {
+            return Err(reject(out, "no objects were requested"));
+        }
+        self.check_wants(out, &wants)?;
+
+        let odb = self.repo.odb()?;
+        let common = haves
+            .into_iter()
+            .filter(|id| odb.exists(*id))
+            .collect::<Vec<_>>();
+        let mut buf = Vec::new();
+        if !done {
+            pkt_line(&mut buf, "acknowledgments");
+            if common.is_empty() {
+                pkt_line(&mut buf, "NAK");
+            }
+            for id in &common {
+                pkt_line(&mut buf, &format!("ACK {}", id));
+            }
+            buf.extend_from_slice(FLUSH);
+            return send(out, &buf);
+        }
+        if caps.deepens() {
+            pkt_line(&mut buf, "shallow-info");
+            self.shallow_info(&mut buf, &wants, &caps)?;
+            buf.extend_from_slice(DELIM);
+        }
+        pkt_line(&mut buf, "packfile");
+        send(out, &buf)?;
+        self.send_pack(out, &wants, &common, &caps)
+    }
+
+    /// Find the commit a `deepen-not` line excludes the history of, named
+    /// by a reference as short as `main` or `v1.0`.
+    fn parse_deepen_not<W: Write>(&self, out: &mut W, name: &str) -> Result<Oid, Error> {
+        self.repo
+            .resolve_reference_from_short_name(name)
+            .and_then(|r| r.peel_to_commit())
+            .map(|commit| commit.id())
+            .map_err(|_| reject(out, &format!("invalid deepen-not '{}'", name)))
+    }
+
+    /// Make sure the client only asks for the objects references point to
+    /// and for commits reachable from them, unless any object is allowed.
+    fn check_wants<W: Write>(&self, out: &mut W, wants: &[Oid]) -> Result<(), Error> {
+        let refs = refs(self.repo)?;
+        let ours = refs
+            .iter()
+            .flat_map(|r| Some(r.id).into_iter().chain(r.peeled))
+            .collect::<HashSet<_>>();
+        let mut pending = Vec::new();
+        for id in wants.iter().filter(|id| !ours.contains(id)) {
+            let object = match self.repo.find_object(*id, None) {
+                Ok(object) => object,
+                Err(_) => return Err(reject(out, &format!("not our ref {}", id))),
+            };
+            if self.allow_any_sha1_in_want {
+                continue;
+            }
+            match object.kind() {
+                Some(ObjectType::Commit) => pending.push(*id),
+                _ => return Err(reject(out, &format!("not our ref {}", id))),
+            }
+        }
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        // Walk the history of the references once, until every commit asked
+        // for is found.
+        let mut walk = self.repo.revwalk()?;
+        for id in &ours {
+            if let Ok(commit) = self.repo.find_object(*id, None)?.peel_to_commit() {
+                walk.push(commit.id())?;
+            }
+        }
+        for id in walk {
+            let id = id?;
+            pending.retain(|want| *want != id);
+            if pending.is_empty() {
+                return Ok(());
+            }
+        }
+        Err(reject(out, &format!("not our ref {}", pending[0])))
+    }
+
+    /// Send the objects reachable from `wants` but not from `common`.
+    fn send_pack<W: Write>(
+        &self,
+        out: &mut W,
+        wants: &[Oid],
+        common: &[Oid],
+        caps: &FetchCaps,
+    ) -> Result<(), Error> {
+        let mut pb = self.repo.packbuilder()?;
+        let mut commits = Vec::new();
+        let mut sent = None;
+        for id in wants {
+            let object = self.repo.find_object(*id, None)?;
+            let target = match object.kind() {
+                Some(ObjectType::Tag) => {
+                    pb.insert_object(*id, None)?;
+                    object.peel(ObjectType::Any)?
+                }
+                _ => object,
+            };
+            match target.kind() {
+                Some(ObjectType::Commit) => commits.push(target.id()),
+                _ => pb.insert_recursive(target.id(), None)?,
+            }
+        }
+
+        if caps.deepens() || !caps.shallow.is_empty() {
+            // Walks can't be limited in depth, and would skip the trees and
+            // blobs of the commits the client has, which it might not have
+            // if they are shallow, so send every commit in full.
+            let plan = self.shallow_plan(&commits, common, caps)?;
+            sent = Some(plan.commits);
+        } else if caps.filter.is_some() {
+            sent = Some(
+                self.walk(&commits, common)?
+                    .collect::<Result<Vec<_>, _>>()?,
+            );
+        }
+        if let Some(sent) = &sent {
+            let has = self.client_objects(common, caps)?;
+            self.insert_commits(&mut pb, sent, caps.filter.as_ref(), &has)?;
+        }
+
+        if caps.include_tag {
+            let sent = match &sent {
+                Some(commits) => commits.iter().cloned().collect::<HashSet<_>>(),
+                None => self
+                    .walk(&commits, common)?
+                    .collect::<Result<HashSet<_>, _>>()?,
+            };
+            for r in refs(self.repo)? {
+                match r.peeled {
+                    Some(peeled) if sent.contains(&peeled) && !wants.contains(&r.id) => {
+                        pb.insert_object(r.id, None)?
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if sent.is_none() {
+            pb.insert_walk(&mut self.walk(&commits, common)?)?;
+        }
+
+        // Send the pack in packets as large as the band allows, the
+        // packbuilder hands it out in pieces as small as a few bytes.
+        let mut pack = Buf::new();
+        pb.write_buf(&mut pack)?;
+        write_band(out, caps.sideband, 1, &pack)?;
+        if caps.sideband > 0 {
+            send(out, FLUSH)?;
+        }
+        out.flush().map_err(io_error)
+    }
+
+    /// Add `commits` to `pb` along with their trees and blobs which pass
+    /// `filter`, leaving out those in `has`.
+    fn insert_commits(
+        &self,
+        pb: &mut PackBuilder<'_>,
+        commits: &[Oid],
+        filter: Option<&ObjectFilter>,
+        has: &HashSet<Oid>,
+    ) -> Result<(), Error> {
+        let odb = self.repo.odb()?;
+        // Trees are sent if they pass the filter at the lowest depth they are
+        // found at.
+        let mut trees = HashMap::new();
+        let mut blobs = HashSet::new();
+        for id in commits {
+            pb.insert_object(*id, None)?;
+            let mut stack = vec![(self.repo.find_commit(*id)?.tree_id(), 0)];
+            while let Some((id, depth)) = stack.pop() {
+                if has.contains(&id) || !filter.is_none_or(|f| f.includes_tree(depth)) {
+                    continue;
+                }
+                match trees.get(&id) {
+                    Some(seen) if *seen <= depth => continue,
+                    Some(_) => {}
+                    None => pb.insert_object(id, None)?,
+                }
+                trees.insert(id, depth);
+                for entry in self.repo.find_tree(id)?.iter() {
+                    match entry.kind() {
+                        Some(ObjectType::Tree) => stack.push((entry.id(), depth + 1)),
+                        Some(ObjectType::Blob)
+                            if !blobs.contains(&entry.id()) && !has.contains(&entry.id()) =>
+                        {
+                            let included = match filter {
+                                Some(filter) => {
+                                    let (size, _) = odb.read_header(entry.id())?;
+                                    filter.includes_blob(size, depth + 1)
+                                }
+                                None => true,
+                            };
+                            if included {
+                                blobs.insert(entry.id());
+                                pb.insert_object(entry.id(), entry.name())?;
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Find the trees and blobs of the common commits and of the shallow
+    /// commits of the client, which it already has.
+    fn client_objects(&self, common: &[Oid], caps: &FetchCaps) -> Result<HashSet<Oid>, Error> {
+        let mut stack = Vec::new();
+        for id in common.iter().chain(&caps.shallow) {
+            let commit = self
+                .repo
+                .find_object(*id, None)
+                .and_then(|object| object.peel_to_commit());
+            if let Ok(commit) = commit {
+                stack.push(commit.tree_id());
+            }
+        }
+        let mut has = HashSet::new();
+        while let Some(id) = stack.pop() {
+            if !has.insert(id) {
+                continue;
+            }
+            for entry in self.repo.find_tree(id)?.iter() {
+                match entry.kind() {
+                    Some(ObjectType::Tree) => stack.push(entry.id()),
+                    _ => {
+                        has.insert(entry.id());
+                    }
+                }
+            }
+        }
+        Ok(has)
+    }
+
+    /// Write the `shallow` and `unshallow` lines telling the client which of
+    /// the commits it will have are missing their parents.
+    fn shallow_info(
+        &self,
+        buf: &mut Vec<u8>,
+        wants: &[Oid],
+        caps: &FetchCaps,
+    ) -> Result<(), Error> {
+        let mut commits = Vec::new();
+        for id in wants {
+            if let Ok(commit) = self.repo.find_object(*id, None)?.peel_to_commit() {
+                commits.push(commit.id());
+            }
+        }
+        let plan = self.shallow_plan(&commits, &[], caps)?;
+        // These lines don't end with a newline, as in git itself, which
+        // libgit2 relies on.
+        for id in plan.shallow {
+            pkt(buf, &format!("shallow {}", id));
+        }
+        for id in plan.unshallow {
+            pkt(buf, &format!("unshallow {}", id));
+        }
+        Ok(())
+    }
+
+    /// Find the commits reachable from `commits` which the client is
+    /// missing, up to the requested depth, time or excluded commits.
+    fn shallow_plan(
+        &self,
+        commits: &[Oid],
+        common: &[Oid],
+        caps: &FetchCaps,
+    ) -> Result<ShallowPlan, Error> {
+        // Everything the client has: the history of the common commits, up
+        // to its own shallow commits.
+        let mut has = HashSet::new();
+        let mut stack = caps.shallow.iter().cloned().collect::<Vec<_>>();
+        for id in common {
+            if let Ok(commit) = self.repo.find_object(*id, None)?.peel_to_commit() {
+                stack.push(commit.id());
+            }
+        }
+        while let Some(id) = stack.pop() {
+            if !has.insert(id) || caps.shallow.contains(&id) {
+                continue;
+            }
+            if let Ok(commit) = self.repo.find_commit(id) {
+                stack.extend(commit.parent_ids());
+            }
+        }
+
+        // The commits not to send. A commit with any of its parents in there
+        // becomes shallow, like one at the requested depth.
+        let mut excluded = HashSet::new();
+        if !caps.deepen_not.is_empty() {
+            let mut walk = self.repo.revwalk()?;
+            for id in &caps.deepen_not {
+                walk.push(*id)?;
+            }
+            for id in walk {
+                excluded.insert(id?);
+            }
+        }
+        let cut = |id: Oid| -> Result<bool, Error> {
+            if excluded.contains(&id) {
+                return Ok(true);
+            }
+            match caps.deepen_since {
+                Some(since) => Ok(self.repo.find_commit(id)?.time().seconds() < since),
+                None => Ok(false),
+            }
+        };
+
+        let mut plan = ShallowPlan {
+            commits: Vec::new(),
+            shallow: Vec::new(),
+            unshallow: Vec::new(),
+        };
+        let mut seen = HashSet::new();
+        let mut queue = commits.iter().map(|id| (*id, 1)).collect::<VecDeque<_>>();
+        while let Some((id, depth)) = queue.pop_front() {
+            if !seen.insert(id) {
+                continue;
+            }
+            let commit = self.repo.find_commit(id)?;
+            let mut parents = Vec::new();
+            for parent in commit.parent_ids() {
+                if !cut(parent)? {
+                    parents.push(parent);
+                }
+            }
+            let last =
+                (caps.depth > 0 && depth >= caps.depth) || parents.len() < commit.parent_count();
+            if caps.shallow.contains(&id) {
+                // Only deepen the history of the client when asked to.
+                if last || !caps.deepens() {
+                    continue;
+                }
+                plan.unshallow.push(id);
+            } else if has.contains(&id) {
+                // The client may be shallow further down, so keep walking
+                // when deepening.
+                if last || !caps.deepens() {
+                    continue;
+                }
+            } else {
+                plan.commits.push(id);
+                if last {
+                    if commit.parent_count() > 0 {
+                        plan.shallow.push(id);
+                    }
+                    continue;
+                }
+            }
+            queue.extend(parents.into_iter().map(|parent| (parent, depth + 1)));
+        }
+        Ok(plan)
+    }
+
+    fn walk(&self, commits: &[Oid], common: &[Oid]) -> Result<Revwalk<'repo>, Error> {
+        let mut walk = self.repo.revwalk()?;
+        for id in commits {
+            walk.push(*id)?;
+        }
+        for id in common {
+            if let Ok(commit) = self.repo.find_object(*id, None)?.peel_to_commit() {
+                walk.hide(commit.id())?;
+            }
+        }
+        Ok(walk)
+    }
+}
+
+/// The server side of pushes, the equivalent of `git receive-pack`.
+///
+/// It advertises the references of the repository, stores the pack sent by
+/// the client in the object database and updates the references in a single
+/// [`Transaction`](crate::Transaction). The equivalents of the
+/// `pre-receive`, `update` and `post-receive` hooks can be set to enforce
+/// policies on the pushed updates.
+///
+/// Objects are stored before the hooks run, so they stay in the repository
+/// even if every update is rejected.
+pub struct ReceivePack<'repo> {
+    repo: &'repo Repository,
+    stateless_rpc: bool,
+    pre_receive: Option<Box<PreReceive<'repo>>>,
+    update: Option<Box<Update<'repo>>>,
+    post_receive: Option<Box<PostReceive<'repo>>>,
+}
+
+/// An update of a reference requested by a client of [`ReceivePack`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefUpdate {
+    name: String,
+    old_id: Oid,
+    new_id: Oid,
+}
+
+/// Callback run once with all of the updates of a push.
+pub type PreReceive<'a> = dyn FnMut(&[RefUpdate]) -> Result<(), Error> + 'a;
+
+/// Callback run for each update of a push.
+pub type Update<'a> = dyn FnMut(&RefUpdate) -> Result<(), Error> + 'a;
+
+/// Callback run with the updates which were applied.
+pub type PostReceive<'a> = dyn FnMut(&[RefUpdate]) + 'a;
+
+/// The options a client asked for when pushing.
+#[derive(Default)]
+struct PushCaps {
+    report_status: bool,
+    atomic: bool,
+    sideband: usize,
+}
+
+const RECEIVE_CAPABILITIES: &str = "report-status delete-refs side-band-64k quiet atomic ofs-delta";
+
+impl<'repo> ReceivePack<'repo> {
+    /// Create a new server for pushes to `repo`.
+    pub fn new(repo: &'repo Repository) -> ReceivePack<'repo> {
+        ReceivePack {
+            repo,
+            stateless_rpc: false,
+            pre_receive: None,
+            update: None,
+            post_receive: None,
+        }
+    }
+
+    /// Serve a single request of a stateless protocol such as smart HTTP.
+    ///
+    /// See [`UploadPack::stateless_rpc`]; the advertisement written by
+    /// [`ReceivePack::advertise`] must be preceded by the
+    /// `# service=git-receive-pack` packet. Defaults to `false`.
+    pub fn stateless_rpc(&mut self, stateless: bool) -> &mut ReceivePack<'repo> {
+        self.stateless_rpc = stateless;
+        self
+    }
+
+    /// Set the callback run before any reference is updated, with all of
+    /// the updates requested by the client.
+    ///
+    /// Returning an error rejects every update, with the message of the
+    /// error as the reason.
+    pub fn pre_receive<F>(&mut self, cb: F) -> &mut ReceivePack<'repo>
+    where
+        F: FnMut(&[RefUpdate]) -> Result<(), Error> + 'repo,
+    {
+        self.pre_receive = Some(Box::new(cb) as Box<PreReceive<'repo>>);
+        self
+    }
+
+    /// Set the callback run for each update which passed the
+    /// [`pre_receive`](ReceivePack::pre_receive) callback.
+    ///
+    /// Returning an error rejects that update, with the message of the error
+    /// as the reason.
+    pub fn update<F>(&mut self, cb: F) -> &mut ReceivePack<'repo>
+    where
+        F: FnMut(&RefUpdate) -> Result<(), Error> + 'repo,
+    {
+        self.update = Some(Box::new(cb) as Box<Update<'repo>>);
+        self
+    }
+
+    /// Set the callback run after the references have been updated, with the
+    /// updates which were applied.
+    pub fn post_receive<F>(&mut self, cb: F) -> &mut ReceivePack<'repo>
+    where
+        F: FnMut(&[RefUpdate]) + 'repo,
+    {
+        self.post_receive = Some(Box::new(cb) as Box<PostReceive<'repo>>);
+        self
+    }
+
+    /// Write the references of the repository, as advertised to clients
+    /// when they connect.
+    pub fn advertise<W: Write>(&self, mut out: W) -> Result<(), Error> {
+        let refs = refs(self.repo)?
+            .into_iter()
+            .filter(|r| r.name != "HEAD")
+            .collect::<Vec<_>>();
+        let caps = format!("{} agent={}", RECEIVE_CAPABILITIES, agent());
+        let mut buf = Vec::new();
+        if refs.is_empty() {
+            pkt_line(
+                &mut buf,
+                &format!("{} capabilities^{{}}\0{}", Oid::zero(), caps),
+            );
+        }
+        for (i, r) in refs.iter().enumerate() {
+            if i == 0 {
+                pkt_line(&mut buf, &format!("{} {}\0{}", r.id, r.name, caps));
+            } else {
+                pkt_line(&mut buf, &format!("{} {}", r.id, r.name));
+            }
+        }
+        buf.extend_from_slice(FLUSH);
+        send(&mut out, &buf)
+    }
+
+    /// Serve a client connected over `stream`.
+    ///
+    /// Updates rejected by the callbacks, or because the reference changed
+    /// in the meantime, are reported to the client and are not an error. An
+    /// error is returned if the pack sent by the client can't be stored.
+    pub fn serve<S: Read + Write>(&mut self, mut stream: S) -> Result<(), Error> {
+        if !self.stateless_rpc {
+            self.advertise(&mut stream)?;
+        }
+        let mut updates = Vec::new();
+        let mut caps = PushCaps::default();
+        loop {
+            let data = match read_pkt(&mut stream)? {
+                None | Some(Pkt::Flush) => break,
+                Some(Pkt::Data(data)) => data,
+                Some(_) => return Err(protocol_error("unexpected special packet")),
+            };
+            let mut parts = data.splitn(2, |b| *b == 0);
+            let line = text(parts.next().unwrap_or(&[]))?;
+            if updates.is_empty() {
+                caps = PushCaps::parse(text(parts.next().unwrap_or(&[]))?);
+            }
+            let mut fields = line.splitn(3, ' ');
+            let (old, new) = (fields.next().unwrap_or(""), fields.next().unwrap_or(""));
+            let name = match fields.next() {
+                Some(name) => name.to_string(),
+                None => return Err(reject(&mut stream, &format!("invalid command '{}'", line))),
+            };
+            updates.push(RefUpdate {
+                name,
+                old_id: parse_oid(old)?,
+                new_id: parse_oid(new)?,
+            });
+        }
+        if updates.is_empty() {
+            return Ok(());
+        }
+
+        let unpacked = if updates.iter().any(|u| !u.new_id.is_zero()) {
+            self.receive_objects(&mut stream)
+        } else {
+            Ok(())
+        };
+        let statuses = match unpacked {
+            Ok(()) => self.apply(&updates, caps.atomic),
+            Err(_) => vec![Err("unpacker error".to_string()); updates.len()],
+        };
+
+        if caps.report_status {
+            let mut report = Vec::new();
+            match &unpacked {
+                Ok(()) => pkt_line(&mut report, "unpack ok"),
+                Err(e) => pkt_line(&mut report, &format!("unpack {}", e.message())),
+            }
+            for (update, status) in updates.iter().zip(&statuses) {
+                match status {
+                    Ok(()) => pkt_line(&mut report, &format!("ok {}", update.name)),
+                    Err(msg) => pkt_line(&mut report, &format!("ng {} {}", update.name, msg)),
+                }
+            }
+            report.extend_from_slice(FLUSH);
+            write_band(&mut stream, caps.sideband, 1, &report)?;
+            if caps.sideband > 0 {
+                send(&mut stream, FLUSH)?;
+            }
+            stream.flush().map_err(io_error)?;
+        }
+        unpacked?;
+
+        let applied = updates
+            .into_iter()
+            .zip(statuses)
+            .filter(|(_, status)| status.is_ok())
+            .map(|(update, _)| update)
+            .collect::<Vec<_>>();
+        if let Some(cb) = self.post_receive.as_mut().filter(|_| !applied.is_empty()) {
+            cb(&applied);
+        }
+        Ok(())
+    }
+
+    /// Store the pack following the commands in the object database.
+    fn receive_objects<R: Read>(&self, input: &mut R) -> Result<(), Error> {
+        let odb = self.repo.odb()?;
+        let mut writer = odb.packwriter()?;
+        let mut end = PackEnd::default();
+        let mut buf = vec![0; 64 * 1024];
+        while !end.complete() {
+            let n = match input.read(&mut buf) {
+                Ok(0) => return Err(protocol_error("unexpected end of pack")),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(io_error(e)),
+            };
+            writer.write_all(&buf[..n]).map_err(io_error)?;
+            end.update(&buf[..n]);
+        }
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Run the callbacks and apply the updates which they accept, returning
+    /// the status of each update.
+    fn apply(&mut self, updates: &[RefUpdate], atomic: bool) -> Vec<Result<(), String>> {
+        let odb = match self.repo.odb() {
+            Ok(odb) => odb,
+            Err(e) => return vec![Err(e.message().to_string()); updates.len()],
+        };
+        let mut statuses = updates
+            .iter()
+            .map(|u| {
+                if !u.name.starts_with("refs/") || !Reference::is_valid_name(&u.name) {
+                    Err("funny refname".to_string())
+                } else if !u.new_id.is_zero() && !odb.exists(u.new_id) {
+                    Err("missing necessary objects".to_string())
+                } else {
+                    Ok(())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(cb) = self.pre_receive.as_mut() {
+            let accepted = accepted(updates, &statuses);
+            if let Err(e) = cb(&accepted) {
+                for status in statuses.iter_mut().filter(|s| s.is_ok()) {
+                    *status = Err(e.message().to_string());
+                }
+            }
+        }
+        if let Some(cb) = self.update.as_mut() {
+            for (update, status) in updates.iter().zip(statuses.iter_mut()) {
+                if let (Ok(()), Err(e)) = (&status, cb(update)) {
+                    *status = Err(e.message().to_string());
+                }
+            }
+        }
+        if atomic {
+            fail_atomic(&mut statuses);
+        }
+
+        if let Err(e) = self.commit(updates, &mut statuses, atomic) {
+            for status in statuses.iter_mut().filter(|s| s.is_ok()) {
+                *status = Err(e.message().to_string());
+            }
+        }
+        statuses
+    }
+
+    /// Update the accepted references in a single transaction.
+    fn commit(
+        &self,
+        updates: &[RefUpdate],
+        statuses: &mut [Result<(), String>],
+        atomic: bool,
+    ) -> Result<(), Error> {
+        let mut tx = self.repo.transaction()?;
+        for (update, status) in updates.iter().zip(statuses.iter_mut()) {
+            if status.is_err() {
+                continue;
+            }
+            if tx.lock_ref(&update.name).is_err() {
+                *status = Err("failed to lock".to_string());
+                continue;
+            }
+            let current = match self.repo.refname_to_id(&update.name) {
+                Ok(id) => id,
+                Err(ref e) if e.code() == ErrorCode::NotFound => Oid::zero(),
+                Err(e) => return Err(e),
+            };
+            if current != update.old_id {
+                *status = Err("stale info".to_string());
+            }
+        }
+        if atomic {
+            fail_atomic(statuses);
+        }
+        for (update, status) in updates.iter().zip(statuses.iter()) {
+            match status {
+                Ok(()) if update.new_id.is_zero() => tx.remove(&update.name)?,
+                Ok(()) => tx.set_target(&update.name, update.new_id, None, "push")?,
+                Err(_) => {}
+            }
+        }
+        tx.commit()
+    }
+}
+
+impl RefUpdate {
+    /// The name of the reference to update.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The value of the reference the client expects, or zero if it is
+    /// creating the reference.
+    pub fn old_id(&self) -> Oid {
+        self.old_id
+    }
+
+    /// The new value of the reference, or zero if the client is deleting it.
+    pub fn new_id(&self) -> Oid {
+        self.new_id
+    }
+}
+
+impl PushCaps {
+    fn parse(caps: &str) -> PushCaps {
+        let mut ret = PushCaps::default();
+        for cap in caps.split(' ') {
+            match cap {
+                "report-status" => ret.report_status = true,
+                "atomic" => ret.atomic = true,
+                "side-band-64k" => ret.sideband = 65515,
+                _ => {}
+            }
+        }
+        ret
+    }
+}
+
+fn accepted(updates: &[RefUpdate], statuses: &[Result<(), String>]) -> Vec<RefUpdate> {
+    updates
+        .iter()
+        .zip(statuses)
+        .filter(|(_, status)| status.is_ok())
+        .map(|(update, _)| update.clone())
+        .collect()
+}
+
+/// Reject every update if any of them was, for atomic pushes.
+fn fail_atomic(statuses: &mut [Result<(), String>]) {
+    if statuses.iter().any(|s| s.is_err()) {
+        for status in statuses.iter_mut().filter(|s| s.is_ok()) {
+            *status = Err("atomic push failure".to_string());
+        }
+    }
+}
+
+/// Finds the end of a pack read from a stream.
+///
+/// Clients don't mark the end of the pack they send, and don't send anything
+/// after it until the server reports back, so reading until the end of the
+/// stream would never return. Instead the pack ends once the data read is
+/// followed by its SHA-1 checksum.
+#[derive(Default)]
+struct PackEnd {
+    hash: Sha1,
+    tail: Vec<u8>,
+    len: usize,
+}
+
+impl PackEnd {
+    fn update(&mut self, data: &[u8]) {
+        self.tail.extend_from_slice(data);
+        self.len += data.len();
+        if self.tail.len() > 20 {
+            let n = self.tail.len() - 20;
+            self.hash.update(&self.tail[..n]);
+            self.tail.drain(..n);
+        }
+    }
+
+    fn complete(&self) -> bool {
+        // The pack header is 12 bytes long.
+        self.len >= 32 && self.hash.clone().finish()[..] == self.tail[..]
+    }
+}
+
+impl FetchCaps {
+    /// Whether the client asked for a shallow history.
+    fn deepens(&self) -> bool {
+        self.depth > 0 || self.deepen_since.is_some() || !self.deepen_not.is_empty()
+    }
+
+    fn parse<'a>(caps: impl Iterator<Item = &'a str>) -> FetchCaps {
+        let mut ret = FetchCaps::default();
+        for cap in caps {
+            match cap {
+                "multi_ack" => ret.multi_ack = true,
+                "multi_ack_detailed" => ret.multi_ack_detailed = true,
+                "include-tag" => ret.include_tag = true,
+                "side-band" => ret.sideband = ret.sideband.max(995),
+                "side-band-64k" => ret.sideband = 65515,
+                _ => {}
+            }
+        }
+        ret
+    }
+}
+
+/// The references of the repository, `HEAD` first if it isn't unborn.
+fn refs(repo: &Repository) -> Result<Vec<AdvertisedRef>, Error> {
+    let mut refs = Vec::new();
+    let head = repo.find_reference("HEAD")?;
+    for reference in repo.references()?.chain(Some(Ok(head))) {
+        let reference = reference?;
+        let name = match reference.name() {
+            Some(name) => name.to_string(),
+            None => continue,
+        };
+        // Skip symbolic references which don't point anywhere.
+        let id = match reference.resolve().map(|r| r.target()) {
+            Ok(Some(id)) => id,
+            _ => continue,
+        };
+        let object = repo.find_object(id, None)?;
+        let peeled = match object.kind() {
+            Some(ObjectType::Tag) => Some(object.peel(ObjectType::Any)?.id()),
+            _ => None,
+        };
+        refs.push(AdvertisedRef {
+            name,
+            id,
+            peeled,
+            symref_target: reference.symbolic_target().map(String::from),
+        });
+    }
+    refs.sort_by(|a, b| (a.name != "HEAD", &a.name).cmp(&(b.name != "HEAD", &b.name)));
+    Ok(refs)
+}
+
+/// A packet of the pkt-line format.
+enum Pkt {
+    Flush,
+    Delim,
+    End,
+    Data(Vec<u8>),
+}
+
+const FLUSH: &[u8] = b"0000";
+const DELIM: &[u8] = b"0001";
+
+fn agent() -> String {
+    format!("git2-rs/{}", env!("CARGO_PKG_VERSION"))
+}
+
+/// Read the next packet, or `None` if the stream has ended.
+fn read_pkt<R: Read>(r: &mut R) -> Result<Option<Pkt>, Error> {
+    let mut len = [0; 4];
+    let mut read = 0;
+    while read < len.len() {
+        match r.read(&mut len[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(protocol_error("unexpected end of stream")),
+            Ok(n) => read += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(io_error(e)),
+        }
+    }
+    let len = str::from_utf8(&len)
+        .ok()
+        .and_then(|len| usize::from_str_radix(len, 16).ok())
+        .ok_or_else(|| protocol_error("invalid packet length"))?;
+    match len {
+        0 => Ok(Some(Pkt::Flush)),
+        1 => Ok(Some(Pkt::Delim)),
+        2 => Ok(Some(Pkt::End)),
+        3 => Err(protocol_error("invalid packet length")),
+        _ => {
+            let mut data = vec![0; len - 4];
+            r.read_exact(&mut data).map_err(io_error)?;
+            Ok(Some(Pkt::Data(data)))
+        }
+    }
+}
+
+/// Append a packet containing `data` to `buf`.
+fn pkt(buf: &mut Vec<u8>, data: &str) {
+    buf.extend_from_slice(format!("{:04x}{}", data.len() + 4, data).as_bytes());
+}
+
+/// Append a packet containing `line` and a trailing newline to `buf`.
+fn pkt_line(buf: &mut Vec<u8>, line: &str) {
+    pkt(buf, &format!("{}\n", line));
+}
+
+/// Write `data` as packets on the given side-band, or as is if `max` is
+/// zero.
+fn write_band<W: Write>(out: &mut W, max: usize, band: u8, data: &[u8]) -> Result<(), Error> {
+    if max == 0 {
+        return send(out, data);
+    }
+    for chunk in data.chunks(max) {
+        let header = format!("{:04x}", chunk.len() + 5);
+        send(out, header.as_bytes())?;
+        send(out, &[band])?;
+        send(out, chunk)?;
+    }
+    Ok(())
+}
+
+fn send<W: Write>(out: &mut W, data: &[u8]) -> Result<(), Error> {
+    out.write_all(data).map_err(io_error)
+}
+
+/// The contents of a packet, without its trailing newline.
+fn text(data: &[u8]) -> Result<&str, Error> {
+    let data = data.strip_suffix(b"\n").unwrap_or(data);
+    str::from_utf8(data).map_err(|_| protocol_error("packet is not valid UTF-8"))
+}
+
+fn parse_depth<W: Write>(out: &mut W, s: &str) -> Result<usize, Error> {
+    match s.parse() {
+        Ok(depth) if depth > 0 => Ok(depth),
+        _ => Err(reject(out, &format!("invalid depth '{}'", s))),
+    }
+}
+
+fn parse_time<W: Write>(out: &mut W, s: &str) -> Result<i64, Error> {
+    s.parse()
+        .map_err(|_| reject(out, &format!("invalid deepen-since '{}'", s)))
+}
+
+fn parse_filter<W: Write>(out: &mut W, s: &str) -> Result<ObjectFilter, Error> {
+    s.parse()
+        .map_err(|_| reject(out, &format!("unsupported filter '{}'", s)))
+}
+
+fn parse_oid(s: &str) -> Result<Oid, Error> {
+    Oid::from_str(s).map_err(|_| protocol_error(&format!("invalid object id '{}'", s)))
+}
+
+/// Send an error to the client, returning it.
+fn reject<W: Write>(out: &mut W, msg: &str) -> Error {
+    let mut buf = Vec::new();
+    pkt_line(&mut buf, &format!("ERR {}", msg));
+    // The client may be gone already, and the error is what matters.
+    let _ = out.write_all(&buf).and_then(|()| out.flush());
+    protocol_error(msg)
+}
+
+fn protocol_error(msg: &str) -> Error {
+    Error::new(ErrorCode::GenericError, ErrorClass::Net, msg)
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::new(ErrorCode::GenericError, ErrorClass::Net, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::{self, prelude::*, Cursor};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::Once;
+    use std::thread;
+
+    use super::{read_pkt, Pkt, ProtocolVersion, ReceivePack, UploadPack};
+    use crate::transport::{self, Service, SmartSubtransport, SmartSubtransportStream, Transport};
+    use crate::{
+        AutotagOption, Error, FetchOptions, ObjectType, Oid, PushOptions, RemoteCallbacks,
+        Repository,
+    };
+    use tempfile::TempDir;
+
+    /// A request and its response, as sent over a stateless protocol.
+    struct Rpc {
+        path: PathBuf,
+        service: Service,
+        request: Vec<u8>,
+        response: Option<Cursor<Vec<u8>>>,
+    }
+
+    impl Read for Rpc {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.response.is_none() {
+                let mut stream = Duplex::new(&self.request);
+                serve(&self.path, self.service, true, &mut stream);
+                self.response = Some(Cursor::new(stream.output));
+            }
+            self.response.as_mut().unwrap().read(buf)
+        }
+    }
+
+    impl Write for Rpc {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.request.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Duplex {
+        fn new(input: &[u8]) -> Duplex {
+            Duplex {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The push policy of the test server: `refs/heads/protected` can't be
+    /// updated, pushes to `refs/heads/frozen/*` are rejected as a whole, and
+    /// the applied updates are logged to `pushed` in the repository.
+    fn receive_pack(repo: &Repository) -> ReceivePack<'_> {
+        let log = repo.path().join("pushed");
+        let mut receive_pack = ReceivePack::new(repo);
+        receive_pack
+            .pre_receive(|updates| {
+                match updates
+                    .iter()
+                    .any(|u| u.name().starts_with("refs/heads/frozen/"))
+                {
+                    true => Err(Error::from_str("frozen")),
+                    false => Ok(()),
+                }
+            })
+            .update(|update| match update.name() {
+                "refs/heads/protected" => Err(Error::from_str("protected branch")),
+                _ => Ok(()),
+            })
+            .post_receive(move |updates| {
+                let mut log = t!(OpenOptions::new().create(true).append(true).open(&log));
+                for u in updates {
+                    t!(writeln!(log, "{} {} {}", u.old_id(), u.new_id(), u.name()));
+                }
+            });
+        receive_pack
+    }
+
+    fn serve(path: &Path, service: Service, stateless: bool, stream: impl Read + Write) {
+        let repo = t!(Repository::open(path));
+        match service {
+            Service::UploadPackLs | Service::UploadPack => t!(UploadPack::new(&repo)
+                .stateless_rpc(stateless)
+                .serve(stream)),
+            Service::ReceivePackLs | Service::ReceivePack => {
+                t!(receive_pack(&repo).stateless_rpc(stateless).serve(stream))
+            }
+        }
+    }
+
+    struct TestTransport {
+        rpc: bool,
+    }
+
+    impl SmartSubtransport for TestTransport {
+        fn action(
+            &self,
+            url: &str,
+            action: Service,
+        ) -> Result<Box<dyn SmartSubtransportStream>, Error> {
+            let path = PathBuf::from(url.split_once("://").unwrap().1);
+            if !self.rpc {
+                let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+                let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+                let (server, _) = listener.accept().unwrap();
+                thread::spawn(move || serve(&path, action, false, server));
+                return Ok(Box::new(client));
+            }
+            let repo = Repository::open(&path)?;
+            let mut advertisement = Vec::new();
+            match action {
+                Service::UploadPackLs => {
+                    super::pkt_line(&mut advertisement, "# service=git-upload-pack");
+                    advertisement.extend_from_slice(b"0000");
+                    UploadPack::new(&repo).advertise(&mut advertisement)?;
+                }
+                Service::ReceivePackLs => {
+                    super::pkt_line(&mut advertisement, "# service=git-receive-pack");
+                    advertisement.extend_from_slice(b"0000");
+                    ReceivePack::new(&repo).advertise(&mut advertisement)?;
+                }
+                Service::UploadPack | Service::ReceivePack => {
+                    return Ok(Box::new(Rpc {
+                        path,
+                        service: action,
+                        request: Vec::new(),
+                        response: None,
+                    }))
+                }
+            }
+            Ok(Box::new(Duplex::new(&advertisement)))
+        }
+
+        fn close(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn register() {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| unsafe {
+            transport::register("git2-test", |remote| {
+                Transport::smart(remote, false, TestTransport { rpc: false })
+            })
+            .unwrap();
+            transport::register("git2-test-rpc", |remote| {
+                Transport::smart(remote, true, TestTransport { rpc: true })
+            })
+            .unwrap();
+        });
+    }
+
+    fn commit_file(repo: &Repository, path: &str) -> Oid {
+        let workdir = repo.workdir().unwrap();
+        t!(fs::write(workdir.join(path), path));
+        let mut index = t!(repo.index());
+        t!(index.add_path(Path::new(path)));
+        let tree = t!(repo.find_tree(t!(index.write_tree())));
+        let sig = t!(repo.signature());
+        let parent = t!(repo.head().and_then(|h| h.peel_to_commit()));
+        t!(repo.commit(Some("HEAD"), &sig, &sig, path, &tree, &[&parent]))
+    }
+
+    fn fetch(repo: &Repository, url: &str) -> usize {
+        let mut remote = t!(repo.remote_anonymous(url));
+        let mut opts = FetchOptions::new();
+        opts.download_tags(AutotagOption::Auto);
+        let refspec = "+refs/heads/*:refs/remotes/origin/*";
+        t!(remote.fetch(&[refspec], Some(&mut opts), None));
+        remote.stats().total_objects()
+    }
+
+    #[test]
+    fn fetch_v0() {
+        register();
+        let (td, server) = crate::test::repo_init();
+        let first = commit_file(&server, "a");
+        let target = t!(server.find_object(first, None));
+        let sig = t!(server.signature());
+        let tag = t!(server.tag("v1", &target, &sig, "v1", false));
+        let (_td2, client) = crate::test::repo_init();
+
+        // A clone sends everything, along with the tag.
+        for url in ["git2-test://", "git2-test-rpc://"] {
+            let (_td2, client) = crate::test::repo_init();
+            let url = format!("{}{}", url, td.path().display());
+            fetch(&client, &url);
+            let main = t!(client.refname_to_id("refs/remotes/origin/main"));
+            assert_eq!(main, first);
+            assert_eq!(t!(client.refname_to_id("refs/tags/v1")), tag);
+        }
+
+        // Fetching again only sends what's missing, whichever side has
+        // more commits.
+        let url = format!("git2-test://{}", td.path().display());
+        fetch(&client, &url);
+        let second = commit_file(&server, "b");
+        commit_file(&client, "c");
+        commit_file(&client, "d");
+        let url = format!("git2-test-rpc://{}", td.path().display());
+        assert_eq!(fetch(&client, &url), 3);
+        let main = t!(client.refname_to_id("refs/remotes/origin/main"));
+        assert_eq!(main, second);
+
+        // A shallow fetch doesn't send the trees and blobs of the commits
+        // the client has: only the commit, its tree and the new blob.
+        let third = commit_file(&server, "f");
+        let mut remote = t!(client.remote_anonymous(&url));
+        let mut opts = FetchOptions::new();
+        opts.depth(1);
+        t!(remote.fetch(
+            &["+refs/heads/main:refs/remotes/origin/main"],
+            Some(&mut opts),
+            None
+        ));
+        assert_eq!(t!(client.refname_to_id("refs/remotes/origin/main")), third);
+        assert_eq!(remote.stats().total_objects(), 3);
+
+        // Only commits reachable from a reference can be fetched.
+        let url = format!("git2-test://{}", td.path().display());
+        let mut remote = t!(client.remote_anonymous(&url));
+        let tree = t!(server.find_commit(third)).tree();
+        let dangling = t!(server.commit(None, &sig, &sig, "dangling", &t!(tree), &[]));
+        assert!(remote.fetch(&[&dangling.to_string()], None, None).is_err());
+        t!(remote.fetch(&[&first.to_string()], None, None));
+        let third = commit_file(&server, "e");
+        let tree = t!(server.find_commit(third)).tree_id();
+        assert!(client.find_object(tree, None).is_err());
+        assert!(remote.fetch(&[&tree.to_string()], None, None).is_err());
+
+        // Unless any object is allowed, as partial clones need.
+        let mut advertisement = Vec::new();
+        t!(UploadPack::new(&server)
+            .allow_any_sha1_in_want(true)
+            .advertise(&mut advertisement));
+        let advertisement = String::from_utf8_lossy(&advertisement).into_owned();
+        assert!(advertisement.contains(" allow-any-sha1-in-want"));
+        unsafe {
+            let upstream = t!(Repository::open(td.path()));
+            t!(crate::transport::register(
+                "git2-test-any",
+                crate::transport::in_process(upstream)
+            ));
+        }
+        let mut remote = t!(client.remote_anonymous("git2-test-any://server"));
+        t!(remote.fetch(&[&tree.to_string()], None, None));
+        assert_eq!(
+            t!(client.find_object(tree, None)).kind(),
+            Some(ObjectType::Tree)
+        );
+    }
+
+    fn push(repo: &Repository, url: &str, refspecs: &[&str]) -> Vec<(String, Option<String>)> {
+        let mut remote = t!(repo.remote_anonymous(url));
+        let mut statuses = Vec::new();
+        let mut cbs = RemoteCallbacks::new();
+        cbs.push_update_reference(|name, status| {
+            statuses.push((name.to_string(), status.map(String::from)));
+            Ok(())
+        });
+        let mut opts = PushOptions::new();
+        opts.remote_callbacks(cbs);
+        t!(remote.push(refspecs, Some(&mut opts)));
+        drop(opts);
+        statuses
+    }
+
+    fn status(name: &str, status: Option<&str>) -> (String, Option<String>) {
+        (name.to_string(), status.map(String::from))
+    }
+
+    #[test]
+    fn receive() {
+        register();
+        let td = TempDir::new().unwrap();
+        let server = t!(Repository::init_bare(td.path()));
+        let (_td2, client) = crate::test::repo_init();
+        let first = t!(client.refname_to_id("HEAD"));
+        let url = format!("git2-test://{}", td.path().display());
+        let rpc_url = format!("git2-test-rpc://{}", td.path().display());
+
+        // The update callback rejects single updates.
+        let statuses = push(
+            &client,
+            &url,
+            &["refs/heads/main", "refs/heads/main:refs/heads/protected"],
+        );
+        assert_eq!(
+            statuses,
+            [
+                status("refs/heads/main", None),
+                status("refs/heads/protected", Some("protected branch")),
+            ]
+        );
+        assert_eq!(t!(server.refname_to_id("refs/heads/main")), first);
+        assert!(server.find_reference("refs/heads/protected").is_err());
+
+        let second = commit_file(&client, "a");
+        let statuses = push(&client, &rpc_url, &["refs/heads/main"]);
+        assert_eq!(statuses, [status("refs/heads/main", None)]);
+        assert_eq!(t!(server.refname_to_id("refs/heads/main")), second);
+
+        // The pre-receive callback rejects the whole push.
+        let statuses = push(
+            &client,
+            &url,
+            &["main:refs/heads/other", "main:refs/heads/frozen/a"],
+        );
+        assert_eq!(
+            statuses,
+            [
+                status("refs/heads/frozen/a", Some("frozen")),
+                status("refs/heads/other", Some("frozen")),
+            ]
+        );
+        assert!(server.find_reference("refs/heads/other").is_err());
+
+        let statuses = push(&client, &rpc_url, &[":refs/heads/main"]);
+        assert_eq!(statuses, [status("refs/heads/main", None)]);
+        assert!(server.find_reference("refs/heads/main").is_err());
+
+        let log = t!(fs::read_to_string(td.path().join("pushed")));
+        let zero = Oid::zero();
+        assert_eq!(
+            log.lines().collect::<Vec<_>>(),
+            [
+                format!("{} {} refs/heads/main", zero, first),
+                format!("{} {} refs/heads/main", first, second),
+                format!("{} {} refs/heads/main", second, zero),
+            ]
+        );
+    }
+
+    fn lines(stream: &mut impl Read) -> Vec<String> {
+        let mut lines = Vec::new();
+        while let Some(Pkt::Data(data)) = t!(read_pkt(stream)) {
+            lines.push(t!(super::text(&data)).to_string());
+        }
+        lines
+    }
+
+    #[test]
+    fn serve_v2() {
+        let (_td, repo) = crate::test::repo_init();
+        let head = t!(repo.refname_to_id("HEAD"));
+        let target = t!(repo.find_object(head, None));
+        let sig = t!(repo.signature());
+        let tag = t!(repo.tag("v1", &target, &sig, "v1", false));
+        let (_td2, client) = crate::test::repo_init();
+        let have = commit_file(&client, "a");
+
+        let mut request = Vec::new();
+        super::pkt_line(&mut request, "command=ls-refs");
+        super::pkt_line(&mut request, "object-format=sha1");
+        request.extend_from_slice(b"0001");
+        super::pkt_line(&mut request, "symrefs");
+        super::pkt_line(&mut request, "peel");
+        super::pkt_line(&mut request, "ref-prefix HEAD");
+        super::pkt_line(&mut request, "ref-prefix refs/tags/");
+        request.extend_from_slice(b"0000");
+        super::pkt_line(&mut request, "command=fetch");
+        request.extend_from_slice(b"0001");
+        super::pkt_line(&mut request, &format!("want {}", head));
+        super::pkt_line(&mut request, &format!("have {}", have));
+        request.extend_from_slice(b"0000");
+        super::pkt_line(&mut request, "command=fetch");
+        request.extend_from_slice(b"0001");
+        super::pkt_line(&mut request, &format!("want {}", head));
+        super::pkt_line(&mut request, "done");
+        request.extend_from_slice(b"0000");
+        request.extend_from_slice(b"0000");
+
+        let mut stream = Duplex::new(&request);
+        t!(UploadPack::new(&repo)
+            .protocol_version(ProtocolVersion::from_parameters("foo=bar:version=2"))
+            .serve(&mut stream));
+        let mut response = Cursor::new(stream.output);
+
+        let caps = lines(&mut response);
+        assert_eq!(caps[0], "version 2");
+        assert!(caps.iter().any(|c| c == "fetch=shallow filter"));
+        assert_eq!(
+            lines(&mut response),
+            [
+                format!("{} HEAD symref-target:refs/heads/main", head),
+                format!("{} refs/tags/v1 peeled:{}", tag, head),
+            ]
+        );
+        assert_eq!(lines(&mut response), ["acknowledgments", "NAK"]);
+        match t!(read_pkt(&mut response)) {
+            Some(Pkt::Data(data)) => assert_eq!(data, b"packfile\n"),
+            _ => panic!("expected the packfile section"),
+        }
+
+        let odb = t!(client.odb());
+        let mut writer = t!(odb.packwriter());
+        while let Some(Pkt::Data(data)) = t!(read_pkt(&mut response)) {
+            assert_eq!(data[0], 1);
+            t!(writer.write_all(&data[1..]));
+        }
+        t!(writer.commit());
+        assert!(odb.exists(head));
+        assert_eq!(response.position(), response.get_ref().len() as u64);
+    }
+}
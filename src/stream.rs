@@ -0,0 +1,458 @@
+//! Interfaces for replacing the network streams used by libgit2
+//!
+//! libgit2's built-in transports open their connections through a stream
+//! abstraction: plain sockets for `http://` and `git://` URLs and TLS streams
+//! for `https://` URLs. Registering a custom [`Stream`] keeps libgit2's own
+//! protocol handling while replacing only the bytes on the wire, for example
+//! to use a different TLS implementation or to tunnel connections through a
+//! proxy.
+//!
+//! Note that libgit2's SSH transport hands the standard stream directly to
+//! libssh2 as a raw socket, which is why [`register`] is unsafe.
+
+use libc::{c_char, c_int, c_uint, c_void, size_t, ssize_t};
+use std::ffi::{CStr, CString};
+use std::io::prelude::*;
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::{Arc, Mutex};
+
+use crate::{panic, raw, Error, ErrorClass, ErrorCode};
+
+/// A connection to a remote host, used by libgit2's built-in transports.
+///
+/// Reading and writing go through the standard [`Read`] and [`Write`]
+/// traits, and are only done after a successful [`Stream::connect`].
+pub trait Stream: Read + Write + Send + 'static {
+    /// Establish the connection.
+    ///
+    /// For TLS streams, an error with the code [`ErrorCode::Certificate`]
+    /// reports that the server's certificate could not be validated; the
+    /// connection then continues if the
+    /// [`certificate_check`](crate::RemoteCallbacks::certificate_check)
+    /// callback accepts the certificate.
+    fn connect(&mut self) -> Result<(), Error>;
+
+    /// The DER encoded X.509 certificate presented by the server, which is
+    /// passed to the
+    /// [`certificate_check`](crate::RemoteCallbacks::certificate_check)
+    /// callback.
+    ///
+    /// This is only called for TLS streams, after [`Stream::connect`]. The
+    /// default implementation reports no certificate.
+    fn certificate(&mut self) -> Result<Option<Vec<u8>>, Error> {
+        Ok(None)
+    }
+
+    /// Shut down the connection.
+    fn close(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// The kinds of streams which can be replaced.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum StreamType {
+    /// A plain socket, used for `http://` and `git://` URLs.
+    Standard,
+    /// A TLS encrypted socket, used for `https://` URLs.
+    Tls,
+}
+
+type StreamFactory = dyn Fn(&str, u16) -> Result<Box<dyn Stream>, Error> + Send + Sync + 'static;
+
+// libgit2 doesn't pass a payload to stream constructors, so the registered
+// factories are kept here.
+static STANDARD: Mutex<Option<Arc<StreamFactory>>> = Mutex::new(None);
+static TLS: Mutex<Option<Arc<StreamFactory>>> = Mutex::new(None);
+
+const ENCRYPTED: c_uint = 1 << 0;
+
+/// Instance of a `git_stream`, must use `#[repr(C)]` to ensure that the C
+/// fields come first.
+#[repr(C)]
+struct RawStream {
+    raw: raw::git_stream,
+    obj: Box<dyn Stream>,
+    cert: raw::git_cert_x509,
+    cert_data: Vec<u8>,
+}
+
+/// Register a factory for the streams of the given type, replacing the ones
+/// built into libgit2.
+///
+/// The factory is called with the host and port to connect to, and returns
+/// a stream which is not connected yet. Registering a factory for a type
+/// which already has one replaces it.
+///
+/// TLS streams registered this way are not used to tunnel through HTTP
+/// proxies, which fails instead.
+///
+/// This function is unsafe as it mutates the global state but cannot guarantee
+/// thread-safety. It needs to be externally synchronized with calls to access
+/// the global state.
+///
+/// # Safety
+///
+/// libgit2's SSH transport takes the socket out of the standard stream it
+/// opens, assuming that it is libgit2's own socket stream. A
+/// [`StreamType::Standard`] stream must therefore not be registered while
+/// `ssh://` remotes are used by libgit2's built-in SSH transport.
+pub unsafe fn register<F>(kind: StreamType, factory: F) -> Result<(), Error>
+where
+    F: Fn(&str, u16) -> Result<Box<dyn Stream>, Error> + Send + Sync + 'static,
+{
+    crate::init();
+    let (slot, init): (_, extern "C" fn(_, _, _) -> _) = match kind {
+        StreamType::Standard => (&STANDARD, standard_init),
+        StreamType::Tls => (&TLS, tls_init),
+    };
+    let mut registration = raw::git_stream_registration {
+        version: raw::GIT_STREAM_VERSION,
+        init: Some(init),
+        wrap: None,
+    };
+    *slot.lock().unwrap() = Some(Arc::new(factory));
+    try_call!(raw::git_stream_register(kind_raw(kind), &mut registration));
+    Ok(())
+}
+
+/// Remove the factory registered for the given type of stream, going back to
+/// the stream built into libgit2.
+pub fn unregister(kind: StreamType) -> Result<(), Error> {
+    crate::init();
+    unsafe {
+        try_call!(raw::git_stream_register(kind_raw(kind), ptr::null_mut()));
+    }
+    match kind {
+        StreamType::Standard => *STANDARD.lock().unwrap() = None,
+        StreamType::Tls => *TLS.lock().unwrap() = None,
+    }
+    Ok(())
+}
+
+fn kind_raw(kind: StreamType) -> raw::git_stream_t {
+    match kind {
+        StreamType::Standard => raw::GIT_STREAM_STANDARD,
+        StreamType::Tls => raw::GIT_STREAM_TLS,
+    }
+}
+
+extern "C" fn standard_init(
+    out: *mut *mut raw::git_stream,
+    host: *const c_char,
+    port: *const c_char,
+) -> c_int {
+    stream_init(&STANDARD, false, out, host, port)
+}
+
+extern "C" fn tls_init(
+    out: *mut *mut raw::git_stream,
+    host: *const c_char,
+    port: *const c_char,
+) -> c_int {
+    stream_init(&TLS, true, out, host, port)
+}
+
+fn stream_init(
+    slot: &Mutex<Option<Arc<StreamFactory>>>,
+    encrypted: bool,
+    out: *mut *mut raw::git_stream,
+    host: *const c_char,
+    port: *const c_char,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let factory = match slot.lock().unwrap().clone() {
+            Some(factory) => factory,
+            None => return -1,
+        };
+        let host = CStr::from_ptr(host).to_string_lossy();
+        let port = CStr::from_ptr(port).to_string_lossy();
+        let port = match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                let msg = format!("invalid port '{}'", port);
+                return Error::new(ErrorCode::GenericError, ErrorClass::Net, msg)
+                    .raw_set_git_error();
+            }
+        };
+        match factory(&host, port) {
+            Ok(obj) => {
+                let mut stream = Box::new(RawStream {
+                    raw: mem::zeroed(),
+                    obj,
+                    cert: mem::zeroed(),
+                    cert_data: Vec::new(),
+                });
+                stream.raw.version = raw::GIT_STREAM_VERSION;
+                stream.raw.flags = if encrypted { ENCRYPTED } else { 0 };
+                stream.raw.connect = Some(stream_connect);
+                stream.raw.certificate = Some(stream_certificate);
+                stream.raw.read = Some(stream_read);
+                stream.raw.write = Some(stream_write);
+                stream.raw.close = Some(stream_close);
+                stream.raw.free = Some(stream_free);
+                *out = Box::into_raw(stream) as *mut raw::git_stream;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_connect(stream: *mut raw::git_stream) -> c_int {
+    panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawStream);
+        match s.obj.connect() {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_certificate(
+    out: *mut *mut raw::git_cert,
+    stream: *mut raw::git_stream,
+) -> c_int {
+    panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawStream);
+        match s.obj.certificate() {
+            Ok(cert) => {
+                s.cert.parent.cert_type = match cert {
+                    Some(_) => raw::GIT_CERT_X509,
+                    None => raw::GIT_CERT_NONE,
+                };
+                s.cert_data = cert.unwrap_or_default();
+                s.cert.data = s.cert_data.as_mut_ptr() as *mut c_void;
+                s.cert.len = s.cert_data.len();
+                *out = &mut s.cert.parent;
+                0
+            }
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_read(stream: *mut raw::git_stream, data: *mut c_void, len: size_t) -> ssize_t {
+    let ret = panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawStream);
+        let buf = slice::from_raw_parts_mut(data as *mut u8, len);
+        s.obj.read(buf)
+    });
+    match ret {
+        Some(Ok(n)) => n as ssize_t,
+        Some(Err(e)) => unsafe {
+            set_err_io(&e);
+            -1
+        },
+        None => -1,
+    }
+}
+
+extern "C" fn stream_write(
+    stream: *mut raw::git_stream,
+    data: *const c_char,
+    len: size_t,
+    _flags: c_int,
+) -> ssize_t {
+    let ret = panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawStream);
+        let buf = slice::from_raw_parts(data as *const u8, len);
+        s.obj.write(buf)
+    });
+    match ret {
+        Some(Ok(n)) => n as ssize_t,
+        Some(Err(e)) => unsafe {
+            set_err_io(&e);
+            -1
+        },
+        None => -1,
+    }
+}
+
+unsafe fn set_err_io(e: &std::io::Error) {
+    let s = CString::new(e.to_string()).unwrap();
+    raw::git_error_set_str(raw::GIT_ERROR_NET as c_int, s.as_ptr());
+}
+
+extern "C" fn stream_close(stream: *mut raw::git_stream) -> c_int {
+    panic::wrap(|| unsafe {
+        let s = &mut *(stream as *mut RawStream);
+        match s.obj.close() {
+            Ok(()) => 0,
+            Err(e) => e.raw_set_git_error(),
+        }
+    })
+    .unwrap_or(-1)
+}
+
+extern "C" fn stream_free(stream: *mut raw::git_stream) {
+    let _ = panic::wrap(|| unsafe {
+        drop(Box::from_raw(stream as *mut RawStream));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, prelude::*};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::{Stream, StreamType};
+    use crate::{CertificateCheckStatus, Error, FetchOptions, RemoteCallbacks};
+
+    struct Tcp {
+        addr: (String, u16),
+        conn: Option<TcpStream>,
+        cert: Option<Vec<u8>>,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl Read for Tcp {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.conn.as_mut().unwrap().read(buf)
+        }
+    }
+
+    impl Write for Tcp {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.conn.as_mut().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Stream for Tcp {
+        fn connect(&mut self) -> Result<(), Error> {
+            self.log.lock().unwrap().push("connect".to_string());
+            let conn = TcpStream::connect((self.addr.0.as_str(), self.addr.1))
+                .map_err(|e| Error::from_str(&e.to_string()))?;
+            self.conn = Some(conn);
+            Ok(())
+        }
+
+        fn certificate(&mut self) -> Result<Option<Vec<u8>>, Error> {
+            Ok(self.cert.clone())
+        }
+
+        fn close(&mut self) -> Result<(), Error> {
+            self.log.lock().unwrap().push("close".to_string());
+            Ok(())
+        }
+    }
+
+    /// Unregisters a stream type when dropped, so that a failing test
+    /// doesn't leave its streams registered for the rest of the process.
+    struct Registered(StreamType);
+
+    impl Registered {
+        fn new<F>(kind: StreamType, factory: F) -> Registered
+        where
+            F: Fn(&str, u16) -> Result<Box<dyn Stream>, Error> + Send + Sync + 'static,
+        {
+            unsafe { super::register(kind, factory).unwrap() };
+            Registered(kind)
+        }
+    }
+
+    impl Drop for Registered {
+        fn drop(&mut self) {
+            super::unregister(self.0).unwrap();
+        }
+    }
+
+    /// Accepts connections and answers every request with a 404, returning
+    /// the requests it received.
+    fn server() -> (u16, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = conn.read(&mut buf).unwrap();
+                assert!(n > 0);
+                request.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            vec![String::from_utf8(request).unwrap()]
+        });
+        (port, handle)
+    }
+
+    #[test]
+    fn smoke() {
+        let (_td, repo) = crate::test::repo_init();
+        let log = Arc::new(Mutex::new(Vec::new()));
+
+        // Plain HTTP goes through the standard stream.
+        let (port, server_thread) = server();
+        let factory_log = log.clone();
+        let registered = Registered::new(StreamType::Standard, move |host, port| {
+            factory_log
+                .lock()
+                .unwrap()
+                .push(format!("{}:{}", host, port));
+            Ok(Box::new(Tcp {
+                addr: ("127.0.0.1".to_string(), port),
+                conn: None,
+                cert: None,
+                log: factory_log.clone(),
+            }))
+        });
+        let url = format!("http://git.invalid:{}/repo.git", port);
+        let mut remote = repo.remote_anonymous(&url).unwrap();
+        let err = remote.fetch(&["main"], None, None).unwrap_err();
+        assert!(err.message().contains("404"), "{}", err);
+        let requests = server_thread.join().unwrap();
+        assert!(requests[0].starts_with("GET /repo.git/info/refs?service=git-upload-pack"));
+        assert!(requests[0].contains(&format!("Host: git.invalid:{}", port)));
+        assert_eq!(
+            *log.lock().unwrap(),
+            [
+                format!("git.invalid:{}", port),
+                "connect".into(),
+                "close".into()
+            ]
+        );
+        drop(registered);
+
+        // HTTPS goes through the TLS stream, whose certificate is checked by
+        // the remote callbacks.
+        let (port, server_thread) = server();
+        let _registered = Registered::new(StreamType::Tls, move |_host, port| {
+            Ok(Box::new(Tcp {
+                addr: ("127.0.0.1".to_string(), port),
+                conn: None,
+                cert: Some(b"not really der".to_vec()),
+                log: Arc::new(Mutex::new(Vec::new())),
+            }))
+        });
+        let url = format!("https://git.invalid:{}/repo.git", port);
+        let mut remote = repo.remote_anonymous(&url).unwrap();
+        let mut checked = false;
+        let mut cbs = RemoteCallbacks::new();
+        cbs.certificate_check(|cert, host| {
+            assert_eq!(host, "git.invalid");
+            assert_eq!(cert.as_x509().unwrap().data(), b"not really der");
+            checked = true;
+            Ok(CertificateCheckStatus::CertificateOk)
+        });
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(cbs);
+        let err = remote.fetch(&["main"], Some(&mut opts), None).unwrap_err();
+        assert!(err.message().contains("404"), "{}", err);
+        drop(opts);
+        assert!(checked);
+        server_thread.join().unwrap();
+    }
+}
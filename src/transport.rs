@@ -1,16 +1,24 @@
 //! Interfaces for adding custom transports to libgit2
 
 use libc::{c_char, c_int, c_uint, c_void, size_t};
+use std::env;
 use std::ffi::{CStr, CString};
 use std::io;
 use std::io::prelude::*;
 use std::mem;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
 use std::ptr;
 use std::slice;
 use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
+use crate::bundle::Bundle;
+use crate::server::{ReceivePack, UploadPack};
 use crate::util::Binding;
-use crate::{panic, raw, Error, Remote};
+use crate::{panic, raw, Cred, CredentialType, Error, ErrorClass, ErrorCode, Oid, Remote};
+use crate::{RemoteRedirect, Repository};
 
 /// A transport is a structure which knows how to transfer data to and from a
 /// remote.
@@ -51,6 +59,38 @@ pub trait SmartSubtransport: Send + 'static {
     /// 1. UploadPackLs -> UploadPack
     /// 2. ReceivePackLs -> ReceivePack
     fn close(&self) -> Result<(), Error>;
+
+    /// Called once when this subtransport is attached to the smart transport
+    /// created by [`Transport::smart`].
+    ///
+    /// The given handle can be kept to reach the options of the fetch or push
+    /// being carried out, for example to ask for credentials.
+    fn attach(&mut self, transport: SmartTransport) {
+        let _ = transport;
+    }
+}
+
+/// A handle to the smart transport which owns a [`SmartSubtransport`].
+///
+/// It gives the subtransport access to the callbacks and options of the
+/// operation it takes part in. Handles are cheap to clone, so they can be
+/// passed on to the streams of the subtransport. Once the transport has been
+/// freed all methods return an error.
+#[derive(Clone)]
+pub struct SmartTransport {
+    raw: Arc<Mutex<TransportPtr>>,
+}
+
+struct TransportPtr(*mut raw::git_transport);
+
+// The pointer is only dereferenced while the lock is held, and is cleared
+// when the transport is freed.
+unsafe impl Send for TransportPtr {}
+
+/// The options given to the fetch or push carried out by a smart transport,
+/// as returned by [`SmartTransport::connect_options`].
+pub struct ConnectOptions {
+    raw: raw::git_remote_connect_options,
 }
 
 /// Actions that a smart transport can ask a subtransport to perform
@@ -89,6 +129,9 @@ struct RawSmartSubtransport {
     raw: raw::git_smart_subtransport,
     stream: Option<*mut raw::git_smart_subtransport_stream>,
     rpc: bool,
+    /// The capabilities advertised by the server.
+    capabilities: Vec<String>,
+    owner: SmartTransport,
     obj: Box<dyn SmartSubtransport>,
 }
 
@@ -98,6 +141,157 @@ struct RawSmartSubtransport {
 struct RawSmartSubtransportStream {
     raw: raw::git_smart_subtransport_stream,
     obj: Box<dyn SmartSubtransportStream>,
+    service: Service,
+    /// The start of the advertisement read from the stream, kept until the
+    /// capabilities of the server are known.
+    advertisement: Option<Vec<u8>>,
+    /// The operation the stream takes part in, looked up on the first write.
+    operation: Option<Operation>,
+    /// Whether the request sent on this stream has been given the object
+    /// filter, the `deepen-since` and `deepen-not` lines or the `atomic`
+    /// capability.
+    amended: bool,
+}
+
+/// What a fetch or push asks of the server beyond what libgit2 sends, as
+/// given to smart transports by [`SmartTransport::operation`].
+///
+/// libgit2 doesn't know about object filters, shallow fetches by date or
+/// reference, or atomic pushes. The smart transports created with
+/// [`Transport::smart`] add them to the requests libgit2 writes to their
+/// subtransport, and fail if the server doesn't advertise the capabilities
+/// they need, so subtransports only have to look at them to adapt requests
+/// of their own.
+#[derive(Clone, Debug, Default)]
+pub struct Operation {
+    refspecs: Option<Vec<String>>,
+    filter: Option<String>,
+    deepen: Vec<String>,
+    atomic: bool,
+    /// Whether the filter was sent to the server, shared with the copies
+    /// handed to transports.
+    filter_sent: Arc<AtomicBool>,
+}
+
+/// The operations started with an [`Operation`], by the payload of the
+/// callbacks they give libgit2, which libgit2 hands on to their transports.
+static OPERATIONS: Mutex<Vec<(usize, Operation)>> = Mutex::new(Vec::new());
+
+/// The URL prefixes of the transports added with [`register`].
+static REGISTERED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+impl Operation {
+    /// A fetch of `refspecs`, which are those of the remote if empty.
+    pub(crate) fn fetch(
+        refspecs: Vec<String>,
+        filter: Option<String>,
+        deepen: Vec<String>,
+    ) -> Operation {
+        Operation {
+            refspecs: Some(refspecs),
+            filter,
+            deepen,
+            ..Operation::default()
+        }
+    }
+
+    /// A push, sent as atomic if `atomic` is set.
+    pub(crate) fn push(atomic: bool) -> Operation {
+        Operation {
+            atomic,
+            ..Operation::default()
+        }
+    }
+
+    /// The refspecs given to the fetch, or `None` if this isn't one.
+    ///
+    /// libgit2 lists the refs of a remote when connecting to it, before it
+    /// looks at the refspecs of the fetch, so this lets transports know
+    /// which refs are wanted. An empty list means the fetch refspecs of the
+    /// remote are used, and `None` that any ref may be wanted, as when
+    /// connecting with [`Remote::connect_auth`].
+    pub fn refspecs(&self) -> Option<&[String]> {
+        self.refspecs.as_deref()
+    }
+
+    /// The object filter of the fetch, such as `blob:none`.
+    pub fn filter(&self) -> Option<&str> {
+        self.filter.as_deref()
+    }
+
+    /// The `deepen-since` and `deepen-not` lines limiting the history of a
+    /// shallow fetch, which replace the depth libgit2 asks for.
+    pub fn deepen(&self) -> &[String] {
+        &self.deepen
+    }
+
+    /// Whether the push is atomic.
+    pub fn atomic(&self) -> bool {
+        self.atomic
+    }
+
+    /// Whether the filter was sent to the server.
+    pub(crate) fn filter_sent(&self) -> bool {
+        self.filter_sent.load(Ordering::SeqCst)
+    }
+
+    /// Run `f`, an operation with `url` whose callbacks have `payload`, with
+    /// this given to the smart transports it uses.
+    ///
+    /// The built-in transports of libgit2 can't send what libgit2 doesn't
+    /// know about, so asking them to fails right away.
+    pub(crate) fn run<T>(
+        self,
+        url: &str,
+        payload: *mut c_void,
+        f: impl FnOnce() -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let unsupported = if self.filter.is_some() {
+            Some("object filters")
+        } else if !self.deepen.is_empty() {
+            Some("shallow fetches by date or reference")
+        } else if self.atomic {
+            Some("atomic pushes")
+        } else {
+            None
+        };
+        if let (Some(what), false) = (unsupported, registered(url)) {
+            return Err(Error::new(
+                ErrorCode::GenericError,
+                ErrorClass::Net,
+                format!(
+                    "{} can't be sent to '{}', only transports added with \
+                     transport::register support them",
+                    what, url
+                ),
+            ));
+        }
+        struct Remove(usize);
+        impl Drop for Remove {
+            fn drop(&mut self) {
+                let mut operations = OPERATIONS.lock().unwrap();
+                let pos = operations.iter().rposition(|(key, _)| *key == self.0);
+                operations.remove(pos.unwrap());
+            }
+        }
+        OPERATIONS.lock().unwrap().push((payload as usize, self));
+        let _remove = Remove(payload as usize);
+        f()
+    }
+}
+
+/// Whether libgit2 picks a transport added with [`register`] for `url`.
+fn registered(url: &str) -> bool {
+    let registered = REGISTERED.lock().unwrap();
+    let matches = |url: &str| {
+        registered.iter().any(|prefix| {
+            url.get(..prefix.len())
+                .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+        })
+    };
+    // Like libgit2, take URLs with a colon but no scheme to be scp-like
+    // ssh URLs.
+    matches(url) || (!url.contains("://") && url.contains(':') && matches("ssh://"))
 }
 
 /// Add a custom transport definition, to be used in addition to the built-in
@@ -113,14 +307,123 @@ where
     let mut data = Box::new(TransportData {
         factory: Box::new(factory),
     });
+    let registered = format!("{}://", prefix);
     let prefix = CString::new(prefix)?;
     let datap = (&mut *data) as *mut TransportData as *mut c_void;
     let factory: raw::git_transport_cb = Some(transport_factory);
     try_call!(raw::git_transport_register(prefix, factory, datap));
     mem::forget(data);
+    REGISTERED.lock().unwrap().push(registered);
     Ok(())
 }
 
+/// Create a transport factory which serves `repo` from the same process.
+///
+/// Remotes using the transport talk the smart protocol to
+/// [`UploadPack`] and [`ReceivePack`] through in-memory buffers, so fetching
+/// from and pushing to `repo` needs neither a server nor a path on disk.
+/// As both ends belong to the same process, any object of `repo` can be
+/// fetched, see [`UploadPack::allow_any_sha1_in_want`]. Every URL using the
+/// scheme the factory is registered for refers to `repo`:
+///
+/// ```no_run
+/// use git2::{transport, Repository};
+///
+/// # fn main() -> Result<(), git2::Error> {
+/// let upstream = Repository::init_bare("/path/to/upstream")?;
+/// unsafe {
+///     transport::register("mem", transport::in_process(upstream))?;
+/// }
+/// let repo = Repository::open("/path/to/repo")?;
+/// repo.remote("origin", "mem://upstream")?.fetch(&["main"], None, None)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn in_process(
+    repo: Repository,
+) -> impl Fn(&Remote<'_>) -> Result<Transport, Error> + Send + Sync + 'static {
+    let repo = Arc::new(Mutex::new(repo));
+    move |remote| {
+        let subtransport = InProcess { repo: repo.clone() };
+        Transport::smart(remote, true, subtransport)
+    }
+}
+
+/// Create a transport factory which runs git over an external `ssh`
+/// command, the way git does.
+///
+/// The command run is `command` if given, and otherwise the value of the
+/// `GIT_SSH_COMMAND` environment variable, the program named by `GIT_SSH`, or
+/// `ssh`. `command` and `GIT_SSH_COMMAND` are run through `sh`, which on
+/// Windows has to be on the `PATH`, as it is with Git for Windows. The
+/// command is passed the port of the URL if there is one, the host, and the
+/// `git-upload-pack` or `git-receive-pack` command to run there. As the ssh
+/// configuration, agent and keys of the user all apply, this works with
+/// setups libssh2 does not support.
+///
+/// Like git, the way the port is passed depends on the program: `-p <port>`
+/// for OpenSSH, `-P <port>` for `plink` and `putty`, and `-batch -P <port>`
+/// for `tortoiseplink`. The `GIT_SSH_VARIANT` environment variable overrides
+/// the guess with one of `ssh`, `plink`, `putty`, `tortoiseplink` or
+/// `simple`, the latter for programs which take only the host and the
+/// command. URLs whose user, host or port start with `-` are rejected, so
+/// they can't be mistaken for options.
+///
+/// URLs can be given as `<scheme>://[user@]host[:port]/path` or as
+/// `[user@]host:path`. libgit2 uses the transport registered for `ssh` for
+/// the latter.
+///
+/// ```no_run
+/// use git2::transport;
+///
+/// # fn main() -> Result<(), git2::Error> {
+/// unsafe {
+///     transport::register("ssh", transport::ssh_command(None))?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn ssh_command(
+    command: Option<&str>,
+) -> impl Fn(&Remote<'_>) -> Result<Transport, Error> + Send + Sync + 'static {
+    let command = command.map(|s| s.to_string());
+    move |remote| {
+        let subtransport = SshCommand {
+            command: command.clone(),
+        };
+        Transport::smart(remote, false, subtransport)
+    }
+}
+
+/// Create a transport factory which fetches from bundle files.
+///
+/// The path of the bundle is the part of the URL after the scheme the
+/// factory is registered for, as in `bundle:///path/to/repo.bundle`. Fetches
+/// get the whole pack of the bundle, and pushing is not supported.
+///
+/// The transport can't tell whether the repository fetched into has the
+/// [prerequisites](Bundle::prerequisites) of the bundle, so check with
+/// [`Bundle::verify`] beforehand.
+///
+/// ```no_run
+/// use git2::bundle::Bundle;
+/// use git2::{transport, Repository};
+///
+/// # fn main() -> Result<(), git2::Error> {
+/// unsafe {
+///     transport::register("bundle", transport::bundle())?;
+/// }
+/// let repo = Repository::open("/path/to/repo")?;
+/// Bundle::open("/path/to/repo.bundle")?.verify(&repo)?;
+/// repo.remote_anonymous("bundle:///path/to/repo.bundle")?
+///     .fetch(&["refs/heads/*:refs/remotes/bundle/*"], None, None)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn bundle() -> impl Fn(&Remote<'_>) -> Result<Transport, Error> + Send + Sync + 'static {
+    |remote| Transport::smart(remote, true, BundleFile)
+}
+
 impl Transport {
     /// Creates a new transport which will use the "smart" transport protocol
     /// for transferring data.
@@ -146,6 +449,10 @@ impl Transport {
             },
             stream: None,
             rpc,
+            capabilities: Vec::new(),
+            owner: SmartTransport {
+                raw: Arc::new(Mutex::new(TransportPtr(ptr::null_mut()))),
+            },
             obj: Box::new(subtransport),
         });
         let mut defn = raw::git_smart_subtransport_definition {
@@ -177,15 +484,129 @@ impl Transport {
 
         extern "C" fn smart_factory(
             out: *mut *mut raw::git_smart_subtransport,
-            _owner: *mut raw::git_transport,
+            owner: *mut raw::git_transport,
             ptr: *mut c_void,
         ) -> c_int {
-            unsafe {
+            panic::wrap(|| unsafe {
+                let transport = &mut *(ptr as *mut RawSmartSubtransport);
+                transport.owner.raw.lock().unwrap().0 = owner;
+                transport.obj.attach(transport.owner.clone());
                 *out = ptr as *mut raw::git_smart_subtransport;
                 0
-            }
+            })
+            .unwrap_or(-1)
+        }
+    }
+}
+
+impl SmartTransport {
+    fn with<T>(
+        &self,
+        f: impl FnOnce(*mut raw::git_transport) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        let raw = self.raw.lock().unwrap();
+        if raw.0.is_null() {
+            return Err(Error::from_str("the transport has been freed"));
+        }
+        f(raw.0)
+    }
+
+    /// Ask the credentials callback of the remote operation for credentials.
+    ///
+    /// This invokes the callback registered with
+    /// [`RemoteCallbacks::credentials`](crate::RemoteCallbacks::credentials)
+    /// with the URL of the remote. If no callback was registered, the
+    /// returned error has a [`raw_code`](Error::raw_code) of
+    /// `GIT_PASSTHROUGH`.
+    pub fn credentials(
+        &self,
+        username: Option<&str>,
+        allowed: CredentialType,
+    ) -> Result<Cred, Error> {
+        let username = crate::opt_cstr(username)?;
+        self.with(|transport| unsafe {
+            let mut out = ptr::null_mut();
+            try_call!(raw::git_transport_smart_credentials(
+                &mut out,
+                transport,
+                username,
+                allowed.bits() as c_int
+            ));
+            Ok(Cred::from_raw(out))
+        })
+    }
+
+    /// Get a copy of the options of the remote operation, such as its proxy
+    /// settings and custom headers.
+    pub fn connect_options(&self) -> Result<ConnectOptions, Error> {
+        self.with(|transport| unsafe {
+            let mut raw = mem::zeroed();
+            try_call!(raw::git_transport_remote_connect_options(
+                &mut raw, transport
+            ));
+            Ok(ConnectOptions { raw })
+        })
+    }
+
+    /// Get what the remote operation asks of the server beyond what libgit2
+    /// sends, such as the refspecs and object filter of a fetch.
+    ///
+    /// Operations not started by a [`Remote`] or a
+    /// [`RepoBuilder`](crate::build::RepoBuilder), such as connecting with
+    /// [`Remote::connect_auth`], have none of these.
+    pub fn operation(&self) -> Result<Operation, Error> {
+        let payload = self.connect_options()?.raw.callbacks.payload as usize;
+        let operations = OPERATIONS.lock().unwrap();
+        let operation = operations.iter().rev().find(|(key, _)| *key == payload);
+        Ok(operation.map(|(_, op)| op.clone()).unwrap_or_default())
+    }
+}
+
+impl ConnectOptions {
+    /// The extra headers to send with each request, set through
+    /// [`FetchOptions::custom_headers`](crate::FetchOptions::custom_headers)
+    /// or [`PushOptions::custom_headers`](crate::PushOptions::custom_headers).
+    ///
+    /// Headers which are not valid UTF-8 are skipped.
+    pub fn custom_headers(&self) -> Vec<&str> {
+        let headers = &self.raw.custom_headers;
+        (0..headers.count)
+            .filter_map(|i| unsafe {
+                let header = crate::opt_bytes(self, *headers.strings.add(i))?;
+                str::from_utf8(header).ok()
+            })
+            .collect()
+    }
+
+    /// The URL of the proxy to connect through, if one was given with
+    /// [`ProxyOptions::url`](crate::ProxyOptions::url).
+    pub fn proxy_url(&self) -> Option<&str> {
+        if self.raw.proxy_opts.kind != raw::GIT_PROXY_SPECIFIED {
+            return None;
+        }
+        unsafe {
+            crate::opt_bytes(self, self.raw.proxy_opts.url).and_then(|s| str::from_utf8(s).ok())
         }
     }
+
+    /// Whether the proxy should be detected from the configuration, as asked
+    /// for with [`ProxyOptions::auto`](crate::ProxyOptions::auto).
+    pub fn proxy_auto(&self) -> bool {
+        self.raw.proxy_opts.kind == raw::GIT_PROXY_AUTO
+    }
+
+    /// Which HTTP redirects should be followed, as set through
+    /// [`FetchOptions::follow_redirects`](crate::FetchOptions::follow_redirects)
+    /// or [`PushOptions::follow_redirects`](crate::PushOptions::follow_redirects).
+    pub fn follow_redirects(&self) -> RemoteRedirect {
+        RemoteRedirect::from_raw(self.raw.follow_redirects)
+    }
+}
+
+impl Drop for ConnectOptions {
+    fn drop(&mut self) {
+        unsafe { raw::git_remote_connect_options_dispose(&mut self.raw) }
+    }
 }
 
 impl Drop for Transport {
@@ -269,6 +690,13 @@ extern "C" fn subtransport_action(
                     free: Some(stream_free),
                 },
                 obj,
+                service: action,
+                advertisement: match action {
+                    Service::UploadPackLs | Service::ReceivePackLs => Some(Vec::new()),
+                    _ => None,
+                },
+                operation: None,
+                amended: false,
             }));
             transport.stream = Some(*stream);
         } else {
@@ -300,7 +728,8 @@ extern "C" fn subtransport_close(transport: *mut raw::git_smart_subtransport) ->
 // object.
 extern "C" fn subtransport_free(transport: *mut raw::git_smart_subtransport) {
     let _ = panic::wrap(|| unsafe {
-        mem::transmute::<_, Box<RawSmartSubtransport>>(transport);
+        let transport = mem::transmute::<_, Box<RawSmartSubtransport>>(transport);
+        transport.owner.raw.lock().unwrap().0 = ptr::null_mut();
     });
 }
 
@@ -318,6 +747,17 @@ extern "C" fn stream_read(
         match transport.obj.read(buf) {
             Ok(n) => {
                 *bytes_read = n as size_t;
+                if let Some(advertisement) = transport.advertisement.as_mut() {
+                    advertisement.extend_from_slice(&buf[..n]);
+                    let subtransport = transport.raw.subtransport as *mut RawSmartSubtransport;
+                    if let Some(caps) = advertised_capabilities(advertisement) {
+                        (*subtransport).capabilities =
+                            caps.split(' ').map(|cap| cap.to_string()).collect();
+                        transport.advertisement = None;
+                    } else if n == 0 || advertisement.len() > 65536 {
+                        transport.advertisement = None;
+                    }
+                }
                 Ok(n)
             }
             e => e,
@@ -343,6 +783,54 @@ extern "C" fn stream_write(
     let ret = panic::wrap(|| unsafe {
         let transport = &mut *(stream as *mut RawSmartSubtransportStream);
         let buf = slice::from_raw_parts(buffer as *const u8, len as usize);
+        let subtransport = transport.raw.subtransport as *mut RawSmartSubtransport;
+        if transport.amended {
+            return transport.obj.write_all(buf);
+        }
+        if transport.operation.is_none() {
+            let operation = (*subtransport).owner.operation();
+            transport.operation = Some(operation.map_err(io::Error::other)?);
+        }
+        let operation = transport.operation.as_ref().unwrap();
+        let supports = |cap: &str| (*subtransport).capabilities.iter().any(|c| c == cap);
+        // Streams of stateful transports are reused from listing the refs to
+        // sending the request.
+        let receive_pack = matches!(
+            transport.service,
+            Service::ReceivePackLs | Service::ReceivePack
+        );
+        let filter = operation.filter();
+        let deepen = operation.deepen();
+        if !receive_pack && (filter.is_some() || !deepen.is_empty()) {
+            if let Some(amended) = amend_wants(buf, filter, deepen) {
+                if filter.is_some() && !supports("filter") {
+                    return Err(io::Error::other(
+                        "the server doesn't support object filters",
+                    ));
+                }
+                for line in deepen {
+                    let cap = line.split(' ').next().unwrap_or("");
+                    if !supports(cap) {
+                        let msg = format!("the server doesn't support {}", cap);
+                        return Err(io::Error::other(msg));
+                    }
+                }
+                transport.amended = true;
+                if filter.is_some() {
+                    operation.filter_sent.store(true, Ordering::SeqCst);
+                }
+                return transport.obj.write_all(&amended);
+            }
+        }
+        if receive_pack && operation.atomic() {
+            if let Some(commands) = add_atomic(buf) {
+                if !supports("atomic") {
+                    return Err(io::Error::other("the server doesn't support atomic pushes"));
+                }
+                transport.amended = true;
+                return transport.obj.write_all(&commands);
+            }
+        }
         transport.obj.write_all(buf)
     });
     match ret {
@@ -355,6 +843,94 @@ extern "C" fn stream_write(
     }
 }
 
+/// Find the capabilities on the first reference of an advertisement, once
+/// it has been read in full.
+fn advertised_capabilities(advertisement: &[u8]) -> Option<String> {
+    let nul = advertisement.iter().position(|&b| b == 0)?;
+    let end = advertisement[nul..].iter().position(|&b| b == b'\n')?;
+    Some(String::from_utf8_lossy(&advertisement[nul + 1..nul + end]).into_owned())
+}
+
+/// Add the `filter` and `deepen` lines to the wants of a request to
+/// upload-pack, along with their capabilities, or return `None` if
+/// `request` has no wants.
+///
+/// The `deepen` lines replace the depth libgit2 asked for.
+fn amend_wants(request: &[u8], filter: Option<&str>, deepen: &[String]) -> Option<Vec<u8>> {
+    let pkt = |line: &[u8]| {
+        let mut pkt = format!("{:04x}", line.len() + 4).into_bytes();
+        pkt.extend_from_slice(line);
+        pkt
+    };
+    let mut caps = String::new();
+    let mut lines = Vec::new();
+    for line in deepen {
+        let cap = line.split(' ').next().unwrap_or("");
+        if !caps.split(' ').any(|c| c == cap) {
+            caps.push(' ');
+            caps.push_str(cap);
+        }
+        lines.push(format!("{}\n", line));
+    }
+    if let Some(filter) = filter {
+        caps.push_str(" filter");
+        lines.push(format!("filter {}\n", filter));
+    }
+    let mut out = Vec::with_capacity(request.len() + 64);
+    let mut wants = false;
+    let mut pos = 0;
+    while pos + 4 <= request.len() {
+        let len = str::from_utf8(&request[pos..pos + 4]).ok()?;
+        let len = usize::from_str_radix(len, 16).ok()?;
+        if len == 0 {
+            if wants {
+                // These go last, right before the flush ending the wants
+                // and shallows, with the filter after the deepens.
+                for line in &lines {
+                    out.extend(pkt(line.as_bytes()));
+                }
+                out.extend_from_slice(&request[pos..]);
+                return Some(out);
+            }
+            out.extend_from_slice(b"0000");
+            pos += 4;
+            continue;
+        }
+        if len < 4 || pos + len > request.len() {
+            return None;
+        }
+        let line = &request[pos + 4..pos + len];
+        if !wants && line.starts_with(b"want ") {
+            wants = true;
+            let mut line = line.strip_suffix(b"\n").unwrap_or(line).to_vec();
+            line.extend_from_slice(caps.as_bytes());
+            line.push(b'\n');
+            out.extend(pkt(&line));
+        } else if !deepen.is_empty() && line.starts_with(b"deepen ") {
+            // Servers don't take a depth along with the other limits.
+        } else {
+            out.extend_from_slice(&request[pos..pos + len]);
+        }
+        pos += len;
+    }
+    None
+}
+
+/// Add the `atomic` capability to the commands of a request to
+/// receive-pack, or return `None` if `request` has no commands.
+fn add_atomic(request: &[u8]) -> Option<Vec<u8>> {
+    let len = str::from_utf8(request.get(..4)?).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    let line = request.get(4..len)?;
+    line.iter().position(|&b| b == 0)?;
+    let mut line = line.strip_suffix(b"\n").unwrap_or(line).to_vec();
+    line.extend_from_slice(b" atomic\n");
+    let mut out = format!("{:04x}", line.len() + 4).into_bytes();
+    out.extend_from_slice(&line);
+    out.extend_from_slice(&request[len..]);
+    Some(out)
+}
+
 unsafe fn set_err_io(e: &io::Error) {
     let s = CString::new(e.to_string()).unwrap();
     raw::git_error_set_str(raw::GIT_ERROR_NET as c_int, s.as_ptr());
@@ -368,11 +944,523 @@ extern "C" fn stream_free(stream: *mut raw::git_smart_subtransport_stream) {
     });
 }
 
+/// Subtransport serving a repository of the same process.
+struct InProcess {
+    repo: Arc<Mutex<Repository>>,
+}
+
+/// A single request to an [`InProcess`] subtransport, served once the
+/// response is first read.
+struct InProcessRequest {
+    repo: Arc<Mutex<Repository>>,
+    service: Service,
+    request: Vec<u8>,
+    response: Option<io::Cursor<Vec<u8>>>,
+}
+
+/// The request and response of a stateless exchange, as seen by the server.
+struct Exchange<'a> {
+    request: &'a [u8],
+    response: Vec<u8>,
+}
+
+impl SmartSubtransport for InProcess {
+    fn action(
+        &self,
+        _url: &str,
+        action: Service,
+    ) -> Result<Box<dyn SmartSubtransportStream>, Error> {
+        let repo = self.repo.lock().unwrap();
+        let mut response = Vec::new();
+        match action {
+            Service::UploadPackLs => {
+                service_header(&mut response, "git-upload-pack");
+                UploadPack::new(&repo)
+                    .allow_any_sha1_in_want(true)
+                    .advertise(&mut response)?;
+            }
+            Service::ReceivePackLs => {
+                service_header(&mut response, "git-receive-pack");
+                ReceivePack::new(&repo).advertise(&mut response)?;
+            }
+            Service::UploadPack | Service::ReceivePack => {
+                return Ok(Box::new(InProcessRequest {
+                    repo: self.repo.clone(),
+                    service: action,
+                    request: Vec::new(),
+                    response: None,
+                }));
+            }
+        }
+        Ok(Box::new(io::Cursor::new(response)))
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Write the packet which precedes the advertisement of smart HTTP servers,
+/// and which libgit2 expects from stateless transports.
+fn service_header(buf: &mut Vec<u8>, service: &str) {
+    let line = format!("# service={}\n", service);
+    buf.extend_from_slice(format!("{:04x}{}0000", line.len() + 4, line).as_bytes());
+}
+
+impl InProcessRequest {
+    fn serve(&self) -> Result<Vec<u8>, Error> {
+        let repo = self.repo.lock().unwrap();
+        let mut exchange = Exchange {
+            request: &self.request,
+            response: Vec::new(),
+        };
+        match self.service {
+            Service::UploadPack => UploadPack::new(&repo)
+                .stateless_rpc(true)
+                .allow_any_sha1_in_want(true)
+                .serve(&mut exchange)?,
+            _ => ReceivePack::new(&repo)
+                .stateless_rpc(true)
+                .serve(&mut exchange)?,
+        }
+        Ok(exchange.response)
+    }
+}
+
+impl Read for InProcessRequest {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.response.is_none() {
+            let response = self.serve().map_err(|e| io::Error::other(e.message()))?;
+            self.response = Some(io::Cursor::new(response));
+        }
+        self.response.as_mut().unwrap().read(buf)
+    }
+}
+
+impl Write for InProcessRequest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.request.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> Read for Exchange<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.request.read(buf)
+    }
+}
+
+impl<'a> Write for Exchange<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.response.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A subtransport serving the contents of a bundle file.
+struct BundleFile;
+
+/// A single request to a [`BundleFile`] subtransport.
+struct BundleRequest {
+    url: String,
+    request: Vec<u8>,
+    response: Option<Box<dyn Read + Send>>,
+}
+
+impl SmartSubtransport for BundleFile {
+    fn action(
+        &self,
+        url: &str,
+        action: Service,
+    ) -> Result<Box<dyn SmartSubtransportStream>, Error> {
+        match action {
+            Service::UploadPackLs => {
+                let bundle = open_bundle(url)?;
+                let mut response = Vec::new();
+                service_header(&mut response, "git-upload-pack");
+                let mut lines = bundle
+                    .heads()
+                    .iter()
+                    .map(|head| format!("{} {}", head.id(), head.name()))
+                    .collect::<Vec<_>>();
+                if lines.is_empty() {
+                    lines.push(format!("{} capabilities^{{}}", Oid::zero()));
+                }
+                // libgit2 ignores the references if no capabilities are sent
+                // along with the first one.
+                lines[0].push_str("\0ofs-delta");
+                for line in lines {
+                    let line = format!("{:04x}{}\n", line.len() + 5, line);
+                    response.extend_from_slice(line.as_bytes());
+                }
+                response.extend_from_slice(b"0000");
+                Ok(Box::new(io::Cursor::new(response)))
+            }
+            Service::UploadPack => Ok(Box::new(BundleRequest {
+                url: url.to_string(),
+                request: Vec::new(),
+                response: None,
+            })),
+            Service::ReceivePackLs | Service::ReceivePack => {
+                Err(Error::from_str("cannot push to a bundle"))
+            }
+        }
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+fn open_bundle(url: &str) -> Result<Bundle, Error> {
+    let path = url.split_once("://").map(|(_, path)| path).unwrap_or(url);
+    Bundle::open(path)
+}
+
+impl BundleRequest {
+    /// Answer a round of negotiation. Nothing is ever in common, and once the
+    /// client is done the whole pack is sent.
+    fn serve(&self) -> Result<Box<dyn Read + Send>, Error> {
+        let mut request = &self.request[..];
+        let mut done = false;
+        while request.len() >= 4 {
+            let len = str::from_utf8(&request[..4])
+                .ok()
+                .and_then(|len| usize::from_str_radix(len, 16).ok())
+                .filter(|len| *len == 0 || (*len >= 4 && *len <= request.len()))
+                .ok_or_else(|| Error::from_str("invalid packet in fetch request"))?;
+            let (line, rest) = request.split_at(len.max(4));
+            let line = &line[4..];
+            if line.starts_with(b"deepen") {
+                return Err(Error::from_str("bundles do not support shallow fetches"));
+            }
+            done |= line == b"done\n" || line == b"done";
+            request = rest;
+        }
+        let nak = io::Cursor::new(b"0008NAK\n".to_vec());
+        if !done {
+            return Ok(Box::new(nak));
+        }
+        Ok(Box::new(nak.chain(open_bundle(&self.url)?.pack()?)))
+    }
+}
+
+impl Read for BundleRequest {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.response.is_none() {
+            let response = self.serve().map_err(|e| io::Error::other(e.message()))?;
+            self.response = Some(response);
+        }
+        self.response.as_mut().unwrap().read(buf)
+    }
+}
+
+impl Write for BundleRequest {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.request.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A subtransport running git over an external ssh command.
+struct SshCommand {
+    command: Option<String>,
+}
+
+/// The git command running on the other side of an ssh connection.
+struct SshStream {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+    /// Collects what the command printed on stderr, to report it if the
+    /// command fails.
+    stderr: Option<thread::JoinHandle<String>>,
+}
+
+/// The parts of an ssh URL the command needs.
+#[derive(Debug, PartialEq)]
+struct SshUrl {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+    path: String,
+}
+
+/// The kind of ssh program, which decides how options are passed to it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum SshVariant {
+    /// OpenSSH, or anything taking the same options.
+    Ssh,
+    /// PuTTY's `plink` and `putty`, which take the port as `-P`.
+    Plink,
+    /// TortoiseGit's `plink`, which also needs `-batch`.
+    TortoisePlink,
+    /// A program which takes only the host and the command.
+    Simple,
+}
+
+impl SmartSubtransport for SshCommand {
+    fn action(
+        &self,
+        url: &str,
+        action: Service,
+    ) -> Result<Box<dyn SmartSubtransportStream>, Error> {
+        // The stream of the listing is reused for the rest of the operation.
+        let service = match action {
+            Service::UploadPackLs => "git-upload-pack",
+            Service::ReceivePackLs => "git-receive-pack",
+            Service::UploadPack | Service::ReceivePack => {
+                return Err(Error::from_str("ssh command used without listing refs"))
+            }
+        };
+        let url = SshUrl::parse(url)?;
+        let (mut cmd, variant) = self.command()?;
+        if variant == SshVariant::TortoisePlink {
+            cmd.arg("-batch");
+        }
+        if let Some(port) = url.port {
+            match variant {
+                SshVariant::Ssh => cmd.arg("-p"),
+                SshVariant::Plink | SshVariant::TortoisePlink => cmd.arg("-P"),
+                SshVariant::Simple => {
+                    return Err(Error::from_str(
+                        "ssh variant 'simple' does not support setting port",
+                    ))
+                }
+            };
+            cmd.arg(port.to_string());
+        }
+        if variant == SshVariant::Ssh {
+            // Nothing after this is an option, whatever the host looks like.
+            cmd.arg("--");
+        }
+        match url.user {
+            Some(ref user) => cmd.arg(format!("{}@{}", user, url.host)),
+            None => cmd.arg(&url.host),
+        };
+        cmd.arg(format!("{} {}", service, sq_quote(&url.path)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| Error::from_str(&format!("failed to run ssh command {:?}: {}", cmd, e)))?;
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = thread::spawn(move || {
+            let mut out = String::new();
+            drop(stderr.read_to_string(&mut out));
+            out
+        });
+        Ok(Box::new(SshStream {
+            stdin: child.stdin.take(),
+            stdout: child.stdout.take().unwrap(),
+            stderr: Some(stderr),
+            child,
+        }))
+    }
+
+    fn close(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+impl SshCommand {
+    fn command(&self) -> Result<(Command, SshVariant), Error> {
+        let shell = self
+            .command
+            .clone()
+            .or_else(|| env::var("GIT_SSH_COMMAND").ok());
+        let (cmd, program) = match shell {
+            Some(shell) => {
+                let program = shell.split_whitespace().next().unwrap_or("").to_string();
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(format!("{} \"$@\"", shell)).arg(shell);
+                (cmd, program)
+            }
+            None => {
+                let program = env::var_os("GIT_SSH").unwrap_or_else(|| "ssh".into());
+                let cmd = Command::new(&program);
+                (cmd, program.to_string_lossy().into_owned())
+            }
+        };
+        let variant = match env::var("GIT_SSH_VARIANT") {
+            Ok(variant) => SshVariant::from_name(&variant).ok_or_else(|| {
+                Error::from_str(&format!("unknown value for GIT_SSH_VARIANT: '{}'", variant))
+            })?,
+            Err(_) => SshVariant::guess(&program),
+        };
+        Ok((cmd, variant))
+    }
+}
+
+impl SshVariant {
+    fn from_name(name: &str) -> Option<SshVariant> {
+        match name {
+            "auto" | "ssh" => Some(SshVariant::Ssh),
+            "plink" | "putty" => Some(SshVariant::Plink),
+            "tortoiseplink" => Some(SshVariant::TortoisePlink),
+            "simple" => Some(SshVariant::Simple),
+            _ => None,
+        }
+    }
+
+    /// Guess the variant from the name of the program, like git does.
+    fn guess(program: &str) -> SshVariant {
+        let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+        let name = name.strip_suffix(".exe").unwrap_or(name);
+        match &*name.to_lowercase() {
+            "plink" | "putty" => SshVariant::Plink,
+            "tortoiseplink" => SshVariant::TortoisePlink,
+            _ => SshVariant::Ssh,
+        }
+    }
+}
+
+impl SshUrl {
+    fn parse(url: &str) -> Result<SshUrl, Error> {
+        let invalid = || Error::from_str(&format!("invalid ssh url '{}'", url));
+        let scp_like = !url.contains("://");
+        let (authority, path) = match url.split_once("://") {
+            Some((_, rest)) => {
+                let i = rest.find('/').ok_or_else(invalid)?;
+                let path = &rest[i..];
+                // Like git, `/~user/repo` is relative to the home directory.
+                (
+                    &rest[..i],
+                    path.strip_prefix('/')
+                        .filter(|p| p.starts_with('~'))
+                        .unwrap_or(path),
+                )
+            }
+            None => {
+                // Skip over the brackets of an IPv6 address.
+                let colon = url.find(':').ok_or_else(invalid)?;
+                let end = match url[..colon].find('[') {
+                    Some(start) => start + url[start..].find(']').ok_or_else(invalid)?,
+                    None => 0,
+                };
+                let i = end + url[end..].find(':').ok_or_else(invalid)?;
+                (&url[..i], &url[i + 1..])
+            }
+        };
+        let (user, hostport) = match authority.rsplit_once('@') {
+            Some((user, hostport)) => (Some(user), hostport),
+            None => (None, authority),
+        };
+        let (host, port) = match hostport.strip_prefix('[') {
+            Some(rest) => {
+                let (host, rest) = rest.split_once(']').ok_or_else(invalid)?;
+                (host, rest.strip_prefix(':'))
+            }
+            None if scp_like => (hostport, None),
+            None => match hostport.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (hostport, None),
+            },
+        };
+        if host.is_empty() || path.is_empty() {
+            return Err(invalid());
+        }
+        // Anything starting with a dash would be taken as an option by ssh,
+        // like `-oProxyCommand=...` (CVE-2017-1000117).
+        if user.is_some_and(|u| u.starts_with('-')) || host.starts_with('-') {
+            return Err(Error::from_str(&format!(
+                "strange hostname '{}' blocked",
+                hostport
+            )));
+        }
+        let port = match port.filter(|p| !p.is_empty()) {
+            Some(port) => Some(
+                port.parse::<u16>()
+                    .map_err(|_| Error::from_str(&format!("strange port '{}' blocked", port)))?,
+            ),
+            None => None,
+        };
+        Ok(SshUrl {
+            user: user.map(|u| u.to_string()),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Quote `s` for the shell on the other end, the way git does.
+fn sq_quote(s: &str) -> String {
+    let mut out = String::from("'");
+    for c in s.chars() {
+        match c {
+            '\'' | '!' => {
+                out.push_str("'\\");
+                out.push(c);
+                out.push('\'');
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('\'');
+    out
+}
+
+impl Read for SshStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            // The command exited, report why if it failed.
+            let status = self.child.wait()?;
+            if !status.success() {
+                let stderr = match self.stderr.take() {
+                    Some(stderr) => stderr.join().unwrap_or_default(),
+                    None => String::new(),
+                };
+                return Err(io::Error::other(format!(
+                    "ssh command failed with {}: {}",
+                    status,
+                    stderr.trim()
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SshStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stdin.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdin.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for SshStream {
+    fn drop(&mut self) {
+        // Closing stdin tells the command on the other end that we're done.
+        drop(self.stdin.take());
+        drop(self.child.wait());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{ErrorClass, ErrorCode};
+    use crate::build::RepoBuilder;
+    use crate::RemoteCallbacks;
+    use crate::{Direction, ErrorClass, ErrorCode, FetchOptions, FetchPrune, ProxyOptions};
     use std::sync::Once;
+    use tempfile::TempDir;
 
     struct DummyTransport;
 
@@ -418,4 +1506,351 @@ mod tests {
             Err(e) => assert_eq!(e, dummy_error()),
         }
     }
+
+    struct InspectTransport {
+        owner: Option<SmartTransport>,
+        seen: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl SmartSubtransport for InspectTransport {
+        fn action(
+            &self,
+            _url: &str,
+            _service: Service,
+        ) -> Result<Box<dyn SmartSubtransportStream>, Error> {
+            let owner = self.owner.as_ref().unwrap();
+            let cred = owner.credentials(Some("user"), CredentialType::USER_PASS_PLAINTEXT)?;
+            let (username, password) = cred.userpass().unwrap();
+            let opts = owner.connect_options()?;
+            let mut seen = self.seen.lock().unwrap();
+            seen.push(format!("{}:{}", username, password));
+            seen.extend(opts.custom_headers().iter().map(|s| s.to_string()));
+            seen.extend(opts.proxy_url().map(|s| s.to_string()));
+            seen.push(format!("{:?}", opts.follow_redirects()));
+            Err(dummy_error())
+        }
+
+        fn close(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn attach(&mut self, transport: SmartTransport) {
+            self.owner = Some(transport);
+        }
+    }
+
+    #[test]
+    fn smart_transport_options() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        unsafe {
+            t!(register("git2-inspect", move |remote| {
+                let subtransport = InspectTransport {
+                    owner: None,
+                    seen: seen2.clone(),
+                };
+                Transport::smart(remote, true, subtransport)
+            }));
+        }
+
+        let (_td, repo) = crate::test::repo_init();
+        let mut remote = t!(repo.remote_anonymous("git2-inspect://example.com/repo"));
+        let mut cb = RemoteCallbacks::new();
+        cb.credentials(|url, username, allowed| {
+            assert_eq!(url, "git2-inspect://example.com/repo");
+            assert_eq!(username, Some("user"));
+            assert!(allowed.is_user_pass_plaintext());
+            Cred::userpass_plaintext("user", "secret")
+        });
+        let mut proxy = ProxyOptions::new();
+        proxy.url("http://proxy.example.com:3128");
+        let mut opts = FetchOptions::new();
+        opts.remote_callbacks(cb)
+            .proxy_options(proxy)
+            .follow_redirects(RemoteRedirect::All)
+            .custom_headers(&["X-Custom: 1"]);
+        let err = remote.fetch(&["main"], Some(&mut opts), None).unwrap_err();
+        assert_eq!(err, dummy_error());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                "user:secret",
+                "X-Custom: 1",
+                "http://proxy.example.com:3128",
+                "All"
+            ]
+        );
+
+        // Without a callback there is nothing to ask for credentials.
+        let err = remote.fetch(&["main"], None, None).unwrap_err();
+        assert_eq!(err.raw_code(), raw::GIT_PASSTHROUGH);
+    }
+
+    struct OperationTransport {
+        owner: Option<SmartTransport>,
+        seen: Arc<Mutex<Vec<Option<Vec<String>>>>>,
+    }
+
+    impl SmartSubtransport for OperationTransport {
+        fn action(
+            &self,
+            _url: &str,
+            _service: Service,
+        ) -> Result<Box<dyn SmartSubtransportStream>, Error> {
+            let operation = self.owner.as_ref().unwrap().operation()?;
+            let refspecs = operation.refspecs().map(|r| r.to_vec());
+            self.seen.lock().unwrap().push(refspecs);
+            Err(dummy_error())
+        }
+
+        fn close(&self) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn attach(&mut self, transport: SmartTransport) {
+            self.owner = Some(transport);
+        }
+    }
+
+    #[test]
+    fn operation_refspecs() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen2 = seen.clone();
+        unsafe {
+            t!(register("git2-operation", move |remote| {
+                let subtransport = OperationTransport {
+                    owner: None,
+                    seen: seen2.clone(),
+                };
+                Transport::smart(remote, true, subtransport)
+            }));
+        }
+
+        let (_td, repo) = crate::test::repo_init();
+        let mut remote = t!(repo.remote("origin", "git2-operation://example.com/repo"));
+        assert!(remote.fetch(&["refs/pull/1/head"], None, None).is_err());
+        assert!(remote.fetch(&[] as &[&str], None, None).is_err());
+        assert!(remote.connect(Direction::Fetch).is_err());
+        assert!(remote.push(&["refs/heads/main"], None).is_err());
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                Some(vec!["refs/pull/1/head".to_string()]),
+                Some(vec![]),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn in_process() {
+        let (td, upstream) = crate::test::repo_init();
+        let (first, _) = crate::test::commit(&upstream);
+        let (second, _) = crate::test::commit(&upstream);
+        let base = t!(upstream.find_commit(first)).parent_id(0).unwrap();
+        t!(upstream.reference("refs/heads/old", base, false, "old"));
+        unsafe {
+            t!(register("git2-mem", super::in_process(upstream)));
+        }
+        let upstream = t!(Repository::open(td.path()));
+
+        let clone_td = TempDir::new().unwrap();
+        let repo = t!(RepoBuilder::new().clone("git2-mem://upstream", clone_td.path()));
+        assert_eq!(t!(repo.refname_to_id("refs/remotes/origin/main")), second);
+        assert_eq!(t!(repo.refname_to_id("refs/remotes/origin/old")), base);
+
+        let mut config = t!(repo.config());
+        t!(config.set_str("user.name", "name"));
+        t!(config.set_str("user.email", "email"));
+        let (third, _) = crate::test::commit(&repo);
+        let mut origin = t!(repo.find_remote("origin"));
+        t!(origin.push(&["refs/heads/main"], None));
+        assert_eq!(t!(upstream.refname_to_id("refs/heads/main")), third);
+
+        t!(t!(upstream.find_reference("refs/heads/old")).delete());
+        let mut opts = FetchOptions::new();
+        opts.prune(FetchPrune::On);
+        t!(origin.fetch(&[] as &[&str], Some(&mut opts), None));
+        assert!(repo.find_reference("refs/remotes/origin/old").is_err());
+
+        let shallow_td = TempDir::new().unwrap();
+        let mut opts = FetchOptions::new();
+        opts.depth(2);
+        let shallow = t!(RepoBuilder::new()
+            .fetch_options(opts)
+            .clone("git2-mem://upstream", shallow_td.path()));
+        assert!(shallow.is_shallow());
+        assert!(shallow.find_commit(second).is_ok());
+        assert!(shallow.find_commit(first).is_err());
+    }
+
+    #[test]
+    fn bundle() {
+        unsafe {
+            t!(register("git2-bundle", super::bundle()));
+        }
+        let (_td, upstream) = crate::test::repo_init();
+        let base = t!(upstream.refname_to_id("HEAD"));
+        let td = TempDir::new().unwrap();
+        let full = td.path().join("full.bundle");
+        let mut walk = t!(upstream.revwalk());
+        t!(walk.push_head());
+        t!(crate::bundle::BundleBuilder::new(&upstream)
+            .reference("main")
+            .write_to_path(&mut walk, &full));
+
+        let clone_td = TempDir::new().unwrap();
+        let url = format!("git2-bundle://{}", full.display());
+        let repo = t!(RepoBuilder::new().clone(&url, clone_td.path()));
+        assert_eq!(t!(repo.refname_to_id("refs/remotes/origin/main")), base);
+
+        let (tip, _) = crate::test::commit(&upstream);
+        let incremental = td.path().join("incremental.bundle");
+        let mut walk = t!(upstream.revwalk());
+        t!(walk.push(tip));
+        t!(walk.hide(base));
+        t!(crate::bundle::BundleBuilder::new(&upstream)
+            .reference("main")
+            .write_to_path(&mut walk, &incremental));
+        let url = format!("git2-bundle://{}", incremental.display());
+        t!(t!(Bundle::open(&incremental)).verify(&repo));
+        let mut remote = t!(repo.remote_anonymous(&url));
+        t!(remote.fetch(&["refs/heads/main:refs/remotes/origin/main"], None, None));
+        assert_eq!(t!(repo.refname_to_id("refs/remotes/origin/main")), tip);
+
+        assert!(remote.push(&["refs/heads/main"], None).is_err());
+    }
+
+    #[test]
+    fn ssh_url() {
+        let url = |user: Option<&str>, host: &str, port: Option<u16>, path: &str| SshUrl {
+            user: user.map(|s| s.to_string()),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        };
+        assert_eq!(
+            t!(SshUrl::parse("ssh://git@example.com:2222/a/b.git")),
+            url(Some("git"), "example.com", Some(2222), "/a/b.git")
+        );
+        assert_eq!(
+            t!(SshUrl::parse("ssh://example.com/~user/repo")),
+            url(None, "example.com", None, "~user/repo")
+        );
+        assert_eq!(
+            t!(SshUrl::parse("ssh://[::1]:22/repo")),
+            url(None, "::1", Some(22), "/repo")
+        );
+        assert_eq!(
+            t!(SshUrl::parse("git@example.com:a/b.git")),
+            url(Some("git"), "example.com", None, "a/b.git")
+        );
+        assert_eq!(
+            t!(SshUrl::parse("[::1]:repo")),
+            url(None, "::1", None, "repo")
+        );
+        assert!(SshUrl::parse("ssh://example.com").is_err());
+        assert!(SshUrl::parse("example.com").is_err());
+        assert_eq!(sq_quote("it's!"), r"'it'\''s'\!''");
+
+        // Hosts, users and ports which ssh would take as options.
+        for bad in [
+            "ssh://-oProxyCommand=touch%20pwned/repo",
+            "ssh://-oProxyCommand=x@example.com/repo",
+            "ssh://example.com:-oProxyCommand=x/repo",
+            "ssh://example.com:22x/repo",
+            "-oProxyCommand=x:repo",
+            "[-oProxyCommand=x]:repo",
+        ] {
+            assert!(SshUrl::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn ssh_variant() {
+        assert_eq!(SshVariant::guess("ssh"), SshVariant::Ssh);
+        assert_eq!(SshVariant::guess("/usr/bin/ssh"), SshVariant::Ssh);
+        assert_eq!(
+            SshVariant::guess(r"C:\Program Files\PuTTY\plink.exe"),
+            SshVariant::Plink
+        );
+        assert_eq!(
+            SshVariant::guess("TortoisePlink.exe"),
+            SshVariant::TortoisePlink
+        );
+        assert_eq!(SshVariant::from_name("simple"), Some(SshVariant::Simple));
+        assert_eq!(SshVariant::from_name("bogus"), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn ssh_command() {
+        use std::os::unix::prelude::*;
+
+        let td = TempDir::new().unwrap();
+        let log = td.path().join("log");
+        let ssh = td.path().join("ssh");
+        let fail = td.path().join("fail");
+        t!(std::fs::write(
+            &ssh,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> '{}'\nfor arg; do :; done\nexec sh -c \"$arg\"\n",
+                log.display()
+            )
+        ));
+        t!(std::fs::write(
+            &fail,
+            "#!/bin/sh\necho 'no route' >&2\nexit 255\n"
+        ));
+        for script in [&ssh, &fail] {
+            t!(std::fs::set_permissions(
+                script,
+                std::fs::Permissions::from_mode(0o755)
+            ));
+        }
+        unsafe {
+            t!(register(
+                "git2-ssh",
+                super::ssh_command(Some(ssh.to_str().unwrap()))
+            ));
+            t!(register(
+                "git2-ssh-fail",
+                super::ssh_command(Some(fail.to_str().unwrap()))
+            ));
+        }
+
+        let (upstream_td, upstream) = crate::test::repo_init();
+        let path = upstream_td.path().to_str().unwrap().to_string();
+        t!(upstream.config())
+            .set_bool("receive.denyCurrentBranch", false)
+            .unwrap();
+        let url = format!("git2-ssh://user@example.com:2222{}", path);
+        let clone_td = TempDir::new().unwrap();
+        let repo = t!(RepoBuilder::new().clone(&url, clone_td.path()));
+        let head = t!(upstream.refname_to_id("refs/heads/main"));
+        assert_eq!(t!(repo.refname_to_id("refs/remotes/origin/main")), head);
+
+        let mut origin = t!(repo.find_remote("origin"));
+        t!(origin.push(&["refs/heads/main:refs/heads/other"], None));
+        assert_eq!(t!(upstream.refname_to_id("refs/heads/other")), head);
+
+        let log = t!(std::fs::read_to_string(&log));
+        assert_eq!(
+            log,
+            format!(
+                "-p 2222 -- user@example.com git-upload-pack '{0}'\n\
+                 -p 2222 -- user@example.com git-receive-pack '{0}'\n",
+                path
+            )
+        );
+
+        let clone_td = TempDir::new().unwrap();
+        let err =
+            match RepoBuilder::new().clone("git2-ssh-fail://example.com/repo", clone_td.path()) {
+                Ok(_) => panic!("clone should fail"),
+                Err(e) => e,
+            };
+        assert!(err.message().contains("no route"), "{}", err);
+    }
 }
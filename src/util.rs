@@ -268,9 +268,132 @@ fn fixup_windows_path(path: CString) -> Result<CString, Error> {
     Ok(path)
 }
 
+#[derive(Clone)]
+/// A SHA-1 hasher, to find the end of the packs clients push.
+///
+/// libgit2 verifies the checksum of packs itself, so this is only used to
+/// split the stream, never to trust data.
+pub(crate) struct Sha1 {
+    state: [u32; 5],
+    buf: Vec<u8>,
+    len: u64,
+}
+
+impl Default for Sha1 {
+    fn default() -> Sha1 {
+        Sha1 {
+            state: [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0],
+            buf: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl Sha1 {
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        self.len += data.len() as u64;
+        self.buf.extend_from_slice(data);
+        let full = self.buf.len() / 64 * 64;
+        for block in self.buf[..full].chunks(64) {
+            sha1_block(&mut self.state, block);
+        }
+        self.buf.drain(..full);
+    }
+
+    pub(crate) fn finish(mut self) -> [u8; 20] {
+        let bits = self.len * 8;
+        self.buf.push(0x80);
+        while self.buf.len() % 64 != 56 {
+            self.buf.push(0);
+        }
+        self.buf.extend_from_slice(&bits.to_be_bytes());
+        for block in self.buf.chunks(64) {
+            sha1_block(&mut self.state, block);
+        }
+        let mut out = [0; 20];
+        for (chunk, word) in out.chunks_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        out
+    }
+}
+
+fn sha1_block(state: &mut [u32; 5], block: &[u8]) {
+    let mut w = [0u32; 80];
+    for (i, word) in block.chunks(4).enumerate() {
+        w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+    }
+    for i in 16..80 {
+        w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+    }
+    let [mut a, mut b, mut c, mut d, mut e] = *state;
+    for (i, w) in w.iter().enumerate() {
+        let (f, k) = match i {
+            0..=19 => ((b & c) | (!b & d), 0x5a827999),
+            20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+            40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+            _ => (b ^ c ^ d, 0xca62c1d6),
+        };
+        let temp = a
+            .rotate_left(5)
+            .wrapping_add(f)
+            .wrapping_add(e)
+            .wrapping_add(k)
+            .wrapping_add(*w);
+        e = d;
+        d = c;
+        c = b.rotate_left(30);
+        b = a;
+        a = temp;
+    }
+    for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+        *s = s.wrapping_add(v);
+    }
+}
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encode `data` as standard base64, with padding.
+pub(crate) fn base64(data: &[u8]) -> String {
+    let mut ret = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, b)| n | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                ret.push(BASE64[(n >> (18 - 6 * i)) as usize & 63] as char);
+            } else {
+                ret.push('=');
+            }
+        }
+    }
+    ret
+}
+
+/// Decode standard base64, with or without padding.
+pub(crate) fn unbase64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut ret = Vec::with_capacity(s.len() * 3 / 4);
+    let mut n = 0u32;
+    let mut bits = 0;
+    for c in s.bytes() {
+        let v = BASE64.iter().position(|b| *b == c)? as u32;
+        n = n << 6 | v;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            ret.push((n >> bits) as u8);
+        }
+    }
+    Some(ret)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{ObjectType, Oid};
 
     macro_rules! assert_err {
         ($path:expr, $msg:expr) => {
@@ -352,4 +475,23 @@ mod tests {
         assert_err!(r"\foo", r"repo path `\foo` should be relative");
         assert_err!(r"/foo", r"repo path `/foo` should be relative");
     }
+
+    #[test]
+    fn sha1() {
+        let data = b"abc".repeat(100);
+        let mut hash = Sha1::default();
+        hash.update(b"blob 300\0");
+        hash.update(&data);
+        let expected = Oid::hash_object(ObjectType::Blob, &data).unwrap();
+        assert_eq!(&hash.finish()[..], expected.as_bytes());
+    }
+
+    #[test]
+    fn base64() {
+        let key = "AAAAC3NzaC1lZDI1NTE5AAAAIJf9OQa00Gm6jXgeT4Ge0fIPpKM20srq7kOfP7zF7XPM";
+        assert_eq!(super::base64(&super::unbase64(key).unwrap()), key);
+        assert_eq!(super::base64(b"ab"), "YWI=");
+        assert_eq!(super::unbase64("YWI").unwrap(), b"ab");
+        assert!(super::unbase64("not base64!").is_none());
+    }
 }
@@ -14,6 +14,11 @@ fn main() {
         .header("git2/sys/odb_backend.h")
         .header("git2/sys/mempack.h")
         .header("git2/sys/repository.h")
+        .header("git2/sys/config.h")
+        .header("git2/sys/filter.h")
+        .header("git2/sys/merge.h")
+        .header("git2/sys/remote.h")
+        .header("git2/sys/stream.h")
         .header("git2/sys/cred.h")
         .header("git2/sys/email.h")
         .header("git2/cred_helpers.h")
@@ -26,7 +31,9 @@ fn main() {
         // this field is marked as const which ctest complains about
         (struct_ == "git_rebase_operation" && f == "id") ||
         // the real name of this field is ref but that is a reserved keyword
-        (struct_ == "git_worktree_add_options" && f == "reference")
+        (struct_ == "git_worktree_add_options" && f == "reference") ||
+        // the `encrypted` and `proxy_support` bitfields are bound as one field
+        (struct_ == "git_stream" && f == "flags")
     });
     cfg.skip_signededness(|s| match s {
         s if s.ends_with("_cb") => true,